@@ -1,6 +1,7 @@
 window.onload = load_defaults_values
 
-const BASEURL = "http://localhost:9000";
+// The page is served by the vault itself, its CSP only allows calls back to it
+const BASEURL = window.location.origin;
 
 function load_defaults_values() {
     let nip5id = document.getElementById("nostr-id");
@@ -16,6 +17,8 @@ function load_defaults_values() {
     let examplePassword = document.getElementById("password");
     examplePassword.value = "stack_sats_and_pet_cats";
 
+    document.getElementById("upload-key").addEventListener("click", send_key);
+    document.getElementById("fetch-key").addEventListener("click", get_key);
 }
 
 
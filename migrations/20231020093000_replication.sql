@@ -0,0 +1,4 @@
+-- Track when and where a row was last written so peers can resolve conflicts
+ALTER TABLE keys
+    ADD COLUMN updated_at TIMESTAMPTZ NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    ADD COLUMN version_vector JSONB NOT NULL DEFAULT '{}'::jsonb;
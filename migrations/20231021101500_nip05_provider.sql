@@ -0,0 +1,4 @@
+-- Public key and relays served from /.well-known/nostr.json
+ALTER TABLE keys
+    ADD COLUMN pubkey TEXT,
+    ADD COLUMN relays TEXT[] NOT NULL DEFAULT '{}';
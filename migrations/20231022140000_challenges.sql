@@ -0,0 +1,9 @@
+-- Single use nonces handed out to clients, e.g. to prove possession of a key on upload
+CREATE TABLE challenges(
+    nonce TEXT NOT NULL,
+    purpose TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    expires_at TIMESTAMPTZ NOT NULL,
+    used_at TIMESTAMPTZ,
+    PRIMARY KEY(nonce)
+);
@@ -0,0 +1,11 @@
+-- One-time codes that can be used instead of the pin, stored as argon2 hashes
+CREATE TABLE recovery_codes(
+    id BIGINT NOT NULL GENERATED BY DEFAULT AS IDENTITY,
+    key_id BIGINT NOT NULL REFERENCES keys(id) ON DELETE CASCADE,
+    code_hash TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    used_at TIMESTAMPTZ,
+    PRIMARY KEY(id)
+);
+
+CREATE INDEX recovery_codes_key_id_idx ON recovery_codes(key_id);
@@ -0,0 +1,9 @@
+-- Encrypted TOTP secrets, only enforced once confirmed with a first valid code
+CREATE TABLE totp_secrets(
+    key_id BIGINT NOT NULL REFERENCES keys(id) ON DELETE CASCADE,
+    encrypted_secret TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    confirmed_at TIMESTAMPTZ,
+    last_used_step BIGINT,
+    PRIMARY KEY(key_id)
+);
@@ -0,0 +1,24 @@
+-- WebAuthn credentials, either a second factor or, with replaces_pin, a way in without the pin
+CREATE TABLE passkeys(
+    id BIGINT NOT NULL GENERATED BY DEFAULT AS IDENTITY,
+    key_id BIGINT NOT NULL REFERENCES keys(id) ON DELETE CASCADE,
+    credential_id TEXT NOT NULL UNIQUE,
+    passkey JSONB NOT NULL,
+    sign_count BIGINT NOT NULL DEFAULT 0,
+    replaces_pin BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    last_used_at TIMESTAMPTZ,
+    PRIMARY KEY(id)
+);
+
+CREATE INDEX passkeys_key_id_idx ON passkeys(key_id);
+
+-- Registration and authentication state between the start and finish requests
+CREATE TABLE passkey_ceremonies(
+    id UUID NOT NULL,
+    key_id BIGINT NOT NULL REFERENCES keys(id) ON DELETE CASCADE,
+    kind TEXT NOT NULL,
+    state JSONB NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL,
+    PRIMARY KEY(id)
+);
@@ -0,0 +1,6 @@
+-- Lets inactive rows be warned about and purged after a retention period
+ALTER TABLE keys
+    ADD COLUMN last_accessed_at TIMESTAMPTZ NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    ADD COLUMN expiry_warned_at TIMESTAMPTZ;
+
+CREATE INDEX keys_last_accessed_at_idx ON keys (last_accessed_at);
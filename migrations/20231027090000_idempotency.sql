@@ -0,0 +1,12 @@
+-- Responses to requests sent with an Idempotency-Key, replayed to clients that retry them
+CREATE TABLE idempotency(
+    idempotency_key TEXT NOT NULL,
+    scope TEXT NOT NULL,
+    request_fingerprint TEXT NOT NULL,
+    encrypted_response TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    expires_at TIMESTAMPTZ NOT NULL,
+    PRIMARY KEY(idempotency_key, scope)
+);
+
+CREATE INDEX idempotency_expires_at_idx ON idempotency (expires_at);
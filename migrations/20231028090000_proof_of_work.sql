@@ -0,0 +1,10 @@
+-- Proof of work challenges only count for the nip 05 id they were issued for
+ALTER TABLE challenges ADD COLUMN subject TEXT;
+
+-- Failed lookups per nip 05 id, each one raises the fetch proof of work difficulty for a while
+CREATE TABLE failed_attempts(
+    nip_05_id TEXT NOT NULL,
+    attempted_at TIMESTAMPTZ NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc')
+);
+
+CREATE INDEX failed_attempts_nip_05_id_idx ON failed_attempts (nip_05_id, attempted_at);
@@ -0,0 +1,2 @@
+-- Lets per-domain quotas count the keys of a domain without a full scan
+CREATE INDEX keys_domain_idx ON keys (lower(substring(nip_05_id from '@([^@]*)$')));
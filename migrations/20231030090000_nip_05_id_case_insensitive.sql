@@ -0,0 +1,32 @@
+-- nip 05 ids are case-insensitive and stored lowercase from now on. Where several rows lower
+-- to the same id, the most recently accessed one keeps it and the others are moved to
+-- legacy_keys, so the unique index can be built without dropping anyone's blob. Rows whose
+-- id still needs punycode or no longer parses are handled on startup, see
+-- `normalize_stored_nip_05_ids`.
+CREATE TABLE legacy_keys (
+    id BIGINT NOT NULL PRIMARY KEY,
+    nip_05_id TEXT NOT NULL,
+    key_row JSONB NOT NULL,
+    reason TEXT NOT NULL,
+    moved_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+WITH ranked AS (
+    SELECT id, row_number() OVER (
+        PARTITION BY lower(nip_05_id)
+        ORDER BY last_accessed_at DESC, updated_at DESC, id DESC
+    ) AS rank
+    FROM keys
+),
+moved AS (
+    INSERT INTO legacy_keys (id, nip_05_id, key_row, reason)
+    SELECT keys.id, keys.nip_05_id, to_jsonb(keys), 'case collision'
+    FROM keys JOIN ranked USING (id)
+    WHERE ranked.rank > 1
+    RETURNING id
+)
+DELETE FROM keys WHERE id IN (SELECT id FROM moved);
+
+UPDATE keys SET nip_05_id = lower(nip_05_id) WHERE nip_05_id <> lower(nip_05_id);
+
+CREATE UNIQUE INDEX keys_nip_05_id_lower_idx ON keys (lower(nip_05_id));
@@ -0,0 +1,7 @@
+-- Deleted keys leave a tombstone with their last version vector behind, so a peer still
+-- holding an older copy deletes it instead of sending it back
+CREATE TABLE key_tombstones (
+    nip_05_id TEXT NOT NULL PRIMARY KEY,
+    version_vector JSONB NOT NULL,
+    deleted_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,5 @@
+-- The first characters of each code, so a recovery attempt only verifies the one hash it can
+-- match. Codes issued before have none and are still checked one by one until used.
+ALTER TABLE recovery_codes ADD COLUMN code_prefix TEXT;
+
+CREATE INDEX recovery_codes_key_id_code_prefix_idx ON recovery_codes(key_id, code_prefix);
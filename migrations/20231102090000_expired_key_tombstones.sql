@@ -0,0 +1,3 @@
+-- Tombstones left by key expiry only stand in for the local copy. They keep the row's version
+-- vector unchanged and are never sent to peers, so a peer's live copy is not deleted by them
+ALTER TABLE key_tombstones ADD COLUMN expired BOOLEAN NOT NULL DEFAULT FALSE;
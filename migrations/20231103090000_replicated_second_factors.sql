@@ -0,0 +1,10 @@
+-- Second factors stay on the vault they were enrolled on, peers only learn that there is one
+-- and refuse the key rather than serve it with the pin alone
+ALTER TABLE keys ADD COLUMN second_factor_enrolled BOOLEAN NOT NULL DEFAULT FALSE;
+
+UPDATE keys SET second_factor_enrolled = TRUE
+WHERE id IN (
+    SELECT key_id FROM totp_secrets WHERE confirmed_at IS NOT NULL
+    UNION
+    SELECT key_id FROM passkeys
+);
@@ -0,0 +1,6 @@
+-- Keys are chosen by clients, scope them to the caller so one client can not block or probe
+-- another's. Stored responses are bound to the old primary key, so they can't be kept.
+DELETE FROM idempotency;
+ALTER TABLE idempotency ADD COLUMN caller TEXT NOT NULL;
+ALTER TABLE idempotency DROP CONSTRAINT idempotency_pkey;
+ALTER TABLE idempotency ADD PRIMARY KEY (idempotency_key, scope, caller);
@@ -1,16 +1,31 @@
-use crate::domain::{KeyInfo, Lookup, Pin, RowData};
-use crate::telemetry::spawn_blocking_with_tracing;
+use crate::domain::{
+    KeyInfo, Lookup, Nip05ID, NostrPublicKey, Pin, PrivateKeyHash, RecoveryCode, RowData,
+    VersionVector,
+};
+use crate::hashing::spawn_hashing;
+use crate::metrics::{PIN_VERIFICATIONS, PIN_VERIFICATION_DURATION};
+use crate::recovery::RecoveryCodes;
 use anyhow::Context;
 use argon2::password_hash::SaltString;
 use argon2::{Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version};
 use secrecy::{ExposeSecret, Secret};
-use sqlx::PgPool;
+use sqlx::{PgPool, Postgres, Transaction};
 use utoipa::ToSchema;
 
 #[derive(thiserror::Error, Debug)]
 pub enum AuthError {
     #[error("Invalid pin.")]
     InvalidPin(#[source] anyhow::Error),
+    #[error("Invalid recovery code.")]
+    InvalidRecoveryCode(#[source] anyhow::Error),
+    #[error("Second factor required.")]
+    SecondFactorRequired,
+    #[error("Second factor is enrolled on another vault.")]
+    SecondFactorElsewhere,
+    #[error("Invalid second factor.")]
+    InvalidSecondFactor(#[source] anyhow::Error),
+    #[error("Invalid passkey.")]
+    InvalidPasskey(#[source] anyhow::Error),
     #[error(transparent)]
     UnexpectedError(#[from] anyhow::Error),
 }
@@ -27,6 +42,80 @@ pub struct StoredKey {
         example = "$PBKDF2$i=100000,l=256,s=0Bu5lWu4s66/iottrlUGdckjf5nwnpB05jwp4yDh8NU=$AESGM$OrScsD+hHGaRaPbc$XMXVVbjt3JV+QsNb7ZWRc8uNod2YzJL0lSvW1FOiY38ywOu7IEChKs/IqEQ7knhZAmRGYqoB4dhAbdOTwVhYIeQsuf1+f+9ARPEjtURsDg=="
     )]
     pub private_key_hash: String,
+    /// One-time codes that can replace the pin, only returned once, by `upload_key`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[schema(example = json!(["ABCDE-FGH23", "JKMNP-QRS45"]))]
+    pub recovery_codes: Vec<String>,
+}
+
+/// What is stored alongside a key, without any of the secrets.
+#[derive(ToSchema, serde::Serialize, serde::Deserialize)]
+pub struct KeySlots {
+    #[schema(example = "the_name_is_bob_bob_smith@frogs.cloud")]
+    pub nip_05_id: String,
+    /// When the pin or private key hash were last replaced.
+    #[schema(example = "2023-02-12T01:49:35+00:00")]
+    pub updated_at: String,
+    #[schema(example = 9)]
+    pub unused_recovery_codes: i64,
+    /// A confirmed TOTP secret is enrolled.
+    pub totp: bool,
+    pub passkeys: Vec<PasskeySlot>,
+}
+
+#[derive(ToSchema, serde::Serialize, serde::Deserialize)]
+pub struct PasskeySlot {
+    #[schema(example = 7)]
+    pub id: i64,
+    #[schema(example = "2023-02-12T01:49:35+00:00")]
+    pub created_at: String,
+    #[schema(example = "2023-02-14T08:12:03+00:00")]
+    pub last_used_at: Option<String>,
+}
+
+/// `None` if the key is gone.
+#[tracing::instrument(name = "List key slots", skip(pool))]
+pub async fn get_key_slots(key_id: i64, pool: &PgPool) -> Result<Option<KeySlots>, anyhow::Error> {
+    let key = sqlx::query!(
+        r#"
+        SELECT nip_05_id, updated_at,
+            (SELECT COUNT(*) FROM recovery_codes
+                WHERE key_id = keys.id AND used_at IS NULL) AS "unused_recovery_codes!",
+            EXISTS (SELECT 1 FROM totp_secrets
+                WHERE key_id = keys.id AND confirmed_at IS NOT NULL) AS "totp!"
+        FROM keys
+        WHERE id = $1
+        "#,
+        key_id
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to perform a query to retrieve stored key.")?;
+    let key = match key {
+        Some(key) => key,
+        None => return Ok(None),
+    };
+    let passkeys = sqlx::query!(
+        r#"SELECT id, created_at, last_used_at FROM passkeys WHERE key_id = $1 ORDER BY id"#,
+        key_id
+    )
+    .fetch_all(pool)
+    .await
+    .context("Failed to perform a query to retrieve passkeys.")?
+    .into_iter()
+    .map(|passkey| PasskeySlot {
+        id: passkey.id,
+        created_at: passkey.created_at.to_rfc3339(),
+        last_used_at: passkey.last_used_at.map(|used| used.to_rfc3339()),
+    })
+    .collect();
+    Ok(Some(KeySlots {
+        nip_05_id: key.nip_05_id,
+        updated_at: key.updated_at.to_rfc3339(),
+        unused_recovery_codes: key.unused_recovery_codes,
+        totp: key.totp,
+        passkeys,
+    }))
 }
 
 impl std::fmt::Display for StoredKey {
@@ -36,51 +125,458 @@ impl std::fmt::Display for StoredKey {
     }
 }
 
+/// `None` if the nip 05 id is already stored. The key is stored together with its recovery
+/// codes, which are returned formatted.
 #[tracing::instrument(name = "Store private key and pin", skip(key_info, pool))]
 pub async fn save_private_key_and_pin(
     key_info: &KeyInfo,
+    node_id: &str,
     pool: &PgPool,
-) -> Result<StoredKey, anyhow::Error> {
+) -> Result<Option<StoredKey>, anyhow::Error> {
     let pin = key_info.pin.clone();
-    let pin_hash = spawn_blocking_with_tracing(move || compute_pin_hash(pin))
+    let pin_hash = spawn_hashing(move || compute_pin_hash(pin))
         .await?
         .context("Failed to hash pin.")?;
+    let recovery_codes = RecoveryCodes::generate().await?;
+
+    let relays: Vec<String> = key_info
+        .relays
+        .iter()
+        .map(|relay| relay.as_ref().to_string())
+        .collect();
+
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool.")?;
+    // A key stored again after a delete has to be newer than the delete on every peer
+    let tombstone = sqlx::query!(
+        r#"SELECT version_vector FROM key_tombstones WHERE nip_05_id = $1 FOR UPDATE"#,
+        key_info.nip_05_id.as_ref()
+    )
+    .fetch_optional(&mut transaction)
+    .await
+    .context("Failed to perform a query to retrieve the tombstone.")?;
+    let mut version_vector: VersionVector = match tombstone {
+        Some(tombstone) => serde_json::from_value(tombstone.version_vector)
+            .context("Failed to parse version vector.")?,
+        None => VersionVector::default(),
+    };
+    version_vector.increment(node_id);
 
     let record = sqlx::query!(
         r#"
-    INSERT INTO keys (nip_05_id, pin_hash, private_key_hash)
-    VALUES ($1, $2, $3)
+    INSERT INTO keys (nip_05_id, pin_hash, private_key_hash, version_vector, pubkey, relays)
+    VALUES ($1, $2, $3, $4, $5, $6)
+    ON CONFLICT (nip_05_id) DO NOTHING
     RETURNING id, created_at
         "#,
         key_info.nip_05_id.to_string(),
         pin_hash.expose_secret().to_string(),
-        key_info.private_key_hash.as_ref()
+        key_info.private_key_hash.as_ref(),
+        serde_json::to_value(&version_vector)?,
+        key_info.pubkey.to_string(),
+        relays
     )
-    .fetch_one(pool)
+    .fetch_optional(&mut transaction)
     .await
     .map_err(|e| {
         tracing::error!("Failed to execute query: {:?}.", e);
         e
     })?;
+    let record = match record {
+        Some(record) => record,
+        None => return Ok(None),
+    };
+    sqlx::query!(
+        r#"DELETE FROM key_tombstones WHERE nip_05_id = $1"#,
+        key_info.nip_05_id.as_ref()
+    )
+    .execute(&mut transaction)
+    .await
+    .context("Failed to remove the tombstone.")?;
+    let recovery_codes = recovery_codes.store(record.id, &mut transaction).await?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit new key.")?;
     let stored = StoredKey {
         id: record.id,
         nip_05_id: key_info.nip_05_id.to_string(),
         created_at: record.created_at.to_rfc3339(),
         private_key_hash: key_info.private_key_hash.as_ref().to_string(),
+        recovery_codes: recovery_codes.iter().map(RecoveryCode::formatted).collect(),
     };
-    Ok(stored)
+    Ok(Some(stored))
+}
+
+/// What `update_stored_key` replaces, `None` keeps the current value.
+pub struct KeyChanges {
+    pub pin: Option<Pin>,
+    pub private_key_hash: Option<PrivateKeyHash>,
 }
 
-fn compute_pin_hash(raw_pin: Pin) -> Result<Secret<String>, anyhow::Error> {
+/// Replaces the pin and/or blob of an authenticated key, bumping its version vector so the
+/// change wins on every peer.
+#[tracing::instrument(name = "Update stored key", skip(changes, pool))]
+pub async fn update_stored_key(
+    key_id: i64,
+    changes: KeyChanges,
+    node_id: &str,
+    pool: &PgPool,
+) -> Result<StoredKey, anyhow::Error> {
+    let pin_hash = match changes.pin {
+        Some(pin) => Some(
+            spawn_hashing(move || compute_pin_hash(pin))
+                .await
+                .context("Failed to queue hashing task.")?
+                .context("Failed to hash pin.")?,
+        ),
+        None => None,
+    };
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool.")?;
+    let current = sqlx::query!(
+        r#"SELECT version_vector FROM keys WHERE id = $1 FOR UPDATE"#,
+        key_id
+    )
+    .fetch_one(&mut transaction)
+    .await
+    .context("Failed to perform a query to retrieve stored key.")?;
+    let mut version_vector: VersionVector = serde_json::from_value(current.version_vector)
+        .context("Failed to parse version vector.")?;
+    version_vector.increment(node_id);
+    let row = sqlx::query!(
+        r#"
+        UPDATE keys
+        SET pin_hash = COALESCE($2, pin_hash),
+            private_key_hash = COALESCE($3, private_key_hash),
+            version_vector = $4, updated_at = NOW()
+        WHERE id = $1
+        RETURNING id, created_at, nip_05_id, private_key_hash
+        "#,
+        key_id,
+        pin_hash.as_ref().map(|hash| hash.expose_secret().as_str()),
+        changes.private_key_hash.as_ref().map(|hash| hash.as_ref()),
+        serde_json::to_value(&version_vector).context("Failed to serialize version vector.")?
+    )
+    .fetch_one(&mut transaction)
+    .await
+    .context("Failed to update stored key.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit key update.")?;
+
+    Ok(StoredKey {
+        id: row.id,
+        created_at: row.created_at.to_rfc3339(),
+        nip_05_id: row.nip_05_id,
+        private_key_hash: row.private_key_hash,
+        recovery_codes: vec![],
+    })
+}
+
+/// Removes the key along with its recovery codes and second factors, returns its nip 05 id
+/// so the tombstone left behind can be replicated. `None` if it was already gone.
+#[tracing::instrument(name = "Delete stored key", skip(pool))]
+pub async fn delete_stored_key(
+    key_id: i64,
+    node_id: &str,
+    pool: &PgPool,
+) -> Result<Option<String>, anyhow::Error> {
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool.")?;
+    let nip_05_id = tombstone_key(&mut transaction, key_id, node_id).await?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit key deletion.")?;
+    Ok(nip_05_id)
+}
+
+/// Swaps the key for a tombstone carrying its version vector bumped for `node_id`, so peers
+/// holding an older copy delete theirs rather than sending it back.
+async fn tombstone_key(
+    transaction: &mut Transaction<'_, Postgres>,
+    key_id: i64,
+    node_id: &str,
+) -> Result<Option<String>, anyhow::Error> {
+    let current = sqlx::query!(
+        r#"DELETE FROM keys WHERE id = $1 RETURNING nip_05_id, version_vector"#,
+        key_id
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .context("Failed to delete stored key.")?;
+    let current = match current {
+        Some(current) => current,
+        None => return Ok(None),
+    };
+    let mut version_vector: VersionVector = serde_json::from_value(current.version_vector)
+        .context("Failed to parse version vector.")?;
+    version_vector.increment(node_id);
+    sqlx::query!(
+        r#"
+        INSERT INTO key_tombstones (nip_05_id, version_vector)
+        VALUES ($1, $2)
+        ON CONFLICT (nip_05_id) DO UPDATE
+        SET version_vector = EXCLUDED.version_vector, deleted_at = EXCLUDED.deleted_at,
+            expired = FALSE
+        "#,
+        current.nip_05_id,
+        serde_json::to_value(&version_vector).context("Failed to serialize version vector.")?
+    )
+    .execute(&mut *transaction)
+    .await
+    .context("Failed to store tombstone.")?;
+    Ok(Some(current.nip_05_id))
+}
+
+/// Removes the key from this vault only. The tombstone keeps the key's version vector as it
+/// was and is not offered to peers, so their copies stay, and only a copy changed after the
+/// expiry is merged back in.
+pub(crate) async fn expire_key(
+    transaction: &mut Transaction<'_, Postgres>,
+    key_id: i64,
+) -> Result<Option<String>, anyhow::Error> {
+    let expired = sqlx::query!(
+        r#"
+        WITH expired AS (DELETE FROM keys WHERE id = $1 RETURNING nip_05_id, version_vector)
+        INSERT INTO key_tombstones (nip_05_id, version_vector, expired)
+        SELECT nip_05_id, version_vector, TRUE FROM expired
+        ON CONFLICT (nip_05_id) DO UPDATE
+        SET version_vector = EXCLUDED.version_vector, deleted_at = EXCLUDED.deleted_at,
+            expired = TRUE
+        RETURNING nip_05_id
+        "#,
+        key_id
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .context("Failed to expire stored key.")?;
+    Ok(expired.map(|expired| expired.nip_05_id))
+}
+
+/// Records that a TOTP secret or passkey is enrolled for the key. Returns its nip 05 id the
+/// first time, so the flag can be replicated to peers that can not check the factor.
+#[tracing::instrument(name = "Mark second factor enrolled", skip(pool))]
+pub async fn mark_second_factor_enrolled(
+    key_id: i64,
+    pool: &PgPool,
+) -> Result<Option<String>, anyhow::Error> {
+    Ok(sqlx::query!(
+        r#"
+        UPDATE keys SET second_factor_enrolled = TRUE
+        WHERE id = $1 AND NOT second_factor_enrolled
+        RETURNING nip_05_id
+        "#,
+        key_id
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to mark second factor as enrolled.")?
+    .map(|row| row.nip_05_id))
+}
+
+/// Whether a second factor is enrolled for the key, here or on the vault it was replicated
+/// from.
+#[tracing::instrument(name = "Check for an enrolled second factor", skip(pool))]
+pub async fn is_second_factor_enrolled(key_id: i64, pool: &PgPool) -> Result<bool, anyhow::Error> {
+    Ok(sqlx::query!(
+        r#"SELECT second_factor_enrolled FROM keys WHERE id = $1"#,
+        key_id
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to perform a query to check for a second factor.")?
+    .map_or(false, |row| row.second_factor_enrolled))
+}
+
+/// What `normalize_stored_nip_05_ids` changed, or would change when not applied.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct NormalizationReport {
+    /// Stored id and the normalized id it was rewritten to.
+    pub normalized: Vec<(String, String)>,
+    /// Stored id and why it was moved to `legacy_keys`.
+    pub moved: Vec<(String, &'static str)>,
+}
+
+/// Rewrites ids stored before nip 05 ids were normalized that the migration could not fix in
+/// SQL, i.e. internationalized domains still in unicode. A row whose normalized id is taken,
+/// or whose id no longer parses, is moved to `legacy_keys` for the operator. Unless `apply`
+/// is set nothing is written, the report only tells what would be.
+#[tracing::instrument(name = "Normalize stored nip 05 ids", skip(pool))]
+pub async fn normalize_stored_nip_05_ids(
+    apply: bool,
+    pool: &PgPool,
+) -> Result<NormalizationReport, anyhow::Error> {
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool.")?;
+    let rows = sqlx::query!(
+        r#"
+        SELECT id, nip_05_id FROM keys
+        WHERE nip_05_id !~ '^[a-z0-9._-]+@[a-z0-9.-]+$'
+        ORDER BY id
+        FOR UPDATE
+        "#
+    )
+    .fetch_all(&mut transaction)
+    .await
+    .context("Failed to find nip 05 ids to normalize.")?;
+    let mut report = NormalizationReport::default();
+    for row in rows {
+        let reason = match Nip05ID::parse(row.nip_05_id.clone()) {
+            Ok(nip_05_id) => {
+                let updated = sqlx::query!(
+                    r#"
+                    UPDATE keys SET nip_05_id = $2
+                    WHERE id = $1
+                        AND NOT EXISTS (
+                            SELECT 1 FROM keys other
+                            WHERE lower(other.nip_05_id) = $2 AND other.id <> $1
+                        )
+                    "#,
+                    row.id,
+                    nip_05_id.as_ref()
+                )
+                .execute(&mut transaction)
+                .await
+                .context("Failed to normalize nip 05 id.")?;
+                if updated.rows_affected() == 1 {
+                    tracing::info!(
+                        nip_05_id = %row.nip_05_id,
+                        normalized = %nip_05_id,
+                        apply,
+                        "Normalized nip 05 id."
+                    );
+                    report
+                        .normalized
+                        .push((row.nip_05_id, nip_05_id.to_string()));
+                    continue;
+                }
+                "normalized id taken"
+            }
+            Err(_) => "invalid nip 05 id",
+        };
+        sqlx::query!(
+            r#"
+            WITH moved AS (DELETE FROM keys WHERE id = $1 RETURNING *)
+            INSERT INTO legacy_keys (id, nip_05_id, key_row, reason)
+            SELECT id, nip_05_id, to_jsonb(moved), $2 FROM moved
+            "#,
+            row.id,
+            reason
+        )
+        .execute(&mut transaction)
+        .await
+        .context("Failed to move key to legacy_keys.")?;
+        tracing::warn!(nip_05_id = %row.nip_05_id, reason, apply, "Moved key to legacy_keys.");
+        report.moved.push((row.nip_05_id, reason));
+    }
+    if apply {
+        transaction
+            .commit()
+            .await
+            .context("Failed to commit normalized nip 05 ids.")?;
+    } else {
+        transaction
+            .rollback()
+            .await
+            .context("Failed to roll back nip 05 id normalization.")?;
+    }
+    Ok(report)
+}
+
+/// The public key a blob was uploaded with, replicated rows from older vaults may lack one.
+#[tracing::instrument(name = "Get stored public key", skip(pool))]
+pub async fn get_stored_pubkey(
+    key_id: i64,
+    pool: &PgPool,
+) -> Result<Option<NostrPublicKey>, anyhow::Error> {
+    let pubkey = sqlx::query!(r#"SELECT pubkey FROM keys WHERE id = $1"#, key_id)
+        .fetch_one(pool)
+        .await
+        .context("Failed to perform a query to retrieve the public key.")?
+        .pubkey;
+    pubkey
+        .map(|pubkey| NostrPublicKey::parse(pubkey).map_err(anyhow::Error::msg))
+        .transpose()
+}
+
+pub(crate) fn compute_pin_hash(raw_pin: Pin) -> Result<Secret<String>, anyhow::Error> {
+    compute_secret_hash(raw_pin.as_ref())
+}
+
+/// Argon2id hash in PHC string format, shared by pins and recovery codes.
+pub(crate) fn compute_secret_hash(secret: &str) -> Result<Secret<String>, anyhow::Error> {
     let salt = SaltString::generate(&mut rand::thread_rng());
-    let pin_hash = Argon2::new(
+    let hash = Argon2::new(
         Algorithm::Argon2id,
         Version::V0x13,
         Params::new(15000, 2, 1, None).unwrap(),
     )
-    .hash_password(raw_pin.as_ref().as_bytes(), &salt)?
+    .hash_password(secret.as_bytes(), &salt)?
     .to_string();
-    Ok(Secret::new(pin_hash))
+    Ok(Secret::new(hash))
+}
+
+/// Compared against when there is nothing stored, so misses take as long as hits.
+pub(crate) fn dummy_secret_hash() -> Secret<String> {
+    Secret::new(
+        "$argon2id$v=19$m=15000,t=2,p=1$\
+            gZiV/M1gPc22ElAH/Jh1Hw$\
+            CWOrkoo7oJBQ/iyh7uJ0LO2aLEfrHwTWllSAxT0zRno"
+            .to_string(),
+    )
+}
+
+/// Looks a key up without checking any pin, callers must authenticate the request some other way.
+#[tracing::instrument(name = "Get key by nip 05 id", skip(pool))]
+pub async fn get_key_by_nip_05_id(
+    nip_05_id: &Nip05ID,
+    pool: &PgPool,
+) -> Result<Option<StoredKey>, anyhow::Error> {
+    Ok(sqlx::query!(
+        r#"
+        SELECT id, created_at, nip_05_id, private_key_hash
+        FROM keys
+        WHERE nip_05_id = $1
+        "#,
+        nip_05_id.as_ref()
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to performed a query to retrieve stored key.")?
+    .map(|row| StoredKey {
+        id: row.id,
+        created_at: row.created_at.to_rfc3339(),
+        nip_05_id: row.nip_05_id,
+        private_key_hash: row.private_key_hash,
+        recovery_codes: vec![],
+    }))
+}
+
+/// Id and current pin hash, what session tokens are checked against.
+#[tracing::instrument(name = "Get pin hash", skip(pool))]
+pub async fn get_pin_hash(
+    nip_05_id: &str,
+    pool: &PgPool,
+) -> Result<Option<(i64, Secret<String>)>, anyhow::Error> {
+    Ok(sqlx::query!(
+        r#"SELECT id, pin_hash FROM keys WHERE nip_05_id = $1"#,
+        nip_05_id
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to perform a query to retrieve the pin hash.")?
+    .map(|row| (row.id, Secret::new(row.pin_hash))))
 }
 
 #[tracing::instrument(name = "Get stored key", skip(lookup, pool))]
@@ -107,35 +603,71 @@ pub async fn get_stored_key(
         pin_hash: Secret::new(row.pin_hash),
     });
 
-    let mut expected_pin_hash = Secret::new(
-        "$argon2id$v=19$m=15000,t=2,p=1$\
-            gZiV/M1gPc22ElAH/Jh1Hw$\
-            CWOrkoo7oJBQ/iyh7uJ0LO2aLEfrHwTWllSAxT0zRno"
-            .to_string(),
-    );
+    let mut expected_pin_hash = dummy_secret_hash();
 
     if stored_key.as_ref().is_some() {
         expected_pin_hash = stored_key.clone().unwrap().pin_hash;
     }
     let pin = lookup.pin.clone();
-    spawn_blocking_with_tracing(move || verify_pin(expected_pin_hash, pin))
+    spawn_hashing(move || verify_pin(expected_pin_hash, pin))
         .await
-        .context("Failed to spawn blocking task.")??;
+        .context("Failed to queue hashing task.")??;
+    if let Some(row) = &stored_key {
+        record_access(row.id, pool).await?;
+    }
 
     Ok(stored_key.map(|row| StoredKey {
         id: row.id,
         nip_05_id: row.nip_05_id,
         created_at: row.created_at.to_rfc3339(),
         private_key_hash: row.private_key_hash.expose_secret().to_string(),
+        recovery_codes: vec![],
     }))
 }
 
+/// Keeps the key from expiring, and clears any pending expiry warning.
+#[tracing::instrument(name = "Record key access", skip(pool))]
+pub async fn record_access(key_id: i64, pool: &PgPool) -> Result<(), anyhow::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE keys
+        SET last_accessed_at = NOW(), expiry_warned_at = NULL
+        WHERE id = $1
+        "#,
+        key_id
+    )
+    .execute(pool)
+    .await
+    .context("Failed to record key access.")?;
+    Ok(())
+}
+
+pub(crate) fn verify_secret_hash(expected_hash: &Secret<String>, candidate: &str) -> bool {
+    PasswordHash::new(expected_hash.expose_secret())
+        .map(|expected_hash| {
+            Argon2::default()
+                .verify_password(candidate.as_bytes(), &expected_hash)
+                .is_ok()
+        })
+        .unwrap_or(false)
+}
+
 fn verify_pin(expected_pin_hash: Secret<String>, pin_candidate: Pin) -> Result<(), AuthError> {
     let expected_pin_hash = PasswordHash::new(expected_pin_hash.expose_secret())
         .context("Failed to parse hash in PHC string format.")?;
 
-    Argon2::default()
-        .verify_password(pin_candidate.as_ref().as_bytes(), &expected_pin_hash)
+    let timer = PIN_VERIFICATION_DURATION.start_timer();
+    let verified =
+        Argon2::default().verify_password(pin_candidate.as_ref().as_bytes(), &expected_pin_hash);
+    timer.observe_duration();
+    PIN_VERIFICATIONS
+        .with_label_values(&[if verified.is_ok() {
+            "success"
+        } else {
+            "failure"
+        }])
+        .inc();
+    verified
         .context("Invalid pin.")
         .map_err(AuthError::InvalidPin)
 }
@@ -0,0 +1,193 @@
+use crate::configuration::ChallengeSettings;
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use rand::RngCore;
+use sqlx::{PgExecutor, PgPool};
+use utoipa::ToSchema;
+
+/// What a challenge was issued for, a nonce can only be redeemed for its own purpose.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChallengePurpose {
+    Upload,
+    /// Proof of work for an upload, see `crate::proof_of_work`.
+    UploadWork,
+    /// Proof of work for a fetch.
+    FetchWork,
+    /// Payment hash of an upload invoice, see `crate::payments`.
+    Payment,
+}
+
+impl ChallengePurpose {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChallengePurpose::Upload => "upload",
+            ChallengePurpose::UploadWork => "upload_work",
+            ChallengePurpose::FetchWork => "fetch_work",
+            ChallengePurpose::Payment => "payment",
+        }
+    }
+}
+
+#[derive(ToSchema, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Challenge {
+    #[schema(example = "9c1185a5c5e9fc54612808977ee8f548b2258d31ddadef707ba62c2a8b3fd0e6")]
+    pub challenge: String,
+    #[schema(value_type = String, example = "2023-02-12T01:54:35+00:00")]
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Hands out single use nonces that expire after the configured ttl.
+#[derive(Clone)]
+pub struct ChallengeIssuer {
+    ttl: chrono::Duration,
+}
+
+impl ChallengeIssuer {
+    pub fn new(settings: &ChallengeSettings) -> Self {
+        Self {
+            ttl: chrono::Duration::seconds(settings.ttl_seconds as i64),
+        }
+    }
+
+    pub async fn issue(
+        &self,
+        purpose: ChallengePurpose,
+        pool: &PgPool,
+    ) -> Result<Challenge, anyhow::Error> {
+        self.issue_for(purpose, None, pool).await
+    }
+
+    /// A challenge that can only be consumed for `subject`, e.g. a nip 05 id.
+    #[tracing::instrument(name = "Issue challenge", skip(self, pool))]
+    pub async fn issue_for(
+        &self,
+        purpose: ChallengePurpose,
+        subject: Option<&str>,
+        pool: &PgPool,
+    ) -> Result<Challenge, anyhow::Error> {
+        let mut nonce = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let challenge = Challenge {
+            challenge: hex::encode(nonce),
+            expires_at: Utc::now() + self.ttl,
+        };
+        register_challenge(
+            &challenge.challenge,
+            purpose,
+            subject,
+            challenge.expires_at,
+            pool,
+        )
+        .await?;
+        Ok(challenge)
+    }
+}
+
+/// Stores a nonce chosen elsewhere, e.g. a payment hash, to be consumed like any other.
+pub async fn register_challenge(
+    nonce: &str,
+    purpose: ChallengePurpose,
+    subject: Option<&str>,
+    expires_at: DateTime<Utc>,
+    pool: &PgPool,
+) -> Result<(), anyhow::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO challenges (nonce, purpose, subject, expires_at)
+        VALUES ($1, $2, $3, $4)
+        "#,
+        nonce,
+        purpose.as_str(),
+        subject,
+        expires_at
+    )
+    .execute(pool)
+    .await
+    .context("Failed to store challenge.")?;
+    Ok(())
+}
+
+/// Marks the challenge as used, returns false if it is unknown, expired, already used
+/// or was issued for something else. Takes a transaction where it has to be used up together
+/// with something else.
+#[tracing::instrument(name = "Consume challenge", skip(executor))]
+pub async fn consume_challenge<'c>(
+    nonce: &str,
+    purpose: ChallengePurpose,
+    executor: impl PgExecutor<'c>,
+) -> Result<bool, anyhow::Error> {
+    let consumed = sqlx::query!(
+        r#"
+        UPDATE challenges
+        SET used_at = NOW()
+        WHERE nonce = $1 AND purpose = $2 AND used_at IS NULL AND expires_at > NOW()
+        RETURNING nonce
+        "#,
+        nonce,
+        purpose.as_str()
+    )
+    .fetch_optional(executor)
+    .await
+    .context("Failed to consume challenge.")?;
+    Ok(consumed.is_some())
+}
+
+/// Like `consume_challenge`, for challenges issued with `ChallengeIssuer::issue_for`.
+#[tracing::instrument(name = "Consume challenge for subject", skip(pool))]
+pub async fn consume_challenge_for(
+    nonce: &str,
+    purpose: ChallengePurpose,
+    subject: &str,
+    pool: &PgPool,
+) -> Result<bool, anyhow::Error> {
+    let consumed = sqlx::query!(
+        r#"
+        UPDATE challenges
+        SET used_at = NOW()
+        WHERE nonce = $1 AND purpose = $2 AND subject = $3
+            AND used_at IS NULL AND expires_at > NOW()
+        RETURNING nonce
+        "#,
+        nonce,
+        purpose.as_str(),
+        subject
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to consume challenge.")?;
+    Ok(consumed.is_some())
+}
+
+/// Makes a consumed challenge usable again, for work that failed after it was consumed.
+#[tracing::instrument(name = "Release challenge", skip(pool))]
+pub async fn release_challenge(
+    nonce: &str,
+    purpose: ChallengePurpose,
+    pool: &PgPool,
+) -> Result<(), anyhow::Error> {
+    sqlx::query!(
+        r#"UPDATE challenges SET used_at = NULL WHERE nonce = $1 AND purpose = $2"#,
+        nonce,
+        purpose.as_str()
+    )
+    .execute(pool)
+    .await
+    .context("Failed to release challenge.")?;
+    Ok(())
+}
+
+/// Drops expired challenges and ones used over an hour ago, run periodically by
+/// `Application`. Used rows are kept that long so `release_challenge` can still hand them back.
+#[tracing::instrument(name = "Purge challenges", skip(pool))]
+pub async fn purge_challenges(pool: &PgPool) -> Result<u64, anyhow::Error> {
+    Ok(sqlx::query!(
+        r#"
+        DELETE FROM challenges
+        WHERE expires_at <= NOW() OR used_at <= NOW() - INTERVAL '1 hour'
+        "#
+    )
+    .execute(pool)
+    .await
+    .context("Failed to purge challenges.")?
+    .rows_affected())
+}
@@ -1,13 +1,54 @@
 use secrecy::{ExposeSecret, Secret};
-use serde_aux::field_attributes::deserialize_number_from_string;
+use serde_aux::field_attributes::{
+    deserialize_number_from_string, deserialize_option_number_from_string,
+};
 use sqlx::postgres::{PgConnectOptions, PgSslMode};
 use sqlx::ConnectOptions;
 use std::convert::{TryFrom, TryInto};
+use std::net::IpAddr;
 
 #[derive(Clone, serde::Deserialize)]
 pub struct Settings {
     pub database: DatabaseSettings,
     pub application: ApplicationSettings,
+    #[serde(default)]
+    pub replication: ReplicationSettings,
+    #[serde(default)]
+    pub relay_publisher: RelayPublisherSettings,
+    #[serde(default)]
+    pub nip05_provider: Nip05ProviderSettings,
+    #[serde(default)]
+    pub challenges: ChallengeSettings,
+    #[serde(default)]
+    pub idempotency: IdempotencySettings,
+    #[serde(default)]
+    pub second_factor: SecondFactorSettings,
+    #[serde(default)]
+    pub sessions: SessionSettings,
+    #[serde(default)]
+    pub key_expiry: KeyExpirySettings,
+    #[serde(default)]
+    pub nip_05_id_normalization: Nip05IdNormalizationSettings,
+    #[serde(default)]
+    pub metrics: MetricsSettings,
+    #[serde(default)]
+    pub telemetry: TelemetrySettings,
+    #[serde(default)]
+    pub readiness: ReadinessSettings,
+    #[serde(default)]
+    pub admin: AdminSettings,
+    #[serde(default)]
+    pub security: SecuritySettings,
+    #[serde(default)]
+    pub enumeration_resistance: EnumerationResistanceSettings,
+    #[serde(default)]
+    pub hashing: HashingSettings,
+    #[serde(default)]
+    pub proof_of_work: ProofOfWorkSettings,
+    #[serde(default)]
+    pub payments: PaymentSettings,
+    #[serde(default)]
+    pub domain_policy: DomainPolicySettings,
 }
 
 #[derive(Clone, serde::Deserialize)]
@@ -16,6 +57,526 @@ pub struct ApplicationSettings {
     pub port: u16,
     pub host: String,
     pub base_url: String,
+    /// How long in-flight requests and background tasks get to finish on shutdown.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub shutdown_timeout_seconds: u64,
+    /// Json bodies above this are answered with a 413 before they are parsed.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_body_bytes: usize,
+    /// Serve https instead of http, the files are read again on SIGHUP.
+    #[serde(default)]
+    pub tls: Option<TlsSettings>,
+    #[serde(default)]
+    pub cors: CorsSettings,
+}
+
+/// CORS policies per route group: `keys` covers every route that takes a pin or stores a
+/// key, `public` the health, metrics and nip 05 availability routes. Replication, Swagger and
+/// the example page are not meant to be called cross-origin and get no CORS headers at all.
+/// `/.well-known/nostr.json` is in neither group, NIP-05 requires any origin to read it.
+#[derive(Clone, serde::Deserialize)]
+pub struct CorsSettings {
+    #[serde(default = "CorsPolicySettings::keys")]
+    pub keys: CorsPolicySettings,
+    #[serde(default = "CorsPolicySettings::public")]
+    pub public: CorsPolicySettings,
+}
+
+impl Default for CorsSettings {
+    fn default() -> Self {
+        Self {
+            keys: CorsPolicySettings::keys(),
+            public: CorsPolicySettings::public(),
+        }
+    }
+}
+
+/// `allowed_origins` holds exact origins such as `https://client.example`, or just `"*"`.
+#[derive(Clone, serde::Deserialize)]
+pub struct CorsPolicySettings {
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    #[serde(default)]
+    pub allowed_headers: Vec<String>,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_age_seconds: usize,
+}
+
+impl CorsPolicySettings {
+    /// Only the vault's own origin until operators list the clients they trust.
+    pub fn keys() -> Self {
+        Self {
+            allowed_origins: vec![],
+            allowed_methods: vec![
+                "GET".to_string(),
+                "POST".to_string(),
+                "PUT".to_string(),
+                "DELETE".to_string(),
+            ],
+            allowed_headers: vec!["content-type".to_string(), "authorization".to_string()],
+            max_age_seconds: 3600,
+        }
+    }
+
+    pub fn public() -> Self {
+        Self {
+            allowed_origins: vec!["*".to_string()],
+            allowed_methods: vec!["GET".to_string()],
+            allowed_headers: vec![],
+            max_age_seconds: 86400,
+        }
+    }
+}
+
+#[derive(Clone, serde::Deserialize)]
+pub struct TlsSettings {
+    /// PEM certificate chain, leaf certificate first.
+    pub cert_path: String,
+    /// PEM private key, PKCS#8, PKCS#1 or SEC1.
+    pub key_path: String,
+}
+
+/// What the application port exposes besides the api. The defaults suit local development,
+/// `production.yaml` turns on HSTS and takes Swagger and the directory listing down.
+#[derive(Clone, serde::Deserialize)]
+pub struct SecuritySettings {
+    /// Sent as `Strict-Transport-Security` when set, only put this in front of https.
+    #[serde(default, deserialize_with = "deserialize_option_number_from_string")]
+    pub hsts_max_age_seconds: Option<u64>,
+    /// Serves `/swagger-ui` and `/api-doc/openapi.json`.
+    pub swagger_ui: bool,
+    /// Serves the example client under `/example`.
+    pub example_ui: bool,
+    /// Lists the files under `/example`, has no effect without `example_ui`.
+    pub directory_listing: bool,
+}
+
+impl Default for SecuritySettings {
+    fn default() -> Self {
+        Self {
+            hsts_max_age_seconds: None,
+            swagger_ui: true,
+            example_ui: true,
+            directory_listing: true,
+        }
+    }
+}
+
+/// Makes the key routes answer unknown nip 05 ids exactly like wrong pins, in the same
+/// padded size and after the same delay, so stored ids can not be probed.
+#[derive(Clone, serde::Deserialize)]
+pub struct EnumerationResistanceSettings {
+    pub enabled: bool,
+    /// `/.well-known/nostr.json` and `/nip05/availability` answer which names are taken, that
+    /// is what they are for, so while `enabled` they are only served with this set. Setting
+    /// it lets anyone tell the stored ids of the provider domains apart again.
+    #[serde(default)]
+    pub serve_nip_05_lookups: bool,
+    /// Should be well above the time a pin hash takes.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub min_response_milliseconds: u64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub jitter_milliseconds: u64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub pad_to_bytes: usize,
+}
+
+impl Default for EnumerationResistanceSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            serve_nip_05_lookups: false,
+            min_response_milliseconds: 300,
+            jitter_milliseconds: 100,
+            pad_to_bytes: 1024,
+        }
+    }
+}
+
+/// Argon2 runs on `workers` dedicated threads. Once `max_queued` hashes are waiting, new
+/// ones are refused with a 503 and a `Retry-After` of `retry_after_seconds`.
+#[derive(Clone, serde::Deserialize)]
+pub struct HashingSettings {
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub workers: usize,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_queued: usize,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub retry_after_seconds: u64,
+}
+
+impl Default for HashingSettings {
+    fn default() -> Self {
+        Self {
+            workers: 4,
+            max_queued: 128,
+            retry_after_seconds: 1,
+        }
+    }
+}
+
+/// Leading zero bits the proof of work on uploads and fetches has to have, 0 turns it off.
+/// Every failed fetch of a nip 05 id within `failure_window_seconds` adds
+/// `difficulty_per_failure` bits for that id, up to `max_difficulty`.
+#[derive(Clone, serde::Deserialize)]
+pub struct ProofOfWorkSettings {
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub upload_difficulty: u8,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub fetch_difficulty: u8,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub difficulty_per_failure: u8,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_difficulty: u8,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub failure_window_seconds: u64,
+}
+
+impl Default for ProofOfWorkSettings {
+    fn default() -> Self {
+        Self {
+            upload_difficulty: 0,
+            fetch_difficulty: 0,
+            difficulty_per_failure: 0,
+            max_difficulty: 24,
+            failure_window_seconds: 900,
+        }
+    }
+}
+
+/// Charges for uploads with L402: without a paid token an upload gets a 402 with an invoice
+/// for `upload_price_msat` from `node` and a macaroon signed with `macaroon_root_key`.
+#[derive(Clone, serde::Deserialize)]
+pub struct PaymentSettings {
+    pub enabled: bool,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub upload_price_msat: u64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub invoice_expiry_seconds: u64,
+    /// How long after the invoice was issued a paid token can still be redeemed.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub token_ttl_seconds: u64,
+    /// At least 32 bytes, hex encoded. Required once `enabled`.
+    #[serde(default = "unset_secret")]
+    pub macaroon_root_key: Secret<String>,
+    /// Required once `enabled`, `kind: fake` is only accepted in local development.
+    #[serde(default)]
+    pub node: Option<LightningNodeSettings>,
+}
+
+impl Default for PaymentSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            upload_price_msat: 10_000,
+            invoice_expiry_seconds: 600,
+            token_ttl_seconds: 86400,
+            macaroon_root_key: unset_secret(),
+            node: None,
+        }
+    }
+}
+
+/// Where invoices come from, picked with `kind`.
+#[derive(Clone, serde::Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum LightningNodeSettings {
+    /// Makes up invoices that settle themselves, for tests and local development only.
+    Fake,
+    /// An LND node's REST api, with a macaroon that may create invoices.
+    Lnd {
+        url: String,
+        macaroon_hex: Secret<String>,
+    },
+}
+
+/// Which domains uploads are accepted for. A domain is written as `frogs.cloud`, or as
+/// `*.frogs.cloud` for every subdomain of it, which does not include `frogs.cloud` itself.
+#[derive(Clone, serde::Deserialize)]
+pub struct DomainPolicySettings {
+    /// When not empty, only these domains are accepted.
+    #[serde(default)]
+    pub allowed_domains: Vec<String>,
+    /// Refused even when they are also allowed.
+    #[serde(default)]
+    pub denied_domains: Vec<String>,
+    /// Keys a single domain may hold, 0 for no limit.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_keys_per_domain: u64,
+    /// Limits that replace `max_keys_per_domain` for matching domains, the most specific
+    /// match wins.
+    #[serde(default)]
+    pub quotas: Vec<DomainQuotaSettings>,
+}
+
+impl Default for DomainPolicySettings {
+    fn default() -> Self {
+        Self {
+            allowed_domains: vec![],
+            denied_domains: vec![],
+            max_keys_per_domain: 0,
+            quotas: vec![],
+        }
+    }
+}
+
+#[derive(Clone, serde::Deserialize)]
+pub struct DomainQuotaSettings {
+    pub domain: String,
+    /// 0 for no limit.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_keys: u64,
+}
+
+/// When `port` is set, the replication routes are only served on that port instead of the
+/// application one. It uses the application's TLS certificate, and with `client_ca_path`
+/// only accepts clients presenting a certificate signed by one of the CAs in that file.
+#[derive(Clone, Default, serde::Deserialize)]
+pub struct AdminSettings {
+    #[serde(default, deserialize_with = "deserialize_option_number_from_string")]
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub client_ca_path: Option<String>,
+}
+
+#[derive(Clone, serde::Deserialize)]
+pub struct ReplicationSettings {
+    /// Identifies this vault in the version vector attached to every row.
+    pub node_id: String,
+    /// Key peers must present when pushing to or pulling from this vault. Required whenever
+    /// the replication routes are served.
+    #[serde(default = "unset_secret")]
+    pub auth_key: Secret<String>,
+    #[serde(default)]
+    pub peers: Vec<PeerSettings>,
+    /// Serves the replication routes on the application port when no `admin.port` is set.
+    /// Without it, and without an admin listener, this vault only pushes and pulls.
+    #[serde(default)]
+    pub serve_on_app_port: bool,
+    /// PEM file holding the certificate and key presented to peers that require one.
+    #[serde(default)]
+    pub client_identity_path: Option<String>,
+    /// PEM CA certificate trusted for peers on top of the usual roots.
+    #[serde(default)]
+    pub ca_cert_path: Option<String>,
+}
+
+impl Default for ReplicationSettings {
+    fn default() -> Self {
+        Self {
+            node_id: "nostr-vault".to_string(),
+            auth_key: unset_secret(),
+            peers: vec![],
+            serve_on_app_port: false,
+            client_identity_path: None,
+            ca_cert_path: None,
+        }
+    }
+}
+
+/// Secrets have no default, an empty one is refused by whatever uses it.
+fn unset_secret() -> Secret<String> {
+    Secret::new(String::new())
+}
+
+#[derive(Clone, serde::Deserialize)]
+pub struct PeerSettings {
+    pub url: String,
+    /// Key the peer expects from us, i.e. the peer's own `auth_key`.
+    pub auth_key: Secret<String>,
+}
+
+/// Nostr relays that signed NIP-78 copies of uploaded blobs are forwarded to.
+/// Publishing is disabled while `relays` is empty.
+#[derive(Clone, serde::Deserialize)]
+pub struct RelayPublisherSettings {
+    #[serde(default)]
+    pub relays: Vec<String>,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub timeout_seconds: u64,
+}
+
+impl Default for RelayPublisherSettings {
+    fn default() -> Self {
+        Self {
+            relays: vec![],
+            timeout_seconds: 10,
+        }
+    }
+}
+
+/// Domains this vault hands out `name@domain` identifiers for, served from
+/// `/.well-known/nostr.json`. The provider is disabled while `domains` is empty.
+#[derive(Clone, Default, serde::Deserialize)]
+pub struct Nip05ProviderSettings {
+    #[serde(default)]
+    pub domains: Vec<String>,
+    /// Names nobody may register on the provider's domains.
+    #[serde(default)]
+    pub reserved_names: Vec<String>,
+    /// Reverse proxies whose `Forwarded` or `X-Forwarded-Host` header names the domain a
+    /// request was for. Anyone else is answered for the `Host` they connected with.
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+/// Server-issued nonces clients sign to prove they hold a key.
+#[derive(Clone, serde::Deserialize)]
+pub struct ChallengeSettings {
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub ttl_seconds: u64,
+}
+
+impl Default for ChallengeSettings {
+    fn default() -> Self {
+        Self { ttl_seconds: 300 }
+    }
+}
+
+/// How long responses to requests with an `Idempotency-Key` are kept for retries. A request
+/// still running holds its key for `lease_seconds`, after that a retry may take it over.
+#[derive(Clone, serde::Deserialize)]
+pub struct IdempotencySettings {
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub ttl_seconds: u64,
+    #[serde(
+        default = "default_idempotency_lease",
+        deserialize_with = "deserialize_number_from_string"
+    )]
+    pub lease_seconds: u64,
+}
+
+fn default_idempotency_lease() -> u64 {
+    60
+}
+
+impl Default for IdempotencySettings {
+    fn default() -> Self {
+        Self {
+            ttl_seconds: 86400,
+            lease_seconds: default_idempotency_lease(),
+        }
+    }
+}
+
+/// TOTP enrolment, secrets are stored encrypted with `encryption_key`.
+#[derive(Clone, serde::Deserialize)]
+pub struct SecondFactorSettings {
+    /// Shown by authenticator apps next to the account.
+    pub issuer: String,
+    /// 32 bytes, hex encoded, used with AES-256-GCM. Also keys the stored fingerprints of
+    /// idempotent requests and the decoy passkey options. Has no default, loading the
+    /// configuration fails without it.
+    #[serde(default = "unset_secret")]
+    pub encryption_key: Secret<String>,
+}
+
+impl Default for SecondFactorSettings {
+    fn default() -> Self {
+        Self {
+            issuer: "nostr-vault".to_string(),
+            encryption_key: unset_secret(),
+        }
+    }
+}
+
+/// Tokens issued after a pin was verified, signed with HMAC-SHA256 under `signing_key`.
+#[derive(Clone, serde::Deserialize)]
+pub struct SessionSettings {
+    /// At least 32 bytes, hex encoded. Has no default, loading the configuration fails
+    /// without it.
+    #[serde(default = "unset_secret")]
+    pub signing_key: Secret<String>,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub ttl_seconds: u64,
+}
+
+impl Default for SessionSettings {
+    fn default() -> Self {
+        Self {
+            signing_key: unset_secret(),
+            ttl_seconds: 300,
+        }
+    }
+}
+
+/// Removes rows nobody has fetched within `retention_days`, after warning about them
+/// `warning_days` beforehand. Nothing is deleted while `dry_run` is set.
+#[derive(Clone, serde::Deserialize)]
+pub struct KeyExpirySettings {
+    pub enabled: bool,
+    pub dry_run: bool,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub retention_days: u64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub warning_days: u64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub interval_seconds: u64,
+}
+
+impl Default for KeyExpirySettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            dry_run: true,
+            retention_days: 365,
+            warning_days: 30,
+            interval_seconds: 3600,
+        }
+    }
+}
+
+/// Ids stored before nip 05 ids were normalized are checked on startup. Only a report of
+/// what would be rewritten or moved to `legacy_keys` is logged until `apply` is set.
+#[derive(Clone, Default, serde::Deserialize)]
+pub struct Nip05IdNormalizationSettings {
+    #[serde(default)]
+    pub apply: bool,
+}
+
+/// `/metrics` is served on the application port unless `port` is set, in which case it is
+/// only served there, e.g. to keep it off the public listener.
+#[derive(Clone, Default, serde::Deserialize)]
+pub struct MetricsSettings {
+    #[serde(default, deserialize_with = "deserialize_option_number_from_string")]
+    pub port: Option<u16>,
+}
+
+/// `/readyz` fails once the database takes longer than `database_timeout_milliseconds` to
+/// answer, or more than `max_queued_blocking_tasks` pin hashes are waiting for a thread.
+#[derive(Clone, serde::Deserialize)]
+pub struct ReadinessSettings {
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub database_timeout_milliseconds: u64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_queued_blocking_tasks: i64,
+}
+
+impl Default for ReadinessSettings {
+    fn default() -> Self {
+        Self {
+            database_timeout_milliseconds: 1000,
+            max_queued_blocking_tasks: 64,
+        }
+    }
+}
+
+/// Spans are exported to an OTLP/HTTP collector, e.g. `http://localhost:4318/v1/traces`,
+/// while `otlp_endpoint` is set, on top of the bunyan logs.
+#[derive(Clone, serde::Deserialize)]
+pub struct TelemetrySettings {
+    #[serde(default)]
+    pub otlp_endpoint: Option<String>,
+    pub service_name: String,
+}
+
+impl Default for TelemetrySettings {
+    fn default() -> Self {
+        Self {
+            otlp_endpoint: None,
+            service_name: "nostr-vault".to_string(),
+        }
+    }
 }
 
 #[derive(Clone, serde::Deserialize)]
@@ -74,7 +635,73 @@ pub fn get_configuration() -> Result<Settings, config::ConfigError> {
         )
         .build()?;
 
-    settings.try_deserialize::<Settings>()
+    let settings = settings.try_deserialize::<Settings>()?;
+    settings
+        .validate(&environment)
+        .map_err(config::ConfigError::Message)?;
+    Ok(settings)
+}
+
+/// Keys from local.yaml and the ones base.yaml used to ship with. They are public, so only
+/// local development may use them.
+const DEVELOPMENT_SECRETS: &[&str] = &[
+    "6a1f0b3c9d2e4f5a7b8c0d1e2f3a4b5c6d7e8f9a0b1c2d3e4f5a6b7c8d9e0f1a",
+    "70c246b7c1e797aede9b250ef8fa26b56d8d76509449ab9a3d041172976f0b56",
+    "c4e8a1f07b3d5e9a2c6f0b8d4e1a7c3f9b5d2e8a0c6f4b1d7e3a9c5f0b2d8e6a",
+    "2330bc46628a1be0cc5a58101f67e191859308e05a036f7a70bb7d49d1a969db",
+    "5f2b9e7c1a4d8f3e6b0c9a2d5e8f1b4c7a0d3e6f9b2c5a8d1e4f7b0a3c6d9e2f",
+    "10e5e214d22fc35ca3a6a3dea945a7a40e06eaaee5377da54b3eb2ede1adff13",
+];
+
+impl Settings {
+    /// Refuses configurations that would start with missing or published secrets.
+    fn validate(&self, environment: &Environment) -> Result<(), String> {
+        require_secret(
+            "second_factor.encryption_key",
+            &self.second_factor.encryption_key,
+            environment,
+        )?;
+        require_secret(
+            "sessions.signing_key",
+            &self.sessions.signing_key,
+            environment,
+        )?;
+        if self.payments.enabled {
+            require_secret(
+                "payments.macaroon_root_key",
+                &self.payments.macaroon_root_key,
+                environment,
+            )?;
+            match (&self.payments.node, environment) {
+                (None, _) => return Err("payments.node must be set to take payments.".into()),
+                // A fake node settles every invoice it issues, uploads would be free
+                (Some(LightningNodeSettings::Fake), Environment::Production) => {
+                    return Err("payments.node can only be `fake` in local development.".into())
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+fn require_secret(
+    name: &str,
+    secret: &Secret<String>,
+    environment: &Environment,
+) -> Result<(), String> {
+    let secret = secret.expose_secret();
+    if secret.is_empty() {
+        return Err(format!("{} must be set.", name));
+    }
+    if !matches!(environment, Environment::Local) && DEVELOPMENT_SECRETS.contains(&secret.as_str())
+    {
+        return Err(format!(
+            "{} is a published development key, generate one of your own.",
+            name
+        ));
+    }
+    Ok(())
 }
 
 pub enum Environment {
@@ -105,3 +732,41 @@ impl TryFrom<String> for Environment {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{require_secret, Environment, DEVELOPMENT_SECRETS};
+    use claim::{assert_err, assert_ok};
+    use secrecy::Secret;
+
+    fn secret(value: &str) -> Secret<String> {
+        Secret::new(value.to_string())
+    }
+
+    #[test]
+    fn missing_secrets_are_refused_everywhere() {
+        assert_err!(require_secret("key", &secret(""), &Environment::Local));
+        assert_err!(require_secret("key", &secret(""), &Environment::Production));
+    }
+
+    #[test]
+    fn development_secrets_are_only_accepted_locally() {
+        for development in DEVELOPMENT_SECRETS {
+            assert_ok!(require_secret(
+                "key",
+                &secret(development),
+                &Environment::Local
+            ));
+            assert_err!(require_secret(
+                "key",
+                &secret(development),
+                &Environment::Production
+            ));
+        }
+        assert_ok!(require_secret(
+            "key",
+            &secret("d3b07384d113edec49eaa6238ad5ff00d3b07384d113edec49eaa6238ad5ff00"),
+            &Environment::Production
+        ));
+    }
+}
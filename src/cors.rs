@@ -0,0 +1,186 @@
+use crate::configuration::CorsPolicySettings;
+use actix_cors::Cors;
+use actix_web::dev::RequestHead;
+use actix_web::http::header::{HeaderName, HeaderValue, HOST, WWW_AUTHENTICATE};
+use actix_web::http::Method;
+use anyhow::anyhow;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Origins {
+    Any,
+    Listed(Vec<String>),
+}
+
+/// Validated CORS settings of a route group. A listed group always lets the vault's own
+/// origin in, so pages it serves itself, like `/example`, keep working.
+#[derive(Clone, Debug)]
+pub struct CorsPolicy {
+    origins: Origins,
+    methods: Vec<Method>,
+    headers: Vec<HeaderName>,
+    max_age_seconds: usize,
+}
+
+impl CorsPolicy {
+    /// `group` only names the settings in errors, e.g. `keys`.
+    pub fn parse(group: &str, settings: &CorsPolicySettings) -> Result<Self, anyhow::Error> {
+        let origins = if settings.allowed_origins.iter().any(|o| o == "*") {
+            if settings.allowed_origins.len() > 1 {
+                return Err(anyhow!(
+                    "cors.{}.allowed_origins can not mix \"*\" with other origins.",
+                    group
+                ));
+            }
+            Origins::Any
+        } else {
+            Origins::Listed(
+                settings
+                    .allowed_origins
+                    .iter()
+                    .map(|origin| parse_origin(group, origin))
+                    .collect::<Result<_, _>>()?,
+            )
+        };
+        let methods = settings
+            .allowed_methods
+            .iter()
+            .map(|method| {
+                Method::from_bytes(method.to_uppercase().as_bytes()).map_err(|_| {
+                    anyhow!(
+                        "cors.{}.allowed_methods: {} is not a method.",
+                        group,
+                        method
+                    )
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        let headers = settings
+            .allowed_headers
+            .iter()
+            .map(|header| {
+                HeaderName::from_bytes(header.as_bytes()).map_err(|_| {
+                    anyhow!(
+                        "cors.{}.allowed_headers: {} is not a header.",
+                        group,
+                        header
+                    )
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            origins,
+            methods,
+            headers,
+            max_age_seconds: settings.max_age_seconds,
+        })
+    }
+
+    /// A fresh middleware for one resource, `Cors` can not be shared between workers.
+    pub fn middleware(&self) -> Cors {
+        let mut cors = Cors::default()
+            .allowed_methods(self.methods.clone())
+            // Carries the L402 invoice, browsers hide it from scripts unless exposed
+            .expose_headers([WWW_AUTHENTICATE])
+            .max_age(self.max_age_seconds);
+        if !self.headers.is_empty() {
+            cors = cors.allowed_headers(self.headers.clone());
+        }
+        match &self.origins {
+            Origins::Any => cors.allow_any_origin().send_wildcard(),
+            Origins::Listed(origins) => {
+                let origins = origins.clone();
+                cors.allowed_origin_fn(move |origin, head| {
+                    origins
+                        .iter()
+                        .any(|allowed| allowed.as_bytes() == origin.as_bytes())
+                        || is_same_origin(origin, head)
+                })
+            }
+        }
+    }
+}
+
+/// Browsers send an exact `scheme://host[:port]`, anything else in the config can never
+/// match and is most likely a typo.
+fn parse_origin(group: &str, origin: &str) -> Result<String, anyhow::Error> {
+    let invalid = || {
+        anyhow!(
+            "cors.{}.allowed_origins: {} is not an origin like https://example.com.",
+            group,
+            origin
+        )
+    };
+    let url = reqwest::Url::parse(origin).map_err(|_| invalid())?;
+    if !matches!(url.scheme(), "http" | "https") || url.origin().ascii_serialization() != origin {
+        return Err(invalid());
+    }
+    Ok(origin.to_string())
+}
+
+fn is_same_origin(origin: &HeaderValue, head: &RequestHead) -> bool {
+    let host = match head.headers().get(HOST).and_then(|h| h.to_str().ok()) {
+        Some(host) => host,
+        None => return false,
+    };
+    origin
+        .to_str()
+        .ok()
+        .and_then(|origin| origin.split_once("://"))
+        .map(|(_, origin_host)| origin_host.eq_ignore_ascii_case(host))
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CorsPolicy;
+    use crate::configuration::CorsPolicySettings;
+    use claim::{assert_err, assert_ok};
+
+    fn settings(origins: &[&str]) -> CorsPolicySettings {
+        CorsPolicySettings {
+            allowed_origins: origins.iter().map(|o| o.to_string()).collect(),
+            ..CorsPolicySettings::keys()
+        }
+    }
+
+    #[test]
+    fn exact_origins_are_accepted() {
+        assert_ok!(CorsPolicy::parse(
+            "keys",
+            &settings(&["https://client.example", "http://localhost:3000"])
+        ));
+        assert_ok!(CorsPolicy::parse("keys", &settings(&["*"])));
+    }
+
+    #[test]
+    fn origins_with_a_path_or_without_a_scheme_are_rejected() {
+        for origin in [
+            "https://client.example/",
+            "https://client.example/app",
+            "client.example",
+            "ftp://client.example",
+            "https://Client.example",
+        ] {
+            assert_err!(CorsPolicy::parse("keys", &settings(&[origin])));
+        }
+    }
+
+    #[test]
+    fn wildcard_can_not_be_mixed_with_origins() {
+        assert_err!(CorsPolicy::parse(
+            "keys",
+            &settings(&["*", "https://client.example"])
+        ));
+    }
+
+    #[test]
+    fn invalid_methods_and_headers_are_rejected() {
+        let mut bad_method = settings(&[]);
+        bad_method.allowed_methods = vec!["PO ST".to_string()];
+        let mut bad_header = settings(&[]);
+        bad_header.allowed_headers = vec!["content type".to_string()];
+
+        assert_err!(CorsPolicy::parse("keys", &bad_method));
+        assert_err!(CorsPolicy::parse("keys", &bad_header));
+    }
+}
@@ -0,0 +1,137 @@
+use super::{NostrPublicKey, PrivateKeyHash};
+use secp256k1::{schnorr::Signature, Message, SECP256K1};
+use sha2::{Digest, Sha256};
+use std::str::FromStr;
+
+/// A schnorr signature by `pubkey` over a server-issued challenge and the uploaded blob,
+/// proving the uploader holds the key the blob belongs to.
+#[derive(Debug, Clone)]
+pub struct KeyPossessionProof {
+    pubkey: NostrPublicKey,
+    challenge: String,
+}
+
+impl KeyPossessionProof {
+    /// `sha256(challenge || sha256(private_key_hash))`, the 32 bytes clients sign.
+    pub fn message(challenge: &str, private_key_hash: &str) -> [u8; 32] {
+        let blob_hash = Sha256::digest(private_key_hash.as_bytes());
+        let mut hasher = Sha256::new();
+        hasher.update(challenge.as_bytes());
+        hasher.update(blob_hash);
+        hasher.finalize().into()
+    }
+
+    pub fn parse(
+        pubkey: NostrPublicKey,
+        challenge: String,
+        signature: String,
+        private_key_hash: &PrivateKeyHash,
+    ) -> Result<KeyPossessionProof, String> {
+        if challenge.trim().is_empty() {
+            return Err("A challenge is required to upload a key.".to_string());
+        }
+        let sig = Signature::from_str(&signature)
+            .map_err(|_| format!("{} is not a valid schnorr signature.", signature))?;
+        let message = Message::from_slice(&Self::message(&challenge, private_key_hash.as_ref()))
+            .map_err(|e| e.to_string())?;
+        SECP256K1
+            .verify_schnorr(&sig, &message, pubkey.x_only_public_key())
+            .map_err(|_| format!("Signature does not prove possession of {}.", pubkey))?;
+        Ok(Self { pubkey, challenge })
+    }
+
+    pub fn pubkey(&self) -> &NostrPublicKey {
+        &self.pubkey
+    }
+
+    pub fn challenge(&self) -> &str {
+        &self.challenge
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::KeyPossessionProof;
+    use crate::domain::{NostrPublicKey, PrivateKeyHash};
+    use claim::{assert_err, assert_ok};
+    use secp256k1::{KeyPair, Message};
+    use secrecy::Secret;
+
+    const PRIVATE_KEY_HASH: &str = "$PBKDF2$i=100000,l=256,s=0Bu5lWu4s66/iottrlUGdckjf5nwnpB05jwp4yDh8NU=$AESGM$OrScsD+hHGaRaPbc$XMXVVbjt3JV+QsNb7ZWRc8uNod2YzJL0lSvW1FOiY38ywOu7IEChKs/IqEQ7knhZAmRGYqoB4dhAbdOTwVhYIeQsuf1+f+9ARPEjtURsDg==";
+    const CHALLENGE: &str = "9c1185a5c5e9fc54612808977ee8f548b2258d31ddadef707ba62c2a8b3fd0e6";
+
+    fn private_key_hash() -> PrivateKeyHash {
+        PrivateKeyHash::parse(Secret::new(PRIVATE_KEY_HASH.to_string())).unwrap()
+    }
+
+    fn pubkey(keypair: &KeyPair) -> NostrPublicKey {
+        NostrPublicKey::parse(keypair.x_only_public_key().0.to_string()).unwrap()
+    }
+
+    fn sign(keypair: &KeyPair, challenge: &str, private_key_hash: &str) -> String {
+        let message = KeyPossessionProof::message(challenge, private_key_hash);
+        keypair
+            .sign_schnorr(Message::from_slice(&message).unwrap())
+            .to_string()
+    }
+
+    #[test]
+    fn a_valid_proof() {
+        let keypair = KeyPair::new_global(&mut rand::thread_rng());
+        let signature = sign(&keypair, CHALLENGE, PRIVATE_KEY_HASH);
+        assert_ok!(KeyPossessionProof::parse(
+            pubkey(&keypair),
+            CHALLENGE.to_string(),
+            signature,
+            &private_key_hash()
+        ));
+    }
+
+    #[test]
+    fn a_signature_from_another_key_is_rejected() {
+        let keypair = KeyPair::new_global(&mut rand::thread_rng());
+        let other = KeyPair::new_global(&mut rand::thread_rng());
+        let signature = sign(&other, CHALLENGE, PRIVATE_KEY_HASH);
+        assert_err!(KeyPossessionProof::parse(
+            pubkey(&keypair),
+            CHALLENGE.to_string(),
+            signature,
+            &private_key_hash()
+        ));
+    }
+
+    #[test]
+    fn a_signature_over_another_challenge_is_rejected() {
+        let keypair = KeyPair::new_global(&mut rand::thread_rng());
+        let signature = sign(&keypair, "another challenge", PRIVATE_KEY_HASH);
+        assert_err!(KeyPossessionProof::parse(
+            pubkey(&keypair),
+            CHALLENGE.to_string(),
+            signature,
+            &private_key_hash()
+        ));
+    }
+
+    #[test]
+    fn a_signature_over_another_blob_is_rejected() {
+        let keypair = KeyPair::new_global(&mut rand::thread_rng());
+        let signature = sign(&keypair, CHALLENGE, "$PBKDF2$i=1,l=256,s=a$AESGM$b$c");
+        assert_err!(KeyPossessionProof::parse(
+            pubkey(&keypair),
+            CHALLENGE.to_string(),
+            signature,
+            &private_key_hash()
+        ));
+    }
+
+    #[test]
+    fn a_malformed_signature_is_rejected() {
+        let keypair = KeyPair::new_global(&mut rand::thread_rng());
+        assert_err!(KeyPossessionProof::parse(
+            pubkey(&keypair),
+            CHALLENGE.to_string(),
+            "not a signature".to_string(),
+            &private_key_hash()
+        ));
+    }
+}
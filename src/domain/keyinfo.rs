@@ -1,8 +1,10 @@
-use super::{Nip05ID, Pin, PrivateKeyHash};
+use super::{Nip05ID, NostrPublicKey, Pin, PrivateKeyHash, RelayUrl};
 
 #[derive(Debug, Clone)]
 pub struct KeyInfo {
     pub nip_05_id: Nip05ID,
     pub pin: Pin,
     pub private_key_hash: PrivateKeyHash,
+    pub pubkey: NostrPublicKey,
+    pub relays: Vec<RelayUrl>,
 }
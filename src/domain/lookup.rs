@@ -1,7 +1,8 @@
-use super::{Nip05ID, Pin};
+use super::{Nip05ID, Pin, TotpCode};
 
 #[derive(Debug, Clone)]
 pub struct Lookup {
     pub nip_05_id: Nip05ID,
     pub pin: Pin,
+    pub totp_code: Option<TotpCode>,
 }
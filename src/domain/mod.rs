@@ -1,13 +1,27 @@
+mod key_possession_proof;
 mod keyinfo;
 mod lookup;
 mod nip_05_id;
+mod nostr_event;
+mod nostr_public_key;
 mod pin;
 mod private_key_hash;
+mod recovery_code;
+mod relay_url;
 mod rowdata;
+mod totp_code;
+mod version_vector;
+pub use key_possession_proof::KeyPossessionProof;
 pub use keyinfo::KeyInfo;
 pub use lookup::Lookup;
 pub use rowdata::RowData;
 
-pub use nip_05_id::Nip05ID;
+pub use nip_05_id::{normalize_domain, Nip05ID};
+pub use nostr_event::{AppDataEvent, NostrEvent, APP_DATA_KIND};
+pub use nostr_public_key::NostrPublicKey;
 pub use pin::Pin;
 pub use private_key_hash::PrivateKeyHash;
+pub use recovery_code::RecoveryCode;
+pub use relay_url::RelayUrl;
+pub use totp_code::TotpCode;
+pub use version_vector::{Causality, VersionVector};
@@ -1,18 +1,60 @@
-use validator::validate_email;
+/// Longest local part accepted, the same limit email has.
+const MAX_LOCAL_PART_LENGTH: usize = 64;
 
+/// A `name@domain` identifier as NIP-05 defines it, normalized so every spelling of the same
+/// identifier is stored once: lowercase, with internationalized domains in punycode.
 #[derive(Debug, Clone)]
 pub struct Nip05ID(String);
 
 impl Nip05ID {
     pub fn parse(s: String) -> Result<Nip05ID, String> {
-        if validate_email(&s) {
-            Ok(Self(s))
-        } else {
-            Err(format!("{} is not a valid nip 05 id.", s))
+        let invalid = || format!("{} is not a valid nip 05 id.", s);
+        let (local_part, domain) = s.split_once('@').ok_or_else(invalid)?;
+        // NIP-05 only allows a-z0-9-_. and is case-insensitive
+        let local_part = local_part.to_ascii_lowercase();
+        if local_part.is_empty()
+            || local_part.len() > MAX_LOCAL_PART_LENGTH
+            || !local_part.chars().all(is_local_part_char)
+        {
+            return Err(invalid());
         }
+        let domain = normalize_domain(domain).ok_or_else(invalid)?;
+        Ok(Self(format!("{}@{}", local_part, domain)))
+    }
+
+    /// The name before the `@`, `_` for a domain's root identifier.
+    pub fn local_part(&self) -> &str {
+        self.0
+            .rsplit_once('@')
+            .map_or("", |(local_part, _)| local_part)
+    }
+
+    pub fn domain(&self) -> &str {
+        self.0.rsplit_once('@').map_or("", |(_, domain)| domain)
     }
 }
 
+fn is_local_part_char(c: char) -> bool {
+    c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '-' | '_' | '.')
+}
+
+/// Lowercase ASCII form of a domain, internationalized labels converted to punycode. `None`
+/// if it is not a valid host name.
+pub fn normalize_domain(domain: &str) -> Option<String> {
+    let ascii = idna::domain_to_ascii(domain).ok()?;
+    let is_valid = ascii.len() <= 253
+        && ascii.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        });
+    is_valid.then_some(ascii)
+}
+
 impl std::fmt::Display for Nip05ID {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         self.0.fmt(f)
@@ -27,17 +69,41 @@ impl AsRef<str> for Nip05ID {
 #[cfg(test)]
 mod tests {
     use super::Nip05ID;
-    use claim::assert_err;
+    use claim::{assert_err, assert_ok};
     use fake::faker::internet::en::SafeEmail;
     use fake::Fake;
+    use quickcheck::Arbitrary;
+
+    const LOCAL_PART_CHARS: &[u8] =
+        b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789-_.";
+    const FORBIDDEN_CHARS: &[char] = &['+', '!', '#', '%', '\'', '"', ' ', '/', '@', '(', 'é', 'ß'];
 
     #[derive(Debug, Clone)]
     struct ValidateEmailFixture(pub String);
 
     impl quickcheck::Arbitrary for ValidateEmailFixture {
         fn arbitrary<G: quickcheck::Gen>(g: &mut G) -> Self {
-            let nip05 = SafeEmail().fake_with_rng(g);
-            Self(nip05)
+            // Faker names can carry apostrophes, which NIP-05 does not allow
+            loop {
+                let nip05: String = SafeEmail().fake_with_rng(g);
+                if !nip05.contains('\'') {
+                    return Self(nip05);
+                }
+            }
+        }
+    }
+
+    /// A local part of mixed case letters, digits and `-_.`.
+    #[derive(Debug, Clone)]
+    struct LocalPartFixture(pub String);
+
+    impl quickcheck::Arbitrary for LocalPartFixture {
+        fn arbitrary<G: quickcheck::Gen>(g: &mut G) -> Self {
+            let length = usize::arbitrary(g) % 32 + 1;
+            let local_part = (0..length)
+                .map(|_| LOCAL_PART_CHARS[usize::arbitrary(g) % LOCAL_PART_CHARS.len()] as char)
+                .collect();
+            Self(local_part)
         }
     }
 
@@ -46,6 +112,44 @@ mod tests {
         Nip05ID::parse(valid_nip05.0).is_ok()
     }
 
+    #[quickcheck_macros::quickcheck]
+    fn local_parts_from_the_nip05_alphabet_are_parsed(local_part: LocalPartFixture) -> bool {
+        Nip05ID::parse(format!("{}@frogs.cloud", local_part.0)).is_ok()
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn parsing_folds_case(local_part: LocalPartFixture) -> bool {
+        let lower = Nip05ID::parse(format!("{}@frogs.cloud", local_part.0.to_lowercase()));
+        let upper = Nip05ID::parse(format!("{}@FROGS.Cloud", local_part.0.to_uppercase()));
+        match (lower, upper) {
+            (Ok(lower), Ok(upper)) => {
+                lower.as_ref() == upper.as_ref() && lower.as_ref() == lower.as_ref().to_lowercase()
+            }
+            _ => false,
+        }
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn parsing_a_parsed_nip05_changes_nothing(valid_nip05: ValidateEmailFixture) -> bool {
+        let parsed = Nip05ID::parse(valid_nip05.0).unwrap();
+        Nip05ID::parse(parsed.to_string())
+            .map(|reparsed| reparsed.as_ref() == parsed.as_ref())
+            .unwrap_or(false)
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn local_parts_outside_the_nip05_alphabet_are_rejected(
+        local_part: LocalPartFixture,
+        position: usize,
+        forbidden: usize,
+    ) -> bool {
+        let mut local_part: Vec<char> = local_part.0.chars().collect();
+        let forbidden = FORBIDDEN_CHARS[forbidden % FORBIDDEN_CHARS.len()];
+        local_part.insert(position % (local_part.len() + 1), forbidden);
+        let local_part: String = local_part.into_iter().collect();
+        Nip05ID::parse(format!("{}@frogs.cloud", local_part)).is_err()
+    }
+
     #[test]
     fn empty_string_is_rejected() {
         let nip05 = "".to_string();
@@ -58,9 +162,62 @@ mod tests {
         assert_err!(Nip05ID::parse(nip05));
     }
 
+    #[test]
+    fn nip05_is_split_into_local_part_and_domain() {
+        let nip05 = Nip05ID::parse("bob@frogs.cloud".to_string()).unwrap();
+        assert_eq!(nip05.local_part(), "bob");
+        assert_eq!(nip05.domain(), "frogs.cloud");
+    }
+
     #[test]
     fn nip05_missing_subject_is_rejected() {
         let nip05 = "@domain.com".to_string();
         assert_err!(Nip05ID::parse(nip05));
     }
+
+    #[test]
+    fn the_root_identifier_of_a_domain_is_accepted() {
+        let nip05 = assert_ok!(Nip05ID::parse("_@frogs.cloud".to_string()));
+        assert_eq!(nip05.local_part(), "_");
+    }
+
+    #[test]
+    fn single_label_domains_are_accepted() {
+        assert_ok!(Nip05ID::parse("bob@localhost".to_string()));
+    }
+
+    #[test]
+    fn nip05_is_lowercased() {
+        let nip05 = assert_ok!(Nip05ID::parse("Bob.Smith@Frogs.CLOUD".to_string()));
+        assert_eq!(nip05.as_ref(), "bob.smith@frogs.cloud");
+    }
+
+    #[test]
+    fn internationalized_domains_are_stored_as_punycode() {
+        let unicode = assert_ok!(Nip05ID::parse("bob@Bücher.example".to_string()));
+        let punycode = assert_ok!(Nip05ID::parse("bob@xn--bcher-kva.example".to_string()));
+        assert_eq!(unicode.domain(), "xn--bcher-kva.example");
+        assert_eq!(unicode.as_ref(), punycode.as_ref());
+    }
+
+    #[test]
+    fn malformed_domains_are_rejected() {
+        for nip05 in [
+            "bob@frogs..cloud",
+            "bob@frogs.cloud.",
+            "bob@-frogs.cloud",
+            "bob@frogs_pond.cloud",
+            "bob@frogs.cloud@toads.cloud",
+            "bob@",
+        ] {
+            assert_err!(Nip05ID::parse(nip05.to_string()), "{}", nip05);
+        }
+    }
+
+    #[test]
+    fn quoted_and_overlong_local_parts_are_rejected() {
+        assert_err!(Nip05ID::parse(r#""bob"@frogs.cloud"#.to_string()));
+        assert_err!(Nip05ID::parse(format!("{}@frogs.cloud", "a".repeat(65))));
+        assert_ok!(Nip05ID::parse(format!("{}@frogs.cloud", "a".repeat(64))));
+    }
 }
@@ -0,0 +1,173 @@
+use super::PrivateKeyHash;
+use secp256k1::{schnorr::Signature, Message, XOnlyPublicKey, SECP256K1};
+use sha2::{Digest, Sha256};
+use std::str::FromStr;
+use utoipa::ToSchema;
+
+/// NIP-78 arbitrary custom app data, a parameterized replaceable event.
+pub const APP_DATA_KIND: u32 = 30078;
+
+/// A signed nostr event as described in NIP-01.
+#[derive(ToSchema, Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct NostrEvent {
+    #[schema(example = "4376c65d2f232afbe9b882a35baa4f6fe8667c4e684749af565f981833ed6a65")]
+    pub id: String,
+    #[schema(example = "6e468422dfb74a5738702a8823b9b28168abab8655faacb6853cd0ee15deee93")]
+    pub pubkey: String,
+    #[schema(example = 1676166575)]
+    pub created_at: i64,
+    #[schema(example = 30078)]
+    pub kind: u32,
+    #[schema(example = json!([["d", "nostr-vault"]]))]
+    pub tags: Vec<Vec<String>>,
+    pub content: String,
+    #[schema(
+        example = "908a15e46fb4d8675bab026fc230a0e3542bfade63da02d542fb78b2a8513fcd0092619a2c8c1221e581946e0191f2af505dfdf8657a414dbca329186f009262"
+    )]
+    pub sig: String,
+}
+
+impl NostrEvent {
+    /// sha256 of the NIP-01 serialization `[0, pubkey, created_at, kind, tags, content]`.
+    pub fn compute_id(&self) -> [u8; 32] {
+        let serialized = serde_json::json!([
+            0,
+            self.pubkey,
+            self.created_at,
+            self.kind,
+            self.tags,
+            self.content
+        ])
+        .to_string();
+        Sha256::digest(serialized.as_bytes()).into()
+    }
+
+    pub fn verify_signature(&self) -> Result<(), String> {
+        let id = self.compute_id();
+        if hex::encode(id) != self.id {
+            return Err(format!("{} is not the id of the provided event.", self.id));
+        }
+        let pubkey = XOnlyPublicKey::from_str(&self.pubkey)
+            .map_err(|_| format!("{} is not a valid nostr public key.", self.pubkey))?;
+        let sig = Signature::from_str(&self.sig)
+            .map_err(|_| format!("{} is not a valid schnorr signature.", self.sig))?;
+        let message = Message::from_slice(&id).map_err(|e| e.to_string())?;
+        SECP256K1
+            .verify_schnorr(&sig, &message, &pubkey)
+            .map_err(|_| {
+                format!(
+                    "{} is not a valid signature for event {}.",
+                    self.sig, self.id
+                )
+            })
+    }
+
+    pub fn tag_value(&self, name: &str) -> Option<&str> {
+        self.tags
+            .iter()
+            .find(|tag| tag.first().map(String::as_str) == Some(name))
+            .and_then(|tag| tag.get(1))
+            .map(String::as_str)
+    }
+}
+
+/// A signed NIP-78 event carrying the same encrypted blob that is being stored.
+#[derive(Debug, Clone)]
+pub struct AppDataEvent(NostrEvent);
+
+impl AsRef<NostrEvent> for AppDataEvent {
+    fn as_ref(&self) -> &NostrEvent {
+        &self.0
+    }
+}
+
+impl AppDataEvent {
+    pub fn parse(
+        event: NostrEvent,
+        private_key_hash: &PrivateKeyHash,
+    ) -> Result<AppDataEvent, String> {
+        if event.kind != APP_DATA_KIND {
+            return Err(format!(
+                "{} is not a valid app data event kind, expected {}.",
+                event.kind, APP_DATA_KIND
+            ));
+        }
+        if event.tag_value("d").map_or(true, |d| d.trim().is_empty()) {
+            return Err("App data event is missing its d tag.".to_string());
+        }
+        if event.content != private_key_hash.as_ref() {
+            return Err("App data event content does not match the private key.".to_string());
+        }
+        event.verify_signature()?;
+        Ok(Self(event))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AppDataEvent, NostrEvent, APP_DATA_KIND};
+    use crate::domain::PrivateKeyHash;
+    use claim::{assert_err, assert_ok};
+    use secp256k1::{KeyPair, Message};
+    use secrecy::Secret;
+
+    const PRIVATE_KEY_HASH: &str = "$PBKDF2$i=100000,l=256,s=0Bu5lWu4s66/iottrlUGdckjf5nwnpB05jwp4yDh8NU=$AESGM$OrScsD+hHGaRaPbc$XMXVVbjt3JV+QsNb7ZWRc8uNod2YzJL0lSvW1FOiY38ywOu7IEChKs/IqEQ7knhZAmRGYqoB4dhAbdOTwVhYIeQsuf1+f+9ARPEjtURsDg==";
+
+    fn private_key_hash() -> PrivateKeyHash {
+        PrivateKeyHash::parse(Secret::new(PRIVATE_KEY_HASH.to_string())).unwrap()
+    }
+
+    fn signed_event(kind: u32, tags: Vec<Vec<String>>, content: &str) -> NostrEvent {
+        let keypair = KeyPair::new_global(&mut rand::thread_rng());
+        let mut event = NostrEvent {
+            id: String::new(),
+            pubkey: keypair.x_only_public_key().0.to_string(),
+            created_at: 1676166575,
+            kind,
+            tags,
+            content: content.to_string(),
+            sig: String::new(),
+        };
+        let id = event.compute_id();
+        event.id = hex::encode(id);
+        event.sig = keypair
+            .sign_schnorr(Message::from_slice(&id).unwrap())
+            .to_string();
+        event
+    }
+
+    fn d_tag() -> Vec<Vec<String>> {
+        vec![vec!["d".to_string(), "nostr-vault".to_string()]]
+    }
+
+    #[test]
+    fn a_valid_app_data_event() {
+        let event = signed_event(APP_DATA_KIND, d_tag(), PRIVATE_KEY_HASH);
+        assert_ok!(AppDataEvent::parse(event, &private_key_hash()));
+    }
+
+    #[test]
+    fn a_tampered_event_is_rejected() {
+        let mut event = signed_event(APP_DATA_KIND, d_tag(), PRIVATE_KEY_HASH);
+        event.created_at += 1;
+        assert_err!(event.verify_signature());
+    }
+
+    #[test]
+    fn a_different_blob_is_rejected() {
+        let event = signed_event(APP_DATA_KIND, d_tag(), "$PBKDF2$i=1,l=256,s=a$AESGM$b$c");
+        assert_err!(AppDataEvent::parse(event, &private_key_hash()));
+    }
+
+    #[test]
+    fn other_kinds_are_rejected() {
+        let event = signed_event(1, d_tag(), PRIVATE_KEY_HASH);
+        assert_err!(AppDataEvent::parse(event, &private_key_hash()));
+    }
+
+    #[test]
+    fn a_missing_d_tag_is_rejected() {
+        let event = signed_event(APP_DATA_KIND, vec![], PRIVATE_KEY_HASH);
+        assert_err!(AppDataEvent::parse(event, &private_key_hash()));
+    }
+}
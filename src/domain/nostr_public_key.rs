@@ -0,0 +1,94 @@
+use bech32::FromBase32;
+use secp256k1::XOnlyPublicKey;
+use std::str::FromStr;
+
+/// A nostr public key, accepted as hex or as a NIP-19 `npub` and kept in its 32 byte
+/// lowercase hex form.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NostrPublicKey(XOnlyPublicKey);
+
+impl NostrPublicKey {
+    pub fn parse(s: String) -> Result<NostrPublicKey, String> {
+        let key = if s.starts_with("npub1") {
+            decode_npub(&s)
+        } else {
+            XOnlyPublicKey::from_str(&s).ok()
+        };
+        key.map(Self)
+            .ok_or_else(|| format!("{} is not a valid nostr public key.", s))
+    }
+
+    pub fn x_only_public_key(&self) -> &XOnlyPublicKey {
+        &self.0
+    }
+}
+
+fn decode_npub(npub: &str) -> Option<XOnlyPublicKey> {
+    let (hrp, data, variant) = bech32::decode(npub).ok()?;
+    if hrp != "npub" || variant != bech32::Variant::Bech32 {
+        return None;
+    }
+    let bytes = Vec::<u8>::from_base32(&data).ok()?;
+    XOnlyPublicKey::from_slice(&bytes).ok()
+}
+
+impl std::fmt::Display for NostrPublicKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NostrPublicKey;
+    use claim::{assert_err, assert_ok};
+
+    #[test]
+    fn a_valid_hex_key() {
+        let key = "6e468422dfb74a5738702a8823b9b28168abab8655faacb6853cd0ee15deee93".to_string();
+        assert_ok!(NostrPublicKey::parse(key));
+    }
+
+    #[test]
+    fn uppercase_hex_is_normalised() {
+        let key = "6E468422DFB74A5738702A8823B9B28168ABAB8655FAACB6853CD0EE15DEEE93".to_string();
+        assert_eq!(
+            NostrPublicKey::parse(key).unwrap().to_string(),
+            "6e468422dfb74a5738702a8823b9b28168abab8655faacb6853cd0ee15deee93"
+        );
+    }
+
+    #[test]
+    fn an_npub_is_decoded_to_hex() {
+        // NIP-19 example
+        let npub = "npub10elfcs4fr0l0r8af98jlmgdh9c8tcxjvz9qkw038js35mp4dma8qzvjptg".to_string();
+        assert_eq!(
+            NostrPublicKey::parse(npub).unwrap().to_string(),
+            "7e7e9c42a91bfef19fa929e5fda1b72e0ebc1a4c1141673e2794234d86addf4e"
+        );
+    }
+
+    #[test]
+    fn an_npub_with_a_bad_checksum_is_rejected() {
+        let npub = "npub10elfcs4fr0l0r8af98jlmgdh9c8tcxjvz9qkw038js35mp4dma8qzvjptq".to_string();
+        assert_err!(NostrPublicKey::parse(npub));
+    }
+
+    #[test]
+    fn an_nsec_is_rejected() {
+        let nsec = "nsec1vl029mgpspedva04g90vltkh6fvh240zqtv9k0t9af8935ke9laqsnlfe5".to_string();
+        assert_err!(NostrPublicKey::parse(nsec));
+    }
+
+    #[test]
+    fn a_short_key_is_rejected() {
+        let key = "6e468422dfb74a5738702a8823b9b281".to_string();
+        assert_err!(NostrPublicKey::parse(key));
+    }
+
+    #[test]
+    fn a_non_hex_key_is_rejected() {
+        let key = "z".repeat(64);
+        assert_err!(NostrPublicKey::parse(key));
+    }
+}
@@ -0,0 +1,90 @@
+use rand::Rng;
+use secrecy::{ExposeSecret, Secret};
+
+/// Characters that can't be mistaken for each other when read back from paper.
+const ALPHABET: &[u8] = b"ABCDEFGHJKMNPQRSTVWXYZ23456789";
+const LENGTH: usize = 10;
+/// Leading characters stored in the clear to find the one hash a code is checked against.
+const PREFIX_LENGTH: usize = 2;
+
+/// A one-time code that can stand in for the pin, kept without its `-` separator.
+#[derive(Debug, Clone)]
+pub struct RecoveryCode(Secret<String>);
+
+impl AsRef<str> for RecoveryCode {
+    fn as_ref(&self) -> &str {
+        self.0.expose_secret().as_str()
+    }
+}
+
+impl RecoveryCode {
+    pub fn generate() -> RecoveryCode {
+        let mut rng = rand::thread_rng();
+        let code = (0..LENGTH)
+            .map(|_| ALPHABET[rng.gen_range(0..ALPHABET.len())] as char)
+            .collect();
+        Self(Secret::new(code))
+    }
+
+    /// Accepts codes as handed out, in any case and with or without separators.
+    pub fn parse(secret: Secret<String>) -> Result<RecoveryCode, String> {
+        let code: String = secret
+            .expose_secret()
+            .chars()
+            .filter(|c| *c != '-' && !c.is_whitespace())
+            .map(|c| c.to_ascii_uppercase())
+            .collect();
+        if code.len() != LENGTH || !code.bytes().all(|b| ALPHABET.contains(&b)) {
+            return Err("Provided value is not a valid recovery code.".to_string());
+        }
+        Ok(Self(Secret::new(code)))
+    }
+
+    /// Picks the stored hash to verify against, codes issued together never share one.
+    pub fn prefix(&self) -> &str {
+        &self.as_ref()[..PREFIX_LENGTH]
+    }
+
+    /// The form shown to users, e.g. `ABCDE-FGH23`.
+    pub fn formatted(&self) -> String {
+        let code = self.as_ref();
+        format!("{}-{}", &code[..LENGTH / 2], &code[LENGTH / 2..])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RecoveryCode;
+    use claim::{assert_err, assert_ok};
+    use secrecy::Secret;
+
+    #[test]
+    fn generated_codes_parse() {
+        for _ in 0..100 {
+            let code = RecoveryCode::generate();
+            assert_ok!(RecoveryCode::parse(Secret::new(code.formatted())));
+        }
+    }
+
+    #[test]
+    fn separators_and_case_are_ignored() {
+        let code = RecoveryCode::parse(Secret::new(" abcde-fgh23 ".to_string())).unwrap();
+        assert_eq!(code.as_ref(), "ABCDEFGH23");
+        assert_eq!(code.formatted(), "ABCDE-FGH23");
+    }
+
+    #[test]
+    fn a_short_code_is_rejected() {
+        assert_err!(RecoveryCode::parse(Secret::new("ABCDE-FGH2".to_string())));
+    }
+
+    #[test]
+    fn ambiguous_characters_are_rejected() {
+        assert_err!(RecoveryCode::parse(Secret::new("ABCDE-FGH10".to_string())));
+    }
+
+    #[test]
+    fn an_empty_code_is_rejected() {
+        assert_err!(RecoveryCode::parse(Secret::new("".to_string())));
+    }
+}
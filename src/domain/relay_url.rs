@@ -0,0 +1,44 @@
+use reqwest::Url;
+
+/// A websocket url of a nostr relay.
+#[derive(Debug, Clone)]
+pub struct RelayUrl(String);
+
+impl RelayUrl {
+    pub fn parse(s: String) -> Result<RelayUrl, String> {
+        match Url::parse(&s) {
+            Ok(url) if matches!(url.scheme(), "ws" | "wss") && url.host_str().is_some() => {
+                Ok(Self(s))
+            }
+            _ => Err(format!("{} is not a valid relay url.", s)),
+        }
+    }
+}
+
+impl AsRef<str> for RelayUrl {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RelayUrl;
+    use claim::{assert_err, assert_ok};
+
+    #[test]
+    fn a_websocket_url_is_accepted() {
+        assert_ok!(RelayUrl::parse("wss://relay.damus.io".to_string()));
+        assert_ok!(RelayUrl::parse("ws://127.0.0.1:7000".to_string()));
+    }
+
+    #[test]
+    fn an_http_url_is_rejected() {
+        assert_err!(RelayUrl::parse("https://relay.damus.io".to_string()));
+    }
+
+    #[test]
+    fn garbage_is_rejected() {
+        assert_err!(RelayUrl::parse("relay".to_string()));
+    }
+}
@@ -0,0 +1,53 @@
+use secrecy::{ExposeSecret, Secret};
+
+/// A 6 digit RFC 6238 code from the user's authenticator app.
+#[derive(Debug, Clone)]
+pub struct TotpCode(Secret<String>);
+
+impl AsRef<str> for TotpCode {
+    fn as_ref(&self) -> &str {
+        self.0.expose_secret().as_str()
+    }
+}
+
+impl TotpCode {
+    pub fn parse(secret: Secret<String>) -> Result<TotpCode, String> {
+        let code: String = secret
+            .expose_secret()
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .collect();
+        if code.len() != 6 || !code.chars().all(|c| c.is_ascii_digit()) {
+            return Err("Provided value is not a valid 6 digit code.".to_string());
+        }
+        Ok(Self(Secret::new(code)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TotpCode;
+    use claim::{assert_err, assert_ok};
+    use secrecy::Secret;
+
+    #[test]
+    fn six_digits_are_valid() {
+        assert_ok!(TotpCode::parse(Secret::new("012345".to_string())));
+    }
+
+    #[test]
+    fn spaces_are_ignored() {
+        let code = TotpCode::parse(Secret::new("012 345".to_string())).unwrap();
+        assert_eq!(code.as_ref(), "012345");
+    }
+
+    #[test]
+    fn five_digits_are_rejected() {
+        assert_err!(TotpCode::parse(Secret::new("12345".to_string())));
+    }
+
+    #[test]
+    fn letters_are_rejected() {
+        assert_err!(TotpCode::parse(Secret::new("12345a".to_string())));
+    }
+}
@@ -0,0 +1,94 @@
+use std::collections::BTreeMap;
+
+/// How two version vectors relate to each other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Causality {
+    Before,
+    After,
+    Equal,
+    Concurrent,
+}
+
+/// Per vault write counters, used to tell whether one copy of a row has seen another.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct VersionVector(BTreeMap<String, i64>);
+
+impl VersionVector {
+    pub fn increment(&mut self, node_id: &str) {
+        *self.0.entry(node_id.to_string()).or_insert(0) += 1;
+    }
+
+    pub fn merge(&self, other: &VersionVector) -> VersionVector {
+        let mut merged = self.0.clone();
+        for (node_id, counter) in other.0.iter() {
+            let entry = merged.entry(node_id.clone()).or_insert(0);
+            *entry = (*entry).max(*counter);
+        }
+        VersionVector(merged)
+    }
+
+    pub fn compare(&self, other: &VersionVector) -> Causality {
+        let mut ahead = false;
+        let mut behind = false;
+        for node_id in self.0.keys().chain(other.0.keys()) {
+            let mine = self.0.get(node_id).copied().unwrap_or(0);
+            let theirs = other.0.get(node_id).copied().unwrap_or(0);
+            ahead |= mine > theirs;
+            behind |= mine < theirs;
+        }
+        match (ahead, behind) {
+            (false, false) => Causality::Equal,
+            (true, false) => Causality::After,
+            (false, true) => Causality::Before,
+            (true, true) => Causality::Concurrent,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Causality, VersionVector};
+
+    fn vector(counters: &[(&str, i64)]) -> VersionVector {
+        VersionVector(
+            counters
+                .iter()
+                .map(|(node_id, counter)| (node_id.to_string(), *counter))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn empty_vectors_are_equal() {
+        assert_eq!(
+            VersionVector::default().compare(&VersionVector::default()),
+            Causality::Equal
+        );
+    }
+
+    #[test]
+    fn increment_moves_vector_forward() {
+        let original = vector(&[("a", 1)]);
+        let mut updated = original.clone();
+        updated.increment("b");
+        assert_eq!(updated.compare(&original), Causality::After);
+        assert_eq!(original.compare(&updated), Causality::Before);
+    }
+
+    #[test]
+    fn independent_writes_are_concurrent() {
+        let left = vector(&[("a", 2), ("b", 1)]);
+        let right = vector(&[("a", 1), ("b", 2)]);
+        assert_eq!(left.compare(&right), Causality::Concurrent);
+    }
+
+    #[test]
+    fn merge_dominates_both_sides() {
+        let left = vector(&[("a", 2), ("b", 1)]);
+        let right = vector(&[("a", 1), ("c", 4)]);
+        let merged = left.merge(&right);
+        assert_eq!(merged, vector(&[("a", 2), ("b", 1), ("c", 4)]));
+        assert_eq!(merged.compare(&left), Causality::After);
+        assert_eq!(merged.compare(&right), Causality::After);
+    }
+}
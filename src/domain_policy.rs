@@ -0,0 +1,227 @@
+use crate::configuration::DomainPolicySettings;
+use crate::domain::{normalize_domain, Nip05ID};
+use anyhow::{anyhow, Context};
+use sqlx::PgPool;
+
+#[derive(thiserror::Error, Debug)]
+pub enum DomainPolicyError {
+    #[error("This vault does not accept nip 05 ids on {0}.")]
+    NotAllowed(String),
+    #[error("{0} already holds the {1} keys it is allowed.")]
+    QuotaExceeded(String, u64),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+/// `frogs.cloud`, or `*.frogs.cloud` for its subdomains.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum DomainPattern {
+    Exact(String),
+    /// Stored with the leading dot, `.frogs.cloud`.
+    Subdomains(String),
+}
+
+impl DomainPattern {
+    fn parse(pattern: &str) -> Result<Self, anyhow::Error> {
+        let pattern = pattern.trim();
+        let (wildcard, domain) = match pattern.strip_prefix("*.") {
+            Some(domain) => (true, domain),
+            None => (false, pattern),
+        };
+        let domain = normalize_domain(domain)
+            .ok_or_else(|| anyhow!("{} is not a domain or `*.` followed by a domain.", pattern))?;
+        Ok(match wildcard {
+            true => DomainPattern::Subdomains(format!(".{}", domain)),
+            false => DomainPattern::Exact(domain),
+        })
+    }
+
+    fn matches(&self, domain: &str) -> bool {
+        match self {
+            DomainPattern::Exact(exact) => exact == domain,
+            DomainPattern::Subdomains(suffix) => {
+                domain.len() > suffix.len() && domain.ends_with(suffix.as_str())
+            }
+        }
+    }
+
+    /// Exact domains beat wildcards, longer wildcards beat shorter ones.
+    fn specificity(&self) -> (bool, usize) {
+        match self {
+            DomainPattern::Exact(exact) => (true, exact.len()),
+            DomainPattern::Subdomains(suffix) => (false, suffix.len()),
+        }
+    }
+}
+
+fn parse_patterns(setting: &str, patterns: &[String]) -> Result<Vec<DomainPattern>, anyhow::Error> {
+    patterns
+        .iter()
+        .map(|pattern| {
+            DomainPattern::parse(pattern).with_context(|| format!("domain_policy.{}", setting))
+        })
+        .collect()
+}
+
+/// Which domains uploads are accepted for and how many keys each may hold, see
+/// `DomainPolicySettings`. Only new keys are checked, keys already stored or replicated
+/// from a peer stay where they are.
+#[derive(Clone)]
+pub struct DomainPolicy {
+    allowed: Vec<DomainPattern>,
+    denied: Vec<DomainPattern>,
+    max_keys_per_domain: u64,
+    quotas: Vec<(DomainPattern, u64)>,
+}
+
+impl DomainPolicy {
+    pub fn new(settings: &DomainPolicySettings) -> Result<Self, anyhow::Error> {
+        let quotas = settings
+            .quotas
+            .iter()
+            .map(|quota| {
+                DomainPattern::parse(&quota.domain)
+                    .context("domain_policy.quotas")
+                    .map(|pattern| (pattern, quota.max_keys))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            allowed: parse_patterns("allowed_domains", &settings.allowed_domains)?,
+            denied: parse_patterns("denied_domains", &settings.denied_domains)?,
+            max_keys_per_domain: settings.max_keys_per_domain,
+            quotas,
+        })
+    }
+
+    /// Allow and deny lists only, a denied domain stays denied when it is also allowed.
+    pub fn accepts(&self, domain: &str) -> bool {
+        let domain = domain.to_lowercase();
+        let listed = |patterns: &[DomainPattern]| patterns.iter().any(|p| p.matches(&domain));
+        (self.allowed.is_empty() || listed(&self.allowed)) && !listed(&self.denied)
+    }
+
+    /// Keys `domain` may hold, `None` for no limit.
+    pub fn quota(&self, domain: &str) -> Option<u64> {
+        let domain = domain.to_lowercase();
+        let max_keys = self
+            .quotas
+            .iter()
+            .filter(|(pattern, _)| pattern.matches(&domain))
+            .max_by_key(|(pattern, _)| pattern.specificity())
+            .map_or(self.max_keys_per_domain, |(_, max_keys)| *max_keys);
+        (max_keys > 0).then_some(max_keys)
+    }
+
+    /// Checked before a new key is stored. Uploads racing for the last free slot of a
+    /// domain can each pass, so a quota may be overshot by the uploads in flight.
+    #[tracing::instrument(name = "Check domain policy", skip(self, pool))]
+    pub async fn check(&self, nip_05_id: &Nip05ID, pool: &PgPool) -> Result<(), DomainPolicyError> {
+        let domain = nip_05_id.domain().to_lowercase();
+        if !self.accepts(&domain) {
+            return Err(DomainPolicyError::NotAllowed(domain));
+        }
+        let max_keys = match self.quota(&domain) {
+            Some(max_keys) => max_keys,
+            None => return Ok(()),
+        };
+        let keys = sqlx::query!(
+            r#"
+            SELECT COUNT(*) AS "keys!" FROM keys
+            WHERE lower(substring(nip_05_id from '@([^@]*)$')) = $1
+            "#,
+            domain
+        )
+        .fetch_one(pool)
+        .await
+        .context("Failed to count the keys of the domain.")?
+        .keys;
+        if keys as u64 >= max_keys {
+            return Err(DomainPolicyError::QuotaExceeded(domain, max_keys));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DomainPattern, DomainPolicy};
+    use crate::configuration::{DomainPolicySettings, DomainQuotaSettings};
+    use claim::{assert_err, assert_ok};
+
+    fn policy(allowed: &[&str], denied: &[&str]) -> DomainPolicy {
+        DomainPolicy::new(&DomainPolicySettings {
+            allowed_domains: allowed.iter().map(|d| d.to_string()).collect(),
+            denied_domains: denied.iter().map(|d| d.to_string()).collect(),
+            ..DomainPolicySettings::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn wildcards_cover_subdomains_but_not_the_domain_itself() {
+        let pattern = assert_ok!(DomainPattern::parse("*.Frogs.Cloud"));
+
+        assert!(pattern.matches("pond.frogs.cloud"));
+        assert!(pattern.matches("lily.pond.frogs.cloud"));
+        assert!(!pattern.matches("frogs.cloud"));
+        assert!(!pattern.matches("notfrogs.cloud"));
+    }
+
+    #[test]
+    fn malformed_patterns_are_rejected() {
+        assert_err!(DomainPattern::parse(""));
+        assert_err!(DomainPattern::parse("*"));
+        assert_err!(DomainPattern::parse("*frogs.cloud"));
+        assert_err!(DomainPattern::parse("pond.*.cloud"));
+        assert_err!(DomainPattern::parse("frogs..cloud"));
+        assert_err!(DomainPattern::parse("bob@frogs.cloud"));
+    }
+
+    #[test]
+    fn an_empty_allowlist_accepts_every_domain_not_denied() {
+        let policy = policy(&[], &["spam.example", "*.spam.example"]);
+
+        assert!(policy.accepts("frogs.cloud"));
+        assert!(!policy.accepts("spam.example"));
+        assert!(!policy.accepts("more.SPAM.example"));
+    }
+
+    #[test]
+    fn the_denylist_wins_over_the_allowlist() {
+        let policy = policy(&["frogs.cloud", "*.frogs.cloud"], &["toads.frogs.cloud"]);
+
+        assert!(policy.accepts("frogs.cloud"));
+        assert!(policy.accepts("pond.frogs.cloud"));
+        assert!(!policy.accepts("toads.frogs.cloud"));
+        assert!(!policy.accepts("example.com"));
+    }
+
+    #[test]
+    fn the_most_specific_quota_wins() {
+        let policy = DomainPolicy::new(&DomainPolicySettings {
+            max_keys_per_domain: 100,
+            quotas: vec![
+                DomainQuotaSettings {
+                    domain: "*.frogs.cloud".to_string(),
+                    max_keys: 10,
+                },
+                DomainQuotaSettings {
+                    domain: "*.pond.frogs.cloud".to_string(),
+                    max_keys: 5,
+                },
+                DomainQuotaSettings {
+                    domain: "vip.frogs.cloud".to_string(),
+                    max_keys: 0,
+                },
+            ],
+            ..DomainPolicySettings::default()
+        })
+        .unwrap();
+
+        assert_eq!(policy.quota("example.com"), Some(100));
+        assert_eq!(policy.quota("frogs.cloud"), Some(100));
+        assert_eq!(policy.quota("toads.frogs.cloud"), Some(10));
+        assert_eq!(policy.quota("lily.pond.frogs.cloud"), Some(5));
+        assert_eq!(policy.quota("vip.frogs.cloud"), None);
+    }
+}
@@ -0,0 +1,183 @@
+use crate::configuration::EnumerationResistanceSettings;
+use crate::routes::{ErrorCode, ErrorResponse};
+use actix_web::body::{to_bytes, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{Method, StatusCode};
+use actix_web::web::{Bytes, Data};
+use actix_web::{error, HttpResponse};
+use rand::Rng;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+/// What unknown nip 05 ids and wrong pins are both answered with, once masked.
+pub const MASKED_MESSAGE: &str = "The nip 05 id and pin do not match a stored key.";
+
+/// Keeps the key routes from telling which nip 05 ids are stored: unknown ids and wrong pins
+/// get the same error, every body is padded to a multiple of `pad_to_bytes` and every
+/// response takes at least `min_response_milliseconds` plus up to `jitter_milliseconds`.
+#[derive(Clone)]
+pub struct EnumerationResistance {
+    enabled: bool,
+    min_duration: Duration,
+    jitter_milliseconds: u64,
+    pad_to_bytes: usize,
+}
+
+impl EnumerationResistance {
+    pub fn new(settings: &EnumerationResistanceSettings) -> Self {
+        Self {
+            enabled: settings.enabled,
+            min_duration: Duration::from_millis(settings.min_response_milliseconds),
+            jitter_milliseconds: settings.jitter_milliseconds,
+            pad_to_bytes: settings.pad_to_bytes.max(1),
+        }
+    }
+
+    /// `KEY_NOT_FOUND` and `PIN_INVALID` both become a 403 `CREDENTIALS_INVALID`, keeping
+    /// the request id.
+    fn mask(&self, status: StatusCode, body: Bytes) -> (StatusCode, Bytes) {
+        let error = match serde_json::from_slice::<ErrorResponse>(&body) {
+            Ok(error) if matches!(error.code, ErrorCode::KeyNotFound | ErrorCode::PinInvalid) => {
+                error
+            }
+            _ => return (status, body),
+        };
+        let masked = ErrorResponse {
+            code: ErrorCode::CredentialsInvalid,
+            value: MASKED_MESSAGE.to_string(),
+            request_id: error.request_id,
+        };
+        match serde_json::to_vec(&masked) {
+            Ok(masked) => (StatusCode::FORBIDDEN, masked.into()),
+            Err(_) => (status, body),
+        }
+    }
+
+    /// Trailing whitespace, which json parsers skip. Empty bodies, e.g. of a 204, stay empty.
+    fn pad(&self, body: Bytes) -> Bytes {
+        if body.is_empty() {
+            return body;
+        }
+        let padded_length = body.len().div_ceil(self.pad_to_bytes) * self.pad_to_bytes;
+        let mut padded = body.to_vec();
+        padded.resize(padded_length, b' ');
+        padded.into()
+    }
+
+    async fn delay(&self, started: Instant) {
+        let jitter = rand::thread_rng().gen_range(0..=self.jitter_milliseconds);
+        let deadline = started + self.min_duration + Duration::from_millis(jitter);
+        tokio::time::sleep_until(deadline.into()).await;
+    }
+}
+
+/// Wrapped around each key route with `wrap_fn`, does nothing unless
+/// `enumeration_resistance.enabled` is set.
+pub fn shield_lookup<S, B>(
+    req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = Result<ServiceResponse, actix_web::Error>>
+where
+    S: actix_web::dev::Service<
+        ServiceRequest,
+        Response = ServiceResponse<B>,
+        Error = actix_web::Error,
+    >,
+    B: MessageBody + 'static,
+{
+    let started = Instant::now();
+    let resistance = req
+        .app_data::<Data<EnumerationResistance>>()
+        .filter(|resistance| resistance.enabled && req.method() != Method::OPTIONS)
+        .cloned();
+    let response = srv.call(req);
+    async move {
+        let response = response.await?;
+        let resistance = match resistance {
+            Some(resistance) => resistance,
+            None => return Ok(response.map_into_boxed_body()),
+        };
+        let (request, response) = response.into_parts();
+        let status = response.status();
+        let (head, body) = response.into_parts();
+        let body = to_bytes(body).await.map_err(|e| {
+            let e: Box<dyn std::error::Error> = e.into();
+            error::ErrorInternalServerError(e.to_string())
+        })?;
+        let (status, body) = resistance.mask(status, body);
+        let body = resistance.pad(body);
+        resistance.delay(started).await;
+        let mut response: HttpResponse = head.set_body(body).map_into_boxed_body();
+        *response.status_mut() = status;
+        Ok(ServiceResponse::new(request, response))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EnumerationResistance, MASKED_MESSAGE};
+    use crate::configuration::EnumerationResistanceSettings;
+    use crate::routes::{ErrorCode, ErrorResponse};
+    use actix_web::http::StatusCode;
+    use actix_web::web::Bytes;
+
+    fn resistance() -> EnumerationResistance {
+        EnumerationResistance::new(&EnumerationResistanceSettings {
+            enabled: true,
+            min_response_milliseconds: 0,
+            jitter_milliseconds: 0,
+            pad_to_bytes: 256,
+        })
+    }
+
+    fn error_body(code: ErrorCode, value: &str) -> Bytes {
+        serde_json::to_vec(&ErrorResponse {
+            code,
+            value: value.to_string(),
+            request_id: Some("5b0e3a4e-77a1-4c3a-9c55-0d3c6f0a2f4e".to_string()),
+        })
+        .unwrap()
+        .into()
+    }
+
+    #[test]
+    fn unknown_ids_and_wrong_pins_are_masked_the_same() {
+        let resistance = resistance();
+        let not_found = resistance.mask(
+            StatusCode::NOT_FOUND,
+            error_body(ErrorCode::KeyNotFound, "There is no private key."),
+        );
+        let wrong_pin = resistance.mask(
+            StatusCode::FORBIDDEN,
+            error_body(ErrorCode::PinInvalid, "Pin is not valid for provided user."),
+        );
+
+        assert_eq!(not_found, wrong_pin);
+        assert_eq!(not_found.0, StatusCode::FORBIDDEN);
+        let masked: ErrorResponse = serde_json::from_slice(&not_found.1).unwrap();
+        assert_eq!(masked.code, ErrorCode::CredentialsInvalid);
+        assert_eq!(masked.value, MASKED_MESSAGE);
+    }
+
+    #[test]
+    fn other_responses_pass_through() {
+        let body = error_body(ErrorCode::PinMalformed, "8ehd99 is not a valid pin.");
+        let (status, masked) = resistance().mask(StatusCode::BAD_REQUEST, body.clone());
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(masked, body);
+    }
+
+    #[test]
+    fn bodies_are_padded_to_whole_blocks_and_stay_valid_json() {
+        let resistance = resistance();
+        for length in [1, 255, 256, 257] {
+            let padded = resistance.pad(Bytes::from(vec![b' '; length]));
+            assert_eq!(padded.len() % 256, 0);
+            assert!(padded.len() >= length);
+        }
+        assert!(resistance.pad(Bytes::new()).is_empty());
+        let padded = resistance.pad(error_body(ErrorCode::Internal, "x"));
+        assert!(serde_json::from_slice::<ErrorResponse>(&padded).is_ok());
+    }
+}
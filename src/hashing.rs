@@ -0,0 +1,180 @@
+use crate::configuration::HashingSettings;
+use crate::metrics::{BLOCKING_TASKS_QUEUED, BLOCKING_TASKS_RUNNING};
+use anyhow::Context;
+use std::panic::AssertUnwindSafe;
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Instant;
+use tracing::Instrument;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+static POOL: OnceLock<HashingPool> = OnceLock::new();
+
+#[derive(thiserror::Error, Debug)]
+pub enum HashingError {
+    #[error("Too many pins are waiting to be hashed, retry in {0} seconds.")]
+    Overloaded(u64),
+    #[error("The hashing workers have stopped.")]
+    Stopped,
+}
+
+/// Seconds to wait before retrying, if `e` was caused by a full hashing queue.
+pub fn retry_after(e: &anyhow::Error) -> Option<u64> {
+    match e.downcast_ref::<HashingError>() {
+        Some(HashingError::Overloaded(seconds)) => Some(*seconds),
+        _ => None,
+    }
+}
+
+/// Fixed set of threads Argon2 runs on. Each run allocates about 15 MB, so unlike tokio's
+/// blocking pool it never grows: work beyond `max_queued` waiting jobs is refused.
+pub struct HashingPool {
+    sender: SyncSender<Job>,
+    retry_after_seconds: u64,
+}
+
+impl HashingPool {
+    pub fn new(settings: &HashingSettings) -> Result<Self, anyhow::Error> {
+        let (sender, receiver) = sync_channel::<Job>(settings.max_queued);
+        let receiver = Arc::new(Mutex::new(receiver));
+        for worker in 0..settings.workers.max(1) {
+            let receiver = receiver.clone();
+            std::thread::Builder::new()
+                .name(format!("pin-hasher-{}", worker))
+                .spawn(move || loop {
+                    // Only fails once the pool, and with it the sender, is dropped
+                    let job = match receiver.lock().map(|receiver| receiver.recv()) {
+                        Ok(Ok(job)) => job,
+                        _ => break,
+                    };
+                    job();
+                })
+                .context("Failed to start a hashing worker.")?;
+        }
+        Ok(Self {
+            sender,
+            retry_after_seconds: settings.retry_after_seconds,
+        })
+    }
+
+    /// Runs `f` on a worker, the time it spent queued is recorded as `queue_wait_ms`.
+    pub async fn run<F, R>(&self, f: F) -> Result<R, HashingError>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let span = tracing::info_span!(
+            "Wait for hashing worker",
+            queue_wait_ms = tracing::field::Empty
+        );
+        let job_span = span.clone();
+        let enqueued = Instant::now();
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        let job: Job = Box::new(move || {
+            BLOCKING_TASKS_QUEUED.dec();
+            BLOCKING_TASKS_RUNNING.inc();
+            job_span.record("queue_wait_ms", enqueued.elapsed().as_millis() as u64);
+            // A panic drops `sender`, which the caller sees as `Stopped`, the worker lives on
+            let result = std::panic::catch_unwind(AssertUnwindSafe(|| job_span.in_scope(f)));
+            BLOCKING_TASKS_RUNNING.dec();
+            if let Ok(result) = result {
+                let _ = sender.send(result);
+            }
+        });
+        BLOCKING_TASKS_QUEUED.inc();
+        if let Err(e) = self.sender.try_send(job) {
+            BLOCKING_TASKS_QUEUED.dec();
+            return Err(match e {
+                TrySendError::Full(_) => HashingError::Overloaded(self.retry_after_seconds),
+                TrySendError::Disconnected(_) => HashingError::Stopped,
+            });
+        }
+        receiver
+            .instrument(span)
+            .await
+            .map_err(|_| HashingError::Stopped)
+    }
+}
+
+/// Starts the process wide pool, later calls keep the pool that is already running.
+pub fn init(settings: &HashingSettings) -> Result<(), anyhow::Error> {
+    if POOL.get().is_none() {
+        let _ = POOL.set(HashingPool::new(settings)?);
+    }
+    Ok(())
+}
+
+/// Runs a pin or recovery code hash on the process wide pool, started with default settings
+/// if `init` was never called.
+pub async fn spawn_hashing<F, R>(f: F) -> Result<R, HashingError>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    POOL.get_or_init(|| {
+        HashingPool::new(&HashingSettings::default()).expect("Failed to start hashing workers.")
+    })
+    .run(f)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{retry_after, HashingError, HashingPool};
+    use crate::configuration::HashingSettings;
+    use claim::{assert_matches, assert_ok};
+    use futures_util::FutureExt;
+    use std::sync::mpsc::channel;
+
+    #[tokio::test]
+    async fn jobs_beyond_the_queue_are_refused() {
+        let pool = HashingPool::new(&HashingSettings {
+            workers: 1,
+            max_queued: 1,
+            retry_after_seconds: 3,
+        })
+        .unwrap();
+        let (started_sender, started) = channel();
+        let (release, released) = channel::<()>();
+
+        let mut running = Box::pin(pool.run(move || {
+            started_sender.send(()).unwrap();
+            released.recv().unwrap();
+            1
+        }));
+        assert!(running.as_mut().now_or_never().is_none());
+        started.recv().unwrap();
+        let mut queued = Box::pin(pool.run(|| 2));
+        assert!(queued.as_mut().now_or_never().is_none());
+
+        assert_matches!(pool.run(|| 3).await, Err(HashingError::Overloaded(3)));
+
+        release.send(()).unwrap();
+        assert_eq!(assert_ok!(running.await), 1);
+        assert_eq!(assert_ok!(queued.await), 2);
+        assert_eq!(assert_ok!(pool.run(|| 4).await), 4);
+    }
+
+    #[tokio::test]
+    async fn a_panicking_job_does_not_take_the_worker_down() {
+        let pool = HashingPool::new(&HashingSettings {
+            workers: 1,
+            max_queued: 1,
+            retry_after_seconds: 1,
+        })
+        .unwrap();
+
+        let panicked: Result<(), _> = pool.run(|| panic!("hash failed")).await;
+        assert_matches!(panicked, Err(HashingError::Stopped));
+        assert_eq!(assert_ok!(pool.run(|| 5).await), 5);
+    }
+
+    #[test]
+    fn retry_after_is_found_behind_context() {
+        let e = anyhow::Error::from(HashingError::Overloaded(2)).context("Failed to hash pin.");
+
+        assert_eq!(retry_after(&e), Some(2));
+        assert_eq!(retry_after(&anyhow::anyhow!("db is down")), None);
+    }
+}
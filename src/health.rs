@@ -0,0 +1,135 @@
+use crate::configuration::ReadinessSettings;
+use crate::metrics::BLOCKING_TASKS_QUEUED;
+use sqlx::PgPool;
+use std::collections::{BTreeMap, HashSet};
+use std::time::{Duration, Instant};
+use utoipa::ToSchema;
+
+#[derive(ToSchema, serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckStatus {
+    Ok,
+    Failing,
+}
+
+#[derive(ToSchema, serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct CheckResult {
+    pub status: CheckStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = "2 migrations have not been applied.")]
+    pub detail: Option<String>,
+}
+
+impl CheckResult {
+    fn ok() -> Self {
+        Self {
+            status: CheckStatus::Ok,
+            detail: None,
+        }
+    }
+
+    fn failing(detail: impl Into<String>) -> Self {
+        Self {
+            status: CheckStatus::Failing,
+            detail: Some(detail.into()),
+        }
+    }
+}
+
+#[derive(ToSchema, serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct ReadinessReport {
+    pub status: CheckStatus,
+    #[schema(example = "0.1.3")]
+    pub version: String,
+    pub uptime_seconds: u64,
+    /// Keyed by `database`, `migrations` and `blocking_pool`.
+    pub checks: BTreeMap<String, CheckResult>,
+}
+
+/// Decides whether this instance should be sent traffic: the database answers, its schema
+/// is up to date and the blocking pool, where pins are hashed, is keeping up.
+pub struct Readiness {
+    settings: ReadinessSettings,
+    started_at: Instant,
+}
+
+impl Readiness {
+    pub fn new(settings: &ReadinessSettings) -> Self {
+        Self {
+            settings: settings.clone(),
+            started_at: Instant::now(),
+        }
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+
+    #[tracing::instrument(name = "Check readiness", skip(self, pool))]
+    pub async fn check(&self, pool: &PgPool) -> ReadinessReport {
+        let timeout = Duration::from_millis(self.settings.database_timeout_milliseconds);
+        let mut checks = BTreeMap::new();
+        let database =
+            match tokio::time::timeout(timeout, sqlx::query("SELECT 1").execute(pool)).await {
+                Ok(Ok(_)) => CheckResult::ok(),
+                Ok(Err(e)) => {
+                    tracing::warn!("Database is not reachable: {:?}", e);
+                    CheckResult::failing("Database is not reachable.")
+                }
+                Err(_) => CheckResult::failing("Database did not answer in time."),
+            };
+        let migrations = if database.status == CheckStatus::Ok {
+            check_migrations(pool).await
+        } else {
+            CheckResult::failing("Skipped, the database is not reachable.")
+        };
+        checks.insert("database".to_string(), database);
+        checks.insert("migrations".to_string(), migrations);
+        checks.insert("blocking_pool".to_string(), self.check_blocking_pool());
+
+        let status = if checks.values().all(|c| c.status == CheckStatus::Ok) {
+            CheckStatus::Ok
+        } else {
+            CheckStatus::Failing
+        };
+        ReadinessReport {
+            status,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            uptime_seconds: self.uptime().as_secs(),
+            checks,
+        }
+    }
+
+    fn check_blocking_pool(&self) -> CheckResult {
+        let queued = BLOCKING_TASKS_QUEUED.get();
+        if queued > self.settings.max_queued_blocking_tasks {
+            CheckResult::failing(format!("{} tasks are waiting for a thread.", queued))
+        } else {
+            CheckResult::ok()
+        }
+    }
+}
+
+/// Every migration shipped with this build has to be recorded as applied.
+async fn check_migrations(pool: &PgPool) -> CheckResult {
+    let applied: HashSet<i64> =
+        match sqlx::query_scalar::<_, i64>("SELECT version FROM _sqlx_migrations WHERE success")
+            .fetch_all(pool)
+            .await
+        {
+            Ok(versions) => versions.into_iter().collect(),
+            Err(e) => {
+                tracing::warn!("Failed to read applied migrations: {:?}", e);
+                return CheckResult::failing("Applied migrations could not be read.");
+            }
+        };
+    let pending = sqlx::migrate!("./migrations")
+        .iter()
+        .filter(|migration| !applied.contains(&migration.version))
+        .count();
+    if pending == 0 {
+        CheckResult::ok()
+    } else {
+        CheckResult::failing(format!("{} migrations have not been applied.", pending))
+    }
+}
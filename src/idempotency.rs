@@ -0,0 +1,497 @@
+use crate::authentication::StoredKey;
+use crate::configuration::IdempotencySettings;
+use actix_web::HttpRequest;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use anyhow::{anyhow, Context};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use secrecy::{ExposeSecret, Secret};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use std::future::Future;
+
+pub const IDEMPOTENCY_KEY: &str = "Idempotency-Key";
+const NONCE_LENGTH: usize = 12;
+
+#[derive(thiserror::Error, Debug)]
+pub enum IdempotencyError {
+    #[error("{0}")]
+    InvalidKey(String),
+    #[error("Idempotency-Key was already used for a different request.")]
+    KeyReused,
+    #[error("A request with this Idempotency-Key is still being processed.")]
+    InProgress,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+/// Client chosen value of the `Idempotency-Key` header, e.g. a uuid.
+#[derive(Debug, Clone)]
+pub struct IdempotencyKey(String);
+
+impl IdempotencyKey {
+    pub fn parse(s: String) -> Result<IdempotencyKey, String> {
+        if s.is_empty() || s.len() > 255 || !s.chars().all(|c| c.is_ascii_graphic()) {
+            return Err(format!(
+                "{} is not a valid Idempotency-Key, use 1 to 255 visible ascii characters.",
+                s
+            ));
+        }
+        Ok(Self(s))
+    }
+}
+
+impl AsRef<str> for IdempotencyKey {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Hash over everything a request asks for. Only the fingerprint is stored, the response is
+/// encrypted with a second key derived from the same fields, so the recovery codes in it can
+/// only be read back by whoever sends the very same request again. Both are keyed with the
+/// server's secret, a leaked table gives no way to brute force the pins in the fields.
+pub struct RequestFingerprint {
+    scope: &'static str,
+    caller: Vec<u8>,
+    hasher: Sha256,
+}
+
+impl RequestFingerprint {
+    /// Requests in the same `scope` share keys, e.g. `/upload_key` and `POST /v1/keys`. Keys
+    /// are only shared by requests of the same `caller`, e.g. the npub signing an upload.
+    pub fn new(scope: &'static str, caller: impl AsRef<[u8]>) -> Self {
+        let caller = caller.as_ref().to_vec();
+        Self {
+            scope,
+            caller: caller.clone(),
+            hasher: Sha256::new(),
+        }
+        .field(scope.as_bytes())
+        .field(caller)
+    }
+
+    /// Length prefixed, so fields can not run into each other.
+    pub fn field(mut self, value: impl AsRef<[u8]>) -> Self {
+        let value = value.as_ref();
+        self.hasher.update((value.len() as u64).to_be_bytes());
+        self.hasher.update(value);
+        self
+    }
+
+    pub fn optional_field(self, value: Option<impl AsRef<[u8]>>) -> Self {
+        match value {
+            Some(value) => self.field([1u8]).field(value),
+            None => self.field([0u8]),
+        }
+    }
+
+    fn finish(self, key: IdempotencyKey, keyer: &Hmac<Sha256>) -> Entry {
+        let digest = self.hasher.finalize();
+        let derive = |label: &[u8], value: &[u8]| {
+            let mut keyer = keyer.clone();
+            keyer.update(label);
+            keyer.update(value);
+            keyer.finalize().into_bytes()
+        };
+        Entry {
+            key,
+            scope: self.scope,
+            caller: hex::encode(derive(b"caller", &self.caller)),
+            fingerprint: hex::encode(derive(b"fingerprint", &digest)),
+            cipher: Aes256Gcm::new(&derive(b"response key", &digest)),
+        }
+    }
+}
+
+/// The row a request with an `Idempotency-Key` is stored under.
+struct Entry {
+    key: IdempotencyKey,
+    scope: &'static str,
+    /// Keyed hash of the caller, so the table doesn't tell who sent what.
+    caller: String,
+    fingerprint: String,
+    cipher: Aes256Gcm,
+}
+
+/// Remembers the responses to requests sent with an `Idempotency-Key` for `ttl_seconds`.
+#[derive(Clone)]
+pub struct Idempotency {
+    ttl: chrono::Duration,
+    lease: chrono::Duration,
+    /// Keyed with `fingerprint_key`, turns request fingerprints into what is stored.
+    keyer: Hmac<Sha256>,
+}
+
+impl Idempotency {
+    pub fn new(settings: &IdempotencySettings, fingerprint_key: &Secret<String>) -> Self {
+        Self {
+            ttl: chrono::Duration::seconds(settings.ttl_seconds as i64),
+            lease: chrono::Duration::seconds(settings.lease_seconds as i64),
+            keyer: Hmac::<Sha256>::new_from_slice(fingerprint_key.expose_secret().as_bytes())
+                .expect("HMAC accepts keys of any size"),
+        }
+    }
+
+    /// Runs `handle` once per key. A retry with the same key and fingerprint gets the stored
+    /// key back without running it again. Failed requests are forgotten, so they can be
+    /// retried with the same key, and so are requests dropped half way once their lease is
+    /// released or runs out.
+    pub async fn run<F, Fut, E>(
+        &self,
+        request: &HttpRequest,
+        fingerprint: RequestFingerprint,
+        pool: &PgPool,
+        handle: F,
+    ) -> Result<StoredKey, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<StoredKey, E>>,
+        E: From<IdempotencyError>,
+    {
+        let key = match request.headers().get(IDEMPOTENCY_KEY) {
+            Some(value) => value
+                .to_str()
+                .map_err(|_| {
+                    IdempotencyError::InvalidKey(
+                        "Idempotency-Key must be visible ascii characters.".to_string(),
+                    )
+                })
+                .and_then(|value| {
+                    IdempotencyKey::parse(value.to_string()).map_err(IdempotencyError::InvalidKey)
+                })?,
+            None => return handle().await,
+        };
+        let entry = fingerprint.finish(key, &self.keyer);
+
+        let claimed_at = match self.claim(&entry, pool).await? {
+            Some(claimed_at) => claimed_at,
+            None => return Ok(replay(&entry, pool).await?),
+        };
+        let mut lease = Lease {
+            entry: &entry,
+            claimed_at,
+            pool,
+            held: true,
+        };
+        match handle().await {
+            Ok(stored_key) => {
+                save_response(&entry, claimed_at, Utc::now() + self.ttl, &stored_key, pool).await?;
+                lease.held = false;
+                Ok(stored_key)
+            }
+            Err(e) => {
+                lease.held = false;
+                release(&entry, claimed_at, pool).await?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Inserts an in progress row for the key, leased for `lease_seconds`, taking over a row
+    /// whose lease or ttl has run out. Returns when it was claimed, which marks the claim.
+    #[tracing::instrument(
+        name = "Claim idempotency key",
+        skip(self, entry, pool),
+        fields(key = %entry.key.as_ref(), scope = %entry.scope)
+    )]
+    async fn claim(
+        &self,
+        entry: &Entry,
+        pool: &PgPool,
+    ) -> Result<Option<DateTime<Utc>>, IdempotencyError> {
+        let claimed = sqlx::query!(
+            r#"
+            INSERT INTO idempotency
+                (idempotency_key, scope, caller, request_fingerprint, expires_at)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (idempotency_key, scope, caller) DO UPDATE
+            SET request_fingerprint = EXCLUDED.request_fingerprint,
+                encrypted_response = NULL,
+                created_at = clock_timestamp(),
+                expires_at = EXCLUDED.expires_at
+            WHERE idempotency.expires_at <= NOW()
+            RETURNING created_at
+            "#,
+            entry.key.as_ref(),
+            entry.scope,
+            entry.caller,
+            entry.fingerprint,
+            Utc::now() + self.lease
+        )
+        .fetch_optional(pool)
+        .await
+        .context("Failed to claim idempotency key.")?;
+        Ok(claimed.map(|row| row.created_at))
+    }
+}
+
+/// Held while a claimed request runs. Dropped without its response saved, e.g. because the
+/// client went away and actix dropped the handler, it releases the claim in the background.
+struct Lease<'a> {
+    entry: &'a Entry,
+    claimed_at: DateTime<Utc>,
+    pool: &'a PgPool,
+    held: bool,
+}
+
+impl Drop for Lease<'_> {
+    fn drop(&mut self) {
+        if !self.held {
+            return;
+        }
+        let runtime = match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => runtime,
+            // Shutting down, the lease runs out on its own
+            Err(_) => return,
+        };
+        let (key, scope, caller) = (
+            self.entry.key.as_ref().to_string(),
+            self.entry.scope,
+            self.entry.caller.clone(),
+        );
+        let (claimed_at, pool) = (self.claimed_at, self.pool.clone());
+        runtime.spawn(async move {
+            if let Err(e) = delete_claim(&key, scope, &caller, claimed_at, &pool).await {
+                tracing::error!("Failed to release dropped idempotency key: {:?}", e);
+            }
+        });
+    }
+}
+
+#[tracing::instrument(
+    name = "Replay idempotent response",
+    skip(entry, pool),
+    fields(key = %entry.key.as_ref(), scope = %entry.scope)
+)]
+async fn replay(entry: &Entry, pool: &PgPool) -> Result<StoredKey, IdempotencyError> {
+    let saved = sqlx::query!(
+        r#"
+        SELECT request_fingerprint, encrypted_response
+        FROM idempotency
+        WHERE idempotency_key = $1 AND scope = $2 AND caller = $3
+        "#,
+        entry.key.as_ref(),
+        entry.scope,
+        entry.caller
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to look up idempotency key.")?
+    // Released by a failed request since the claim, the client may simply retry
+    .ok_or(IdempotencyError::InProgress)?;
+    if saved.request_fingerprint != entry.fingerprint {
+        return Err(IdempotencyError::KeyReused);
+    }
+    let encrypted = saved
+        .encrypted_response
+        .ok_or(IdempotencyError::InProgress)?;
+    Ok(decrypt(entry, &encrypted)?)
+}
+
+/// Stores the response and keeps it for the ttl, unless the claim was taken over meanwhile.
+async fn save_response(
+    entry: &Entry,
+    claimed_at: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+    stored_key: &StoredKey,
+    pool: &PgPool,
+) -> Result<(), IdempotencyError> {
+    let encrypted = encrypt(entry, stored_key)?;
+    sqlx::query!(
+        r#"
+        UPDATE idempotency
+        SET encrypted_response = $5, expires_at = $6
+        WHERE idempotency_key = $1 AND scope = $2 AND caller = $3 AND created_at = $4
+        "#,
+        entry.key.as_ref(),
+        entry.scope,
+        entry.caller,
+        claimed_at,
+        encrypted,
+        expires_at
+    )
+    .execute(pool)
+    .await
+    .context("Failed to save idempotent response.")?;
+    Ok(())
+}
+
+async fn release(
+    entry: &Entry,
+    claimed_at: DateTime<Utc>,
+    pool: &PgPool,
+) -> Result<(), IdempotencyError> {
+    Ok(delete_claim(
+        entry.key.as_ref(),
+        entry.scope,
+        &entry.caller,
+        claimed_at,
+        pool,
+    )
+    .await?)
+}
+
+/// Only deletes the row while it's still the given claim, not one that took it over.
+async fn delete_claim(
+    key: &str,
+    scope: &str,
+    caller: &str,
+    claimed_at: DateTime<Utc>,
+    pool: &PgPool,
+) -> Result<(), anyhow::Error> {
+    sqlx::query!(
+        r#"
+        DELETE FROM idempotency
+        WHERE idempotency_key = $1 AND scope = $2 AND caller = $3 AND created_at = $4
+            AND encrypted_response IS NULL
+        "#,
+        key,
+        scope,
+        caller,
+        claimed_at
+    )
+    .execute(pool)
+    .await
+    .context("Failed to release idempotency key.")?;
+    Ok(())
+}
+
+/// Drops every row past its ttl, run periodically by `Application`.
+#[tracing::instrument(name = "Purge expired idempotency keys", skip(pool))]
+pub async fn purge_expired(pool: &PgPool) -> Result<u64, anyhow::Error> {
+    Ok(
+        sqlx::query!(r#"DELETE FROM idempotency WHERE expires_at <= NOW()"#)
+            .execute(pool)
+            .await
+            .context("Failed to purge expired idempotency keys.")?
+            .rows_affected(),
+    )
+}
+
+/// Key, scope and caller are bound in as associated data so responses can't be swapped
+/// between rows.
+fn encrypt(entry: &Entry, stored_key: &StoredKey) -> Result<String, anyhow::Error> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = entry
+        .cipher
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: &serde_json::to_vec(stored_key)?,
+                aad: associated_data(entry).as_bytes(),
+            },
+        )
+        .map_err(|_| anyhow!("Failed to encrypt idempotent response."))?;
+    let mut encrypted = nonce.to_vec();
+    encrypted.extend(ciphertext);
+    Ok(base64::encode(encrypted))
+}
+
+fn decrypt(entry: &Entry, encrypted: &str) -> Result<StoredKey, anyhow::Error> {
+    let encrypted =
+        base64::decode(encrypted).context("Stored idempotent response is not base64.")?;
+    if encrypted.len() <= NONCE_LENGTH {
+        return Err(anyhow!("Stored idempotent response is truncated."));
+    }
+    let (nonce, ciphertext) = encrypted.split_at(NONCE_LENGTH);
+    let plaintext = entry
+        .cipher
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: associated_data(entry).as_bytes(),
+            },
+        )
+        .map_err(|_| anyhow!("Failed to decrypt idempotent response."))?;
+    serde_json::from_slice(&plaintext).context("Stored idempotent response is not a key.")
+}
+
+fn associated_data(entry: &Entry) -> String {
+    format!("{}\n{}\n{}", entry.scope, entry.caller, entry.key.as_ref())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IdempotencyKey, RequestFingerprint};
+    use claim::{assert_err, assert_ok};
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    fn keyer(key: &str) -> Hmac<Sha256> {
+        Hmac::<Sha256>::new_from_slice(key.as_bytes()).unwrap()
+    }
+
+    fn key() -> IdempotencyKey {
+        IdempotencyKey::parse("key".to_string()).unwrap()
+    }
+
+    fn fingerprint(fields: &[&str]) -> String {
+        fields
+            .iter()
+            .fold(RequestFingerprint::new("test", "bob"), |f, field| {
+                f.field(field)
+            })
+            .finish(key(), &keyer("server key"))
+            .fingerprint
+    }
+
+    #[test]
+    fn keys_must_be_short_visible_ascii() {
+        assert_ok!(IdempotencyKey::parse(
+            "3f1c2a9e-5d7b-4c1e-9a0f-2b8d6e4c7a15".to_string()
+        ));
+        assert_err!(IdempotencyKey::parse("".to_string()));
+        assert_err!(IdempotencyKey::parse("has space".to_string()));
+        assert_err!(IdempotencyKey::parse("a".repeat(256)));
+    }
+
+    #[test]
+    fn field_boundaries_are_part_of_the_fingerprint() {
+        assert_eq!(fingerprint(&["ab", "c"]), fingerprint(&["ab", "c"]));
+        assert_ne!(fingerprint(&["ab", "c"]), fingerprint(&["a", "bc"]));
+    }
+
+    #[test]
+    fn fingerprints_differ_per_scope() {
+        let keyer = keyer("server key");
+        let upload = RequestFingerprint::new("create_key", "bob")
+            .field("a")
+            .finish(key(), &keyer)
+            .fingerprint;
+        let update = RequestFingerprint::new("update_key", "bob")
+            .field("a")
+            .finish(key(), &keyer)
+            .fingerprint;
+        assert_ne!(upload, update);
+    }
+
+    #[test]
+    fn callers_get_their_own_rows() {
+        let keyer = keyer("server key");
+        let entry = |caller: &str| {
+            RequestFingerprint::new("create_key", caller)
+                .field("a")
+                .finish(key(), &keyer)
+        };
+        let (bob, alice) = (entry("bob"), entry("alice"));
+        assert_ne!(bob.caller, alice.caller);
+        assert_ne!(bob.fingerprint, alice.fingerprint);
+        assert_eq!(bob.caller, entry("bob").caller);
+        assert!(!bob.caller.contains("bob"));
+    }
+
+    #[test]
+    fn fingerprints_depend_on_the_server_key() {
+        let fingerprint = |secret: &str| {
+            RequestFingerprint::new("update_key", "bob")
+                .field(401267u64.to_be_bytes())
+                .finish(key(), &keyer(secret))
+                .fingerprint
+        };
+        assert_eq!(fingerprint("server key"), fingerprint("server key"));
+        assert_ne!(fingerprint("server key"), fingerprint("other key"));
+    }
+}
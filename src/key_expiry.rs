@@ -0,0 +1,147 @@
+use crate::authentication::expire_key;
+use crate::configuration::KeyExpirySettings;
+use crate::metrics::{EXPIRED_KEYS, PURGEABLE_KEYS};
+use anyhow::Context;
+use chrono::{Duration, Utc};
+use sqlx::PgPool;
+
+/// What a single expiry pass did, or in dry-run mode would have done.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ExpiryReport {
+    pub warned: u64,
+    pub purged: u64,
+}
+
+/// Warns about, then purges, rows nobody has fetched within the retention period.
+/// Rows are warned about `warning_days` before they expire and are only purged once the
+/// warning is at least that old, any successful fetch in between clears the warning.
+/// Purging only removes this vault's copy, peers keep serving theirs. Access times are not
+/// replicated, so a key idle here may still be in use elsewhere. Passes are counted in
+/// `expired_keys_total`, dry runs only set the `purgeable_keys` gauge.
+#[derive(Clone)]
+pub struct KeyExpiry {
+    settings: KeyExpirySettings,
+}
+
+impl KeyExpiry {
+    pub fn new(settings: &KeyExpirySettings) -> Self {
+        Self {
+            settings: settings.clone(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.settings.enabled
+    }
+
+    pub fn interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.settings.interval_seconds)
+    }
+
+    #[tracing::instrument(
+        name = "Expire inactive keys",
+        skip(self, pool),
+        fields(dry_run = self.settings.dry_run)
+    )]
+    pub async fn run_once(&self, pool: &PgPool) -> Result<ExpiryReport, anyhow::Error> {
+        let retention = Duration::days(self.settings.retention_days as i64);
+        let warning = Duration::days(self.settings.warning_days as i64).min(retention);
+        let now = Utc::now();
+        let expired_before = now - retention;
+        let warn_before = expired_before + warning;
+        let warned_before = now - warning;
+
+        let report = if self.settings.dry_run {
+            let counts = sqlx::query!(
+                r#"
+                SELECT
+                    COUNT(*) FILTER (WHERE expiry_warned_at IS NULL) AS "warned!",
+                    COUNT(*) FILTER (WHERE last_accessed_at < $2) AS "purged!"
+                FROM keys
+                WHERE last_accessed_at < $1
+                "#,
+                warn_before,
+                expired_before
+            )
+            .fetch_one(pool)
+            .await
+            .context("Failed to count inactive keys.")?;
+            ExpiryReport {
+                warned: counts.warned as u64,
+                purged: counts.purged as u64,
+            }
+        } else {
+            let warned = sqlx::query!(
+                r#"
+                UPDATE keys
+                SET expiry_warned_at = NOW()
+                WHERE last_accessed_at < $1 AND expiry_warned_at IS NULL
+                RETURNING nip_05_id
+                "#,
+                warn_before
+            )
+            .fetch_all(pool)
+            .await
+            .context("Failed to mark inactive keys.")?;
+            for row in &warned {
+                tracing::warn!(
+                    nip_05_id = %row.nip_05_id,
+                    "Key has not been accessed in {} days and will be purged in {} days.",
+                    self.settings.retention_days - warning.num_days() as u64,
+                    warning.num_days()
+                );
+            }
+
+            let mut transaction = pool
+                .begin()
+                .await
+                .context("Failed to acquire a Postgres connection from the pool.")?;
+            let purgeable = sqlx::query!(
+                r#"
+                SELECT id FROM keys
+                WHERE last_accessed_at < $1 AND expiry_warned_at <= $2
+                FOR UPDATE
+                "#,
+                expired_before,
+                warned_before
+            )
+            .fetch_all(&mut transaction)
+            .await
+            .context("Failed to find inactive keys.")?;
+            let mut purged = Vec::with_capacity(purgeable.len());
+            for row in purgeable {
+                if let Some(nip_05_id) = expire_key(&mut transaction, row.id).await? {
+                    purged.push(nip_05_id);
+                }
+            }
+            transaction
+                .commit()
+                .await
+                .context("Failed to commit purged keys.")?;
+            for nip_05_id in &purged {
+                tracing::warn!(nip_05_id = %nip_05_id, "Purged inactive key.");
+            }
+            ExpiryReport {
+                warned: warned.len() as u64,
+                purged: purged.len() as u64,
+            }
+        };
+
+        if self.settings.dry_run {
+            PURGEABLE_KEYS.set(report.purged as i64);
+        } else {
+            EXPIRED_KEYS
+                .with_label_values(&["warned"])
+                .inc_by(report.warned);
+            EXPIRED_KEYS
+                .with_label_values(&["purged"])
+                .inc_by(report.purged);
+        }
+        tracing::info!(
+            warned = report.warned,
+            purged = report.purged,
+            "Key expiry pass finished."
+        );
+        Ok(report)
+    }
+}
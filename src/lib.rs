@@ -1,7 +1,30 @@
 pub mod authentication;
+pub mod challenge;
 pub mod configuration;
+pub mod cors;
 pub mod domain;
+pub mod domain_policy;
+pub mod enumeration;
+pub mod hashing;
+pub mod health;
+pub mod idempotency;
+pub mod key_expiry;
+pub mod lightning;
+pub mod metrics;
+pub mod nip05_provider;
+pub mod passkeys;
+pub mod payments;
+pub mod proof_of_work;
+pub mod recovery;
+pub mod relay_publisher;
+pub mod replication;
+pub mod request_id;
 pub mod routes;
+pub mod second_factor;
+pub mod security_headers;
+pub mod session;
 pub mod startup;
+pub mod supervisor;
 pub mod telemetry;
+pub mod tls;
 pub mod utils;
@@ -0,0 +1,151 @@
+use crate::configuration::LightningNodeSettings;
+use anyhow::{anyhow, Context};
+use futures_util::future::BoxFuture;
+use rand::RngCore;
+use secrecy::{ExposeSecret, Secret};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+/// A BOLT11 invoice and the hash its preimage has to match.
+#[derive(Debug, Clone)]
+pub struct Invoice {
+    pub bolt11: String,
+    pub payment_hash: [u8; 32],
+}
+
+/// What the payment gate needs from a Lightning node. Payments are checked against the
+/// payment hash, so the node is only asked for invoices.
+pub trait LightningNode: Send + Sync {
+    fn create_invoice<'a>(
+        &'a self,
+        amount_msat: u64,
+        memo: &'a str,
+        expiry_seconds: u64,
+    ) -> BoxFuture<'a, Result<Invoice, anyhow::Error>>;
+}
+
+pub fn node_from_settings(
+    settings: &LightningNodeSettings,
+) -> Result<Arc<dyn LightningNode>, anyhow::Error> {
+    Ok(match settings {
+        LightningNodeSettings::Fake => Arc::new(FakeNode),
+        LightningNodeSettings::Lnd { url, macaroon_hex } => {
+            Arc::new(LndNode::new(url, macaroon_hex.clone())?)
+        }
+    })
+}
+
+/// Makes up invoices without a node. The preimage travels inside the invoice, so `pay` can
+/// settle any invoice it issued without keeping state.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FakeNode;
+
+impl FakeNode {
+    const PREFIX: &'static str = "lnfake";
+
+    /// The preimage a payer of `bolt11` would get back, if the invoice came from a `FakeNode`.
+    pub fn pay(bolt11: &str) -> Option<[u8; 32]> {
+        let (_, preimage) = bolt11.strip_prefix(Self::PREFIX)?.split_once('p')?;
+        hex::decode(preimage).ok()?.try_into().ok()
+    }
+}
+
+impl LightningNode for FakeNode {
+    fn create_invoice<'a>(
+        &'a self,
+        amount_msat: u64,
+        _memo: &'a str,
+        _expiry_seconds: u64,
+    ) -> BoxFuture<'a, Result<Invoice, anyhow::Error>> {
+        let mut preimage = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut preimage);
+        let invoice = Invoice {
+            bolt11: format!("{}{}p{}", Self::PREFIX, amount_msat, hex::encode(preimage)),
+            payment_hash: Sha256::digest(preimage).into(),
+        };
+        Box::pin(async move { Ok(invoice) })
+    }
+}
+
+/// Creates invoices through LND's REST api.
+pub struct LndNode {
+    http_client: reqwest::Client,
+    url: String,
+    macaroon: Secret<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct LndInvoice {
+    r_hash: String,
+    payment_request: String,
+}
+
+impl LndNode {
+    pub fn new(url: &str, macaroon: Secret<String>) -> Result<Self, anyhow::Error> {
+        let http_client = reqwest::Client::builder()
+            .timeout(std::time::Duration::from_secs(10))
+            .build()
+            .context("Failed to build the LND client.")?;
+        Ok(Self {
+            http_client,
+            url: url.trim_end_matches('/').to_string(),
+            macaroon,
+        })
+    }
+}
+
+impl LightningNode for LndNode {
+    fn create_invoice<'a>(
+        &'a self,
+        amount_msat: u64,
+        memo: &'a str,
+        expiry_seconds: u64,
+    ) -> BoxFuture<'a, Result<Invoice, anyhow::Error>> {
+        Box::pin(async move {
+            let invoice: LndInvoice = self
+                .http_client
+                .post(format!("{}/v1/invoices", self.url))
+                .header("Grpc-Metadata-macaroon", self.macaroon.expose_secret())
+                .json(&serde_json::json!({
+                    "value_msat": amount_msat.to_string(),
+                    "memo": memo,
+                    "expiry": expiry_seconds.to_string(),
+                }))
+                .send()
+                .await
+                .context("Failed to reach LND.")?
+                .error_for_status()
+                .context("LND refused to create an invoice.")?
+                .json()
+                .await
+                .context("Failed to parse the invoice from LND.")?;
+            let payment_hash = base64::decode(&invoice.r_hash)
+                .ok()
+                .and_then(|hash| hash.try_into().ok())
+                .ok_or_else(|| anyhow!("LND returned a payment hash that is not 32 bytes."))?;
+            Ok(Invoice {
+                bolt11: invoice.payment_request,
+                payment_hash,
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FakeNode, LightningNode};
+    use sha2::{Digest, Sha256};
+
+    #[tokio::test]
+    async fn fake_invoices_settle_with_their_own_preimage() {
+        let invoice = FakeNode
+            .create_invoice(10_000, "upload", 600)
+            .await
+            .unwrap();
+
+        let preimage = FakeNode::pay(&invoice.bolt11).unwrap();
+        let payment_hash: [u8; 32] = Sha256::digest(preimage).into();
+        assert_eq!(payment_hash, invoice.payment_hash);
+        assert!(FakeNode::pay("lnbc10u1pjexample").is_none());
+    }
+}
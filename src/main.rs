@@ -1,14 +1,16 @@
 use nostr_vault::configuration::get_configuration;
 use nostr_vault::startup::Application;
-use nostr_vault::telemetry::{get_subscriber, init_subscriber};
+use nostr_vault::telemetry::{get_subscriber, init_subscriber, otlp_tracer, shutdown_tracing};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let subscriber = get_subscriber("nostr_vault".into(), "info".into(), std::io::stdout);
+    let configuration = get_configuration().expect("Failed to read configuration.");
+    let tracer = otlp_tracer(&configuration.telemetry)?;
+    let subscriber = get_subscriber("nostr_vault".into(), "info".into(), std::io::stdout, tracer);
     init_subscriber(subscriber);
 
-    let configuration = get_configuration().expect("Failed to read configuration.");
     let application = Application::build(configuration).await?;
     application.run_until_stopped().await?;
+    shutdown_tracing().await;
     Ok(())
 }
@@ -0,0 +1,122 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram, register_histogram_vec, register_int_counter_vec, register_int_gauge,
+    register_int_gauge_vec, Encoder, Histogram, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec,
+    TextEncoder,
+};
+use sqlx::PgPool;
+use std::future::Future;
+use std::time::Instant;
+
+// Label values are limited to route patterns, methods, status codes and fixed outcomes,
+// nothing that identifies a user (e.g. a nip 05 id) may ever end up in one.
+lazy_static! {
+    pub static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "http_requests_total",
+        "HTTP requests by route pattern, method and status.",
+        &["route", "method", "status"]
+    )
+    .unwrap();
+    pub static ref HTTP_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "http_request_duration_seconds",
+        "HTTP request latency by route pattern and method.",
+        &["route", "method"]
+    )
+    .unwrap();
+    pub static ref PIN_VERIFICATIONS: IntCounterVec = register_int_counter_vec!(
+        "pin_verifications_total",
+        "Argon2 pin verifications by outcome.",
+        &["outcome"]
+    )
+    .unwrap();
+    pub static ref PIN_VERIFICATION_DURATION: Histogram = register_histogram!(
+        "pin_verification_duration_seconds",
+        "Time spent in Argon2 verifying a pin."
+    )
+    .unwrap();
+    pub static ref BLOCKING_TASKS_QUEUED: IntGauge = register_int_gauge!(
+        "blocking_tasks_queued",
+        "Tasks handed to the blocking pool that have not started running yet."
+    )
+    .unwrap();
+    pub static ref BLOCKING_TASKS_RUNNING: IntGauge = register_int_gauge!(
+        "blocking_tasks_running",
+        "Tasks currently running on the blocking pool."
+    )
+    .unwrap();
+    pub static ref DB_POOL_CONNECTIONS: IntGaugeVec = register_int_gauge_vec!(
+        "db_pool_connections",
+        "Postgres pool connections by state.",
+        &["state"]
+    )
+    .unwrap();
+    pub static ref STORED_KEYS: IntGauge =
+        register_int_gauge!("stored_keys", "Rows in the keys table.").unwrap();
+    pub static ref EXPIRED_KEYS: IntCounterVec = register_int_counter_vec!(
+        "expired_keys_total",
+        "Keys warned about or purged by the key expiry job.",
+        &["action"]
+    )
+    .unwrap();
+    pub static ref PURGEABLE_KEYS: IntGauge = register_int_gauge!(
+        "purgeable_keys",
+        "Keys the last dry run of the key expiry job would have purged."
+    )
+    .unwrap();
+}
+
+/// Wrapped around the app with `wrap_fn`, records every request against its route pattern.
+pub fn track_request<S, B>(
+    req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = Result<ServiceResponse<B>, actix_web::Error>>
+where
+    S: actix_web::dev::Service<
+        ServiceRequest,
+        Response = ServiceResponse<B>,
+        Error = actix_web::Error,
+    >,
+    B: MessageBody,
+{
+    let started = Instant::now();
+    let method = req.method().to_string();
+    let response = srv.call(req);
+    async move {
+        let response = response.await?;
+        let route = response
+            .request()
+            .match_pattern()
+            .unwrap_or_else(|| "unmatched".to_string());
+        HTTP_REQUESTS
+            .with_label_values(&[&route, &method, response.status().as_str()])
+            .inc();
+        HTTP_REQUEST_DURATION
+            .with_label_values(&[&route, &method])
+            .observe(started.elapsed().as_secs_f64());
+        Ok(response)
+    }
+}
+
+/// Refreshes the gauges that are read from the db and pool, then renders everything in
+/// the Prometheus text format.
+pub async fn render(pool: &PgPool) -> Result<String, anyhow::Error> {
+    let idle = pool.num_idle() as i64;
+    DB_POOL_CONNECTIONS.with_label_values(&["idle"]).set(idle);
+    DB_POOL_CONNECTIONS
+        .with_label_values(&["in_use"])
+        .set(pool.size() as i64 - idle);
+
+    match sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM keys"#)
+        .fetch_one(pool)
+        .await
+    {
+        Ok(row) => STORED_KEYS.set(row.count),
+        Err(e) => tracing::warn!("Failed to count stored keys: {:?}", e),
+    }
+
+    let mut buffer = vec![];
+    TextEncoder::new().encode(&prometheus::gather(), &mut buffer)?;
+    Ok(String::from_utf8(buffer)?)
+}
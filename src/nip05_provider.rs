@@ -0,0 +1,164 @@
+use crate::configuration::Nip05ProviderSettings;
+use crate::domain::{normalize_domain, Nip05ID};
+use anyhow::Context;
+use sqlx::PgPool;
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use utoipa::ToSchema;
+
+/// Hands out `name@domain` identifiers for the configured domains.
+#[derive(Clone)]
+pub struct Nip05Provider {
+    domains: Vec<String>,
+    reserved_names: Vec<String>,
+    trusted_proxies: Vec<IpAddr>,
+}
+
+impl Nip05Provider {
+    pub fn new(settings: &Nip05ProviderSettings) -> Self {
+        Self {
+            domains: settings
+                .domains
+                .iter()
+                .map(|domain| normalize_domain(domain).unwrap_or_else(|| domain.to_lowercase()))
+                .collect(),
+            reserved_names: settings
+                .reserved_names
+                .iter()
+                .map(|name| name.to_lowercase())
+                .collect(),
+            trusted_proxies: settings.trusted_proxies.clone(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.domains.is_empty()
+    }
+
+    pub fn default_domain(&self) -> Option<&str> {
+        self.domains.first().map(String::as_str)
+    }
+
+    pub fn serves(&self, domain: &str) -> bool {
+        self.domains
+            .iter()
+            .any(|served| served.eq_ignore_ascii_case(domain))
+    }
+
+    /// Whether the peer may tell which domain a request was for with forwarded headers.
+    pub fn trusts_proxy(&self, peer: IpAddr) -> bool {
+        self.trusted_proxies.contains(&peer)
+    }
+
+    pub fn is_reserved(&self, name: &str) -> bool {
+        self.reserved_names
+            .iter()
+            .any(|reserved| reserved.eq_ignore_ascii_case(name))
+    }
+
+    /// Only the provider's own domains are policed, other identifiers are just stored.
+    pub fn check_registration(&self, nip_05_id: &Nip05ID) -> Result<(), String> {
+        if self.serves(nip_05_id.domain()) && self.is_reserved(nip_05_id.local_part()) {
+            return Err(format!("{} is a reserved name.", nip_05_id.local_part()));
+        }
+        Ok(())
+    }
+}
+
+/// Body of `/.well-known/nostr.json` as described in NIP-05.
+#[derive(ToSchema, serde::Serialize, serde::Deserialize)]
+pub struct NostrJson {
+    #[schema(example = json!({"bob": "6e468422dfb74a5738702a8823b9b28168abab8655faacb6853cd0ee15deee93"}))]
+    pub names: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    #[schema(example = json!({"6e468422dfb74a5738702a8823b9b28168abab8655faacb6853cd0ee15deee93": ["wss://relay.damus.io"]}))]
+    pub relays: BTreeMap<String, Vec<String>>,
+}
+
+#[derive(ToSchema, serde::Serialize, serde::Deserialize)]
+pub struct NameAvailability {
+    #[schema(example = "bob")]
+    pub name: String,
+    #[schema(example = "frogs.cloud")]
+    pub domain: String,
+    pub available: bool,
+    #[schema(example = "bob@frogs.cloud is already registered.")]
+    pub reason: Option<String>,
+}
+
+#[tracing::instrument(name = "Look up nostr.json entry", skip(pool))]
+pub async fn get_nostr_json(
+    name: &str,
+    domain: &str,
+    pool: &PgPool,
+) -> Result<NostrJson, anyhow::Error> {
+    let mut nostr_json = NostrJson {
+        names: BTreeMap::new(),
+        relays: BTreeMap::new(),
+    };
+    // Nothing can be stored under a name that does not parse
+    let nip_05_id = match Nip05ID::parse(format!("{}@{}", name, domain)) {
+        Ok(nip_05_id) => nip_05_id,
+        Err(_) => return Ok(nostr_json),
+    };
+    let row = sqlx::query!(
+        r#"
+        SELECT pubkey AS "pubkey!", relays
+        FROM keys
+        WHERE nip_05_id = $1 AND pubkey IS NOT NULL
+        "#,
+        nip_05_id.as_ref()
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to perform a query to look up nostr.json entry.")?;
+
+    if let Some(row) = row {
+        if !row.relays.is_empty() {
+            nostr_json.relays.insert(row.pubkey.clone(), row.relays);
+        }
+        nostr_json.names.insert(name.to_string(), row.pubkey);
+    }
+    Ok(nostr_json)
+}
+
+#[tracing::instrument(name = "Check name availability", skip(provider, pool))]
+pub async fn check_availability(
+    name: &str,
+    domain: &str,
+    provider: &Nip05Provider,
+    pool: &PgPool,
+) -> Result<NameAvailability, anyhow::Error> {
+    let unavailable = |reason: String| NameAvailability {
+        name: name.to_string(),
+        domain: domain.to_string(),
+        available: false,
+        reason: Some(reason),
+    };
+    let nip_05_id = match Nip05ID::parse(format!("{}@{}", name, domain)) {
+        Ok(nip_05_id) => nip_05_id,
+        Err(e) => return Ok(unavailable(e)),
+    };
+    if let Err(e) = provider.check_registration(&nip_05_id) {
+        return Ok(unavailable(e));
+    }
+
+    let taken = sqlx::query!(
+        r#"SELECT EXISTS(SELECT 1 FROM keys WHERE nip_05_id = $1) AS "taken!""#,
+        nip_05_id.as_ref()
+    )
+    .fetch_one(pool)
+    .await
+    .context("Failed to perform a query to check name availability.")?
+    .taken;
+
+    if taken {
+        return Ok(unavailable(format!("{} is already registered.", nip_05_id)));
+    }
+    Ok(NameAvailability {
+        name: name.to_string(),
+        domain: domain.to_string(),
+        available: true,
+        reason: None,
+    })
+}
@@ -0,0 +1,388 @@
+use crate::authentication::{get_key_by_nip_05_id, AuthError};
+use crate::configuration::ChallengeSettings;
+use crate::domain::Nip05ID;
+use anyhow::{anyhow, Context};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use secrecy::{ExposeSecret, Secret};
+use sha2::Sha256;
+use sqlx::PgPool;
+use utoipa::ToSchema;
+use webauthn_rs::prelude::{
+    CreationChallengeResponse, Passkey, PasskeyAuthentication, PasskeyRegistration,
+    PublicKeyCredential, RegisterPublicKeyCredential, RequestChallengeResponse, Url, Uuid,
+    Webauthn, WebauthnBuilder,
+};
+
+const REGISTRATION: &str = "registration";
+const AUTHENTICATION: &str = "authentication";
+
+/// Options to hand to `navigator.credentials`, plus the id to send back with the result.
+#[derive(ToSchema, Debug, serde::Serialize, serde::Deserialize)]
+#[aliases(
+    PasskeyRegistrationOptions = PasskeyCeremony<CreationChallengeResponse>,
+    PasskeyAuthenticationOptions = PasskeyCeremony<RequestChallengeResponse>
+)]
+pub struct PasskeyCeremony<T> {
+    #[schema(value_type = String, example = "7b4c5e3a-2c1f-4a52-9b55-7f0f2b7c9d31")]
+    pub ceremony_id: Uuid,
+    #[schema(value_type = Object)]
+    pub options: T,
+}
+
+/// A signed WebAuthn assertion answering an authentication ceremony.
+#[derive(ToSchema, Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct PasskeyAssertion {
+    #[schema(value_type = String, example = "7b4c5e3a-2c1f-4a52-9b55-7f0f2b7c9d31")]
+    pub ceremony_id: Uuid,
+    #[schema(value_type = Object)]
+    pub credential: PublicKeyCredential,
+}
+
+/// WebAuthn registration and assertion, with the relying party derived from `base_url`.
+/// Disabled when `base_url` has no domain name, as WebAuthn does not work with bare ips.
+pub struct Passkeys {
+    webauthn: Option<(Webauthn, String)>,
+    ceremony_ttl: chrono::Duration,
+    /// Keyed with `decoy_key`, derives the credential ids offered for unknown nip 05 ids.
+    decoy_signer: Hmac<Sha256>,
+}
+
+impl Passkeys {
+    pub fn new(base_url: &str, challenges: &ChallengeSettings, decoy_key: &Secret<String>) -> Self {
+        let ceremony_ttl = std::time::Duration::from_secs(challenges.ttl_seconds);
+        let webauthn = match build_webauthn(base_url, ceremony_ttl) {
+            Ok(webauthn) => Some(webauthn),
+            Err(e) => {
+                tracing::warn!("Passkeys are disabled: {:?}", e);
+                None
+            }
+        };
+        Self {
+            webauthn,
+            ceremony_ttl: chrono::Duration::seconds(challenges.ttl_seconds as i64),
+            decoy_signer: Hmac::<Sha256>::new_from_slice(decoy_key.expose_secret().as_bytes())
+                .expect("HMAC accepts keys of any size"),
+        }
+    }
+
+    fn webauthn(&self) -> Result<&Webauthn, anyhow::Error> {
+        self.webauthn
+            .as_ref()
+            .map(|(webauthn, _)| webauthn)
+            .ok_or_else(|| anyhow!("Passkeys require base_url to be a domain name."))
+    }
+
+    #[tracing::instrument(name = "Start passkey registration", skip(self, pool))]
+    pub async fn start_registration(
+        &self,
+        key_id: i64,
+        nip_05_id: &Nip05ID,
+        pool: &PgPool,
+    ) -> Result<PasskeyCeremony<CreationChallengeResponse>, anyhow::Error> {
+        let existing = load_passkeys(key_id, pool).await?;
+        let exclude_credentials = existing
+            .iter()
+            .map(|passkey| passkey.cred_id().clone())
+            .collect();
+        let (options, state) = self
+            .webauthn()?
+            .start_passkey_registration(
+                user_handle(key_id),
+                nip_05_id.as_ref(),
+                nip_05_id.as_ref(),
+                Some(exclude_credentials),
+            )
+            .context("Failed to start passkey registration.")?;
+        let ceremony_id = self
+            .store_ceremony(key_id, REGISTRATION, serde_json::to_value(&state)?, pool)
+            .await?;
+        Ok(PasskeyCeremony {
+            ceremony_id,
+            options,
+        })
+    }
+
+    /// Stores the passkey, returns the id of the key it was registered for.
+    #[tracing::instrument(name = "Finish passkey registration", skip(self, credential, pool))]
+    pub async fn finish_registration(
+        &self,
+        ceremony_id: Uuid,
+        credential: &RegisterPublicKeyCredential,
+        replaces_pin: bool,
+        pool: &PgPool,
+    ) -> Result<i64, AuthError> {
+        let (key_id, state) = take_ceremony(ceremony_id, REGISTRATION, pool)
+            .await?
+            .ok_or_else(|| AuthError::InvalidPasskey(anyhow!("Unknown or expired ceremony.")))?;
+        let state: PasskeyRegistration =
+            serde_json::from_value(state).context("Failed to parse registration state.")?;
+        let passkey = self
+            .webauthn()?
+            .finish_passkey_registration(credential, &state)
+            .map_err(|e| AuthError::InvalidPasskey(anyhow::Error::new(e)))?;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO passkeys (key_id, credential_id, passkey, sign_count, replaces_pin)
+            VALUES ($1, $2, $3, 0, $4)
+            "#,
+            key_id,
+            passkey.cred_id().to_string(),
+            serde_json::to_value(&passkey).context("Failed to serialize passkey.")?,
+            replaces_pin
+        )
+        .execute(pool)
+        .await
+        .context("Failed to store passkey.")?;
+        Ok(key_id)
+    }
+
+    /// Unknown nip 05 ids and users without passkeys get a decoy ceremony, so the response
+    /// does not tell whether either exists. It is never stored, finishing it fails like an
+    /// expired one.
+    #[tracing::instrument(name = "Start passkey authentication", skip(self, pool))]
+    pub async fn start_authentication(
+        &self,
+        nip_05_id: &Nip05ID,
+        pool: &PgPool,
+    ) -> Result<PasskeyCeremony<RequestChallengeResponse>, anyhow::Error> {
+        let key_id = match get_key_by_nip_05_id(nip_05_id, pool).await? {
+            Some(key) => key.id,
+            None => return self.decoy_authentication(nip_05_id),
+        };
+        let passkeys = load_passkeys(key_id, pool).await?;
+        if passkeys.is_empty() {
+            return self.decoy_authentication(nip_05_id);
+        }
+
+        let (options, state) = self
+            .webauthn()?
+            .start_passkey_authentication(&passkeys)
+            .context("Failed to start passkey authentication.")?;
+        let ceremony_id = self
+            .store_ceremony(key_id, AUTHENTICATION, serde_json::to_value(&state)?, pool)
+            .await?;
+        Ok(PasskeyCeremony {
+            ceremony_id,
+            options,
+        })
+    }
+
+    /// Options shaped like those of a user with a single passkey, whose credential id is
+    /// stable per nip 05 id like a real one.
+    fn decoy_authentication(
+        &self,
+        nip_05_id: &Nip05ID,
+    ) -> Result<PasskeyCeremony<RequestChallengeResponse>, anyhow::Error> {
+        let (_, rp_id) = self
+            .webauthn
+            .as_ref()
+            .ok_or_else(|| anyhow!("Passkeys require base_url to be a domain name."))?;
+        let mut challenge = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut challenge);
+        let mut signer = self.decoy_signer.clone();
+        signer.update(nip_05_id.as_ref().as_bytes());
+        let credential_id = signer.finalize().into_bytes();
+        let options = serde_json::from_value(serde_json::json!({
+            "publicKey": {
+                "challenge": base64::encode_config(challenge, base64::URL_SAFE_NO_PAD),
+                "timeout": self.ceremony_ttl.num_milliseconds(),
+                "rpId": rp_id,
+                "allowCredentials": [{
+                    "type": "public-key",
+                    "id": base64::encode_config(credential_id, base64::URL_SAFE_NO_PAD),
+                }],
+                "userVerification": "required",
+            }
+        }))
+        .context("Failed to build decoy authentication options.")?;
+        Ok(PasskeyCeremony {
+            ceremony_id: Uuid::new_v4(),
+            options,
+        })
+    }
+
+    /// Checks the assertion was made for `key_id`, returning whether the passkey used may
+    /// stand in for the pin. The sign count is checked and advanced under a row lock.
+    #[tracing::instrument(name = "Verify passkey", skip(self, assertion, pool))]
+    pub async fn verify(
+        &self,
+        key_id: i64,
+        assertion: &PasskeyAssertion,
+        pool: &PgPool,
+    ) -> Result<bool, AuthError> {
+        let (ceremony_key_id, state) = take_ceremony(assertion.ceremony_id, AUTHENTICATION, pool)
+            .await?
+            .ok_or_else(|| AuthError::InvalidPasskey(anyhow!("Unknown or expired ceremony.")))?;
+        if ceremony_key_id != key_id {
+            return Err(AuthError::InvalidPasskey(anyhow!(
+                "Ceremony was started for another user."
+            )));
+        }
+        let state: PasskeyAuthentication =
+            serde_json::from_value(state).context("Failed to parse authentication state.")?;
+
+        let mut transaction = pool
+            .begin()
+            .await
+            .context("Failed to acquire a Postgres connection from the pool.")?;
+        let stored = sqlx::query!(
+            r#"
+            SELECT id, passkey, sign_count, replaces_pin
+            FROM passkeys
+            WHERE key_id = $1 AND credential_id = $2
+            FOR UPDATE
+            "#,
+            key_id,
+            assertion.credential.raw_id.to_string()
+        )
+        .fetch_optional(&mut transaction)
+        .await
+        .context("Failed to perform a query to retrieve passkey.")?
+        .ok_or_else(|| AuthError::InvalidPasskey(anyhow!("Unknown passkey.")))?;
+        let mut passkey: Passkey =
+            serde_json::from_value(stored.passkey).context("Failed to parse passkey.")?;
+
+        let result = self
+            .webauthn()?
+            .finish_passkey_authentication(&assertion.credential, &state)
+            .map_err(|e| AuthError::InvalidPasskey(anyhow::Error::new(e)))?;
+        // The ceremony state holds the counter from when it started, so check against the
+        // stored one as well in case another ceremony finished in between.
+        let sign_count = result.counter() as i64;
+        if (sign_count > 0 || stored.sign_count > 0) && sign_count <= stored.sign_count {
+            return Err(AuthError::InvalidPasskey(anyhow!(
+                "Sign count did not increase, the passkey may have been cloned."
+            )));
+        }
+        passkey.update_credential(&result);
+
+        sqlx::query!(
+            r#"
+            UPDATE passkeys
+            SET passkey = $2, sign_count = $3, last_used_at = NOW()
+            WHERE id = $1
+            "#,
+            stored.id,
+            serde_json::to_value(&passkey).context("Failed to serialize passkey.")?,
+            sign_count
+        )
+        .execute(&mut transaction)
+        .await
+        .context("Failed to update passkey.")?;
+        transaction
+            .commit()
+            .await
+            .context("Failed to commit passkey update.")?;
+        Ok(stored.replaces_pin)
+    }
+
+    async fn store_ceremony(
+        &self,
+        key_id: i64,
+        kind: &str,
+        state: serde_json::Value,
+        pool: &PgPool,
+    ) -> Result<Uuid, anyhow::Error> {
+        let ceremony_id = Uuid::new_v4();
+        sqlx::query!(
+            r#"
+            INSERT INTO passkey_ceremonies (id, key_id, kind, state, expires_at)
+            VALUES ($1, $2, $3, $4, $5)
+            "#,
+            ceremony_id,
+            key_id,
+            kind,
+            state,
+            Utc::now() + self.ceremony_ttl
+        )
+        .execute(pool)
+        .await
+        .context("Failed to store passkey ceremony.")?;
+        Ok(ceremony_id)
+    }
+}
+
+#[tracing::instrument(name = "Check for passkeys", skip(pool))]
+pub async fn has_passkeys(key_id: i64, pool: &PgPool) -> Result<bool, anyhow::Error> {
+    Ok(sqlx::query!(
+        r#"SELECT EXISTS(SELECT 1 FROM passkeys WHERE key_id = $1) AS "exists!""#,
+        key_id
+    )
+    .fetch_one(pool)
+    .await
+    .context("Failed to perform a query to check for passkeys.")?
+    .exists)
+}
+
+/// The relying party and its id. Ceremonies time out in the browser when they expire here.
+fn build_webauthn(
+    base_url: &str,
+    ceremony_ttl: std::time::Duration,
+) -> Result<(Webauthn, String), anyhow::Error> {
+    let rp_origin = Url::parse(base_url).context("base_url is not a valid url.")?;
+    let rp_id = rp_origin
+        .domain()
+        .ok_or_else(|| anyhow!("{} has no domain name to use as relying party.", base_url))?
+        .to_string();
+    let webauthn = WebauthnBuilder::new(&rp_id, &rp_origin)
+        .context("Invalid relying party.")?
+        .rp_name("nostr-vault")
+        .timeout(ceremony_ttl)
+        .build()
+        .context("Invalid relying party.")?;
+    Ok((webauthn, rp_id))
+}
+
+/// Stable per key, so every passkey a user registers belongs to the same WebAuthn user.
+fn user_handle(key_id: i64) -> Uuid {
+    Uuid::from_u64_pair(0, key_id as u64)
+}
+
+async fn load_passkeys(key_id: i64, pool: &PgPool) -> Result<Vec<Passkey>, anyhow::Error> {
+    sqlx::query!(
+        r#"SELECT passkey FROM passkeys WHERE key_id = $1 ORDER BY id"#,
+        key_id
+    )
+    .fetch_all(pool)
+    .await
+    .context("Failed to perform a query to retrieve passkeys.")?
+    .into_iter()
+    .map(|row| serde_json::from_value(row.passkey).context("Failed to parse passkey."))
+    .collect()
+}
+
+/// Drops ceremonies that expired before being finished, run periodically by `Application`.
+#[tracing::instrument(name = "Purge expired passkey ceremonies", skip(pool))]
+pub async fn purge_expired_ceremonies(pool: &PgPool) -> Result<u64, anyhow::Error> {
+    Ok(
+        sqlx::query!(r#"DELETE FROM passkey_ceremonies WHERE expires_at <= NOW()"#)
+            .execute(pool)
+            .await
+            .context("Failed to purge expired passkey ceremonies.")?
+            .rows_affected(),
+    )
+}
+
+/// Ceremonies are single use, taking one removes it.
+async fn take_ceremony(
+    ceremony_id: Uuid,
+    kind: &str,
+    pool: &PgPool,
+) -> Result<Option<(i64, serde_json::Value)>, anyhow::Error> {
+    Ok(sqlx::query!(
+        r#"
+        DELETE FROM passkey_ceremonies
+        WHERE id = $1 AND kind = $2 AND expires_at > NOW()
+        RETURNING key_id, state
+        "#,
+        ceremony_id,
+        kind
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to take passkey ceremony.")?
+    .map(|row| (row.key_id, row.state)))
+}
@@ -0,0 +1,372 @@
+use crate::challenge::{
+    consume_challenge, register_challenge, release_challenge, ChallengePurpose,
+};
+use crate::configuration::PaymentSettings;
+use crate::lightning::LightningNode;
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::HttpRequest;
+use anyhow::{anyhow, Context};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use secrecy::ExposeSecret;
+use sha2::{Digest, Sha256};
+use sqlx::{PgPool, Postgres, Transaction};
+use std::sync::Arc;
+
+/// Version of the macaroon identifier layout, its first two bytes.
+const IDENTIFIER_VERSION: u16 = 0;
+/// Caveat every upload macaroon carries, a macaroon for another service is not accepted.
+const SERVICE_CAVEAT: &str = "service=upload";
+
+#[derive(thiserror::Error, Debug)]
+pub enum PaymentError {
+    #[error("Uploads cost {} msat, pay the invoice and send `Authorization: L402 <macaroon>:<preimage>`.", .0.amount_msat)]
+    Required(L402Challenge),
+    #[error("{0}")]
+    Invalid(String),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+/// What a 402 asks the client to pay, sent as the `WWW-Authenticate` header.
+#[derive(Clone, Debug)]
+pub struct L402Challenge {
+    /// Becomes valid for one upload once the invoice is paid.
+    pub macaroon: String,
+    /// BOLT11 invoice, its preimage is the proof of payment.
+    pub invoice: String,
+    pub amount_msat: u64,
+}
+
+impl L402Challenge {
+    /// `L402 macaroon="...", invoice="..."`
+    pub fn header_value(&self) -> String {
+        format!(
+            r#"L402 macaroon="{}", invoice="{}""#,
+            self.macaroon, self.invoice
+        )
+    }
+}
+
+/// A macaroon with the preimage of its invoice. It only counts once `spend` succeeds.
+#[derive(Debug)]
+pub struct PaidToken {
+    payment_hash: String,
+}
+
+impl PaidToken {
+    /// Marks the payment as used, fails if another upload already used it. Spent in the
+    /// transaction using up the upload's challenge, so neither is lost without the other.
+    #[tracing::instrument(name = "Spend paid token", skip(transaction))]
+    pub async fn spend(
+        &self,
+        transaction: &mut Transaction<'_, Postgres>,
+    ) -> Result<(), PaymentError> {
+        if !consume_challenge(&self.payment_hash, ChallengePurpose::Payment, transaction).await? {
+            return Err(PaymentError::Invalid(
+                "Payment is unknown, expired or already used for an upload.".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Gives the payment back after the upload it was spent on did not go through.
+    #[tracing::instrument(name = "Refund paid token", skip(pool))]
+    pub async fn refund(&self, pool: &PgPool) -> Result<(), anyhow::Error> {
+        release_challenge(&self.payment_hash, ChallengePurpose::Payment, pool).await
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Macaroon {
+    /// Hex of version, payment hash and a random token id.
+    identifier: String,
+    caveats: Vec<String>,
+    /// Hex HMAC chain over the identifier and every caveat, keyed with the root key.
+    signature: String,
+}
+
+/// L402 gate in front of uploads, see `PaymentSettings`.
+#[derive(Clone)]
+pub struct Payments {
+    price_msat: u64,
+    invoice_expiry_seconds: u64,
+    token_ttl: chrono::Duration,
+    /// Keyed with the macaroon root key, cloned for every macaroon.
+    signer: Hmac<Sha256>,
+    /// `None` while payments are off.
+    node: Option<Arc<dyn LightningNode>>,
+}
+
+impl Payments {
+    pub fn new(
+        settings: &PaymentSettings,
+        node: Option<Arc<dyn LightningNode>>,
+    ) -> Result<Self, anyhow::Error> {
+        let root_key = hex::decode(settings.macaroon_root_key.expose_secret())
+            .context("Macaroon root key is not valid hex.")?;
+        if settings.enabled && root_key.len() < 32 {
+            return Err(anyhow!("Macaroon root key must be at least 32 bytes."));
+        }
+        let node = match settings.enabled {
+            true => {
+                Some(node.ok_or_else(|| anyhow!("payments.node must be set to take payments."))?)
+            }
+            false => None,
+        };
+        Ok(Self {
+            price_msat: settings.upload_price_msat,
+            invoice_expiry_seconds: settings.invoice_expiry_seconds,
+            token_ttl: chrono::Duration::seconds(settings.token_ttl_seconds as i64),
+            signer: Hmac::<Sha256>::new_from_slice(&root_key)
+                .expect("HMAC accepts keys of any size"),
+            node,
+        })
+    }
+
+    /// `None` while payments are off. Otherwise a token from the `Authorization` header, or
+    /// `PaymentError::Required` with a fresh invoice when there is none.
+    #[tracing::instrument(name = "Authorize payment", skip(self, request, pool))]
+    pub async fn authorize(
+        &self,
+        request: &HttpRequest,
+        pool: &PgPool,
+    ) -> Result<Option<PaidToken>, PaymentError> {
+        let node = match &self.node {
+            Some(node) => node,
+            None => return Ok(None),
+        };
+        match request.headers().get(AUTHORIZATION) {
+            Some(header) => {
+                let header = header.to_str().map_err(|_| {
+                    PaymentError::Invalid("Authorization header is not valid.".to_string())
+                })?;
+                self.verify(header).map(Some)
+            }
+            None => Err(PaymentError::Required(self.challenge(node, pool).await?)),
+        }
+    }
+
+    /// Creates an invoice and a macaroon bound to its payment hash.
+    async fn challenge(
+        &self,
+        node: &Arc<dyn LightningNode>,
+        pool: &PgPool,
+    ) -> Result<L402Challenge, anyhow::Error> {
+        let invoice = node
+            .create_invoice(
+                self.price_msat,
+                "nostr vault upload",
+                self.invoice_expiry_seconds,
+            )
+            .await?;
+        let expires_at = Utc::now() + self.token_ttl;
+        let payment_hash = hex::encode(invoice.payment_hash);
+        register_challenge(
+            &payment_hash,
+            ChallengePurpose::Payment,
+            None,
+            expires_at,
+            pool,
+        )
+        .await?;
+        let macaroon = self.mint(&invoice.payment_hash, expires_at.timestamp())?;
+        Ok(L402Challenge {
+            macaroon,
+            invoice: invoice.bolt11,
+            amount_msat: self.price_msat,
+        })
+    }
+
+    fn mint(&self, payment_hash: &[u8; 32], expires_at: i64) -> Result<String, anyhow::Error> {
+        let mut token_id = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut token_id);
+        let mut identifier = IDENTIFIER_VERSION.to_be_bytes().to_vec();
+        identifier.extend_from_slice(payment_hash);
+        identifier.extend_from_slice(&token_id);
+        let identifier = hex::encode(identifier);
+        let caveats = vec![
+            SERVICE_CAVEAT.to_string(),
+            format!("expires_at={}", expires_at),
+        ];
+        let signature = hex::encode(self.chain(&identifier, &caveats));
+        let macaroon = Macaroon {
+            identifier,
+            caveats,
+            signature,
+        };
+        Ok(encode(serde_json::to_vec(&macaroon)?))
+    }
+
+    /// Checks `L402 <macaroon>:<preimage>` without touching the database, whether the
+    /// payment was already used is only known once the token is spent.
+    fn verify(&self, header: &str) -> Result<PaidToken, PaymentError> {
+        let invalid = |message: &str| PaymentError::Invalid(message.to_string());
+        let (macaroon, preimage) = header
+            .strip_prefix("L402 ")
+            .or_else(|| header.strip_prefix("LSAT "))
+            .and_then(|token| token.trim().split_once(':'))
+            .ok_or_else(|| invalid("Authorization must be `L402 <macaroon>:<preimage>`."))?;
+        let macaroon: Macaroon = decode(macaroon)
+            .ok()
+            .and_then(|macaroon| serde_json::from_slice(&macaroon).ok())
+            .ok_or_else(|| invalid("Macaroon is not valid."))?;
+        let signature =
+            hex::decode(&macaroon.signature).map_err(|_| invalid("Macaroon is not valid."))?;
+        let expected = self.chain(&macaroon.identifier, &macaroon.caveats);
+        if expected.len() != signature.len()
+            || expected
+                .iter()
+                .zip(signature.iter())
+                .fold(0, |acc, (x, y)| acc | (x ^ y))
+                != 0
+        {
+            return Err(invalid("Macaroon is not valid."));
+        }
+        let mut has_service = false;
+        for caveat in &macaroon.caveats {
+            match caveat.split_once('=') {
+                Some(("service", "upload")) => has_service = true,
+                Some(("expires_at", expires_at)) => {
+                    let expires_at: i64 = expires_at
+                        .parse()
+                        .map_err(|_| invalid("Macaroon is not valid."))?;
+                    if expires_at <= Utc::now().timestamp() {
+                        return Err(invalid("Macaroon has expired."));
+                    }
+                }
+                _ => return Err(invalid("Macaroon has a caveat this vault does not know.")),
+            }
+        }
+        if !has_service {
+            return Err(invalid("Macaroon is not for uploads."));
+        }
+        let identifier = hex::decode(&macaroon.identifier)
+            .ok()
+            .filter(|identifier| {
+                identifier.len() == 66 && identifier[..2] == IDENTIFIER_VERSION.to_be_bytes()
+            })
+            .ok_or_else(|| invalid("Macaroon is not valid."))?;
+        let payment_hash = &identifier[2..34];
+        let preimage =
+            hex::decode(preimage.trim()).map_err(|_| invalid("Preimage is not valid hex."))?;
+        if Sha256::digest(&preimage).as_slice() != payment_hash {
+            return Err(invalid(
+                "Preimage does not match the invoice of the macaroon.",
+            ));
+        }
+        Ok(PaidToken {
+            payment_hash: hex::encode(payment_hash),
+        })
+    }
+
+    /// `HMAC(root, identifier)`, then every caveat keyed with the signature before it.
+    fn chain(&self, identifier: &str, caveats: &[String]) -> Vec<u8> {
+        let mut mac = self.signer.clone();
+        mac.update(identifier.as_bytes());
+        let mut signature = mac.finalize().into_bytes().to_vec();
+        for caveat in caveats {
+            let mut mac =
+                Hmac::<Sha256>::new_from_slice(&signature).expect("HMAC accepts keys of any size");
+            mac.update(caveat.as_bytes());
+            signature = mac.finalize().into_bytes().to_vec();
+        }
+        signature
+    }
+}
+
+fn encode(data: impl AsRef<[u8]>) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+fn decode(data: &str) -> Result<Vec<u8>, base64::DecodeError> {
+    base64::decode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode, Macaroon, Payments};
+    use crate::configuration::PaymentSettings;
+    use crate::lightning::{FakeNode, LightningNode};
+    use claim::{assert_err, assert_ok};
+    use secrecy::Secret;
+    use std::sync::Arc;
+
+    const KEY: &str = "5e1b0f3c9a2d4e6f8a0b1c2d3e4f5a6b7c8d9e0f1a2b3c4d5e6f7a8b9c0d1e2f";
+
+    fn payments(root_key: &str) -> Payments {
+        Payments::new(
+            &PaymentSettings {
+                enabled: true,
+                macaroon_root_key: Secret::new(root_key.to_string()),
+                ..PaymentSettings::default()
+            },
+            Some(Arc::new(FakeNode)),
+        )
+        .unwrap()
+    }
+
+    /// A macaroon for a fresh fake invoice and the header paying it.
+    async fn paid_header(payments: &Payments, expires_at: i64) -> (String, String) {
+        let invoice = FakeNode
+            .create_invoice(10_000, "upload", 600)
+            .await
+            .unwrap();
+        let macaroon = payments.mint(&invoice.payment_hash, expires_at).unwrap();
+        let preimage = FakeNode::pay(&invoice.bolt11).unwrap();
+        let header = format!("L402 {}:{}", macaroon, hex::encode(preimage));
+        (macaroon, header)
+    }
+
+    fn in_an_hour() -> i64 {
+        chrono::Utc::now().timestamp() + 3600
+    }
+
+    #[test]
+    fn a_short_root_key_is_refused_when_enabled() {
+        let settings = PaymentSettings {
+            enabled: true,
+            macaroon_root_key: Secret::new("abcd".to_string()),
+            ..PaymentSettings::default()
+        };
+        assert!(Payments::new(&settings, Some(Arc::new(FakeNode))).is_err());
+    }
+
+    #[test]
+    fn payments_need_a_node_when_enabled() {
+        let settings = PaymentSettings {
+            enabled: true,
+            macaroon_root_key: Secret::new(KEY.to_string()),
+            ..PaymentSettings::default()
+        };
+        assert!(Payments::new(&settings, None).is_err());
+    }
+
+    #[tokio::test]
+    async fn the_preimage_of_the_invoice_unlocks_the_macaroon() {
+        let payments = payments(KEY);
+        let (macaroon, header) = paid_header(&payments, in_an_hour()).await;
+
+        assert_ok!(payments.verify(&header));
+        assert_err!(payments.verify(&format!("L402 {}:{}", macaroon, "00".repeat(32))));
+        assert_err!(payments(&"ab".repeat(32)).verify(&header));
+        assert_err!(payments.verify("L402 not-a-macaroon:00"));
+        assert_err!(payments.verify("Bearer token"));
+    }
+
+    #[tokio::test]
+    async fn expired_or_extended_macaroons_are_rejected() {
+        let payments = payments(KEY);
+        let (_, expired) = paid_header(&payments, chrono::Utc::now().timestamp() - 1).await;
+        assert_err!(payments.verify(&expired));
+
+        // A caveat added without the root key breaks the chain
+        let (macaroon, header) = paid_header(&payments, in_an_hour()).await;
+        let mut extended: Macaroon = serde_json::from_slice(&decode(&macaroon).unwrap()).unwrap();
+        extended.caveats[1] = "expires_at=9999999999".to_string();
+        let extended = encode(serde_json::to_vec(&extended).unwrap());
+        let preimage = header.rsplit_once(':').unwrap().1;
+        assert_err!(payments.verify(&format!("L402 {}:{}", extended, preimage)));
+    }
+}
@@ -0,0 +1,252 @@
+use crate::challenge::{consume_challenge_for, ChallengeIssuer, ChallengePurpose};
+use crate::configuration::ProofOfWorkSettings;
+use crate::domain::Nip05ID;
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use utoipa::ToSchema;
+
+#[derive(thiserror::Error, Debug)]
+pub enum WorkError {
+    #[error("A proof of work with {0} leading zero bits is required, ask /work_challenge for a challenge.")]
+    Required(u8),
+    #[error("{0}")]
+    Invalid(String),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+/// Route a proof of work is done for, a challenge only counts for its own route.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ToSchema, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WorkRoute {
+    /// `/upload_key` and `POST /v1/keys`
+    Upload,
+    /// Every route that checks a pin or recovery code, `/fetch_key` and
+    /// `POST /v1/keys/{nip_05_id}/retrieve` among them
+    Fetch,
+}
+
+impl WorkRoute {
+    fn purpose(&self) -> ChallengePurpose {
+        match self {
+            WorkRoute::Upload => ChallengePurpose::UploadWork,
+            WorkRoute::Fetch => ChallengePurpose::FetchWork,
+        }
+    }
+}
+
+#[derive(ToSchema, serde::Serialize, serde::Deserialize)]
+pub struct WorkChallenge {
+    #[schema(example = "9c1185a5c5e9fc54612808977ee8f548b2258d31ddadef707ba62c2a8b3fd0e6")]
+    pub challenge: String,
+    #[schema(value_type = String, example = "2023-02-12T01:54:35+00:00")]
+    pub expires_at: DateTime<Utc>,
+    /// Leading zero bits `sha256(challenge || ":" || nip_05_id || ":" || nonce)` needs right
+    /// now, 0 if no proof of work is required.
+    #[schema(example = 16)]
+    pub difficulty: u8,
+}
+
+/// Sent with an upload or fetch once a difficulty above 0 is required.
+#[derive(ToSchema, Clone, serde::Serialize, serde::Deserialize)]
+pub struct WorkProof {
+    /// Nonce from `/work_challenge`, issued for the same route and nip 05 id.
+    #[schema(example = "9c1185a5c5e9fc54612808977ee8f548b2258d31ddadef707ba62c2a8b3fd0e6")]
+    pub challenge: String,
+    /// Counter the client increments until the hash has enough leading zero bits.
+    #[schema(example = 48213)]
+    pub nonce: u64,
+}
+
+/// NIP-13 style proof of work in front of uploads and fetches, see `ProofOfWorkSettings`.
+#[derive(Clone)]
+pub struct ProofOfWork {
+    upload_difficulty: u8,
+    fetch_difficulty: u8,
+    difficulty_per_failure: u8,
+    max_difficulty: u8,
+    failure_window: chrono::Duration,
+}
+
+impl ProofOfWork {
+    pub fn new(settings: &ProofOfWorkSettings) -> Self {
+        Self {
+            upload_difficulty: settings.upload_difficulty,
+            fetch_difficulty: settings.fetch_difficulty,
+            difficulty_per_failure: settings.difficulty_per_failure,
+            // More than 64 bits would never be found
+            max_difficulty: settings.max_difficulty.min(64),
+            failure_window: chrono::Duration::seconds(settings.failure_window_seconds as i64),
+        }
+    }
+
+    /// Whether failed fetches are recorded, so they need purging.
+    pub fn scales_with_failures(&self) -> bool {
+        self.difficulty_per_failure > 0
+    }
+
+    /// Bits required for `route` on `nip_05_id` at the moment.
+    #[tracing::instrument(name = "Proof of work difficulty", skip(self, pool))]
+    pub async fn difficulty(
+        &self,
+        route: WorkRoute,
+        nip_05_id: &Nip05ID,
+        pool: &PgPool,
+    ) -> Result<u8, anyhow::Error> {
+        let base = match route {
+            WorkRoute::Upload => self.upload_difficulty,
+            WorkRoute::Fetch => self.fetch_difficulty,
+        };
+        if route != WorkRoute::Fetch || !self.scales_with_failures() {
+            return Ok(base.min(self.max_difficulty));
+        }
+        let failures = sqlx::query!(
+            r#"
+            SELECT COUNT(*) AS "failures!" FROM failed_attempts
+            WHERE nip_05_id = $1 AND attempted_at > $2
+            "#,
+            nip_05_id.as_ref(),
+            Utc::now() - self.failure_window
+        )
+        .fetch_one(pool)
+        .await
+        .context("Failed to count failed attempts.")?
+        .failures;
+        let extra = failures.saturating_mul(self.difficulty_per_failure as i64);
+        let difficulty = (base as i64).saturating_add(extra);
+        Ok(difficulty.min(self.max_difficulty as i64) as u8)
+    }
+
+    pub async fn issue(
+        &self,
+        route: WorkRoute,
+        nip_05_id: &Nip05ID,
+        issuer: &ChallengeIssuer,
+        pool: &PgPool,
+    ) -> Result<WorkChallenge, anyhow::Error> {
+        let difficulty = self.difficulty(route, nip_05_id, pool).await?;
+        let challenge = issuer
+            .issue_for(route.purpose(), Some(nip_05_id.as_ref()), pool)
+            .await?;
+        Ok(WorkChallenge {
+            challenge: challenge.challenge,
+            expires_at: challenge.expires_at,
+            difficulty,
+        })
+    }
+
+    /// Consumes the challenge of `proof` and checks it against the difficulty required now,
+    /// not the one the challenge was issued with, so challenges collected before a run of
+    /// failures do not get around it.
+    #[tracing::instrument(name = "Check proof of work", skip(self, proof, pool))]
+    pub async fn check(
+        &self,
+        route: WorkRoute,
+        nip_05_id: &Nip05ID,
+        proof: Option<&WorkProof>,
+        pool: &PgPool,
+    ) -> Result<(), WorkError> {
+        let difficulty = self.difficulty(route, nip_05_id, pool).await?;
+        let proof = match proof {
+            Some(proof) => proof,
+            None if difficulty == 0 => return Ok(()),
+            None => return Err(WorkError::Required(difficulty)),
+        };
+        if !consume_challenge_for(&proof.challenge, route.purpose(), nip_05_id.as_ref(), pool)
+            .await?
+        {
+            return Err(WorkError::Invalid(
+                "Proof of work challenge is unknown, expired, already used or for another nip 05 id."
+                    .to_string(),
+            ));
+        }
+        let digest = work_digest(&proof.challenge, nip_05_id.as_ref(), proof.nonce);
+        if leading_zero_bits(&digest) < difficulty as u32 {
+            return Err(WorkError::Invalid(format!(
+                "Proof of work has fewer than the {} leading zero bits required.",
+                difficulty
+            )));
+        }
+        Ok(())
+    }
+
+    /// Counts against the nip 05 id for `failure_window_seconds`, a no-op unless the
+    /// difficulty scales with failures.
+    #[tracing::instrument(name = "Record failed attempt", skip(self, pool))]
+    pub async fn record_failure(
+        &self,
+        nip_05_id: &Nip05ID,
+        pool: &PgPool,
+    ) -> Result<(), anyhow::Error> {
+        if !self.scales_with_failures() {
+            return Ok(());
+        }
+        sqlx::query!(
+            r#"INSERT INTO failed_attempts (nip_05_id) VALUES ($1)"#,
+            nip_05_id.as_ref()
+        )
+        .execute(pool)
+        .await
+        .context("Failed to record failed attempt.")?;
+        Ok(())
+    }
+
+    /// Drops failed attempts that no longer count, returns how many were removed.
+    pub async fn purge_failed_attempts(&self, pool: &PgPool) -> Result<u64, anyhow::Error> {
+        let purged = sqlx::query!(
+            r#"DELETE FROM failed_attempts WHERE attempted_at <= $1"#,
+            Utc::now() - self.failure_window
+        )
+        .execute(pool)
+        .await
+        .context("Failed to purge failed attempts.")?;
+        Ok(purged.rows_affected())
+    }
+}
+
+/// `sha256(challenge || ":" || nip_05_id || ":" || nonce)`, with the nonce in decimal.
+pub fn work_digest(challenge: &str, nip_05_id: &str, nonce: u64) -> [u8; 32] {
+    Sha256::digest(format!("{}:{}:{}", challenge, nip_05_id, nonce)).into()
+}
+
+pub fn leading_zero_bits(digest: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in digest {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{leading_zero_bits, work_digest};
+
+    #[test]
+    fn leading_zero_bits_stop_at_the_first_set_bit() {
+        assert_eq!(leading_zero_bits(&[0xff]), 0);
+        assert_eq!(leading_zero_bits(&[0x00, 0x0f, 0x00]), 12);
+        assert_eq!(leading_zero_bits(&[0x00, 0x00, 0x01]), 23);
+        assert_eq!(leading_zero_bits(&[0x00; 4]), 32);
+    }
+
+    #[test]
+    fn a_found_nonce_has_the_difficulty_it_was_searched_for() {
+        let nonce = (0..)
+            .find(|nonce| leading_zero_bits(&work_digest("abc", "bob@frogs.cloud", *nonce)) >= 8)
+            .unwrap();
+
+        assert!(leading_zero_bits(&work_digest("abc", "bob@frogs.cloud", nonce)) >= 8);
+        // Bound to the nip 05 id, the same nonce is worth nothing for another one
+        let other: Vec<u32> = (0..4)
+            .map(|i| {
+                leading_zero_bits(&work_digest("abc", &format!("eve{}@frogs.cloud", i), nonce))
+            })
+            .collect();
+        assert!(other.iter().any(|bits| *bits < 8));
+    }
+}
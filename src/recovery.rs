@@ -0,0 +1,228 @@
+use crate::authentication::{
+    compute_pin_hash, compute_secret_hash, dummy_secret_hash, verify_secret_hash, AuthError,
+    StoredKey,
+};
+use crate::domain::{Nip05ID, Pin, RecoveryCode, VersionVector};
+use crate::hashing::spawn_hashing;
+use anyhow::{anyhow, Context};
+use secrecy::{ExposeSecret, Secret};
+use sqlx::{PgPool, Postgres, Transaction};
+use std::future::Future;
+
+/// How many recovery codes are handed out with every upload.
+pub const RECOVERY_CODE_COUNT: usize = 10;
+
+#[derive(Debug, Clone)]
+pub struct Recovery {
+    pub nip_05_id: Nip05ID,
+    pub recovery_code: RecoveryCode,
+    pub new_pin: Pin,
+}
+
+/// A fresh set of codes, hashed before the transaction storing them is opened.
+pub struct RecoveryCodes {
+    codes: Vec<RecoveryCode>,
+    code_hashes: Vec<String>,
+}
+
+impl RecoveryCodes {
+    /// Generates the codes for a new key, only their hashes are ever stored.
+    #[tracing::instrument(name = "Generate recovery codes")]
+    pub async fn generate() -> Result<Self, anyhow::Error> {
+        let mut codes: Vec<RecoveryCode> = Vec::with_capacity(RECOVERY_CODE_COUNT);
+        while codes.len() < RECOVERY_CODE_COUNT {
+            let code = RecoveryCode::generate();
+            if codes.iter().all(|issued| issued.prefix() != code.prefix()) {
+                codes.push(code);
+            }
+        }
+        let to_hash = codes.clone();
+        let code_hashes = spawn_hashing(move || {
+            to_hash
+                .iter()
+                .map(|code| {
+                    compute_secret_hash(code.as_ref()).map(|hash| hash.expose_secret().clone())
+                })
+                .collect::<Result<Vec<String>, _>>()
+        })
+        .await?
+        .context("Failed to hash recovery codes.")?;
+        Ok(Self { codes, code_hashes })
+    }
+
+    /// Stores the hashes for the key in the transaction creating it, so a key is never
+    /// stored without its codes. Returns the codes to hand out.
+    #[tracing::instrument(name = "Store recovery codes", skip(self, transaction))]
+    pub async fn store(
+        self,
+        key_id: i64,
+        transaction: &mut Transaction<'_, Postgres>,
+    ) -> Result<Vec<RecoveryCode>, anyhow::Error> {
+        let code_prefixes: Vec<String> = self
+            .codes
+            .iter()
+            .map(|code| code.prefix().to_string())
+            .collect();
+        sqlx::query!(
+            r#"
+            INSERT INTO recovery_codes (key_id, code_hash, code_prefix)
+            SELECT $1, code_hash, code_prefix
+            FROM UNNEST($2::text[], $3::text[]) AS codes(code_hash, code_prefix)
+            "#,
+            key_id,
+            &self.code_hashes,
+            &code_prefixes
+        )
+        .execute(transaction)
+        .await
+        .context("Failed to store recovery codes.")?;
+        Ok(self.codes)
+    }
+}
+
+/// Consumes the recovery code and replaces the pin, returning the stored key. Only the code
+/// sharing the candidate's prefix is verified, and `verify_second_factor` has to pass for the
+/// key before the code is used up, so a recovery code does not get around TOTP or passkeys.
+/// The hashing and the second factor check run before the key is locked, the code is only
+/// used up if nobody else did so meanwhile.
+#[tracing::instrument(
+    name = "Recover key",
+    skip(recovery, verify_second_factor, pool),
+    fields(nip_05_id = %recovery.nip_05_id)
+)]
+pub async fn reset_pin_with_recovery_code<F, Fut>(
+    recovery: &Recovery,
+    verify_second_factor: F,
+    node_id: &str,
+    pool: &PgPool,
+) -> Result<StoredKey, AuthError>
+where
+    F: FnOnce(i64) -> Fut,
+    Fut: Future<Output = Result<(), AuthError>>,
+{
+    let key_id = sqlx::query!(
+        r#"SELECT id FROM keys WHERE nip_05_id = $1"#,
+        recovery.nip_05_id.to_string()
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to perform a query to retrieve stored key.")?
+    .map(|key| key.id);
+
+    let unused_codes = match key_id {
+        Some(key_id) => sqlx::query!(
+            r#"
+            SELECT id, code_hash
+            FROM recovery_codes
+            WHERE key_id = $1 AND used_at IS NULL
+                AND (code_prefix = $2 OR code_prefix IS NULL)
+            "#,
+            key_id,
+            recovery.recovery_code.prefix()
+        )
+        .fetch_all(pool)
+        .await
+        .context("Failed to perform a query to retrieve recovery codes.")?
+        .into_iter()
+        .map(|row| (row.id, Secret::new(row.code_hash)))
+        .collect(),
+        None => vec![],
+    };
+
+    let candidate = recovery.recovery_code.clone();
+    let matched_code = spawn_hashing(move || {
+        if unused_codes.is_empty() {
+            verify_secret_hash(&dummy_secret_hash(), candidate.as_ref());
+            return None;
+        }
+        unused_codes
+            .into_iter()
+            .find(|(_, code_hash)| verify_secret_hash(code_hash, candidate.as_ref()))
+            .map(|(id, _)| id)
+    })
+    .await
+    .context("Failed to queue hashing task.")?;
+
+    let (key_id, code_id) = match (key_id, matched_code) {
+        (Some(key_id), Some(code_id)) => (key_id, code_id),
+        _ => return Err(unmatched_recovery_code()),
+    };
+
+    verify_second_factor(key_id).await?;
+
+    let new_pin = recovery.new_pin.clone();
+    let pin_hash = spawn_hashing(move || compute_pin_hash(new_pin))
+        .await
+        .context("Failed to queue hashing task.")?
+        .context("Failed to hash pin.")?;
+
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool.")?;
+    let key = sqlx::query!(
+        r#"
+        SELECT id, created_at, nip_05_id, private_key_hash, version_vector
+        FROM keys
+        WHERE id = $1
+        FOR UPDATE
+        "#,
+        key_id
+    )
+    .fetch_optional(&mut transaction)
+    .await
+    .context("Failed to perform a query to retrieve stored key.")?
+    // Deleted since the code was checked
+    .ok_or_else(unmatched_recovery_code)?;
+    let consumed = sqlx::query!(
+        r#"
+        UPDATE recovery_codes SET used_at = NOW()
+        WHERE id = $1 AND key_id = $2 AND used_at IS NULL
+        "#,
+        code_id,
+        key.id
+    )
+    .execute(&mut transaction)
+    .await
+    .context("Failed to consume recovery code.")?
+    .rows_affected();
+    if consumed != 1 {
+        // Used by a concurrent recovery
+        return Err(unmatched_recovery_code());
+    }
+
+    let mut version_vector: VersionVector =
+        serde_json::from_value(key.version_vector).context("Failed to parse version vector.")?;
+    version_vector.increment(node_id);
+    sqlx::query!(
+        r#"
+        UPDATE keys
+        SET pin_hash = $2, updated_at = NOW(), version_vector = $3,
+            last_accessed_at = NOW(), expiry_warned_at = NULL
+        WHERE id = $1
+        "#,
+        key.id,
+        pin_hash.expose_secret(),
+        serde_json::to_value(&version_vector).context("Failed to serialize version vector.")?
+    )
+    .execute(&mut transaction)
+    .await
+    .context("Failed to reset pin.")?;
+
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit pin reset.")?;
+
+    Ok(StoredKey {
+        id: key.id,
+        created_at: key.created_at.to_rfc3339(),
+        nip_05_id: key.nip_05_id,
+        private_key_hash: key.private_key_hash,
+        recovery_codes: vec![],
+    })
+}
+
+fn unmatched_recovery_code() -> AuthError {
+    AuthError::InvalidRecoveryCode(anyhow!("Recovery code does not match any unused code."))
+}
@@ -0,0 +1,86 @@
+use crate::configuration::RelayPublisherSettings;
+use crate::domain::{AppDataEvent, NostrEvent};
+use anyhow::{anyhow, Context};
+use futures_util::{SinkExt, StreamExt};
+use std::time::Duration;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::Instrument;
+
+/// Forwards user-signed NIP-78 events to the configured relays so the blob can be
+/// recovered from nostr even without any vault.
+#[derive(Clone)]
+pub struct RelayPublisher {
+    relays: Vec<String>,
+    timeout: Duration,
+}
+
+impl RelayPublisher {
+    pub fn new(settings: &RelayPublisherSettings) -> Self {
+        Self {
+            relays: settings.relays.clone(),
+            timeout: Duration::from_secs(settings.timeout_seconds),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.relays.is_empty()
+    }
+
+    /// Publishes to every relay in the background, failures are only logged.
+    pub fn publish(&self, event: AppDataEvent) {
+        if !self.is_enabled() {
+            return;
+        }
+        for relay in self.relays.iter() {
+            let relay = relay.clone();
+            let event = event.as_ref().clone();
+            let timeout = self.timeout;
+            let span = tracing::info_span!("Publish event to relay", %relay, event_id = %event.id);
+            tokio::spawn(
+                async move {
+                    match tokio::time::timeout(timeout, publish_to_relay(&relay, &event)).await {
+                        Ok(Ok(())) => tracing::info!("Relay accepted event."),
+                        Ok(Err(e)) => tracing::warn!("Relay did not accept event: {:?}", e),
+                        Err(_) => tracing::warn!("Timed out publishing event to relay."),
+                    }
+                }
+                .instrument(span),
+            );
+        }
+    }
+}
+
+/// Sends `["EVENT", <event>]` and waits for the relay's matching `["OK", ...]`.
+pub async fn publish_to_relay(relay: &str, event: &NostrEvent) -> Result<(), anyhow::Error> {
+    let (mut socket, _) = tokio_tungstenite::connect_async(relay)
+        .await
+        .context("Failed to connect to relay.")?;
+    let request = serde_json::json!(["EVENT", event]).to_string();
+    socket
+        .send(Message::Text(request))
+        .await
+        .context("Failed to send event to relay.")?;
+
+    let mut outcome = Err(anyhow!("Relay closed the connection without answering."));
+    while let Some(message) = socket.next().await {
+        let text = match message.context("Failed to read from relay.")? {
+            Message::Text(text) => text,
+            _ => continue,
+        };
+        let reply: serde_json::Value = match serde_json::from_str(&text) {
+            Ok(reply) => reply,
+            Err(_) => continue,
+        };
+        if reply[0] != "OK" || reply[1] != event.id.as_str() {
+            continue;
+        }
+        outcome = if reply[2].as_bool() == Some(true) {
+            Ok(())
+        } else {
+            Err(anyhow!("Relay rejected event: {}", reply[3]))
+        };
+        break;
+    }
+    let _ = socket.close(None).await;
+    outcome
+}
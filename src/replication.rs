@@ -0,0 +1,467 @@
+use crate::configuration::{PeerSettings, ReplicationSettings};
+use crate::domain::{Causality, Nip05ID, VersionVector};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use secrecy::ExposeSecret;
+use sqlx::{PgPool, Postgres, Transaction};
+use tracing::Instrument;
+use utoipa::ToSchema;
+
+/// A full copy of a `keys` row as exchanged between vaults, or the tombstone a deleted one
+/// left behind.
+#[derive(ToSchema, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ReplicatedKey {
+    #[schema(example = "the_name_is_bob_bob_smith@frogs.cloud")]
+    pub nip_05_id: String,
+    #[schema(
+        example = "$argon2id$v=19$m=15000,t=2,p=1$gZiV/M1gPc22ElAH/Jh1Hw$CWOrkoo7oJBQ/iyh7uJ0LO2aLEfrHwTWllSAxT0zRno"
+    )]
+    pub pin_hash: String,
+    #[schema(
+        example = "$PBKDF2$i=100000,l=256,s=0Bu5lWu4s66/iottrlUGdckjf5nwnpB05jwp4yDh8NU=$AESGM$OrScsD+hHGaRaPbc$XMXVVbjt3JV+QsNb7ZWRc8uNod2YzJL0lSvW1FOiY38ywOu7IEChKs/IqEQ7knhZAmRGYqoB4dhAbdOTwVhYIeQsuf1+f+9ARPEjtURsDg=="
+    )]
+    pub private_key_hash: String,
+    #[schema(value_type = String, example = "2023-02-12T01:49:35+00:00")]
+    pub created_at: DateTime<Utc>,
+    #[schema(value_type = String, example = "2023-02-12T01:49:35+00:00")]
+    pub updated_at: DateTime<Utc>,
+    #[schema(value_type = Object, example = json!({"vault-a": 2, "vault-b": 1}))]
+    pub version_vector: VersionVector,
+    #[serde(default)]
+    pub pubkey: Option<String>,
+    #[serde(default)]
+    pub relays: Vec<String>,
+    /// A TOTP secret or passkey is enrolled on the vault the key came from. Only that vault
+    /// can check it, the others refuse to serve the key. Enrolments do not bump the version
+    /// vector, a copy that knows of one adds it to the merged row instead.
+    #[serde(default)]
+    pub second_factor_enrolled: bool,
+    /// The key was deleted at `updated_at`, the hashes are empty.
+    #[serde(default)]
+    pub deleted: bool,
+}
+
+impl ReplicatedKey {
+    /// Last-writer-wins: a causally newer copy always wins. Between concurrent copies a
+    /// delete wins, then `updated_at` and the blob itself so every vault picks the same one.
+    pub fn supersedes(&self, other: &ReplicatedKey) -> bool {
+        match self.version_vector.compare(&other.version_vector) {
+            Causality::After => true,
+            Causality::Before | Causality::Equal => false,
+            Causality::Concurrent => {
+                (self.deleted, self.updated_at, &self.private_key_hash)
+                    > (other.deleted, other.updated_at, &other.private_key_hash)
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Replicator {
+    node_id: String,
+    peers: Vec<PeerSettings>,
+    http_client: reqwest::Client,
+}
+
+impl Replicator {
+    pub fn new(settings: &ReplicationSettings) -> Result<Self, anyhow::Error> {
+        let mut http_client =
+            reqwest::Client::builder().timeout(std::time::Duration::from_secs(10));
+        if let Some(path) = &settings.client_identity_path {
+            let pem = std::fs::read(path).with_context(|| format!("Failed to read {}.", path))?;
+            http_client = http_client.identity(
+                reqwest::Identity::from_pem(&pem)
+                    .with_context(|| format!("Invalid client identity in {}.", path))?,
+            );
+        }
+        if let Some(path) = &settings.ca_cert_path {
+            let pem = std::fs::read(path).with_context(|| format!("Failed to read {}.", path))?;
+            http_client = http_client.add_root_certificate(
+                reqwest::Certificate::from_pem(&pem)
+                    .with_context(|| format!("Invalid CA certificate in {}.", path))?,
+            );
+        }
+        Ok(Self {
+            node_id: settings.node_id.clone(),
+            peers: settings.peers.clone(),
+            http_client: http_client
+                .build()
+                .context("Failed to build the replication client.")?,
+        })
+    }
+
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    pub fn has_peers(&self) -> bool {
+        !self.peers.is_empty()
+    }
+
+    /// Pushes the current copy of a row to every peer without holding up the caller.
+    pub fn replicate(&self, nip_05_id: String, pool: PgPool) {
+        if !self.has_peers() {
+            return;
+        }
+        let replicator = self.clone();
+        let span = tracing::info_span!("Replicate key to peers");
+        tokio::spawn(
+            async move {
+                match get_replicated_key(&nip_05_id, &pool).await {
+                    Ok(Some(key)) => replicator.push_to_peers(&key).await,
+                    Ok(None) => tracing::warn!("Key was purged before it could be replicated."),
+                    Err(e) => tracing::error!("Failed to load key for replication: {:?}", e),
+                }
+            }
+            .instrument(span),
+        );
+    }
+
+    #[tracing::instrument(name = "Push key to peers", skip(self, key))]
+    pub async fn push_to_peers(&self, key: &ReplicatedKey) {
+        for peer in self.peers.iter() {
+            let result = self
+                .http_client
+                .post(format!("{}/replication/keys", peer.url))
+                .bearer_auth(peer.auth_key.expose_secret())
+                .json(key)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            if let Err(e) = result {
+                tracing::warn!("Failed to push key to peer {}: {:?}", peer.url, e);
+            }
+        }
+    }
+
+    /// Pulls every row each peer holds and merges it locally, returns how many rows changed.
+    #[tracing::instrument(name = "Pull keys from peers", skip(self, pool))]
+    pub async fn pull_from_peers(&self, pool: &PgPool) -> usize {
+        let mut applied = 0;
+        for peer in self.peers.iter() {
+            let keys = match self.fetch_peer_keys(peer).await {
+                Ok(keys) => keys,
+                Err(e) => {
+                    tracing::warn!("Failed to pull keys from peer {}: {:?}", peer.url, e);
+                    continue;
+                }
+            };
+            for key in keys.iter() {
+                match apply_replicated_key(key, pool).await {
+                    Ok(true) => applied += 1,
+                    Ok(false) => {}
+                    Err(e) => tracing::error!("Failed to apply replicated key: {:?}", e),
+                }
+            }
+        }
+        applied
+    }
+
+    async fn fetch_peer_keys(
+        &self,
+        peer: &PeerSettings,
+    ) -> Result<Vec<ReplicatedKey>, reqwest::Error> {
+        self.http_client
+            .get(format!("{}/replication/keys", peer.url))
+            .bearer_auth(peer.auth_key.expose_secret())
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Vec<ReplicatedKey>>()
+            .await
+    }
+}
+
+struct ReplicatedKeyRow {
+    nip_05_id: String,
+    pin_hash: String,
+    private_key_hash: String,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    version_vector: serde_json::Value,
+    pubkey: Option<String>,
+    relays: Vec<String>,
+    second_factor_enrolled: bool,
+}
+
+impl TryFrom<ReplicatedKeyRow> for ReplicatedKey {
+    type Error = anyhow::Error;
+
+    fn try_from(row: ReplicatedKeyRow) -> Result<Self, Self::Error> {
+        Ok(Self {
+            nip_05_id: row.nip_05_id,
+            pin_hash: row.pin_hash,
+            private_key_hash: row.private_key_hash,
+            created_at: row.created_at,
+            updated_at: row.updated_at,
+            version_vector: serde_json::from_value(row.version_vector)
+                .context("Failed to parse version vector.")?,
+            pubkey: row.pubkey,
+            relays: row.relays,
+            second_factor_enrolled: row.second_factor_enrolled,
+            deleted: false,
+        })
+    }
+}
+
+struct TombstoneRow {
+    nip_05_id: String,
+    version_vector: serde_json::Value,
+    deleted_at: DateTime<Utc>,
+    expired: bool,
+}
+
+impl TryFrom<TombstoneRow> for ReplicatedKey {
+    type Error = anyhow::Error;
+
+    fn try_from(row: TombstoneRow) -> Result<Self, Self::Error> {
+        Ok(Self {
+            nip_05_id: row.nip_05_id,
+            pin_hash: String::new(),
+            private_key_hash: String::new(),
+            created_at: row.deleted_at,
+            updated_at: row.deleted_at,
+            version_vector: serde_json::from_value(row.version_vector)
+                .context("Failed to parse version vector.")?,
+            pubkey: None,
+            relays: vec![],
+            second_factor_enrolled: false,
+            deleted: true,
+        })
+    }
+}
+
+/// The key, or its tombstone once it was deleted. Keys that only expired here are `None`.
+#[tracing::instrument(name = "Get replicated key", skip(pool))]
+pub async fn get_replicated_key(
+    nip_05_id: &str,
+    pool: &PgPool,
+) -> Result<Option<ReplicatedKey>, anyhow::Error> {
+    let key = sqlx::query_as!(
+        ReplicatedKeyRow,
+        r#"
+        SELECT nip_05_id, pin_hash, private_key_hash, created_at, updated_at, version_vector,
+            pubkey, relays, second_factor_enrolled
+        FROM keys
+        WHERE nip_05_id = $1
+        "#,
+        nip_05_id
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to perform a query to retrieve key for replication.")?;
+    if let Some(key) = key {
+        return ReplicatedKey::try_from(key).map(Some);
+    }
+    sqlx::query_as!(
+        TombstoneRow,
+        r#"
+        SELECT nip_05_id, version_vector, deleted_at, expired
+        FROM key_tombstones
+        WHERE nip_05_id = $1 AND NOT expired
+        "#,
+        nip_05_id
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to perform a query to retrieve tombstone for replication.")?
+    .map(ReplicatedKey::try_from)
+    .transpose()
+}
+
+/// Every key followed by every tombstone, leaving out keys that only expired here.
+#[tracing::instrument(name = "List replicated keys", skip(pool))]
+pub async fn list_replicated_keys(pool: &PgPool) -> Result<Vec<ReplicatedKey>, anyhow::Error> {
+    let keys = sqlx::query_as!(
+        ReplicatedKeyRow,
+        r#"
+        SELECT nip_05_id, pin_hash, private_key_hash, created_at, updated_at, version_vector,
+            pubkey, relays, second_factor_enrolled
+        FROM keys
+        ORDER BY id
+        "#
+    )
+    .fetch_all(pool)
+    .await
+    .context("Failed to perform a query to list keys for replication.")?;
+    let tombstones = sqlx::query_as!(
+        TombstoneRow,
+        r#"
+        SELECT nip_05_id, version_vector, deleted_at, expired
+        FROM key_tombstones
+        WHERE NOT expired
+        ORDER BY deleted_at
+        "#
+    )
+    .fetch_all(pool)
+    .await
+    .context("Failed to perform a query to list tombstones for replication.")?;
+    keys.into_iter()
+        .map(ReplicatedKey::try_from)
+        .chain(tombstones.into_iter().map(ReplicatedKey::try_from))
+        .collect()
+}
+
+/// Merges a copy of a row received from a peer, returns whether the local row changed.
+/// A local tombstone takes part like a row, so a deleted key only comes back from a copy
+/// written after the delete. The nip 05 id is normalized first, peers that have not been
+/// upgraded yet may still send it in another case or with a unicode domain. A key that
+/// expired here is only replaced by a copy changed since, an unchanged one stays expired.
+#[tracing::instrument(name = "Apply replicated key", skip(incoming, pool))]
+pub async fn apply_replicated_key(
+    incoming: &ReplicatedKey,
+    pool: &PgPool,
+) -> Result<bool, anyhow::Error> {
+    let nip_05_id = Nip05ID::parse(incoming.nip_05_id.clone()).map_err(anyhow::Error::msg)?;
+    let incoming = &ReplicatedKey {
+        nip_05_id: nip_05_id.to_string(),
+        ..incoming.clone()
+    };
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool.")?;
+
+    let local = sqlx::query_as!(
+        ReplicatedKeyRow,
+        r#"
+        SELECT nip_05_id, pin_hash, private_key_hash, created_at, updated_at, version_vector,
+            pubkey, relays, second_factor_enrolled
+        FROM keys
+        WHERE nip_05_id = $1
+        FOR UPDATE
+        "#,
+        incoming.nip_05_id
+    )
+    .fetch_optional(&mut transaction)
+    .await
+    .context("Failed to perform a query to retrieve local copy of key.")?
+    .map(ReplicatedKey::try_from)
+    .transpose()?;
+    let mut expired = false;
+    let local = match local {
+        Some(local) => Some(local),
+        None => {
+            let tombstone = sqlx::query_as!(
+                TombstoneRow,
+                r#"
+                SELECT nip_05_id, version_vector, deleted_at, expired
+                FROM key_tombstones
+                WHERE nip_05_id = $1
+                FOR UPDATE
+                "#,
+                incoming.nip_05_id
+            )
+            .fetch_optional(&mut transaction)
+            .await
+            .context("Failed to perform a query to retrieve local tombstone.")?;
+            expired = tombstone
+                .as_ref()
+                .map_or(false, |tombstone| tombstone.expired);
+            tombstone.map(ReplicatedKey::try_from).transpose()?
+        }
+    };
+
+    let changed = match &local {
+        None => store_replicated_key(incoming, &incoming.version_vector, &mut transaction).await?,
+        Some(local) if expired => match incoming.version_vector.compare(&local.version_vector) {
+            Causality::After | Causality::Concurrent => {
+                let merged_vector = local.version_vector.merge(&incoming.version_vector);
+                store_replicated_key(incoming, &merged_vector, &mut transaction).await?
+            }
+            Causality::Before | Causality::Equal => false,
+        },
+        Some(local) => {
+            let merged_vector = local.version_vector.merge(&incoming.version_vector);
+            let winner = if incoming.supersedes(local) {
+                incoming
+            } else {
+                local
+            };
+            let second_factor_enrolled = !winner.deleted
+                && [incoming, local]
+                    .iter()
+                    .any(|key| !key.deleted && key.second_factor_enrolled);
+            if merged_vector == local.version_vector
+                && second_factor_enrolled == local.second_factor_enrolled
+            {
+                false
+            } else {
+                let winner = ReplicatedKey {
+                    second_factor_enrolled,
+                    ..winner.clone()
+                };
+                store_replicated_key(&winner, &merged_vector, &mut transaction).await?
+            }
+        }
+    };
+
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit replicated key.")?;
+    Ok(changed)
+}
+
+/// Writes `winner` under `version_vector`, as a row or as a tombstone replacing the row.
+async fn store_replicated_key(
+    winner: &ReplicatedKey,
+    version_vector: &VersionVector,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<bool, anyhow::Error> {
+    let version_vector = serde_json::to_value(version_vector)?;
+    if winner.deleted {
+        sqlx::query!(r#"DELETE FROM keys WHERE nip_05_id = $1"#, winner.nip_05_id)
+            .execute(&mut *transaction)
+            .await
+            .context("Failed to delete replicated key.")?;
+        sqlx::query!(
+            r#"
+            INSERT INTO key_tombstones (nip_05_id, version_vector, deleted_at)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (nip_05_id) DO UPDATE
+            SET version_vector = EXCLUDED.version_vector, deleted_at = EXCLUDED.deleted_at,
+                expired = FALSE
+            "#,
+            winner.nip_05_id,
+            version_vector,
+            winner.updated_at
+        )
+        .execute(&mut *transaction)
+        .await
+        .context("Failed to store replicated tombstone.")?;
+        return Ok(true);
+    }
+    sqlx::query!(
+        r#"DELETE FROM key_tombstones WHERE nip_05_id = $1"#,
+        winner.nip_05_id
+    )
+    .execute(&mut *transaction)
+    .await
+    .context("Failed to remove tombstone of replicated key.")?;
+    let stored = sqlx::query!(
+        r#"
+        INSERT INTO keys (nip_05_id, pin_hash, private_key_hash, created_at, updated_at,
+            version_vector, pubkey, relays, second_factor_enrolled)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+        ON CONFLICT (nip_05_id) DO UPDATE
+        SET pin_hash = EXCLUDED.pin_hash, private_key_hash = EXCLUDED.private_key_hash,
+            updated_at = EXCLUDED.updated_at, version_vector = EXCLUDED.version_vector,
+            pubkey = EXCLUDED.pubkey, relays = EXCLUDED.relays,
+            second_factor_enrolled = EXCLUDED.second_factor_enrolled
+        "#,
+        winner.nip_05_id,
+        winner.pin_hash,
+        winner.private_key_hash,
+        winner.created_at,
+        winner.updated_at,
+        version_vector,
+        winner.pubkey,
+        winner.relays,
+        winner.second_factor_enrolled
+    )
+    .execute(&mut *transaction)
+    .await
+    .context("Failed to store replicated key.")?;
+    Ok(stored.rows_affected() > 0)
+}
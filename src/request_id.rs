@@ -0,0 +1,53 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::HttpMessage;
+use std::future::Future;
+use tracing_actix_web::RequestId;
+
+pub const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// Id of the request being handled, the same one recorded as `request_id` on its root span.
+/// `None` outside of a request, e.g. while rendering the OpenAPI examples.
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID
+        .try_with(|id| id.clone())
+        .ok()
+        .filter(|id| !id.is_empty())
+}
+
+/// Wrapped inside `TracingLogger` with `wrap_fn`, so error bodies built while handling the
+/// request can quote its id. The id is also sent back as `X-Request-Id`.
+pub fn scope_request_id<S, B>(
+    req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = Result<ServiceResponse<B>, actix_web::Error>>
+where
+    S: actix_web::dev::Service<
+        ServiceRequest,
+        Response = ServiceResponse<B>,
+        Error = actix_web::Error,
+    >,
+    B: MessageBody,
+{
+    let request_id = req
+        .extensions()
+        .get::<RequestId>()
+        .map(|id| id.to_string())
+        .unwrap_or_default();
+    // Middleware may answer from `call` itself, e.g. a rejected CORS preflight
+    let response = REQUEST_ID.sync_scope(request_id.clone(), || srv.call(req));
+    async move {
+        let mut response = REQUEST_ID.scope(request_id.clone(), response).await?;
+        if !request_id.is_empty() {
+            if let Ok(value) = HeaderValue::from_str(&request_id) {
+                response.headers_mut().insert(X_REQUEST_ID, value);
+            }
+        }
+        Ok(response)
+    }
+}
@@ -1,3 +1,12 @@
+use crate::request_id::current_request_id;
+use actix_web::dev::Payload;
+use actix_web::error::{JsonPayloadError, QueryPayloadError};
+use actix_web::http::header::{CONTENT_LENGTH, TRANSFER_ENCODING};
+use actix_web::{web, FromRequest, HttpRequest, HttpResponse, ResponseError};
+use futures_util::future::LocalBoxFuture;
+use reqwest::StatusCode;
+use serde::de::DeserializeOwned;
+use std::fmt::Debug;
 use utoipa::ToSchema;
 
 pub fn error_chain_fmt(
@@ -13,8 +22,197 @@ pub fn error_chain_fmt(
     Ok(())
 }
 
+/// Stable reason for a failure. Clients should branch on these, `value` is meant for people
+/// and its wording may change.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ToSchema, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    /// Body or query could not be parsed, or asks for nothing.
+    RequestMalformed,
+    PayloadTooLarge,
+    ContentTypeUnsupported,
+    RouteNotFound,
+    Nip05Malformed,
+    /// Well formed, but not a name this vault hands out or one that is taken.
+    Nip05Unavailable,
+    /// A key is already stored for the nip 05 id.
+    Nip05Taken,
+    PinMalformed,
+    BlobMalformed,
+    PubkeyMalformed,
+    /// The key was stored without a public key, so a change can not be proven.
+    PubkeyMissing,
+    EventMalformed,
+    RelayMalformed,
+    TotpCodeMalformed,
+    RecoveryCodeMalformed,
+    KeyNotFound,
+    PinInvalid,
+    /// Stands in for `KEY_NOT_FOUND` and `PIN_INVALID` while enumeration resistance is on.
+    CredentialsInvalid,
+    RecoveryCodeInvalid,
+    SecondFactorRequired,
+    SecondFactorInvalid,
+    SecondFactorAlreadyEnrolled,
+    /// The key was replicated from the vault its second factor is enrolled on, fetch it there.
+    SecondFactorElsewhere,
+    PasskeyInvalid,
+    /// Signature or challenge does not prove possession of the key.
+    ProofInvalid,
+    /// Ask `/work_challenge` for a challenge and send a `proof_of_work` with the request.
+    ProofOfWorkRequired,
+    /// The proof of work challenge is not valid or the hash has too few leading zero bits.
+    ProofOfWorkInvalid,
+    /// Pay the invoice from `WWW-Authenticate` and retry with `Authorization: L402 ...`.
+    PaymentRequired,
+    /// The L402 macaroon or preimage is not valid, or the payment was already used.
+    PaymentInvalid,
+    /// The session token is malformed, expired, or revoked by a pin change.
+    SessionInvalid,
+    /// The session token was issued for another key or without the needed scope.
+    SessionScopeMissing,
+    /// Neither a pin nor a session token was sent.
+    CredentialsMissing,
+    DomainUnknown,
+    /// The domain of the nip 05 id is not on the allowlist, or is on the denylist.
+    DomainNotAllowed,
+    /// The domain of the nip 05 id already holds as many keys as it may.
+    DomainQuotaExceeded,
+    PeerUnauthorized,
+    IdempotencyKeyMalformed,
+    /// The `Idempotency-Key` was sent before with a different request.
+    IdempotencyKeyReused,
+    /// The first request with this `Idempotency-Key` has not finished yet.
+    RequestInProgress,
+    RateLimited,
+    /// Too many pins are waiting to be hashed, retry after the `Retry-After` seconds.
+    Overloaded,
+    Internal,
+}
+
 #[derive(ToSchema, serde::Serialize, serde::Deserialize)]
 pub struct ErrorResponse {
+    pub code: ErrorCode,
     #[schema(example = "8ehd99 is not a valid pin.")]
     pub value: String,
+    /// Id of the request in the vault's logs and traces, also sent as `X-Request-Id`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = "5b0e3a4e-77a1-4c3a-9c55-0d3c6f0a2f4e")]
+    pub request_id: Option<String>,
+}
+
+impl ErrorResponse {
+    /// Picks up the id of the request being handled, if any.
+    pub fn new(code: ErrorCode, value: impl Into<String>) -> Self {
+        Self {
+            code,
+            value: value.into(),
+            request_id: current_request_id(),
+        }
+    }
+}
+
+/// Requests rejected before they reach a handler, answered in the same shape as handler
+/// errors instead of actix's plain text.
+#[derive(thiserror::Error)]
+pub enum RequestError {
+    #[error("Request body is larger than the {0} bytes allowed.")]
+    TooLarge(usize),
+    #[error("Request body must be sent as application/json.")]
+    UnsupportedContentType,
+    #[error("{0}")]
+    Malformed(String),
+    #[error("There is nothing at this path.")]
+    RouteNotFound,
+}
+
+impl Debug for RequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl RequestError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            RequestError::TooLarge(_) => ErrorCode::PayloadTooLarge,
+            RequestError::UnsupportedContentType => ErrorCode::ContentTypeUnsupported,
+            RequestError::Malformed(_) => ErrorCode::RequestMalformed,
+            RequestError::RouteNotFound => ErrorCode::RouteNotFound,
+        }
+    }
+}
+
+impl From<JsonPayloadError> for RequestError {
+    fn from(e: JsonPayloadError) -> Self {
+        match e {
+            JsonPayloadError::OverflowKnownLength { limit, .. }
+            | JsonPayloadError::Overflow { limit } => RequestError::TooLarge(limit),
+            JsonPayloadError::ContentType => RequestError::UnsupportedContentType,
+            JsonPayloadError::Deserialize(e) => {
+                RequestError::Malformed(format!("Request body is not valid: {}.", e))
+            }
+            e => RequestError::Malformed(format!("Request body could not be read: {}.", e)),
+        }
+    }
+}
+
+impl From<QueryPayloadError> for RequestError {
+    fn from(e: QueryPayloadError) -> Self {
+        RequestError::Malformed(format!("Query is not valid: {}.", e))
+    }
+}
+
+impl ResponseError for RequestError {
+    fn status_code(&self) -> reqwest::StatusCode {
+        match self {
+            RequestError::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            RequestError::UnsupportedContentType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            RequestError::Malformed(_) => StatusCode::BAD_REQUEST,
+            RequestError::RouteNotFound => StatusCode::NOT_FOUND,
+        }
+    }
+    fn error_response(&self) -> actix_web::HttpResponse<actix_web::body::BoxBody> {
+        HttpResponse::build(self.status_code())
+            .json(ErrorResponse::new(self.code(), self.to_string()))
+    }
+}
+
+/// Json body that may be left out entirely, e.g. the credentials of a `DELETE` sent with a
+/// session token. A body that is sent has to parse, unlike with `Option<web::Json<T>>`,
+/// which quietly turns malformed json into `None`.
+pub struct OptionalJson<T>(pub Option<T>);
+
+impl<T: DeserializeOwned + 'static> FromRequest for OptionalJson<T> {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let headers = req.headers();
+        let empty = match headers.get(CONTENT_LENGTH) {
+            Some(length) => length.as_bytes() == b"0",
+            None => !headers.contains_key(TRANSFER_ENCODING),
+        };
+        if empty {
+            return Box::pin(async { Ok(OptionalJson(None)) });
+        }
+        let json = web::Json::<T>::from_request(req, payload);
+        Box::pin(async move { Ok(OptionalJson(Some(json.await?.into_inner()))) })
+    }
+}
+
+/// Json bodies up to `limit` bytes, anything the extractor rejects becomes a `RequestError`.
+pub fn json_config(limit: usize) -> web::JsonConfig {
+    web::JsonConfig::default()
+        .limit(limit)
+        .error_handler(|e, _| RequestError::from(e).into())
+}
+
+pub fn query_config() -> web::QueryConfig {
+    web::QueryConfig::default().error_handler(|e, _| RequestError::from(e).into())
+}
+
+/// Default service of the app, so unknown paths get a json body too.
+pub async fn route_not_found() -> Result<HttpResponse, RequestError> {
+    Err(RequestError::RouteNotFound)
 }
@@ -1,14 +1,21 @@
-use crate::authentication::{get_stored_key, AuthError, StoredKey};
-use crate::domain::{Lookup, Nip05ID, Pin};
+use crate::authentication::{get_stored_key, is_second_factor_enrolled, AuthError, StoredKey};
+use crate::domain::{Lookup, Nip05ID, Pin, TotpCode};
+use crate::hashing::retry_after;
+use crate::idempotency::IdempotencyError;
+use crate::passkeys::{has_passkeys, PasskeyAssertion, Passkeys};
+use crate::proof_of_work::{ProofOfWork, WorkError, WorkProof, WorkRoute};
 use crate::routes::error_chain_fmt;
+use crate::second_factor::SecondFactor;
+use actix_web::http::header::RETRY_AFTER;
 use actix_web::{web, HttpResponse, ResponseError};
 use reqwest::StatusCode;
 use secrecy::Secret;
 use sqlx::PgPool;
 use std::fmt::Debug;
+use std::future::Future;
 use utoipa::ToSchema;
 
-use super::ErrorResponse;
+use super::{ErrorCode, ErrorResponse};
 
 #[derive(ToSchema, serde::Deserialize)]
 pub struct KeyLookup {
@@ -16,18 +23,101 @@ pub struct KeyLookup {
     pub nip_05_id: String,
     #[schema(value_type = u64, example = "401267")]
     pub pin: Secret<u64>,
+    /// Current code from the authenticator app, required once a second factor is enrolled.
+    #[schema(value_type = Option<String>, example = "287082")]
+    pub totp_code: Option<Secret<String>>,
+    /// Assertion for a ceremony from `/passkey/authenticate/start`, accepted in place of `totp_code`.
+    pub passkey: Option<PasskeyAssertion>,
+    /// Required once `/work_challenge` reports a difficulty above 0 for the nip 05 id.
+    pub proof_of_work: Option<WorkProof>,
 }
 
 #[derive(thiserror::Error)]
 pub enum LookupError {
-    #[error("{0}")]
-    ValidationError(String),
+    #[error("{1}")]
+    ValidationError(ErrorCode, String),
     #[error("There is no private key associated with the provided pin and user.")]
     NotFoundError,
     #[error("Pin is not valid for provided user.")]
     InvalidPin,
+    #[error("Recovery code is not valid for provided user.")]
+    InvalidRecoveryCode,
+    #[error("A code from the enrolled authenticator app or a passkey assertion is required.")]
+    SecondFactorRequired,
+    #[error("The second factor of this key is enrolled on another vault, fetch it from there.")]
+    SecondFactorElsewhere,
+    #[error("Code is not valid or was already used.")]
+    InvalidSecondFactor,
+    #[error("Passkey assertion is not valid.")]
+    InvalidPasskey,
+    #[error("{0}")]
+    InvalidProof(String),
+    #[error("{0}")]
+    InvalidSession(String),
+    #[error("Session token does not cover this operation.")]
+    SessionScopeMissing,
+    #[error("{0}")]
+    CredentialsMissing(String),
+    #[error("A second factor is already enrolled for this user.")]
+    SecondFactorAlreadyEnrolled,
+    #[error("Idempotency-Key was already used for a different request.")]
+    IdempotencyKeyReused,
+    #[error("A request with this Idempotency-Key is still being processed.")]
+    RequestInProgress,
+    #[error("A proof of work with {0} leading zero bits is required, ask /work_challenge for a challenge.")]
+    ProofOfWorkRequired(u8),
+    #[error("{0}")]
+    InvalidProofOfWork(String),
+    #[error("Too many pins are being hashed, retry in {0} seconds.")]
+    Overloaded(u64),
     #[error(transparent)]
-    UnexpectedError(#[from] anyhow::Error),
+    UnexpectedError(anyhow::Error),
+}
+
+impl From<anyhow::Error> for LookupError {
+    fn from(e: anyhow::Error) -> Self {
+        match retry_after(&e) {
+            Some(seconds) => LookupError::Overloaded(seconds),
+            None => LookupError::UnexpectedError(e),
+        }
+    }
+}
+
+impl From<AuthError> for LookupError {
+    fn from(e: AuthError) -> Self {
+        match e {
+            AuthError::InvalidPin(_) => LookupError::InvalidPin,
+            AuthError::InvalidRecoveryCode(_) => LookupError::InvalidRecoveryCode,
+            AuthError::SecondFactorRequired => LookupError::SecondFactorRequired,
+            AuthError::SecondFactorElsewhere => LookupError::SecondFactorElsewhere,
+            AuthError::InvalidSecondFactor(_) => LookupError::InvalidSecondFactor,
+            AuthError::InvalidPasskey(_) => LookupError::InvalidPasskey,
+            AuthError::UnexpectedError(e) => e.into(),
+        }
+    }
+}
+
+impl From<WorkError> for LookupError {
+    fn from(e: WorkError) -> Self {
+        match e {
+            WorkError::Required(difficulty) => LookupError::ProofOfWorkRequired(difficulty),
+            WorkError::Invalid(e) => LookupError::InvalidProofOfWork(e),
+            WorkError::UnexpectedError(e) => e.into(),
+        }
+    }
+}
+
+impl From<IdempotencyError> for LookupError {
+    fn from(e: IdempotencyError) -> Self {
+        match e {
+            IdempotencyError::InvalidKey(e) => {
+                LookupError::ValidationError(ErrorCode::IdempotencyKeyMalformed, e)
+            }
+            IdempotencyError::KeyReused => LookupError::IdempotencyKeyReused,
+            IdempotencyError::InProgress => LookupError::RequestInProgress,
+            IdempotencyError::UnexpectedError(e) => e.into(),
+        }
+    }
 }
 
 impl Debug for LookupError {
@@ -36,19 +126,79 @@ impl Debug for LookupError {
     }
 }
 
+impl LookupError {
+    /// For `map_err` on a failed parse, e.g. `LookupError::malformed(ErrorCode::PinMalformed)`.
+    pub fn malformed(code: ErrorCode) -> impl FnOnce(String) -> Self {
+        move |e| LookupError::ValidationError(code, e)
+    }
+
+    /// Wrong credentials for the nip 05 id, as opposed to a malformed request.
+    fn is_failed_attempt(&self) -> bool {
+        matches!(
+            self,
+            LookupError::NotFoundError
+                | LookupError::InvalidPin
+                | LookupError::InvalidRecoveryCode
+                | LookupError::InvalidSecondFactor
+                | LookupError::InvalidPasskey
+        )
+    }
+
+    fn code(&self) -> ErrorCode {
+        match self {
+            LookupError::ValidationError(code, _) => *code,
+            LookupError::NotFoundError => ErrorCode::KeyNotFound,
+            LookupError::InvalidPin => ErrorCode::PinInvalid,
+            LookupError::InvalidRecoveryCode => ErrorCode::RecoveryCodeInvalid,
+            LookupError::SecondFactorRequired => ErrorCode::SecondFactorRequired,
+            LookupError::SecondFactorElsewhere => ErrorCode::SecondFactorElsewhere,
+            LookupError::InvalidSecondFactor => ErrorCode::SecondFactorInvalid,
+            LookupError::InvalidPasskey => ErrorCode::PasskeyInvalid,
+            LookupError::InvalidProof(_) => ErrorCode::ProofInvalid,
+            LookupError::InvalidSession(_) => ErrorCode::SessionInvalid,
+            LookupError::SessionScopeMissing => ErrorCode::SessionScopeMissing,
+            LookupError::CredentialsMissing(_) => ErrorCode::CredentialsMissing,
+            LookupError::SecondFactorAlreadyEnrolled => ErrorCode::SecondFactorAlreadyEnrolled,
+            LookupError::IdempotencyKeyReused => ErrorCode::IdempotencyKeyReused,
+            LookupError::RequestInProgress => ErrorCode::RequestInProgress,
+            LookupError::ProofOfWorkRequired(_) => ErrorCode::ProofOfWorkRequired,
+            LookupError::InvalidProofOfWork(_) => ErrorCode::ProofOfWorkInvalid,
+            LookupError::Overloaded(_) => ErrorCode::Overloaded,
+            LookupError::UnexpectedError(_) => ErrorCode::Internal,
+        }
+    }
+}
+
 impl ResponseError for LookupError {
     fn status_code(&self) -> reqwest::StatusCode {
         match self {
             LookupError::NotFoundError => StatusCode::NOT_FOUND,
             LookupError::InvalidPin => StatusCode::FORBIDDEN,
-            LookupError::ValidationError(_) => StatusCode::BAD_REQUEST,
+            LookupError::InvalidRecoveryCode => StatusCode::FORBIDDEN,
+            LookupError::SecondFactorRequired => StatusCode::UNAUTHORIZED,
+            LookupError::SecondFactorElsewhere => StatusCode::CONFLICT,
+            LookupError::InvalidSecondFactor => StatusCode::FORBIDDEN,
+            LookupError::InvalidPasskey => StatusCode::FORBIDDEN,
+            LookupError::InvalidProof(_) => StatusCode::FORBIDDEN,
+            LookupError::InvalidSession(_) => StatusCode::UNAUTHORIZED,
+            LookupError::SessionScopeMissing => StatusCode::FORBIDDEN,
+            LookupError::CredentialsMissing(_) => StatusCode::UNAUTHORIZED,
+            LookupError::SecondFactorAlreadyEnrolled => StatusCode::CONFLICT,
+            LookupError::IdempotencyKeyReused => StatusCode::UNPROCESSABLE_ENTITY,
+            LookupError::RequestInProgress => StatusCode::CONFLICT,
+            LookupError::ValidationError(..) => StatusCode::BAD_REQUEST,
+            LookupError::ProofOfWorkRequired(_) => StatusCode::PRECONDITION_REQUIRED,
+            LookupError::InvalidProofOfWork(_) => StatusCode::FORBIDDEN,
+            LookupError::Overloaded(_) => StatusCode::SERVICE_UNAVAILABLE,
             LookupError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
     fn error_response(&self) -> actix_web::HttpResponse<actix_web::body::BoxBody> {
-        HttpResponse::build(self.status_code()).json(ErrorResponse {
-            value: self.to_string(),
-        })
+        let mut response = HttpResponse::build(self.status_code());
+        if let LookupError::Overloaded(seconds) = self {
+            response.insert_header((RETRY_AFTER, seconds.to_string()));
+        }
+        response.json(ErrorResponse::new(self.code(), self.to_string()))
     }
 }
 
@@ -64,46 +214,58 @@ impl ResponseError for LookupError {
                     created_at: "2023-02-12T01:49:35+00:00".to_string(),
                     nip_05_id: "the_name_is_bob_bob_smith@frogs.cloud".to_string(),
                     private_key_hash: "$PBKDF2$i=100000,l=256,s=0Bu5lWu4s66/iottrlUGdckjf5nwnpB05jwp4yDh8NU=$AESGM$OrScsD+hHGaRaPbc$XMXVVbjt3JV+QsNb7ZWRc8uNod2YzJL0lSvW1FOiY38ywOu7IEChKs/IqEQ7knhZAmRGYqoB4dhAbdOTwVhYIeQsuf1+f+9ARPEjtURsDg==".to_string(),
+                    recovery_codes: vec![],
                 }),
                 description = "Successfully found pin."
             ),
             (
                 status = FORBIDDEN,
                 body = ErrorResponse,
-                example=json!(ErrorResponse{
-                    value: "Pin is not valid for provided user.".to_string()
-                }),
-                description = "nip 05 id found, but pin does not match"
+                example=json!(ErrorResponse::new(ErrorCode::PinInvalid, "Pin is not valid for provided user.")),
+                description = "nip 05 id found, but pin, second factor code or passkey assertion does not match"
+            ),
+            (
+                status = UNAUTHORIZED,
+                body = ErrorResponse,
+                example=json!(ErrorResponse::new(ErrorCode::SecondFactorRequired, "A code from the enrolled authenticator app or a passkey assertion is required.")),
+                description = "Pin matches, but a second factor is enrolled and neither `totp_code` nor `passkey` was sent"
+            ),
+            (
+                status = CONFLICT,
+                body = ErrorResponse,
+                example=json!(ErrorResponse::new(ErrorCode::SecondFactorElsewhere, "The second factor of this key is enrolled on another vault, fetch it from there.")),
+                description = "Pin matches, but the key's second factor is enrolled on the peer it was replicated from"
             ),
             (
                 status = BAD_REQUEST,
                 body = ErrorResponse,
-                example=json!(ErrorResponse{
-                    value: "8ehd99 is not a valid pin.".to_string()
-                }),
+                example=json!(ErrorResponse::new(ErrorCode::PinMalformed, "8ehd99 is not a valid pin.")),
                 description = "object used to request the private key fails validation"
             ),
             (
                 status = NOT_FOUND,
                 body = ErrorResponse,
-                example=json!(ErrorResponse{
-                    value: "There is no private key associated with the provided pin and user.".to_string()
-                }),
+                example=json!(ErrorResponse::new(ErrorCode::KeyNotFound, "There is no private key associated with the provided pin and user.")),
                 description = "nip_05_id and pin pairing not found"
             ),
+            (
+                status = SERVICE_UNAVAILABLE,
+                body = ErrorResponse,
+                example=json!(ErrorResponse::new(ErrorCode::Overloaded, "Too many pins are being hashed, retry in 1 seconds.")),
+                description = "Too many pins are waiting to be hashed, retry after `Retry-After` seconds."
+            ),
             (
                 status = INTERNAL_SERVER_ERROR,
                 body =  ErrorResponse,
-                example=json!(ErrorResponse{
-                    value: "Unable to connect to db.".to_string()
-                }),
+                example=json!(ErrorResponse::new(ErrorCode::Internal, "Unable to connect to db.")),
                 description = "Something went terribly wrong."
             ),
         ),
         request_body = KeyLookup
 )]
+#[deprecated(note = "use POST /v1/keys/{nip_05_id}/retrieve")]
 #[tracing::instrument(
-    skip(key_lookup, pool),
+    skip(key_lookup, pool, second_factor, passkeys, proof_of_work),
     fields(
         nip_05_id = %key_lookup.nip_05_id,
     )
@@ -111,19 +273,135 @@ impl ResponseError for LookupError {
 pub async fn fetch_key(
     key_lookup: web::Json<KeyLookup>,
     pool: web::Data<PgPool>,
-) -> Result<String, LookupError> {
-    let nip_05_id = Nip05ID::parse(key_lookup.0.nip_05_id).map_err(LookupError::ValidationError)?;
-    let pin = Pin::parse(key_lookup.0.pin).map_err(LookupError::ValidationError)?;
+    second_factor: web::Data<SecondFactor>,
+    passkeys: web::Data<Passkeys>,
+    proof_of_work: web::Data<ProofOfWork>,
+) -> Result<web::Json<StoredKey>, LookupError> {
+    let key_lookup = key_lookup.0;
+    let lookup = parse_lookup(key_lookup.nip_05_id, key_lookup.pin, key_lookup.totp_code)?;
+
+    let key = authenticate_fetch(
+        &lookup,
+        key_lookup.passkey.as_ref(),
+        key_lookup.proof_of_work.as_ref(),
+        &pool,
+        &second_factor,
+        &passkeys,
+        &proof_of_work,
+    )
+    .await?;
 
-    let lookup = &Lookup { nip_05_id, pin };
+    Ok(web::Json(key))
+}
+
+pub(crate) fn parse_lookup(
+    nip_05_id: String,
+    pin: Secret<u64>,
+    totp_code: Option<Secret<String>>,
+) -> Result<Lookup, LookupError> {
+    let nip_05_id =
+        Nip05ID::parse(nip_05_id).map_err(LookupError::malformed(ErrorCode::Nip05Malformed))?;
+    let pin = Pin::parse(pin).map_err(LookupError::malformed(ErrorCode::PinMalformed))?;
+    let totp_code = totp_code
+        .map(TotpCode::parse)
+        .transpose()
+        .map_err(LookupError::malformed(ErrorCode::TotpCodeMalformed))?;
+    Ok(Lookup {
+        nip_05_id,
+        pin,
+        totp_code,
+    })
+}
 
-    let key = get_stored_key(lookup, &pool).await.map_err(|e| match e {
-        AuthError::InvalidPin(_) => LookupError::InvalidPin,
-        AuthError::UnexpectedError(e) => LookupError::UnexpectedError(e),
-    })?;
+/// `authenticate` behind the fetch proof of work, every failed attempt raises the difficulty
+/// for the nip 05 id.
+pub(crate) async fn authenticate_fetch(
+    lookup: &Lookup,
+    passkey: Option<&PasskeyAssertion>,
+    work: Option<&WorkProof>,
+    pool: &PgPool,
+    second_factor: &SecondFactor,
+    passkeys: &Passkeys,
+    proof_of_work: &ProofOfWork,
+) -> Result<StoredKey, LookupError> {
+    behind_proof_of_work(
+        &lookup.nip_05_id,
+        work,
+        pool,
+        proof_of_work,
+        authenticate(lookup, passkey, pool, second_factor, passkeys),
+    )
+    .await
+}
 
-    match key {
-        Some(val) => Ok(val.to_string()),
-        None => Err(LookupError::NotFoundError),
+/// Runs `attempt` once the fetch proof of work for `nip_05_id` checks out and records it as
+/// a failure if it was refused. Every route taking a pin goes through here, so guesses on
+/// any of them raise the difficulty for all of them.
+pub(crate) async fn behind_proof_of_work<T>(
+    nip_05_id: &Nip05ID,
+    work: Option<&WorkProof>,
+    pool: &PgPool,
+    proof_of_work: &ProofOfWork,
+    attempt: impl Future<Output = Result<T, LookupError>>,
+) -> Result<T, LookupError> {
+    proof_of_work
+        .check(WorkRoute::Fetch, nip_05_id, work, pool)
+        .await?;
+    let result = attempt.await;
+    if let Err(e) = &result {
+        if e.is_failed_attempt() {
+            proof_of_work.record_failure(nip_05_id, pool).await?;
+        }
+    }
+    result
+}
+
+/// Checks the pin, then the second factor.
+async fn authenticate(
+    lookup: &Lookup,
+    passkey: Option<&PasskeyAssertion>,
+    pool: &PgPool,
+    second_factor: &SecondFactor,
+    passkeys: &Passkeys,
+) -> Result<StoredKey, LookupError> {
+    let key = get_stored_key(lookup, pool)
+        .await?
+        .ok_or(LookupError::NotFoundError)?;
+    verify_second_factor(
+        key.id,
+        lookup.totp_code.as_ref(),
+        passkey,
+        pool,
+        second_factor,
+        passkeys,
+    )
+    .await?;
+    Ok(key)
+}
+
+/// Checks a passkey assertion if one is sent, otherwise the TOTP code. Keys with passkeys
+/// but no TOTP factor must always present an assertion, and keys replicated from a vault
+/// that holds their second factor are refused.
+pub(crate) async fn verify_second_factor(
+    key_id: i64,
+    totp_code: Option<&TotpCode>,
+    passkey: Option<&PasskeyAssertion>,
+    pool: &PgPool,
+    second_factor: &SecondFactor,
+    passkeys: &Passkeys,
+) -> Result<(), AuthError> {
+    if let Some(assertion) = passkey {
+        passkeys.verify(key_id, assertion, pool).await?;
+        return Ok(());
+    }
+    if second_factor.verify(key_id, totp_code, pool).await? {
+        return Ok(());
+    }
+    if has_passkeys(key_id, pool).await? {
+        return Err(AuthError::SecondFactorRequired);
+    }
+    if is_second_factor_enrolled(key_id, pool).await? {
+        return Err(AuthError::SecondFactorElsewhere);
     }
+    Ok(())
 }
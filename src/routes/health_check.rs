@@ -1,13 +1,31 @@
-use actix_web::{HttpResponse, Responder};
+use crate::health::{CheckStatus, Readiness, ReadinessReport};
+use actix_web::{web, HttpResponse, Responder};
+use sqlx::PgPool;
 
 #[utoipa::path(
     get,
-    path = "/health_check",
+    path = "/livez",
     responses(
-        (status = OK, description = "Server is up."),
-        (status = INTERNAL_SERVER_ERROR, description = "Something went terribly wrong."),
+        (status = OK, description = "The process is up and serving requests."),
     ),
 )]
-pub async fn health_check() -> impl Responder {
+pub async fn livez() -> impl Responder {
     HttpResponse::Ok().finish()
 }
+
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    responses(
+        (status = OK, body = ReadinessReport, description = "Every dependency check passed."),
+        (status = SERVICE_UNAVAILABLE, body = ReadinessReport,
+            description = "At least one check failed, see `checks` for which."),
+    ),
+)]
+pub async fn readyz(pool: web::Data<PgPool>, readiness: web::Data<Readiness>) -> HttpResponse {
+    let report = readiness.check(&pool).await;
+    match report.status {
+        CheckStatus::Ok => HttpResponse::Ok().json(report),
+        CheckStatus::Failing => HttpResponse::ServiceUnavailable().json(report),
+    }
+}
@@ -0,0 +1,551 @@
+use crate::authentication::{
+    delete_stored_key, get_key_slots, get_stored_pubkey, update_stored_key, KeyChanges, KeySlots,
+    StoredKey,
+};
+use crate::challenge::{consume_challenge, ChallengePurpose};
+use crate::domain::{AppDataEvent, KeyPossessionProof, NostrEvent, Pin, PrivateKeyHash};
+use crate::domain_policy::DomainPolicy;
+use crate::idempotency::{Idempotency, RequestFingerprint};
+use crate::nip05_provider::Nip05Provider;
+use crate::passkeys::{PasskeyAssertion, Passkeys};
+use crate::payments::Payments;
+use crate::proof_of_work::{ProofOfWork, WorkProof};
+use crate::relay_publisher::RelayPublisher;
+use crate::replication::Replicator;
+use crate::second_factor::SecondFactor;
+use crate::session::SessionScope;
+use crate::startup::ApplicationBaseUrl;
+use actix_web::http::header::LOCATION;
+use actix_web::{web, HttpRequest, HttpResponse};
+use secrecy::{ExposeSecret, Secret};
+use sqlx::PgPool;
+use utoipa::ToSchema;
+
+use super::{
+    authenticate_fetch, parse_lookup, store_new_key, BearerSession, ErrorCode, ErrorResponse,
+    LookupError, NewKey, OptionalJson, Session, UploadError,
+};
+
+/// Proves the caller may act on the key in the path, same rules as for `/fetch_key`.
+#[derive(ToSchema, serde::Deserialize)]
+pub struct KeyCredentials {
+    #[schema(value_type = u64, example = "401267")]
+    pub pin: Secret<u64>,
+    #[schema(value_type = Option<String>, example = "287082")]
+    pub totp_code: Option<Secret<String>>,
+    pub passkey: Option<PasskeyAssertion>,
+    /// Required once `/work_challenge` reports a `fetch` difficulty above 0 for the nip 05 id.
+    /// Not needed with a session token.
+    pub proof_of_work: Option<WorkProof>,
+}
+
+#[derive(ToSchema, serde::Deserialize)]
+pub struct KeyUpdate {
+    /// Not needed with a session token that has the `update` scope.
+    #[schema(value_type = Option<u64>, example = "401267")]
+    pub pin: Option<Secret<u64>>,
+    #[schema(value_type = Option<String>, example = "287082")]
+    pub totp_code: Option<Secret<String>>,
+    pub passkey: Option<PasskeyAssertion>,
+    /// Same as for `retrieve`, not needed with a session token.
+    pub proof_of_work: Option<WorkProof>,
+    #[schema(value_type = Option<u64>, example = "829134")]
+    pub new_pin: Option<Secret<u64>>,
+    /// Replacement blob, e.g. the same key encrypted under a new password. It needs a
+    /// `challenge` and `signature` from the public key the key was uploaded with.
+    #[schema(value_type = Option<String>)]
+    pub private_key_hash: Option<Secret<String>>,
+    pub challenge: Option<String>,
+    pub signature: Option<String>,
+    /// Optional kind 30078 event for the new `private_key_hash`, signed by the key's public
+    /// key. It is forwarded to the configured relays like the one sent with an upload.
+    pub nostr_event: Option<NostrEvent>,
+}
+
+impl KeyUpdate {
+    fn fingerprint(&self, nip_05_id: &str, session: Option<&Session>) -> RequestFingerprint {
+        let fingerprint = RequestFingerprint::new("update_key", nip_05_id);
+        // Something only the caller knows, it also keys the stored response
+        let fingerprint = match (session, &self.pin) {
+            (Some(session), _) => fingerprint.field(session.token()),
+            (None, Some(pin)) => fingerprint.field(pin.expose_secret().to_be_bytes()),
+            (None, None) => fingerprint.field(b""),
+        };
+        fingerprint
+            .optional_field(self.totp_code.as_ref().map(|code| code.expose_secret()))
+            .optional_field(
+                self.passkey
+                    .as_ref()
+                    .map(|passkey| serde_json::to_vec(passkey).unwrap_or_default()),
+            )
+            .optional_field(
+                self.new_pin
+                    .as_ref()
+                    .map(|pin| pin.expose_secret().to_be_bytes()),
+            )
+            .optional_field(
+                self.private_key_hash
+                    .as_ref()
+                    .map(|hash| hash.expose_secret()),
+            )
+            .optional_field(self.challenge.as_ref())
+            .optional_field(self.signature.as_ref())
+            .optional_field(
+                self.nostr_event
+                    .as_ref()
+                    .map(|event| serde_json::to_vec(event).unwrap_or_default()),
+            )
+    }
+}
+
+fn key_url(base_url: &ApplicationBaseUrl, nip_05_id: &str) -> String {
+    format!("{}/v1/keys/{}", base_url.0.trim_end_matches('/'), nip_05_id)
+}
+
+/// Id of the key in the path, proven by a session token with `scope` if one was sent and by
+/// the pin and second factor otherwise, behind the same proof of work as `retrieve`.
+#[allow(clippy::too_many_arguments)]
+async fn authorize(
+    nip_05_id: String,
+    scope: SessionScope,
+    session: Option<Session>,
+    credentials: Option<KeyCredentials>,
+    pool: &PgPool,
+    second_factor: &SecondFactor,
+    passkeys: &Passkeys,
+    proof_of_work: &ProofOfWork,
+) -> Result<i64, LookupError> {
+    if let Some(session) = session {
+        return session.authorize(&nip_05_id, scope);
+    }
+    let credentials = credentials.ok_or_else(|| {
+        LookupError::CredentialsMissing(
+            "Send the pin, or a session token as `Authorization: Bearer`.".to_string(),
+        )
+    })?;
+    let lookup = parse_lookup(nip_05_id, credentials.pin, credentials.totp_code)?;
+    let key = authenticate_fetch(
+        &lookup,
+        credentials.passkey.as_ref(),
+        credentials.proof_of_work.as_ref(),
+        pool,
+        second_factor,
+        passkeys,
+        proof_of_work,
+    )
+    .await?;
+    Ok(key.id)
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/keys",
+    responses(
+        (status = CREATED,
+            body = StoredKey,
+            headers(("Location" = String, description = "Url of the new key resource.")),
+            description = "Successfully stored key, `recovery_codes` are not shown again."),
+        (status = BAD_REQUEST, body = ErrorResponse,
+            description = "Object used to upload the private key fails validation."),
+        (status = PAYMENT_REQUIRED, body = ErrorResponse,
+            headers(("WWW-Authenticate" = String, description = "`L402 macaroon=\"...\", invoice=\"...\"`")),
+            description = "Payments are on and no `Authorization: L402` was sent, pay the invoice and retry."),
+        (status = FORBIDDEN, body = ErrorResponse,
+            description = "Signature or challenge does not prove possession of the key, the L402 payment is not valid, or the domain policy refuses the nip 05 id."),
+        (status = CONFLICT, body = ErrorResponse,
+            description = "A key is already stored for the nip 05 id, or a request with the same `Idempotency-Key` is still running."),
+        (status = UNPROCESSABLE_ENTITY, body = ErrorResponse,
+            description = "`Idempotency-Key` was sent before with a different body."),
+        (status = SERVICE_UNAVAILABLE, body = ErrorResponse,
+            description = "Too many pins are waiting to be hashed, retry after `Retry-After` seconds."),
+        (status = INTERNAL_SERVER_ERROR, body = ErrorResponse,
+            description = "Something went terribly wrong."),
+    ),
+    params(
+        ("Idempotency-Key" = Option<String>, Header,
+            description = "Retries with the same key and body get the original response back.")
+    ),
+    request_body = NewKey
+)]
+#[tracing::instrument(
+    skip(
+        request,
+        new_key,
+        pool,
+        replicator,
+        relay_publisher,
+        nip05_provider,
+        base_url,
+        idempotency,
+        proof_of_work,
+        payments,
+        domain_policy
+    ),
+    fields(
+        nip_05_id = %new_key.nip_05_id,
+    )
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn create_key(
+    request: HttpRequest,
+    new_key: web::Json<NewKey>,
+    pool: web::Data<PgPool>,
+    replicator: web::Data<Replicator>,
+    relay_publisher: web::Data<RelayPublisher>,
+    nip05_provider: web::Data<Nip05Provider>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    idempotency: web::Data<Idempotency>,
+    proof_of_work: web::Data<ProofOfWork>,
+    payments: web::Data<Payments>,
+    domain_policy: web::Data<DomainPolicy>,
+) -> Result<HttpResponse, UploadError> {
+    let new_key = new_key.0;
+    let stored_key = idempotency
+        .run(&request, new_key.fingerprint(), &pool, || {
+            store_new_key(
+                &request,
+                new_key,
+                &pool,
+                &replicator,
+                &relay_publisher,
+                &nip05_provider,
+                &proof_of_work,
+                &payments,
+                &domain_policy,
+            )
+        })
+        .await?;
+    Ok(HttpResponse::Created()
+        .insert_header((LOCATION, key_url(&base_url, &stored_key.nip_05_id)))
+        .json(stored_key))
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/keys/{nip_05_id}/retrieve",
+    params(("nip_05_id" = String, Path, example = "the_name_is_bob_bob_smith@frogs.cloud")),
+    responses(
+        (status = OK, body = StoredKey, description = "Successfully found pin."),
+        (status = UNAUTHORIZED, body = ErrorResponse,
+            description = "Pin matches, but a second factor is enrolled and neither `totp_code` nor `passkey` was sent"),
+        (status = FORBIDDEN, body = ErrorResponse,
+            description = "nip 05 id found, but pin, second factor code or passkey assertion does not match"),
+        (status = BAD_REQUEST, body = ErrorResponse,
+            description = "object used to request the private key fails validation"),
+        (status = NOT_FOUND, body = ErrorResponse, description = "nip_05_id and pin pairing not found"),
+        (status = CONFLICT, body = ErrorResponse,
+            description = "Pin matches, but the key's second factor is enrolled on the peer it was replicated from"),
+        (status = SERVICE_UNAVAILABLE, body = ErrorResponse,
+            description = "Too many pins are waiting to be hashed, retry after `Retry-After` seconds."),
+        (status = INTERNAL_SERVER_ERROR, body = ErrorResponse,
+            description = "Something went terribly wrong."),
+    ),
+    request_body = KeyCredentials
+)]
+#[tracing::instrument(
+    skip(credentials, pool, second_factor, passkeys, proof_of_work),
+    fields(nip_05_id = %nip_05_id)
+)]
+pub async fn retrieve_key(
+    nip_05_id: web::Path<String>,
+    credentials: web::Json<KeyCredentials>,
+    pool: web::Data<PgPool>,
+    second_factor: web::Data<SecondFactor>,
+    passkeys: web::Data<Passkeys>,
+    proof_of_work: web::Data<ProofOfWork>,
+) -> Result<web::Json<StoredKey>, LookupError> {
+    let credentials = credentials.0;
+    let lookup = parse_lookup(
+        nip_05_id.into_inner(),
+        credentials.pin,
+        credentials.totp_code,
+    )?;
+    let key = authenticate_fetch(
+        &lookup,
+        credentials.passkey.as_ref(),
+        credentials.proof_of_work.as_ref(),
+        &pool,
+        &second_factor,
+        &passkeys,
+        &proof_of_work,
+    )
+    .await?;
+    Ok(web::Json(key))
+}
+
+#[utoipa::path(
+    put,
+    path = "/v1/keys/{nip_05_id}",
+    responses(
+        (status = OK, body = StoredKey, description = "Key updated and replicated to peers."),
+        (status = UNAUTHORIZED, body = ErrorResponse,
+            description = "Pin matches, but a second factor is enrolled and neither `totp_code` nor `passkey` was sent, or the session token is not valid"),
+        (status = FORBIDDEN, body = ErrorResponse,
+            description = "Credentials do not match, the session token lacks the `update` scope, or the new blob's signature or challenge is not valid"),
+        (status = BAD_REQUEST, body = ErrorResponse,
+            description = "Nothing to update, or the update fails validation"),
+        (status = NOT_FOUND, body = ErrorResponse, description = "nip_05_id and pin pairing not found"),
+        (status = CONFLICT, body = ErrorResponse,
+            description = "A request with the same `Idempotency-Key` is still running."),
+        (status = UNPROCESSABLE_ENTITY, body = ErrorResponse,
+            description = "`Idempotency-Key` was sent before with a different body."),
+        (status = SERVICE_UNAVAILABLE, body = ErrorResponse,
+            description = "Too many pins are waiting to be hashed, retry after `Retry-After` seconds."),
+        (status = INTERNAL_SERVER_ERROR, body = ErrorResponse,
+            description = "Something went terribly wrong."),
+    ),
+    params(
+        ("nip_05_id" = String, Path, example = "the_name_is_bob_bob_smith@frogs.cloud"),
+        ("Idempotency-Key" = Option<String>, Header,
+            description = "Retries with the same key and body get the original response back."),
+        ("Authorization" = Option<String>, Header,
+            description = "`Bearer` session token with the `update` scope, in place of the pin.")
+    ),
+    request_body = KeyUpdate
+)]
+#[tracing::instrument(
+    skip(
+        request,
+        session,
+        key_update,
+        pool,
+        second_factor,
+        passkeys,
+        proof_of_work,
+        replicator,
+        relay_publisher,
+        idempotency
+    ),
+    fields(nip_05_id = %nip_05_id)
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn update_key(
+    request: HttpRequest,
+    nip_05_id: web::Path<String>,
+    session: BearerSession,
+    key_update: web::Json<KeyUpdate>,
+    pool: web::Data<PgPool>,
+    second_factor: web::Data<SecondFactor>,
+    passkeys: web::Data<Passkeys>,
+    proof_of_work: web::Data<ProofOfWork>,
+    replicator: web::Data<Replicator>,
+    relay_publisher: web::Data<RelayPublisher>,
+    idempotency: web::Data<Idempotency>,
+) -> Result<web::Json<StoredKey>, LookupError> {
+    let nip_05_id = nip_05_id.into_inner();
+    let key_update = key_update.0;
+    let session = session.0;
+    let fingerprint = key_update.fingerprint(&nip_05_id, session.as_ref());
+    let key = idempotency
+        .run(&request, fingerprint, &pool, || {
+            apply_key_update(
+                nip_05_id,
+                session,
+                key_update,
+                &pool,
+                &second_factor,
+                &passkeys,
+                &proof_of_work,
+                &replicator,
+                &relay_publisher,
+            )
+        })
+        .await?;
+    Ok(web::Json(key))
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn apply_key_update(
+    nip_05_id: String,
+    session: Option<Session>,
+    key_update: KeyUpdate,
+    pool: &PgPool,
+    second_factor: &SecondFactor,
+    passkeys: &Passkeys,
+    proof_of_work: &ProofOfWork,
+    replicator: &Replicator,
+    relay_publisher: &RelayPublisher,
+) -> Result<StoredKey, LookupError> {
+    if key_update.new_pin.is_none() && key_update.private_key_hash.is_none() {
+        return Err(LookupError::ValidationError(
+            ErrorCode::RequestMalformed,
+            "Send a new_pin and/or a private_key_hash to update.".to_string(),
+        ));
+    }
+    if key_update.nostr_event.is_some() && key_update.private_key_hash.is_none() {
+        return Err(LookupError::ValidationError(
+            ErrorCode::EventMalformed,
+            "An app data event can only be sent with a new private_key_hash.".to_string(),
+        ));
+    }
+    let new_pin = key_update
+        .new_pin
+        .map(Pin::parse)
+        .transpose()
+        .map_err(LookupError::malformed(ErrorCode::PinMalformed))?;
+    let private_key_hash = key_update
+        .private_key_hash
+        .map(PrivateKeyHash::parse)
+        .transpose()
+        .map_err(LookupError::malformed(ErrorCode::BlobMalformed))?;
+    let app_data_event = match (key_update.nostr_event, &private_key_hash) {
+        (Some(event), Some(private_key_hash)) => Some(
+            AppDataEvent::parse(event, private_key_hash)
+                .map_err(LookupError::malformed(ErrorCode::EventMalformed))?,
+        ),
+        _ => None,
+    };
+    let credentials = key_update.pin.map(|pin| KeyCredentials {
+        pin,
+        totp_code: key_update.totp_code,
+        passkey: key_update.passkey,
+        proof_of_work: key_update.proof_of_work,
+    });
+    let key_id = authorize(
+        nip_05_id,
+        SessionScope::Update,
+        session,
+        credentials,
+        pool,
+        second_factor,
+        passkeys,
+        proof_of_work,
+    )
+    .await?;
+
+    if let Some(private_key_hash) = &private_key_hash {
+        let pubkey = get_stored_pubkey(key_id, pool).await?.ok_or_else(|| {
+            LookupError::ValidationError(
+                ErrorCode::PubkeyMissing,
+                "There is no public key on record for this key, upload it again instead."
+                    .to_string(),
+            )
+        })?;
+        let proof = KeyPossessionProof::parse(
+            pubkey,
+            key_update.challenge.unwrap_or_default(),
+            key_update.signature.unwrap_or_default(),
+            private_key_hash,
+        )
+        .map_err(LookupError::InvalidProof)?;
+        if let Some(event) = &app_data_event {
+            if event.as_ref().pubkey != proof.pubkey().to_string() {
+                return Err(LookupError::ValidationError(
+                    ErrorCode::EventMalformed,
+                    "App data event is not signed by the key's public key.".to_string(),
+                ));
+            }
+        }
+        if !consume_challenge(proof.challenge(), ChallengePurpose::Upload, pool).await? {
+            return Err(LookupError::InvalidProof(
+                "Challenge is unknown, expired or already used.".to_string(),
+            ));
+        }
+    }
+
+    let changes = KeyChanges {
+        pin: new_pin,
+        private_key_hash,
+    };
+    let key = update_stored_key(key_id, changes, replicator.node_id(), pool).await?;
+    replicator.replicate(key.nip_05_id.clone(), pool.clone());
+    if let Some(event) = app_data_event {
+        relay_publisher.publish(event);
+    }
+    Ok(key)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/v1/keys/{nip_05_id}",
+    params(
+        ("nip_05_id" = String, Path, example = "the_name_is_bob_bob_smith@frogs.cloud"),
+        ("Authorization" = Option<String>, Header,
+            description = "`Bearer` session token with the `delete` scope, in place of the body.")
+    ),
+    responses(
+        (status = NO_CONTENT, description = "Key, recovery codes and second factors removed, the deletion is replicated to peers."),
+        (status = UNAUTHORIZED, body = ErrorResponse,
+            description = "Pin matches, but a second factor is enrolled and neither `totp_code` nor `passkey` was sent, the session token is not valid, or neither was sent"),
+        (status = FORBIDDEN, body = ErrorResponse,
+            description = "nip 05 id found, but pin, second factor code or passkey assertion does not match, or the session token lacks the `delete` scope"),
+        (status = BAD_REQUEST, body = ErrorResponse,
+            description = "A body was sent, but it is not valid json credentials"),
+        (status = NOT_FOUND, body = ErrorResponse, description = "nip_05_id and pin pairing not found"),
+        (status = SERVICE_UNAVAILABLE, body = ErrorResponse,
+            description = "Too many pins are waiting to be hashed, retry after `Retry-After` seconds."),
+        (status = INTERNAL_SERVER_ERROR, body = ErrorResponse,
+            description = "Something went terribly wrong."),
+    ),
+    request_body = KeyCredentials
+)]
+#[tracing::instrument(
+    skip(session, credentials, pool, second_factor, passkeys, proof_of_work, replicator),
+    fields(nip_05_id = %nip_05_id)
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn delete_key(
+    nip_05_id: web::Path<String>,
+    session: BearerSession,
+    credentials: OptionalJson<KeyCredentials>,
+    pool: web::Data<PgPool>,
+    second_factor: web::Data<SecondFactor>,
+    passkeys: web::Data<Passkeys>,
+    proof_of_work: web::Data<ProofOfWork>,
+    replicator: web::Data<Replicator>,
+) -> Result<HttpResponse, LookupError> {
+    let key_id = authorize(
+        nip_05_id.into_inner(),
+        SessionScope::Delete,
+        session.0,
+        credentials.0,
+        &pool,
+        &second_factor,
+        &passkeys,
+        &proof_of_work,
+    )
+    .await?;
+    if let Some(nip_05_id) = delete_stored_key(key_id, replicator.node_id(), &pool).await? {
+        replicator.replicate(nip_05_id, pool.get_ref().clone());
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/keys/{nip_05_id}/slots",
+    params(
+        ("nip_05_id" = String, Path, example = "the_name_is_bob_bob_smith@frogs.cloud"),
+        ("Authorization" = String, Header,
+            description = "`Bearer` session token with the `list_slots` scope.")
+    ),
+    responses(
+        (status = OK, body = KeySlots,
+            description = "Recovery codes left and second factors enrolled for the key, no secrets."),
+        (status = UNAUTHORIZED, body = ErrorResponse,
+            description = "No session token was sent, or it is not valid"),
+        (status = FORBIDDEN, body = ErrorResponse,
+            description = "The session token is for another nip 05 id or lacks the `list_slots` scope"),
+        (status = NOT_FOUND, body = ErrorResponse, description = "The key was deleted since the token was issued"),
+        (status = INTERNAL_SERVER_ERROR, body = ErrorResponse,
+            description = "Something went terribly wrong."),
+    ),
+)]
+#[tracing::instrument(skip(session, pool), fields(nip_05_id = %nip_05_id))]
+pub async fn list_key_slots(
+    nip_05_id: web::Path<String>,
+    session: BearerSession,
+    pool: web::Data<PgPool>,
+) -> Result<web::Json<KeySlots>, LookupError> {
+    // There is no body to send a pin in
+    let session = session.0.ok_or_else(|| {
+        LookupError::CredentialsMissing(
+            "Send a session token as `Authorization: Bearer`.".to_string(),
+        )
+    })?;
+    let key_id = session.authorize(&nip_05_id, SessionScope::ListSlots)?;
+    let slots = get_key_slots(key_id, &pool)
+        .await?
+        .ok_or(LookupError::NotFoundError)?;
+    Ok(web::Json(slots))
+}
@@ -0,0 +1,23 @@
+use actix_web::{web, HttpResponse};
+use sqlx::PgPool;
+
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    responses(
+        (status = OK, body = String, content_type = "text/plain",
+            description = "Metrics in the Prometheus text format, only served here when `metrics.port` is not set."),
+        (status = INTERNAL_SERVER_ERROR, description = "Something went terribly wrong."),
+    ),
+)]
+pub async fn metrics(pool: web::Data<PgPool>) -> HttpResponse {
+    match crate::metrics::render(&pool).await {
+        Ok(body) => HttpResponse::Ok()
+            .content_type("text/plain; version=0.0.4")
+            .body(body),
+        Err(e) => {
+            tracing::error!("Failed to render metrics: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
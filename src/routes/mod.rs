@@ -1,9 +1,29 @@
 mod error_fmt;
 mod fetch_key;
 mod health_check;
+mod keys;
+mod metrics;
+mod nip05_provider;
+mod passkeys;
+mod recover_key;
+mod replication;
+mod second_factor;
+mod sessions;
+mod upload_challenge;
 mod upload_key;
+mod work_challenge;
 
 pub use error_fmt::*;
 pub use fetch_key::*;
 pub use health_check::*;
+pub use keys::*;
+pub use metrics::*;
+pub use nip05_provider::*;
+pub use passkeys::*;
+pub use recover_key::*;
+pub use replication::*;
+pub use second_factor::*;
+pub use sessions::*;
+pub use upload_challenge::*;
 pub use upload_key::*;
+pub use work_challenge::*;
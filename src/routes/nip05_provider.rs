@@ -0,0 +1,174 @@
+use crate::domain::normalize_domain;
+use crate::nip05_provider::{
+    check_availability, get_nostr_json, NameAvailability, Nip05Provider, NostrJson,
+};
+use crate::routes::error_chain_fmt;
+use actix_web::http::header::{ACCESS_CONTROL_ALLOW_ORIGIN, HOST};
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use reqwest::StatusCode;
+use sqlx::PgPool;
+use std::fmt::Debug;
+use utoipa::IntoParams;
+
+use super::{ErrorCode, ErrorResponse};
+
+#[derive(IntoParams, serde::Deserialize)]
+#[into_params(parameter_in = Query)]
+pub struct NostrJsonQuery {
+    /// Local part of the identifier, `_` for the domain itself.
+    pub name: Option<String>,
+}
+
+#[derive(IntoParams, serde::Deserialize)]
+#[into_params(parameter_in = Query)]
+pub struct AvailabilityQuery {
+    pub name: String,
+    /// Defaults to the first configured provider domain.
+    pub domain: Option<String>,
+}
+
+#[derive(thiserror::Error)]
+pub enum Nip05ProviderError {
+    #[error("This vault does not provide nip 05 ids for {0}.")]
+    UnknownDomain(String),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl Debug for Nip05ProviderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl Nip05ProviderError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            Nip05ProviderError::UnknownDomain(_) => ErrorCode::DomainUnknown,
+            Nip05ProviderError::UnexpectedError(_) => ErrorCode::Internal,
+        }
+    }
+}
+
+impl ResponseError for Nip05ProviderError {
+    fn status_code(&self) -> reqwest::StatusCode {
+        match self {
+            Nip05ProviderError::UnknownDomain(_) => StatusCode::NOT_FOUND,
+            Nip05ProviderError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+    fn error_response(&self) -> actix_web::HttpResponse<actix_web::body::BoxBody> {
+        HttpResponse::build(self.status_code())
+            .insert_header((ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
+            .json(ErrorResponse::new(self.code(), self.to_string()))
+    }
+}
+
+/// The domain a request was addressed to, without any port. Forwarded headers are only
+/// believed when they come from one of the provider's trusted proxies.
+fn requested_domain(request: &HttpRequest, provider: &Nip05Provider) -> String {
+    let host = match request.peer_addr() {
+        Some(peer) if provider.trusts_proxy(peer.ip()) => {
+            request.connection_info().host().to_string()
+        }
+        _ => request
+            .headers()
+            .get(HOST)
+            .and_then(|host| host.to_str().ok())
+            .or_else(|| {
+                request
+                    .uri()
+                    .authority()
+                    .map(|authority| authority.as_str())
+            })
+            .unwrap_or_default()
+            .to_string(),
+    };
+    let host = host.as_str();
+    let domain = match host.rsplit_once(':') {
+        Some((domain, port)) if port.chars().all(|c| c.is_ascii_digit()) => domain,
+        _ => host,
+    };
+    domain.to_lowercase()
+}
+
+#[utoipa::path(
+    get,
+    path = "/.well-known/nostr.json",
+    params(NostrJsonQuery),
+    responses(
+        (status = OK, body = NostrJson, description = "Public key, and relays if any, registered for the name."),
+        (
+            status = NOT_FOUND,
+            body = ErrorResponse,
+            example=json!(ErrorResponse::new(ErrorCode::DomainUnknown, "This vault does not provide nip 05 ids for frogs.cloud.")),
+            description = "Requested host is not one of the provider domains."
+        ),
+        (
+            status = INTERNAL_SERVER_ERROR,
+            body = ErrorResponse,
+            example=json!(ErrorResponse::new(ErrorCode::Internal, "Unable to connect to db.")),
+            description = "Something went terribly wrong."
+        ),
+    ),
+)]
+#[tracing::instrument(skip(request, query, pool, provider))]
+pub async fn nostr_json(
+    request: HttpRequest,
+    query: web::Query<NostrJsonQuery>,
+    pool: web::Data<PgPool>,
+    provider: web::Data<Nip05Provider>,
+) -> Result<HttpResponse, Nip05ProviderError> {
+    let domain = requested_domain(&request, &provider);
+    if !provider.serves(&domain) {
+        return Err(Nip05ProviderError::UnknownDomain(domain));
+    }
+    let nostr_json = match query.0.name {
+        Some(name) => get_nostr_json(&name, &domain, &pool).await?,
+        None => NostrJson {
+            names: Default::default(),
+            relays: Default::default(),
+        },
+    };
+    // NIP-05 requires the document to be readable from any web client.
+    Ok(HttpResponse::Ok()
+        .insert_header((ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
+        .json(nostr_json))
+}
+
+#[utoipa::path(
+    get,
+    path = "/nip05/availability",
+    params(AvailabilityQuery),
+    responses(
+        (status = OK, body = NameAvailability, description = "Whether the name can still be registered."),
+        (
+            status = NOT_FOUND,
+            body = ErrorResponse,
+            example=json!(ErrorResponse::new(ErrorCode::DomainUnknown, "This vault does not provide nip 05 ids for frogs.cloud.")),
+            description = "Domain is not one of the provider domains."
+        ),
+        (
+            status = INTERNAL_SERVER_ERROR,
+            body = ErrorResponse,
+            example=json!(ErrorResponse::new(ErrorCode::Internal, "Unable to connect to db.")),
+            description = "Something went terribly wrong."
+        ),
+    ),
+)]
+#[tracing::instrument(skip(query, pool, provider))]
+pub async fn nip05_availability(
+    query: web::Query<AvailabilityQuery>,
+    pool: web::Data<PgPool>,
+    provider: web::Data<Nip05Provider>,
+) -> Result<HttpResponse, Nip05ProviderError> {
+    let domain = match query.0.domain {
+        Some(domain) => normalize_domain(&domain).unwrap_or_else(|| domain.to_lowercase()),
+        None => provider.default_domain().unwrap_or_default().to_string(),
+    };
+    if !provider.serves(&domain) {
+        return Err(Nip05ProviderError::UnknownDomain(domain));
+    }
+    let availability = check_availability(&query.0.name, &domain, &provider, &pool).await?;
+    Ok(HttpResponse::Ok().json(availability))
+}
@@ -0,0 +1,255 @@
+use crate::authentication::{
+    get_key_by_nip_05_id, mark_second_factor_enrolled, record_access, StoredKey,
+};
+use crate::domain::{Lookup, Nip05ID, Pin, TotpCode};
+use crate::passkeys::{
+    PasskeyAssertion, PasskeyAuthenticationOptions, PasskeyRegistrationOptions, Passkeys,
+};
+use crate::proof_of_work::ProofOfWork;
+use crate::replication::Replicator;
+use crate::second_factor::SecondFactor;
+use actix_web::{web, HttpResponse};
+use sqlx::PgPool;
+use utoipa::ToSchema;
+use webauthn_rs::prelude::{RegisterPublicKeyCredential, Uuid};
+
+use super::{authenticate_fetch, ErrorCode, ErrorResponse, KeyLookup, LookupError};
+
+#[derive(ToSchema, serde::Deserialize)]
+pub struct NewPasskey {
+    #[schema(value_type = String, example = "7b4c5e3a-2c1f-4a52-9b55-7f0f2b7c9d31")]
+    pub ceremony_id: Uuid,
+    /// Result of `navigator.credentials.create` for the options from `/passkey/register/start`.
+    #[schema(value_type = Object)]
+    pub credential: RegisterPublicKeyCredential,
+    /// Lets this passkey fetch the key through `/passkey/fetch_key` without the pin.
+    #[serde(default)]
+    pub replaces_pin: bool,
+}
+
+#[derive(ToSchema, serde::Deserialize)]
+pub struct PasskeyAuthenticationStart {
+    #[schema(example = "the_name_is_bob_bob_smith@frogs.cloud")]
+    pub nip_05_id: String,
+}
+
+#[derive(ToSchema, serde::Deserialize)]
+pub struct PasskeyLookup {
+    #[schema(example = "the_name_is_bob_bob_smith@frogs.cloud")]
+    pub nip_05_id: String,
+    pub passkey: PasskeyAssertion,
+}
+
+#[utoipa::path(
+        post,
+        path = "/passkey/register/start",
+        responses(
+            (status = OK,
+                body = PasskeyRegistrationOptions,
+                description = "Options to pass to `navigator.credentials.create`."
+            ),
+            (
+                status = FORBIDDEN,
+                body = ErrorResponse,
+                example=json!(ErrorResponse::new(ErrorCode::PinInvalid, "Pin is not valid for provided user.")),
+                description = "nip 05 id found, but pin or second factor does not match"
+            ),
+            (
+                status = UNAUTHORIZED,
+                body = ErrorResponse,
+                example=json!(ErrorResponse::new(ErrorCode::SecondFactorRequired, "A code from the enrolled authenticator app or a passkey assertion is required.")),
+                description = "Pin matches, but the second factor enrolled for the key is missing"
+            ),
+            (
+                status = INTERNAL_SERVER_ERROR,
+                body =  ErrorResponse,
+                example=json!(ErrorResponse::new(ErrorCode::Internal, "Unable to connect to db.")),
+                description = "Something went terribly wrong."
+            ),
+        ),
+        request_body = KeyLookup
+)]
+#[tracing::instrument(
+    skip(key_lookup, pool, second_factor, passkeys, proof_of_work),
+    fields(
+        nip_05_id = %key_lookup.nip_05_id,
+    )
+)]
+pub async fn start_passkey_registration(
+    key_lookup: web::Json<KeyLookup>,
+    pool: web::Data<PgPool>,
+    second_factor: web::Data<SecondFactor>,
+    passkeys: web::Data<Passkeys>,
+    proof_of_work: web::Data<ProofOfWork>,
+) -> Result<HttpResponse, LookupError> {
+    let nip_05_id = Nip05ID::parse(key_lookup.0.nip_05_id)
+        .map_err(LookupError::malformed(ErrorCode::Nip05Malformed))?;
+    let pin =
+        Pin::parse(key_lookup.0.pin).map_err(LookupError::malformed(ErrorCode::PinMalformed))?;
+    let totp_code = key_lookup
+        .0
+        .totp_code
+        .map(TotpCode::parse)
+        .transpose()
+        .map_err(LookupError::malformed(ErrorCode::TotpCodeMalformed))?;
+    let lookup = &Lookup {
+        nip_05_id,
+        pin,
+        totp_code,
+    };
+
+    let key = authenticate_fetch(
+        lookup,
+        key_lookup.0.passkey.as_ref(),
+        key_lookup.0.proof_of_work.as_ref(),
+        &pool,
+        &second_factor,
+        &passkeys,
+        &proof_of_work,
+    )
+    .await?;
+    let ceremony = passkeys
+        .start_registration(key.id, &lookup.nip_05_id, &pool)
+        .await?;
+
+    Ok(HttpResponse::Ok().json(ceremony))
+}
+
+#[utoipa::path(
+        post,
+        path = "/passkey/register/finish",
+        responses(
+            (status = OK, description = "Passkey stored, `fetch_key` now requires a second factor and peers refuse to serve the key."),
+            (
+                status = FORBIDDEN,
+                body = ErrorResponse,
+                example=json!(ErrorResponse::new(ErrorCode::PasskeyInvalid, "Passkey assertion is not valid.")),
+                description = "Ceremony is unknown or expired, or the credential does not answer it"
+            ),
+            (
+                status = INTERNAL_SERVER_ERROR,
+                body =  ErrorResponse,
+                example=json!(ErrorResponse::new(ErrorCode::Internal, "Unable to connect to db.")),
+                description = "Something went terribly wrong."
+            ),
+        ),
+        request_body = NewPasskey
+)]
+#[tracing::instrument(
+    skip(registration, pool, passkeys, replicator),
+    fields(
+        ceremony_id = %registration.ceremony_id,
+    )
+)]
+pub async fn finish_passkey_registration(
+    registration: web::Json<NewPasskey>,
+    pool: web::Data<PgPool>,
+    passkeys: web::Data<Passkeys>,
+    replicator: web::Data<Replicator>,
+) -> Result<HttpResponse, LookupError> {
+    let key_id = passkeys
+        .finish_registration(
+            registration.ceremony_id,
+            &registration.credential,
+            registration.replaces_pin,
+            &pool,
+        )
+        .await?;
+    if let Some(nip_05_id) = mark_second_factor_enrolled(key_id, &pool).await? {
+        replicator.replicate(nip_05_id, pool.get_ref().clone());
+    }
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[utoipa::path(
+        post,
+        path = "/passkey/authenticate/start",
+        responses(
+            (status = OK,
+                body = PasskeyAuthenticationOptions,
+                description = "Options to pass to `navigator.credentials.get`. Unknown nip 05 ids and users without passkeys get decoy options no assertion will satisfy."
+            ),
+            (
+                status = INTERNAL_SERVER_ERROR,
+                body =  ErrorResponse,
+                example=json!(ErrorResponse::new(ErrorCode::Internal, "Unable to connect to db.")),
+                description = "Something went terribly wrong."
+            ),
+        ),
+        request_body = PasskeyAuthenticationStart
+)]
+#[tracing::instrument(
+    skip(start, pool, passkeys),
+    fields(
+        nip_05_id = %start.nip_05_id,
+    )
+)]
+pub async fn start_passkey_authentication(
+    start: web::Json<PasskeyAuthenticationStart>,
+    pool: web::Data<PgPool>,
+    passkeys: web::Data<Passkeys>,
+) -> Result<HttpResponse, LookupError> {
+    let nip_05_id = Nip05ID::parse(start.0.nip_05_id)
+        .map_err(LookupError::malformed(ErrorCode::Nip05Malformed))?;
+    let ceremony = passkeys.start_authentication(&nip_05_id, &pool).await?;
+
+    Ok(HttpResponse::Ok().json(ceremony))
+}
+
+#[utoipa::path(
+        post,
+        path = "/passkey/fetch_key",
+        responses(
+            (status = OK,
+                body = StoredKey,
+                description = "Passkey assertion accepted in place of the pin."
+            ),
+            (
+                status = FORBIDDEN,
+                body = ErrorResponse,
+                example=json!(ErrorResponse::new(ErrorCode::PasskeyInvalid, "Passkey assertion is not valid.")),
+                description = "Assertion does not verify, or the passkey was not registered with `replaces_pin`"
+            ),
+            (
+                status = NOT_FOUND,
+                body = ErrorResponse,
+                example=json!(ErrorResponse::new(ErrorCode::KeyNotFound, "There is no private key associated with the provided pin and user.")),
+                description = "nip 05 id not found"
+            ),
+            (
+                status = INTERNAL_SERVER_ERROR,
+                body =  ErrorResponse,
+                example=json!(ErrorResponse::new(ErrorCode::Internal, "Unable to connect to db.")),
+                description = "Something went terribly wrong."
+            ),
+        ),
+        request_body = PasskeyLookup
+)]
+#[tracing::instrument(
+    skip(passkey_lookup, pool, passkeys),
+    fields(
+        nip_05_id = %passkey_lookup.nip_05_id,
+    )
+)]
+pub async fn fetch_key_with_passkey(
+    passkey_lookup: web::Json<PasskeyLookup>,
+    pool: web::Data<PgPool>,
+    passkeys: web::Data<Passkeys>,
+) -> Result<web::Json<StoredKey>, LookupError> {
+    let nip_05_id = Nip05ID::parse(passkey_lookup.0.nip_05_id)
+        .map_err(LookupError::malformed(ErrorCode::Nip05Malformed))?;
+    let key = get_key_by_nip_05_id(&nip_05_id, &pool)
+        .await?
+        .ok_or(LookupError::NotFoundError)?;
+    let replaces_pin = passkeys
+        .verify(key.id, &passkey_lookup.0.passkey, &pool)
+        .await?;
+    // Passkeys registered as a second factor only still need the pin, via `fetch_key`.
+    if !replaces_pin {
+        return Err(LookupError::InvalidPasskey);
+    }
+    record_access(key.id, &pool).await?;
+
+    Ok(web::Json(key))
+}
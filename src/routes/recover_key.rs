@@ -0,0 +1,177 @@
+use crate::authentication::StoredKey;
+use crate::domain::{Nip05ID, Pin, RecoveryCode, TotpCode};
+use crate::idempotency::{Idempotency, RequestFingerprint};
+use crate::passkeys::{PasskeyAssertion, Passkeys};
+use crate::proof_of_work::{ProofOfWork, WorkProof};
+use crate::recovery::{reset_pin_with_recovery_code, Recovery};
+use crate::replication::Replicator;
+use crate::second_factor::SecondFactor;
+use actix_web::{web, HttpRequest};
+use secrecy::{ExposeSecret, Secret};
+use sqlx::PgPool;
+use utoipa::ToSchema;
+
+use super::{behind_proof_of_work, verify_second_factor, ErrorCode, ErrorResponse, LookupError};
+
+#[derive(ToSchema, serde::Deserialize)]
+pub struct KeyRecovery {
+    #[schema(example = "the_name_is_bob_bob_smith@frogs.cloud")]
+    pub nip_05_id: String,
+    /// One of the codes returned by `upload_key`, it can not be used again.
+    #[schema(value_type = String, example = "ABCDE-FGH23")]
+    pub recovery_code: Secret<String>,
+    /// Replaces the forgotten pin.
+    #[schema(value_type = u64, example = "401267")]
+    pub new_pin: Secret<u64>,
+    /// Current code from the authenticator app, required once a second factor is enrolled.
+    #[schema(value_type = Option<String>, example = "287082")]
+    pub totp_code: Option<Secret<String>>,
+    /// Assertion for a ceremony from `/passkey/authenticate/start`, accepted in place of `totp_code`.
+    pub passkey: Option<PasskeyAssertion>,
+    /// Required once `/work_challenge` reports a difficulty above 0 for the nip 05 id.
+    pub proof_of_work: Option<WorkProof>,
+}
+
+impl KeyRecovery {
+    fn fingerprint(&self) -> RequestFingerprint {
+        RequestFingerprint::new("recover_key", &self.nip_05_id)
+            .field(self.recovery_code.expose_secret())
+            .field(self.new_pin.expose_secret().to_be_bytes())
+    }
+}
+
+#[utoipa::path(
+        post,
+        path = "/recover_key",
+        responses(
+            (status = OK,
+                body = StoredKey,
+                description = "Recovery code accepted and pin replaced by `new_pin`."
+            ),
+            (
+                status = FORBIDDEN,
+                body = ErrorResponse,
+                example=json!(ErrorResponse::new(ErrorCode::RecoveryCodeInvalid, "Recovery code is not valid for provided user.")),
+                description = "Recovery code is unknown or already used."
+            ),
+            (
+                status = UNAUTHORIZED,
+                body = ErrorResponse,
+                example=json!(ErrorResponse::new(ErrorCode::SecondFactorRequired, "A code from the enrolled authenticator app or a passkey assertion is required.")),
+                description = "A second factor is enrolled, the recovery code alone is not enough."
+            ),
+            (
+                status = PRECONDITION_REQUIRED,
+                body = ErrorResponse,
+                example=json!(ErrorResponse::new(ErrorCode::ProofOfWorkRequired, "A proof of work with 12 leading zero bits is required, ask /work_challenge for a challenge.")),
+                description = "Too many failed attempts for this nip 05 id, solve a `fetch` work challenge first."
+            ),
+            (
+                status = BAD_REQUEST,
+                body = ErrorResponse,
+                example=json!(ErrorResponse::new(ErrorCode::RecoveryCodeMalformed, "Provided value is not a valid recovery code.")),
+                description = "object used to recover the private key fails validation"
+            ),
+            (
+                status = CONFLICT,
+                body = ErrorResponse,
+                example=json!(ErrorResponse::new(ErrorCode::RequestInProgress, "A request with this Idempotency-Key is still being processed.")),
+                description = "A request with the same `Idempotency-Key` is still running."
+            ),
+            (
+                status = UNPROCESSABLE_ENTITY,
+                body = ErrorResponse,
+                example=json!(ErrorResponse::new(ErrorCode::IdempotencyKeyReused, "Idempotency-Key was already used for a different request.")),
+                description = "`Idempotency-Key` was sent before with a different body."
+            ),
+            (
+                status = SERVICE_UNAVAILABLE,
+                body = ErrorResponse,
+                example=json!(ErrorResponse::new(ErrorCode::Overloaded, "Too many pins are being hashed, retry in 1 seconds.")),
+                description = "Too many pins are waiting to be hashed, retry after `Retry-After` seconds."
+            ),
+            (
+                status = INTERNAL_SERVER_ERROR,
+                body =  ErrorResponse,
+                example=json!(ErrorResponse::new(ErrorCode::Internal, "Unable to connect to db.")),
+                description = "Something went terribly wrong."
+            ),
+        ),
+        params(
+            ("Idempotency-Key" = Option<String>, Header,
+                description = "Retries with the same key and body get the original response back.")
+        ),
+        request_body = KeyRecovery
+)]
+#[tracing::instrument(
+    skip(request, key_recovery, pool, replicator, idempotency, second_factor, passkeys, proof_of_work),
+    fields(
+        nip_05_id = %key_recovery.nip_05_id,
+    )
+)]
+pub async fn recover_key(
+    request: HttpRequest,
+    key_recovery: web::Json<KeyRecovery>,
+    pool: web::Data<PgPool>,
+    replicator: web::Data<Replicator>,
+    idempotency: web::Data<Idempotency>,
+    second_factor: web::Data<SecondFactor>,
+    passkeys: web::Data<Passkeys>,
+    proof_of_work: web::Data<ProofOfWork>,
+) -> Result<web::Json<StoredKey>, LookupError> {
+    let fingerprint = key_recovery.fingerprint();
+    let nip_05_id = Nip05ID::parse(key_recovery.0.nip_05_id)
+        .map_err(LookupError::malformed(ErrorCode::Nip05Malformed))?;
+    let recovery_code = RecoveryCode::parse(key_recovery.0.recovery_code)
+        .map_err(LookupError::malformed(ErrorCode::RecoveryCodeMalformed))?;
+    let new_pin = Pin::parse(key_recovery.0.new_pin)
+        .map_err(LookupError::malformed(ErrorCode::PinMalformed))?;
+    let totp_code = key_recovery
+        .0
+        .totp_code
+        .map(TotpCode::parse)
+        .transpose()
+        .map_err(LookupError::malformed(ErrorCode::TotpCodeMalformed))?;
+    let passkey = key_recovery.0.passkey;
+    let work = key_recovery.0.proof_of_work;
+
+    let recovery = &Recovery {
+        nip_05_id,
+        recovery_code,
+        new_pin,
+    };
+
+    let pool = pool.get_ref();
+    let replicator = replicator.get_ref();
+    let (second_factor, passkeys) = (second_factor.get_ref(), passkeys.get_ref());
+    let verify = |key_id| {
+        verify_second_factor(
+            key_id,
+            totp_code.as_ref(),
+            passkey.as_ref(),
+            pool,
+            second_factor,
+            passkeys,
+        )
+    };
+    let key = idempotency
+        .run(&request, fingerprint, pool, move || async move {
+            let key = behind_proof_of_work(
+                &recovery.nip_05_id,
+                work.as_ref(),
+                pool,
+                &proof_of_work,
+                async {
+                    reset_pin_with_recovery_code(recovery, verify, replicator.node_id(), pool)
+                        .await
+                        .map_err(LookupError::from)
+                },
+            )
+            .await?;
+            replicator.replicate(key.nip_05_id.clone(), pool.clone());
+            Ok::<_, LookupError>(key)
+        })
+        .await?;
+
+    Ok(web::Json(key))
+}
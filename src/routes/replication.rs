@@ -0,0 +1,165 @@
+use crate::domain::Nip05ID;
+use crate::replication::{apply_replicated_key, list_replicated_keys, ReplicatedKey};
+use crate::routes::error_chain_fmt;
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use reqwest::StatusCode;
+use secrecy::{ExposeSecret, Secret};
+use sqlx::PgPool;
+use std::fmt::Debug;
+
+use super::{ErrorCode, ErrorResponse};
+
+/// The `auth_key` the sample configuration used to ship with.
+const SAMPLE_AUTH_KEY: &str = "replication-auth-key";
+
+/// Key peers must present as a bearer token to use the replication routes.
+pub struct ReplicationAuthKey(Secret<String>);
+
+impl ReplicationAuthKey {
+    /// Refuses an unset key and the sample one, either would let anyone write rows.
+    pub fn new(auth_key: Secret<String>) -> Result<Self, anyhow::Error> {
+        let key = auth_key.expose_secret();
+        if key.is_empty() || key == SAMPLE_AUTH_KEY {
+            return Err(anyhow::anyhow!(
+                "replication.auth_key must be set to a secret of your own to serve replication."
+            ));
+        }
+        Ok(Self(auth_key))
+    }
+}
+
+#[derive(thiserror::Error)]
+pub enum ReplicationError {
+    #[error("Missing or invalid peer credentials.")]
+    Unauthorized,
+    #[error("{0}")]
+    Malformed(String),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl Debug for ReplicationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ReplicationError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            ReplicationError::Unauthorized => ErrorCode::PeerUnauthorized,
+            ReplicationError::Malformed(_) => ErrorCode::Nip05Malformed,
+            ReplicationError::UnexpectedError(_) => ErrorCode::Internal,
+        }
+    }
+}
+
+impl ResponseError for ReplicationError {
+    fn status_code(&self) -> reqwest::StatusCode {
+        match self {
+            ReplicationError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ReplicationError::Malformed(_) => StatusCode::BAD_REQUEST,
+            ReplicationError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+    fn error_response(&self) -> actix_web::HttpResponse<actix_web::body::BoxBody> {
+        HttpResponse::build(self.status_code())
+            .json(ErrorResponse::new(self.code(), self.to_string()))
+    }
+}
+
+fn authorize_peer(
+    request: &HttpRequest,
+    auth_key: &ReplicationAuthKey,
+) -> Result<(), ReplicationError> {
+    let expected = auth_key.0.expose_secret();
+    let provided = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or(ReplicationError::Unauthorized)?;
+    if !constant_time_eq(expected.as_bytes(), provided.as_bytes()) {
+        return Err(ReplicationError::Unauthorized);
+    }
+    Ok(())
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[utoipa::path(
+    post,
+    path = "/replication/keys",
+    responses(
+        (status = OK, description = "Key or tombstone was merged into this vault."),
+        (
+            status = BAD_REQUEST,
+            body = ErrorResponse,
+            example=json!(ErrorResponse::new(ErrorCode::Nip05Malformed, "bob+frogs@frogs.cloud is not a valid nip 05 id.")),
+            description = "The nip 05 id is not valid under NIP-05, it is normalized before being stored."
+        ),
+        (
+            status = UNAUTHORIZED,
+            body = ErrorResponse,
+            example=json!(ErrorResponse::new(ErrorCode::PeerUnauthorized, "Missing or invalid peer credentials.")),
+            description = "Caller is not a configured peer."
+        ),
+        (
+            status = INTERNAL_SERVER_ERROR,
+            body = ErrorResponse,
+            example=json!(ErrorResponse::new(ErrorCode::Internal, "Failed to update replicated key.")),
+            description = "Something went terribly wrong."
+        ),
+    ),
+    request_body = ReplicatedKey
+)]
+#[tracing::instrument(
+    skip(request, replicated_key, pool, auth_key),
+    fields(
+        nip_05_id = %replicated_key.nip_05_id,
+    )
+)]
+pub async fn receive_replicated_key(
+    request: HttpRequest,
+    replicated_key: web::Json<ReplicatedKey>,
+    pool: web::Data<PgPool>,
+    auth_key: web::Data<ReplicationAuthKey>,
+) -> Result<HttpResponse, ReplicationError> {
+    authorize_peer(&request, &auth_key)?;
+    Nip05ID::parse(replicated_key.nip_05_id.clone()).map_err(ReplicationError::Malformed)?;
+    apply_replicated_key(&replicated_key.0, &pool).await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[utoipa::path(
+    get,
+    path = "/replication/keys",
+    responses(
+        (status = OK, body = [ReplicatedKey], description = "Every key held by this vault, and the tombstones of deleted ones."),
+        (
+            status = UNAUTHORIZED,
+            body = ErrorResponse,
+            example=json!(ErrorResponse::new(ErrorCode::PeerUnauthorized, "Missing or invalid peer credentials.")),
+            description = "Caller is not a configured peer."
+        ),
+        (
+            status = INTERNAL_SERVER_ERROR,
+            body = ErrorResponse,
+            example=json!(ErrorResponse::new(ErrorCode::Internal, "Failed to perform a query to list keys for replication.")),
+            description = "Something went terribly wrong."
+        ),
+    ),
+)]
+#[tracing::instrument(skip(request, pool, auth_key))]
+pub async fn replicated_keys(
+    request: HttpRequest,
+    pool: web::Data<PgPool>,
+    auth_key: web::Data<ReplicationAuthKey>,
+) -> Result<HttpResponse, ReplicationError> {
+    authorize_peer(&request, &auth_key)?;
+    let keys = list_replicated_keys(&pool).await?;
+    Ok(HttpResponse::Ok().json(keys))
+}
@@ -0,0 +1,188 @@
+use crate::authentication::mark_second_factor_enrolled;
+use crate::domain::{Lookup, Nip05ID, Pin, TotpCode};
+use crate::passkeys::{PasskeyAssertion, Passkeys};
+use crate::proof_of_work::{ProofOfWork, WorkProof};
+use crate::replication::Replicator;
+use crate::second_factor::{SecondFactor, TotpEnrolment};
+use actix_web::{web, HttpResponse};
+use secrecy::Secret;
+use sqlx::PgPool;
+use utoipa::ToSchema;
+
+use super::{authenticate_fetch, ErrorCode, ErrorResponse, LookupError};
+
+#[derive(ToSchema, serde::Deserialize)]
+pub struct SecondFactorEnrolment {
+    #[schema(example = "the_name_is_bob_bob_smith@frogs.cloud")]
+    pub nip_05_id: String,
+    #[schema(value_type = u64, example = "401267")]
+    pub pin: Secret<u64>,
+    /// Required once a passkey is enrolled, same as for `fetch_key`.
+    pub passkey: Option<PasskeyAssertion>,
+    /// Same as for `fetch_key`, pin guesses here count against the nip 05 id too.
+    pub proof_of_work: Option<WorkProof>,
+}
+
+#[derive(ToSchema, serde::Deserialize)]
+pub struct SecondFactorConfirmation {
+    #[schema(example = "the_name_is_bob_bob_smith@frogs.cloud")]
+    pub nip_05_id: String,
+    #[schema(value_type = u64, example = "401267")]
+    pub pin: Secret<u64>,
+    /// Current code shown by the authenticator app for the enrolled secret.
+    #[schema(value_type = String, example = "287082")]
+    pub totp_code: Secret<String>,
+    /// Required once a passkey is enrolled, an assertion for a new ceremony as the one sent to
+    /// `/second_factor/enrol` is used up.
+    pub passkey: Option<PasskeyAssertion>,
+    /// Same as for `fetch_key`.
+    pub proof_of_work: Option<WorkProof>,
+}
+
+#[utoipa::path(
+        post,
+        path = "/second_factor/enrol",
+        responses(
+            (status = OK,
+                body = TotpEnrolment,
+                description = "Secret to add to an authenticator app, enforced once confirmed."
+            ),
+            (
+                status = FORBIDDEN,
+                body = ErrorResponse,
+                example=json!(ErrorResponse::new(ErrorCode::PinInvalid, "Pin is not valid for provided user.")),
+                description = "nip 05 id found, but pin or passkey assertion does not match"
+            ),
+            (
+                status = UNAUTHORIZED,
+                body = ErrorResponse,
+                example=json!(ErrorResponse::new(ErrorCode::SecondFactorRequired, "A code from the enrolled authenticator app or a passkey assertion is required.")),
+                description = "Pin matches, but a passkey is enrolled and no `passkey` was sent"
+            ),
+            (
+                status = CONFLICT,
+                body = ErrorResponse,
+                example=json!(ErrorResponse::new(ErrorCode::SecondFactorAlreadyEnrolled, "A second factor is already enrolled for this user.")),
+                description = "A confirmed second factor already exists"
+            ),
+            (
+                status = INTERNAL_SERVER_ERROR,
+                body =  ErrorResponse,
+                example=json!(ErrorResponse::new(ErrorCode::Internal, "Unable to connect to db.")),
+                description = "Something went terribly wrong."
+            ),
+        ),
+        request_body = SecondFactorEnrolment
+)]
+#[tracing::instrument(
+    skip(enrolment, pool, second_factor, passkeys, proof_of_work),
+    fields(
+        nip_05_id = %enrolment.nip_05_id,
+    )
+)]
+pub async fn enrol_second_factor(
+    enrolment: web::Json<SecondFactorEnrolment>,
+    pool: web::Data<PgPool>,
+    second_factor: web::Data<SecondFactor>,
+    passkeys: web::Data<Passkeys>,
+    proof_of_work: web::Data<ProofOfWork>,
+) -> Result<HttpResponse, LookupError> {
+    let enrolment = enrolment.0;
+    let nip_05_id = Nip05ID::parse(enrolment.nip_05_id)
+        .map_err(LookupError::malformed(ErrorCode::Nip05Malformed))?;
+    let pin = Pin::parse(enrolment.pin).map_err(LookupError::malformed(ErrorCode::PinMalformed))?;
+    let lookup = &Lookup {
+        nip_05_id,
+        pin,
+        totp_code: None,
+    };
+
+    // A second factor already enrolled has to be presented, or the pin alone could add one
+    let key = authenticate_fetch(
+        lookup,
+        enrolment.passkey.as_ref(),
+        enrolment.proof_of_work.as_ref(),
+        &pool,
+        &second_factor,
+        &passkeys,
+        &proof_of_work,
+    )
+    .await?;
+    let enrolment = second_factor
+        .enrol(key.id, &lookup.nip_05_id, &pool)
+        .await?
+        .ok_or(LookupError::SecondFactorAlreadyEnrolled)?;
+
+    Ok(HttpResponse::Ok().json(enrolment))
+}
+
+#[utoipa::path(
+        post,
+        path = "/second_factor/confirm",
+        responses(
+            (status = OK, description = "Second factor is now required by `fetch_key`, peers refuse to serve the key."),
+            (
+                status = FORBIDDEN,
+                body = ErrorResponse,
+                example=json!(ErrorResponse::new(ErrorCode::SecondFactorInvalid, "Code is not valid or was already used.")),
+                description = "Pin, code or passkey assertion does not match, or there is no pending enrolment"
+            ),
+            (
+                status = UNAUTHORIZED,
+                body = ErrorResponse,
+                example=json!(ErrorResponse::new(ErrorCode::SecondFactorRequired, "A code from the enrolled authenticator app or a passkey assertion is required.")),
+                description = "Pin matches, but a passkey is enrolled and no `passkey` was sent"
+            ),
+            (
+                status = INTERNAL_SERVER_ERROR,
+                body =  ErrorResponse,
+                example=json!(ErrorResponse::new(ErrorCode::Internal, "Unable to connect to db.")),
+                description = "Something went terribly wrong."
+            ),
+        ),
+        request_body = SecondFactorConfirmation
+)]
+#[tracing::instrument(
+    skip(confirmation, pool, second_factor, passkeys, proof_of_work, replicator),
+    fields(
+        nip_05_id = %confirmation.nip_05_id,
+    )
+)]
+pub async fn confirm_second_factor(
+    confirmation: web::Json<SecondFactorConfirmation>,
+    pool: web::Data<PgPool>,
+    second_factor: web::Data<SecondFactor>,
+    passkeys: web::Data<Passkeys>,
+    proof_of_work: web::Data<ProofOfWork>,
+    replicator: web::Data<Replicator>,
+) -> Result<HttpResponse, LookupError> {
+    let confirmation = confirmation.0;
+    let nip_05_id = Nip05ID::parse(confirmation.nip_05_id)
+        .map_err(LookupError::malformed(ErrorCode::Nip05Malformed))?;
+    let pin =
+        Pin::parse(confirmation.pin).map_err(LookupError::malformed(ErrorCode::PinMalformed))?;
+    let totp_code = TotpCode::parse(confirmation.totp_code)
+        .map_err(LookupError::malformed(ErrorCode::TotpCodeMalformed))?;
+    let lookup = &Lookup {
+        nip_05_id,
+        pin,
+        totp_code: None,
+    };
+
+    let key = authenticate_fetch(
+        lookup,
+        confirmation.passkey.as_ref(),
+        confirmation.proof_of_work.as_ref(),
+        &pool,
+        &second_factor,
+        &passkeys,
+        &proof_of_work,
+    )
+    .await?;
+    second_factor.confirm(key.id, &totp_code, &pool).await?;
+    if let Some(nip_05_id) = mark_second_factor_enrolled(key.id, &pool).await? {
+        replicator.replicate(nip_05_id, pool.get_ref().clone());
+    }
+
+    Ok(HttpResponse::Ok().finish())
+}
@@ -0,0 +1,175 @@
+use crate::authentication::get_pin_hash;
+use crate::domain::Nip05ID;
+use crate::passkeys::{PasskeyAssertion, Passkeys};
+use crate::proof_of_work::{ProofOfWork, WorkProof};
+use crate::second_factor::SecondFactor;
+use crate::session::{IssuedSession, SessionError, SessionScope, Sessions};
+use actix_web::dev::Payload;
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::{web, FromRequest, HttpRequest, HttpResponse};
+use anyhow::anyhow;
+use futures_util::future::LocalBoxFuture;
+use secrecy::Secret;
+use sqlx::PgPool;
+use utoipa::ToSchema;
+
+use super::{authenticate_fetch, parse_lookup, ErrorCode, ErrorResponse, LookupError};
+
+#[derive(ToSchema, serde::Deserialize)]
+pub struct SessionRequest {
+    #[schema(value_type = u64, example = "401267")]
+    pub pin: Secret<u64>,
+    #[schema(value_type = Option<String>, example = "287082")]
+    pub totp_code: Option<Secret<String>>,
+    pub passkey: Option<PasskeyAssertion>,
+    /// Same as for `retrieve`, pin guesses here count against the nip 05 id too.
+    pub proof_of_work: Option<WorkProof>,
+    /// Operations the token may be used for.
+    #[schema(example = json!(["update", "delete"]))]
+    pub scopes: Vec<SessionScope>,
+}
+
+impl From<SessionError> for LookupError {
+    fn from(e: SessionError) -> Self {
+        match e {
+            SessionError::Invalid(e) => LookupError::InvalidSession(e),
+            SessionError::UnexpectedError(e) => e.into(),
+        }
+    }
+}
+
+/// A verified session token, still bound to the key's current pin.
+pub struct Session {
+    key_id: i64,
+    nip_05_id: String,
+    scopes: Vec<SessionScope>,
+    token: String,
+}
+
+impl Session {
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    /// Id of the key, if the token was issued for `nip_05_id` and covers `scope`.
+    pub fn authorize(&self, nip_05_id: &str, scope: SessionScope) -> Result<i64, LookupError> {
+        let nip_05_id = Nip05ID::parse(nip_05_id.to_string())
+            .map_err(LookupError::malformed(ErrorCode::Nip05Malformed))?;
+        if nip_05_id.as_ref() != self.nip_05_id || !self.scopes.contains(&scope) {
+            return Err(LookupError::SessionScopeMissing);
+        }
+        Ok(self.key_id)
+    }
+}
+
+/// Session from an `Authorization: Bearer` header. `None` without the header, so handlers
+/// fall back to the pin, but a token that is sent has to be valid.
+pub struct BearerSession(pub Option<Session>);
+
+impl FromRequest for BearerSession {
+    type Error = LookupError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let token = req.headers().get(AUTHORIZATION).map(|value| {
+            value
+                .to_str()
+                .ok()
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .map(str::to_string)
+        });
+        let sessions = req.app_data::<web::Data<Sessions>>().cloned();
+        let pool = req.app_data::<web::Data<PgPool>>().cloned();
+        Box::pin(async move {
+            let token = match token {
+                None => return Ok(BearerSession(None)),
+                Some(token) => token.ok_or_else(|| {
+                    LookupError::InvalidSession(
+                        "Authorization must be a Bearer session token.".to_string(),
+                    )
+                })?,
+            };
+            let (sessions, pool) = sessions
+                .zip(pool)
+                .ok_or_else(|| anyhow!("Sessions are not configured for this app."))?;
+            let claims = sessions.verify(&token)?;
+            let revoked =
+                || LookupError::InvalidSession("Session token has been revoked.".to_string());
+            let (key_id, pin_hash) = get_pin_hash(&claims.sub, &pool)
+                .await?
+                .ok_or_else(revoked)?;
+            if !sessions.is_bound_to(&claims, &pin_hash) {
+                return Err(revoked());
+            }
+            Ok(BearerSession(Some(Session {
+                key_id,
+                nip_05_id: claims.sub,
+                scopes: claims.scope,
+                token,
+            })))
+        })
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/keys/{nip_05_id}/sessions",
+    params(("nip_05_id" = String, Path, example = "the_name_is_bob_bob_smith@frogs.cloud")),
+    responses(
+        (status = CREATED, body = IssuedSession,
+            description = "Token to send as `Authorization: Bearer` instead of the pin, until it expires or the pin changes."),
+        (status = UNAUTHORIZED, body = ErrorResponse,
+            description = "Pin matches, but a second factor is enrolled and neither `totp_code` nor `passkey` was sent"),
+        (status = FORBIDDEN, body = ErrorResponse,
+            description = "nip 05 id found, but pin, second factor code or passkey assertion does not match"),
+        (status = BAD_REQUEST, body = ErrorResponse,
+            description = "No scopes asked for, or the request fails validation"),
+        (status = NOT_FOUND, body = ErrorResponse, description = "nip_05_id and pin pairing not found"),
+        (status = SERVICE_UNAVAILABLE, body = ErrorResponse,
+            description = "Too many pins are waiting to be hashed, retry after `Retry-After` seconds."),
+        (status = INTERNAL_SERVER_ERROR, body = ErrorResponse,
+            description = "Something went terribly wrong."),
+    ),
+    request_body = SessionRequest
+)]
+#[tracing::instrument(
+    skip(session_request, pool, second_factor, passkeys, proof_of_work, sessions),
+    fields(nip_05_id = %nip_05_id)
+)]
+pub async fn create_session(
+    nip_05_id: web::Path<String>,
+    session_request: web::Json<SessionRequest>,
+    pool: web::Data<PgPool>,
+    second_factor: web::Data<SecondFactor>,
+    passkeys: web::Data<Passkeys>,
+    proof_of_work: web::Data<ProofOfWork>,
+    sessions: web::Data<Sessions>,
+) -> Result<HttpResponse, LookupError> {
+    let session_request = session_request.0;
+    if session_request.scopes.is_empty() {
+        return Err(LookupError::ValidationError(
+            ErrorCode::RequestMalformed,
+            "Ask for at least one scope.".to_string(),
+        ));
+    }
+    let lookup = parse_lookup(
+        nip_05_id.into_inner(),
+        session_request.pin,
+        session_request.totp_code,
+    )?;
+    let key = authenticate_fetch(
+        &lookup,
+        session_request.passkey.as_ref(),
+        session_request.proof_of_work.as_ref(),
+        &pool,
+        &second_factor,
+        &passkeys,
+        &proof_of_work,
+    )
+    .await?;
+    let (_, pin_hash) = get_pin_hash(&key.nip_05_id, &pool)
+        .await?
+        .ok_or(LookupError::NotFoundError)?;
+    let session = sessions.issue(&key.nip_05_id, &pin_hash, session_request.scopes)?;
+    Ok(HttpResponse::Created().json(session))
+}
@@ -0,0 +1,29 @@
+use crate::challenge::{Challenge, ChallengeIssuer, ChallengePurpose};
+use actix_web::{web, HttpResponse};
+use sqlx::PgPool;
+
+use super::{ErrorCode, ErrorResponse, UploadError};
+
+#[utoipa::path(
+    post,
+    path = "/upload_challenge",
+    responses(
+        (status = OK,
+            body = Challenge,
+            description = "Nonce to sign, together with the blob, for the next `/upload_key`."),
+        (
+            status = INTERNAL_SERVER_ERROR,
+            body = ErrorResponse,
+            example=json!(ErrorResponse::new(ErrorCode::Internal, "Failed to store challenge.")),
+            description = "Something went terribly wrong."
+        ),
+    ),
+)]
+#[tracing::instrument(skip(pool, issuer))]
+pub async fn upload_challenge(
+    pool: web::Data<PgPool>,
+    issuer: web::Data<ChallengeIssuer>,
+) -> Result<HttpResponse, UploadError> {
+    let challenge = issuer.issue(ChallengePurpose::Upload, &pool).await?;
+    Ok(HttpResponse::Ok().json(challenge))
+}
@@ -1,14 +1,28 @@
 use crate::authentication::{save_private_key_and_pin, StoredKey};
-use crate::domain::{KeyInfo, Nip05ID, Pin, PrivateKeyHash};
+use crate::challenge::{consume_challenge, ChallengePurpose};
+use crate::domain::{
+    AppDataEvent, KeyInfo, KeyPossessionProof, Nip05ID, NostrEvent, NostrPublicKey, Pin,
+    PrivateKeyHash, RelayUrl,
+};
+use crate::domain_policy::{DomainPolicy, DomainPolicyError};
+use crate::hashing::retry_after;
+use crate::idempotency::{Idempotency, IdempotencyError, RequestFingerprint};
+use crate::nip05_provider::Nip05Provider;
+use crate::payments::{L402Challenge, PaymentError, Payments};
+use crate::proof_of_work::{ProofOfWork, WorkError, WorkProof, WorkRoute};
+use crate::relay_publisher::RelayPublisher;
+use crate::replication::Replicator;
 use crate::routes::error_chain_fmt;
-use actix_web::{web, HttpResponse, ResponseError};
+use actix_web::http::header::{RETRY_AFTER, WWW_AUTHENTICATE};
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use anyhow::Context;
 use reqwest::StatusCode;
-use secrecy::Secret;
+use secrecy::{ExposeSecret, Secret};
 use sqlx::PgPool;
 use std::fmt::Debug;
 use utoipa::ToSchema;
 
-use super::ErrorResponse;
+use super::{ErrorCode, ErrorResponse};
 
 #[derive(ToSchema, serde::Deserialize)]
 pub struct NewKey {
@@ -18,14 +32,136 @@ pub struct NewKey {
     pub pin: Secret<u64>,
     #[schema(value_type = String, example = "$PBKDF2$i=100000,l=256,s=0Bu5lWu4s66/iottrlUGdckjf5nwnpB05jwp4yDh8NU=$AESGM$OrScsD+hHGaRaPbc$XMXVVbjt3JV+QsNb7ZWRc8uNod2YzJL0lSvW1FOiY38ywOu7IEChKs/IqEQ7knhZAmRGYqoB4dhAbdOTwVhYIeQsuf1+f+9ARPEjtURsDg==")]
     pub private_key_hash: Secret<String>,
+    /// Public key of the uploaded private key, as `npub` or hex. Stored with the row and
+    /// served from `/.well-known/nostr.json` when the vault provides the domain.
+    #[schema(example = "npub1dergggklka99wwrs92yz8wdjs952h2ux2ha2ed598ngwu9w7a6fsh9xzpc")]
+    pub npub: String,
+    /// Nonce from `/upload_challenge`, each one can only be used once.
+    #[schema(example = "9c1185a5c5e9fc54612808977ee8f548b2258d31ddadef707ba62c2a8b3fd0e6")]
+    pub challenge: String,
+    /// Hex schnorr signature by `npub` over `sha256(challenge || sha256(private_key_hash))`.
+    #[schema(
+        example = "908a15e46fb4d8675bab026fc230a0e3542bfade63da02d542fb78b2a8513fcd0092619a2c8c1221e581946e0191f2af505dfdf8657a414dbca329186f009262"
+    )]
+    pub signature: String,
+    /// Optional kind 30078 event, signed by `npub`, whose content is `private_key_hash`.
+    /// When relays are configured it is forwarded to them as a backup of the blob.
+    pub nostr_event: Option<NostrEvent>,
+    #[serde(default)]
+    #[schema(example = json!(["wss://relay.damus.io"]))]
+    pub relays: Vec<String>,
+    /// Required once `/work_challenge` reports a difficulty above 0 for uploads.
+    pub proof_of_work: Option<WorkProof>,
+}
+
+impl NewKey {
+    /// `/upload_key` and `POST /v1/keys` share a scope, a retry may switch between them.
+    pub(crate) fn fingerprint(&self) -> RequestFingerprint {
+        self.relays.iter().fold(
+            RequestFingerprint::new("create_key", &self.npub)
+                .field(&self.nip_05_id)
+                .field(self.pin.expose_secret().to_be_bytes())
+                .field(self.private_key_hash.expose_secret())
+                .field(&self.npub)
+                .field(&self.challenge)
+                .field(&self.signature)
+                .optional_field(
+                    self.nostr_event
+                        .as_ref()
+                        .map(|event| serde_json::to_vec(event).unwrap_or_default()),
+                )
+                .optional_field(
+                    self.proof_of_work
+                        .as_ref()
+                        .map(|work| format!("{}:{}", work.challenge, work.nonce)),
+                ),
+            |fingerprint, relay| fingerprint.field(relay),
+        )
+    }
 }
 
 #[derive(ToSchema, thiserror::Error)]
 pub enum UploadError {
+    #[error("{1}")]
+    ValidationError(ErrorCode, String),
+    #[error("{0}")]
+    InvalidProof(String),
+    #[error("{0} is already stored in this vault.")]
+    Nip05Taken(String),
+    #[error("This vault does not accept nip 05 ids on {0}.")]
+    DomainNotAllowed(String),
+    #[error("{0} already holds the {1} keys it is allowed.")]
+    DomainQuotaExceeded(String, u64),
+    #[error("Idempotency-Key was already used for a different request.")]
+    IdempotencyKeyReused,
+    #[error("A request with this Idempotency-Key is still being processed.")]
+    RequestInProgress,
+    #[error("A proof of work with {0} leading zero bits is required, ask /work_challenge for a challenge.")]
+    ProofOfWorkRequired(u8),
     #[error("{0}")]
-    ValidationError(String),
+    InvalidProofOfWork(String),
+    #[error("Uploads cost {} msat, pay the invoice and send `Authorization: L402 <macaroon>:<preimage>`.", .0.amount_msat)]
+    PaymentRequired(L402Challenge),
+    #[error("{0}")]
+    InvalidPayment(String),
+    #[error("Too many pins are being hashed, retry in {0} seconds.")]
+    Overloaded(u64),
     #[error(transparent)]
-    UnexpectedError(#[from] anyhow::Error),
+    UnexpectedError(anyhow::Error),
+}
+
+impl From<anyhow::Error> for UploadError {
+    fn from(e: anyhow::Error) -> Self {
+        match retry_after(&e) {
+            Some(seconds) => UploadError::Overloaded(seconds),
+            None => UploadError::UnexpectedError(e),
+        }
+    }
+}
+
+impl From<WorkError> for UploadError {
+    fn from(e: WorkError) -> Self {
+        match e {
+            WorkError::Required(difficulty) => UploadError::ProofOfWorkRequired(difficulty),
+            WorkError::Invalid(e) => UploadError::InvalidProofOfWork(e),
+            WorkError::UnexpectedError(e) => e.into(),
+        }
+    }
+}
+
+impl From<DomainPolicyError> for UploadError {
+    fn from(e: DomainPolicyError) -> Self {
+        match e {
+            DomainPolicyError::NotAllowed(domain) => UploadError::DomainNotAllowed(domain),
+            DomainPolicyError::QuotaExceeded(domain, max_keys) => {
+                UploadError::DomainQuotaExceeded(domain, max_keys)
+            }
+            DomainPolicyError::UnexpectedError(e) => e.into(),
+        }
+    }
+}
+
+impl From<PaymentError> for UploadError {
+    fn from(e: PaymentError) -> Self {
+        match e {
+            PaymentError::Required(challenge) => UploadError::PaymentRequired(challenge),
+            PaymentError::Invalid(e) => UploadError::InvalidPayment(e),
+            PaymentError::UnexpectedError(e) => e.into(),
+        }
+    }
+}
+
+impl From<IdempotencyError> for UploadError {
+    fn from(e: IdempotencyError) -> Self {
+        match e {
+            IdempotencyError::InvalidKey(e) => {
+                UploadError::ValidationError(ErrorCode::IdempotencyKeyMalformed, e)
+            }
+            IdempotencyError::KeyReused => UploadError::IdempotencyKeyReused,
+            IdempotencyError::InProgress => UploadError::RequestInProgress,
+            IdempotencyError::UnexpectedError(e) => e.into(),
+        }
+    }
 }
 
 impl Debug for UploadError {
@@ -34,17 +170,61 @@ impl Debug for UploadError {
     }
 }
 
+impl UploadError {
+    /// For `map_err` on a failed parse, e.g. `UploadError::malformed(ErrorCode::BlobMalformed)`.
+    pub fn malformed(code: ErrorCode) -> impl FnOnce(String) -> Self {
+        move |e| UploadError::ValidationError(code, e)
+    }
+
+    fn code(&self) -> ErrorCode {
+        match self {
+            UploadError::ValidationError(code, _) => *code,
+            UploadError::InvalidProof(_) => ErrorCode::ProofInvalid,
+            UploadError::Nip05Taken(_) => ErrorCode::Nip05Taken,
+            UploadError::DomainNotAllowed(_) => ErrorCode::DomainNotAllowed,
+            UploadError::DomainQuotaExceeded(..) => ErrorCode::DomainQuotaExceeded,
+            UploadError::IdempotencyKeyReused => ErrorCode::IdempotencyKeyReused,
+            UploadError::RequestInProgress => ErrorCode::RequestInProgress,
+            UploadError::ProofOfWorkRequired(_) => ErrorCode::ProofOfWorkRequired,
+            UploadError::InvalidProofOfWork(_) => ErrorCode::ProofOfWorkInvalid,
+            UploadError::PaymentRequired(_) => ErrorCode::PaymentRequired,
+            UploadError::InvalidPayment(_) => ErrorCode::PaymentInvalid,
+            UploadError::Overloaded(_) => ErrorCode::Overloaded,
+            UploadError::UnexpectedError(_) => ErrorCode::Internal,
+        }
+    }
+}
+
 impl ResponseError for UploadError {
     fn status_code(&self) -> reqwest::StatusCode {
         match self {
-            UploadError::ValidationError(_) => StatusCode::BAD_REQUEST,
+            UploadError::ValidationError(..) => StatusCode::BAD_REQUEST,
+            UploadError::InvalidProof(_) => StatusCode::FORBIDDEN,
+            UploadError::Nip05Taken(_) => StatusCode::CONFLICT,
+            UploadError::DomainNotAllowed(_) => StatusCode::FORBIDDEN,
+            UploadError::DomainQuotaExceeded(..) => StatusCode::FORBIDDEN,
+            UploadError::IdempotencyKeyReused => StatusCode::UNPROCESSABLE_ENTITY,
+            UploadError::RequestInProgress => StatusCode::CONFLICT,
+            UploadError::ProofOfWorkRequired(_) => StatusCode::PRECONDITION_REQUIRED,
+            UploadError::InvalidProofOfWork(_) => StatusCode::FORBIDDEN,
+            UploadError::PaymentRequired(_) => StatusCode::PAYMENT_REQUIRED,
+            UploadError::InvalidPayment(_) => StatusCode::FORBIDDEN,
+            UploadError::Overloaded(_) => StatusCode::SERVICE_UNAVAILABLE,
             UploadError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
     fn error_response(&self) -> actix_web::HttpResponse<actix_web::body::BoxBody> {
-        HttpResponse::build(self.status_code()).json(ErrorResponse {
-            value: self.to_string(),
-        })
+        let mut response = HttpResponse::build(self.status_code());
+        match self {
+            UploadError::Overloaded(seconds) => {
+                response.insert_header((RETRY_AFTER, seconds.to_string()));
+            }
+            UploadError::PaymentRequired(challenge) => {
+                response.insert_header((WWW_AUTHENTICATE, challenge.header_value()));
+            }
+            _ => {}
+        }
+        response.json(ErrorResponse::new(self.code(), self.to_string()))
     }
 }
 #[utoipa::path(
@@ -58,50 +238,197 @@ impl ResponseError for UploadError {
                 created_at: "2023-02-12T01:49:35+00:00".to_string(),
                 nip_05_id: "the_name_is_bob_bob_smith@frogs.cloud".to_string(),
                 private_key_hash: "$PBKDF2$i=100000,l=256,s=0Bu5lWu4s66/iottrlUGdckjf5nwnpB05jwp4yDh8NU=$AESGM$OrScsD+hHGaRaPbc$XMXVVbjt3JV+QsNb7ZWRc8uNod2YzJL0lSvW1FOiY38ywOu7IEChKs/IqEQ7knhZAmRGYqoB4dhAbdOTwVhYIeQsuf1+f+9ARPEjtURsDg==".to_string(),
+                recovery_codes: vec!["ABCDE-FGH23".to_string(), "JKMNP-QRS45".to_string()],
             }),
-            description = "Successfully stored key."),
+            description = "Successfully stored key, `recovery_codes` are not shown again."),
         (
             status = BAD_REQUEST,
-            body = ErrorResponse,example=json!(ErrorResponse{
-                value: "f913b8539438070c0920853da25e8d1a94d799d2b717ac6358ad77b141792989 is not a valid private key.".to_string()
-            }),
+            body = ErrorResponse,example=json!(ErrorResponse::new(ErrorCode::BlobMalformed, "f913b8539438070c0920853da25e8d1a94d799d2b717ac6358ad77b141792989 is not a valid private key.")),
             description = "Object used to upload the private key fails validation."
         ),
+        (
+            status = PAYMENT_REQUIRED,
+            body = ErrorResponse,example=json!(ErrorResponse::new(ErrorCode::PaymentRequired, "Uploads cost 10000 msat, pay the invoice and send `Authorization: L402 <macaroon>:<preimage>`.")),
+            headers(("WWW-Authenticate" = String, description = "`L402 macaroon=\"...\", invoice=\"...\"`")),
+            description = "Payments are on and no `Authorization: L402` was sent, pay the invoice and retry."
+        ),
+        (
+            status = FORBIDDEN,
+            body = ErrorResponse,example=json!(ErrorResponse::new(ErrorCode::ProofInvalid, "Challenge is unknown, expired or already used.")),
+            description = "Signature or challenge does not prove possession of the key, the L402 payment is not valid, or the domain policy refuses the nip 05 id."
+        ),
+        (
+            status = CONFLICT,
+            body = ErrorResponse,example=json!(ErrorResponse::new(ErrorCode::Nip05Taken, "the_name_is_bob_bob_smith@frogs.cloud is already stored in this vault.")),
+            description = "A key is already stored for the nip 05 id, or a request with the same `Idempotency-Key` is still running."
+        ),
+        (
+            status = UNPROCESSABLE_ENTITY,
+            body = ErrorResponse,example=json!(ErrorResponse::new(ErrorCode::IdempotencyKeyReused, "Idempotency-Key was already used for a different request.")),
+            description = "`Idempotency-Key` was sent before with a different body."
+        ),
+        (
+            status = SERVICE_UNAVAILABLE,
+            body = ErrorResponse,
+            example=json!(ErrorResponse::new(ErrorCode::Overloaded, "Too many pins are being hashed, retry in 1 seconds.")),
+            description = "Too many pins are waiting to be hashed, retry after `Retry-After` seconds."
+        ),
         (
             status = INTERNAL_SERVER_ERROR,
             body = ErrorResponse,
-            example=json!(ErrorResponse{
-                value: "Failed to save private key.".to_string()
-            }),
+            example=json!(ErrorResponse::new(ErrorCode::Internal, "Failed to save private key.")),
             description = "Something went terribly wrong."
         ),
     ),
+    params(
+        ("Idempotency-Key" = Option<String>, Header,
+            description = "Retries with the same key and body get the original response back.")
+    ),
     request_body = NewKey
 )]
+#[deprecated(note = "use POST /v1/keys")]
 #[tracing::instrument(
-    skip(new_key, pool),
+    skip(request, new_key, pool, replicator, relay_publisher, nip05_provider, idempotency, proof_of_work, payments, domain_policy),
     fields(
         nip_05_id = %new_key.nip_05_id,
     )
 )]
+#[allow(clippy::too_many_arguments)]
 pub async fn upload_key(
+    request: HttpRequest,
     new_key: web::Json<NewKey>,
     pool: web::Data<PgPool>,
-) -> Result<String, UploadError> {
-    let nip_05_id = Nip05ID::parse(new_key.0.nip_05_id).map_err(UploadError::ValidationError)?;
-    let private_key_hash =
-        PrivateKeyHash::parse(new_key.0.private_key_hash).map_err(UploadError::ValidationError)?;
-    let pin = Pin::parse(new_key.0.pin).map_err(UploadError::ValidationError)?;
+    replicator: web::Data<Replicator>,
+    relay_publisher: web::Data<RelayPublisher>,
+    nip05_provider: web::Data<Nip05Provider>,
+    idempotency: web::Data<Idempotency>,
+    proof_of_work: web::Data<ProofOfWork>,
+    payments: web::Data<Payments>,
+    domain_policy: web::Data<DomainPolicy>,
+) -> Result<web::Json<StoredKey>, UploadError> {
+    let new_key = new_key.0;
+    let stored_key = idempotency
+        .run(&request, new_key.fingerprint(), &pool, || {
+            store_new_key(
+                &request,
+                new_key,
+                &pool,
+                &replicator,
+                &relay_publisher,
+                &nip05_provider,
+                &proof_of_work,
+                &payments,
+                &domain_policy,
+            )
+        })
+        .await?;
+    Ok(web::Json(stored_key))
+}
+
+/// Validates an upload, checks its possession proof and stores it, shared by `/upload_key`
+/// and `POST /v1/keys`. Payment is only asked for once the upload is known to be valid, so
+/// malformed requests and retries replayed by `Idempotency` don't mint invoices.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn store_new_key(
+    request: &HttpRequest,
+    new_key: NewKey,
+    pool: &PgPool,
+    replicator: &Replicator,
+    relay_publisher: &RelayPublisher,
+    nip05_provider: &Nip05Provider,
+    proof_of_work: &ProofOfWork,
+    payments: &Payments,
+    domain_policy: &DomainPolicy,
+) -> Result<StoredKey, UploadError> {
+    let nip_05_id = Nip05ID::parse(new_key.nip_05_id)
+        .map_err(UploadError::malformed(ErrorCode::Nip05Malformed))?;
+    nip05_provider
+        .check_registration(&nip_05_id)
+        .map_err(UploadError::malformed(ErrorCode::Nip05Unavailable))?;
+    let private_key_hash = PrivateKeyHash::parse(new_key.private_key_hash)
+        .map_err(UploadError::malformed(ErrorCode::BlobMalformed))?;
+    let pin = Pin::parse(new_key.pin).map_err(UploadError::malformed(ErrorCode::PinMalformed))?;
+    let pubkey = NostrPublicKey::parse(new_key.npub)
+        .map_err(UploadError::malformed(ErrorCode::PubkeyMalformed))?;
+    let proof = KeyPossessionProof::parse(
+        pubkey,
+        new_key.challenge,
+        new_key.signature,
+        &private_key_hash,
+    )
+    .map_err(UploadError::InvalidProof)?;
+    let app_data_event = new_key
+        .nostr_event
+        .map(|event| AppDataEvent::parse(event, &private_key_hash))
+        .transpose()
+        .map_err(UploadError::malformed(ErrorCode::EventMalformed))?;
+    if let Some(event) = &app_data_event {
+        if event.as_ref().pubkey != proof.pubkey().to_string() {
+            return Err(UploadError::ValidationError(
+                ErrorCode::EventMalformed,
+                "App data event is not signed by the uploaded key.".to_string(),
+            ));
+        }
+    }
+    let relays = new_key
+        .relays
+        .into_iter()
+        .map(RelayUrl::parse)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(UploadError::malformed(ErrorCode::RelayMalformed))?;
+
+    domain_policy.check(&nip_05_id, pool).await?;
+    let paid = payments.authorize(request, pool).await?;
+    proof_of_work
+        .check(
+            WorkRoute::Upload,
+            &nip_05_id,
+            new_key.proof_of_work.as_ref(),
+            pool,
+        )
+        .await?;
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool.")?;
+    if !consume_challenge(
+        proof.challenge(),
+        ChallengePurpose::Upload,
+        &mut transaction,
+    )
+    .await?
+    {
+        return Err(UploadError::InvalidProof(
+            "Challenge is unknown, expired or already used.".to_string(),
+        ));
+    }
+    if let Some(paid) = &paid {
+        paid.spend(&mut transaction).await?;
+    }
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit spent challenge and payment.")?;
 
     let key_info = &KeyInfo {
         nip_05_id,
         pin,
         private_key_hash,
+        pubkey: proof.pubkey().clone(),
+        relays,
     };
 
-    let stored_key = save_private_key_and_pin(key_info, &pool)
-        .await
-        .expect("Failed to save private key and pin.");
+    let saved = save_private_key_and_pin(key_info, replicator.node_id(), pool).await;
+    if let (Some(paid), Ok(None) | Err(_)) = (&paid, &saved) {
+        // Nothing was stored, so the payment is good for another try
+        paid.refund(pool).await?;
+    }
+    let stored_key =
+        saved?.ok_or_else(|| UploadError::Nip05Taken(key_info.nip_05_id.to_string()))?;
+    replicator.replicate(stored_key.nip_05_id.clone(), pool.clone());
+    if let Some(event) = app_data_event {
+        relay_publisher.publish(event);
+    }
 
-    Ok(stored_key.to_string())
+    Ok(stored_key)
 }
@@ -0,0 +1,51 @@
+use crate::challenge::ChallengeIssuer;
+use crate::domain::Nip05ID;
+use crate::proof_of_work::{ProofOfWork, WorkChallenge, WorkRoute};
+use actix_web::{web, HttpResponse};
+use sqlx::PgPool;
+use utoipa::ToSchema;
+
+use super::{ErrorCode, ErrorResponse, LookupError};
+
+#[derive(ToSchema, serde::Deserialize)]
+pub struct WorkChallengeRequest {
+    #[schema(example = "the_name_is_bob_bob_smith@frogs.cloud")]
+    pub nip_05_id: String,
+    pub route: WorkRoute,
+}
+
+#[utoipa::path(
+    post,
+    path = "/work_challenge",
+    responses(
+        (status = OK,
+            body = WorkChallenge,
+            description = "Challenge to solve for the next upload or fetch of the nip 05 id, at the difficulty required right now."),
+        (status = BAD_REQUEST, body = ErrorResponse, description = "nip_05_id is not valid"),
+        (
+            status = INTERNAL_SERVER_ERROR,
+            body = ErrorResponse,
+            example=json!(ErrorResponse::new(ErrorCode::Internal, "Failed to store challenge.")),
+            description = "Something went terribly wrong."
+        ),
+    ),
+    request_body = WorkChallengeRequest
+)]
+#[tracing::instrument(
+    skip(work_request, pool, issuer, proof_of_work),
+    fields(nip_05_id = %work_request.nip_05_id, route = ?work_request.route)
+)]
+pub async fn work_challenge(
+    work_request: web::Json<WorkChallengeRequest>,
+    pool: web::Data<PgPool>,
+    issuer: web::Data<ChallengeIssuer>,
+    proof_of_work: web::Data<ProofOfWork>,
+) -> Result<HttpResponse, LookupError> {
+    let work_request = work_request.0;
+    let nip_05_id = Nip05ID::parse(work_request.nip_05_id)
+        .map_err(LookupError::malformed(ErrorCode::Nip05Malformed))?;
+    let challenge = proof_of_work
+        .issue(work_request.route, &nip_05_id, &issuer, &pool)
+        .await?;
+    Ok(HttpResponse::Ok().json(challenge))
+}
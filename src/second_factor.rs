@@ -0,0 +1,301 @@
+use crate::authentication::AuthError;
+use crate::configuration::SecondFactorSettings;
+use crate::domain::{Nip05ID, TotpCode};
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use anyhow::{anyhow, Context};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use reqwest::Url;
+use secrecy::ExposeSecret;
+use sha1::Sha1;
+use sqlx::PgPool;
+use utoipa::ToSchema;
+
+/// RFC 6238 defaults, which is what every authenticator app expects.
+const PERIOD_SECONDS: i64 = 30;
+const DIGITS: u32 = 6;
+/// Steps either side of now that are still accepted, to allow for clock drift.
+const ALLOWED_SKEW: i64 = 1;
+const NONCE_LENGTH: usize = 12;
+
+#[derive(ToSchema, serde::Serialize, serde::Deserialize)]
+pub struct TotpEnrolment {
+    /// Base32 secret for authenticator apps that can't scan `otpauth_uri`.
+    #[schema(example = "JBSWY3DPEHPK3PXPJBSWY3DPEHPK3PXP")]
+    pub secret: String,
+    #[schema(
+        example = "otpauth://totp/nostr-vault:bob@frogs.cloud?secret=JBSWY3DPEHPK3PXPJBSWY3DPEHPK3PXP&issuer=nostr-vault&algorithm=SHA1&digits=6&period=30"
+    )]
+    pub otpauth_uri: String,
+}
+
+pub fn current_step() -> i64 {
+    chrono::Utc::now().timestamp() / PERIOD_SECONDS
+}
+
+/// HOTP (RFC 4226) over the time step, i.e. a TOTP code.
+pub fn totp_code(secret: &[u8], step: i64) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any size");
+    mac.update(&step.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+    format!(
+        "{:0width$}",
+        binary % 10u32.pow(DIGITS),
+        width = DIGITS as usize
+    )
+}
+
+/// The step `code` was generated for, skipping steps at or before `last_used_step`.
+fn matching_step(
+    secret: &[u8],
+    code: &TotpCode,
+    now_step: i64,
+    last_used_step: Option<i64>,
+) -> Option<i64> {
+    (now_step - ALLOWED_SKEW..=now_step + ALLOWED_SKEW)
+        .filter(|step| last_used_step.map_or(true, |last| *step > last))
+        .find(|step| totp_code(secret, *step) == code.as_ref())
+}
+
+/// Enrols and checks TOTP second factors, the shared secret never leaves the db unencrypted.
+#[derive(Clone)]
+pub struct SecondFactor {
+    cipher: Aes256Gcm,
+    issuer: String,
+}
+
+impl SecondFactor {
+    pub fn new(settings: &SecondFactorSettings) -> Result<Self, anyhow::Error> {
+        let key = hex::decode(settings.encryption_key.expose_secret())
+            .context("Second factor encryption key is not valid hex.")?;
+        let cipher = Aes256Gcm::new_from_slice(&key)
+            .map_err(|_| anyhow!("Second factor encryption key must be 32 bytes."))?;
+        Ok(Self {
+            cipher,
+            issuer: settings.issuer.clone(),
+        })
+    }
+
+    /// The key id is bound in as associated data so secrets can't be swapped between rows.
+    fn encrypt(&self, key_id: i64, secret: &[u8]) -> Result<String, anyhow::Error> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: secret,
+                    aad: &key_id.to_be_bytes(),
+                },
+            )
+            .map_err(|_| anyhow!("Failed to encrypt second factor secret."))?;
+        let mut encrypted = nonce.to_vec();
+        encrypted.extend(ciphertext);
+        Ok(base64::encode(encrypted))
+    }
+
+    fn decrypt(&self, key_id: i64, encrypted: &str) -> Result<Vec<u8>, anyhow::Error> {
+        let encrypted =
+            base64::decode(encrypted).context("Stored second factor secret is not base64.")?;
+        if encrypted.len() <= NONCE_LENGTH {
+            return Err(anyhow!("Stored second factor secret is truncated."));
+        }
+        let (nonce, ciphertext) = encrypted.split_at(NONCE_LENGTH);
+        self.cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &key_id.to_be_bytes(),
+                },
+            )
+            .map_err(|_| anyhow!("Failed to decrypt second factor secret."))
+    }
+
+    /// Starts (or restarts) enrolment, returns `None` if a confirmed factor already exists.
+    #[tracing::instrument(name = "Enrol second factor", skip(self, pool))]
+    pub async fn enrol(
+        &self,
+        key_id: i64,
+        nip_05_id: &Nip05ID,
+        pool: &PgPool,
+    ) -> Result<Option<TotpEnrolment>, anyhow::Error> {
+        let mut secret = [0u8; 20];
+        rand::thread_rng().fill_bytes(&mut secret);
+        let encrypted_secret = self.encrypt(key_id, &secret)?;
+
+        let stored = sqlx::query!(
+            r#"
+            INSERT INTO totp_secrets (key_id, encrypted_secret)
+            VALUES ($1, $2)
+            ON CONFLICT (key_id) DO UPDATE
+            SET encrypted_secret = EXCLUDED.encrypted_secret, created_at = NOW()
+            WHERE totp_secrets.confirmed_at IS NULL
+            RETURNING key_id
+            "#,
+            key_id,
+            encrypted_secret
+        )
+        .fetch_optional(pool)
+        .await
+        .context("Failed to store second factor secret.")?;
+        if stored.is_none() {
+            return Ok(None);
+        }
+
+        let secret = data_encoding::BASE32_NOPAD.encode(&secret);
+        let mut otpauth_uri = Url::parse("otpauth://totp/").context("Failed to build uri.")?;
+        otpauth_uri.set_path(&format!("/{}:{}", self.issuer, nip_05_id));
+        otpauth_uri
+            .query_pairs_mut()
+            .append_pair("secret", &secret)
+            .append_pair("issuer", &self.issuer)
+            .append_pair("algorithm", "SHA1")
+            .append_pair("digits", &DIGITS.to_string())
+            .append_pair("period", &PERIOD_SECONDS.to_string());
+        Ok(Some(TotpEnrolment {
+            secret,
+            otpauth_uri: otpauth_uri.to_string(),
+        }))
+    }
+
+    /// Activates a pending enrolment once the user proves their app produces valid codes.
+    #[tracing::instrument(name = "Confirm second factor", skip(self, code, pool))]
+    pub async fn confirm(
+        &self,
+        key_id: i64,
+        code: &TotpCode,
+        pool: &PgPool,
+    ) -> Result<(), AuthError> {
+        let pending = sqlx::query!(
+            r#"
+            SELECT encrypted_secret
+            FROM totp_secrets
+            WHERE key_id = $1 AND confirmed_at IS NULL
+            "#,
+            key_id
+        )
+        .fetch_optional(pool)
+        .await
+        .context("Failed to perform a query to retrieve second factor.")?
+        .ok_or_else(|| {
+            AuthError::InvalidSecondFactor(anyhow!("No pending second factor enrolment."))
+        })?;
+        let secret = self.decrypt(key_id, &pending.encrypted_secret)?;
+        let step = matching_step(&secret, code, current_step(), None)
+            .ok_or_else(|| AuthError::InvalidSecondFactor(anyhow!("Code does not match.")))?;
+
+        sqlx::query!(
+            r#"
+            UPDATE totp_secrets
+            SET confirmed_at = NOW(), last_used_step = $2
+            WHERE key_id = $1 AND confirmed_at IS NULL
+            "#,
+            key_id,
+            step
+        )
+        .execute(pool)
+        .await
+        .context("Failed to confirm second factor.")?;
+        Ok(())
+    }
+
+    /// Requires an unused valid code once a factor is confirmed, returns whether one was.
+    #[tracing::instrument(name = "Verify second factor", skip(self, code, pool))]
+    pub async fn verify(
+        &self,
+        key_id: i64,
+        code: Option<&TotpCode>,
+        pool: &PgPool,
+    ) -> Result<bool, AuthError> {
+        let enrolled = sqlx::query!(
+            r#"
+            SELECT encrypted_secret, last_used_step
+            FROM totp_secrets
+            WHERE key_id = $1 AND confirmed_at IS NOT NULL
+            "#,
+            key_id
+        )
+        .fetch_optional(pool)
+        .await
+        .context("Failed to perform a query to retrieve second factor.")?;
+        let enrolled = match enrolled {
+            Some(enrolled) => enrolled,
+            None => return Ok(false),
+        };
+        let code = code.ok_or(AuthError::SecondFactorRequired)?;
+
+        let secret = self.decrypt(key_id, &enrolled.encrypted_secret)?;
+        let step = matching_step(&secret, code, current_step(), enrolled.last_used_step)
+            .ok_or_else(|| {
+                AuthError::InvalidSecondFactor(anyhow!("Code does not match or was already used."))
+            })?;
+
+        // Guards against the same code being raced through two requests at once.
+        let consumed = sqlx::query!(
+            r#"
+            UPDATE totp_secrets
+            SET last_used_step = $2
+            WHERE key_id = $1 AND (last_used_step IS NULL OR last_used_step < $2)
+            RETURNING key_id
+            "#,
+            key_id,
+            step
+        )
+        .fetch_optional(pool)
+        .await
+        .context("Failed to record used second factor code.")?;
+        if consumed.is_none() {
+            return Err(AuthError::InvalidSecondFactor(anyhow!(
+                "Code was already used."
+            )));
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{matching_step, totp_code};
+    use crate::domain::TotpCode;
+    use secrecy::Secret;
+
+    // RFC 6238 appendix B, SHA1, truncated to 6 digits
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn rfc_6238_test_vectors() {
+        for (time, expected) in [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+        ] {
+            assert_eq!(totp_code(RFC_SECRET, time / 30), expected, "time {}", time);
+        }
+    }
+
+    #[test]
+    fn codes_from_adjacent_steps_are_accepted() {
+        let code = TotpCode::parse(Secret::new(totp_code(RFC_SECRET, 99))).unwrap();
+        assert_eq!(matching_step(RFC_SECRET, &code, 100, None), Some(99));
+        assert_eq!(matching_step(RFC_SECRET, &code, 98, None), Some(99));
+        assert_eq!(matching_step(RFC_SECRET, &code, 101, None), None);
+    }
+
+    #[test]
+    fn used_steps_are_rejected() {
+        let code = TotpCode::parse(Secret::new(totp_code(RFC_SECRET, 100))).unwrap();
+        assert_eq!(matching_step(RFC_SECRET, &code, 100, Some(99)), Some(100));
+        assert_eq!(matching_step(RFC_SECRET, &code, 100, Some(100)), None);
+    }
+}
@@ -0,0 +1,33 @@
+use crate::configuration::SecuritySettings;
+use actix_web::http::header::{
+    CACHE_CONTROL, CONTENT_SECURITY_POLICY, REFERRER_POLICY, STRICT_TRANSPORT_SECURITY,
+    X_CONTENT_TYPE_OPTIONS,
+};
+use actix_web::middleware::DefaultHeaders;
+
+/// The example page only loads its own script and calls back to the vault, anything else it
+/// would pull in, e.g. through a key typed into it, stays blocked.
+pub const EXAMPLE_UI_CSP: &str = "default-src 'none'; script-src 'self'; connect-src 'self'; \
+    style-src 'self'; img-src 'self'; base-uri 'none'; form-action 'none'; \
+    frame-ancestors 'none'";
+
+/// Sent on every response of the application port.
+pub fn default_headers(settings: &SecuritySettings) -> DefaultHeaders {
+    let headers = DefaultHeaders::new()
+        .add((X_CONTENT_TYPE_OPTIONS, "nosniff"))
+        .add((REFERRER_POLICY, "no-referrer"));
+    match settings.hsts_max_age_seconds {
+        Some(max_age) => headers.add((STRICT_TRANSPORT_SECURITY, format!("max-age={}", max_age))),
+        None => headers,
+    }
+}
+
+/// Responses of the key routes carry blobs, recovery codes and enrolment secrets, none of
+/// which may end up in a browser or proxy cache.
+pub fn no_store() -> DefaultHeaders {
+    DefaultHeaders::new().add((CACHE_CONTROL, "no-store"))
+}
+
+pub fn example_ui_csp() -> DefaultHeaders {
+    DefaultHeaders::new().add((CONTENT_SECURITY_POLICY, EXAMPLE_UI_CSP))
+}
@@ -0,0 +1,237 @@
+use crate::configuration::SessionSettings;
+use anyhow::{anyhow, Context};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use secrecy::{ExposeSecret, Secret};
+use sha2::Sha256;
+use utoipa::ToSchema;
+
+/// The only header tokens are issued with, anything else is rejected before the signature
+/// is looked at, so there is no `alg` to negotiate.
+const HEADER: &str = r#"{"alg":"HS256","typ":"JWT"}"#;
+
+#[derive(thiserror::Error, Debug)]
+pub enum SessionError {
+    #[error("{0}")]
+    Invalid(String),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+/// Operation a session token may be used for, in place of the pin.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ToSchema, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionScope {
+    /// `PUT /v1/keys/{nip_05_id}`
+    Update,
+    /// `DELETE /v1/keys/{nip_05_id}`
+    Delete,
+    /// `GET /v1/keys/{nip_05_id}/slots`
+    ListSlots,
+}
+
+#[derive(ToSchema, serde::Serialize, serde::Deserialize)]
+pub struct IssuedSession {
+    /// Sent as `Authorization: Bearer <token>`.
+    #[schema(
+        example = "eyJhbGciOiJIUzI1NiIsInR5cCI6IkpXVCJ9.eyJzdWIiOiJib2JAZnJvZ3MuY2xvdWQifQ.c2ln"
+    )]
+    pub token: String,
+    #[schema(example = "2023-02-12T01:54:35+00:00")]
+    pub expires_at: String,
+    pub scopes: Vec<SessionScope>,
+}
+
+/// JWT claims. `pin` ties the token to the pin hash it was issued under, so a new pin,
+/// whether set by an update, a recovery or a peer, revokes every token issued before it.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct SessionClaims {
+    pub sub: String,
+    pub scope: Vec<SessionScope>,
+    pub iat: i64,
+    pub exp: i64,
+    pin: String,
+}
+
+/// Issues and checks the short-lived tokens handed out after a pin was verified.
+#[derive(Clone)]
+pub struct Sessions {
+    /// Keyed with the signing key, cloned for every token.
+    signer: Hmac<Sha256>,
+    ttl: chrono::Duration,
+}
+
+impl Sessions {
+    pub fn new(settings: &SessionSettings) -> Result<Self, anyhow::Error> {
+        let signing_key = hex::decode(settings.signing_key.expose_secret())
+            .context("Session signing key is not valid hex.")?;
+        if signing_key.len() < 32 {
+            return Err(anyhow!("Session signing key must be at least 32 bytes."));
+        }
+        Ok(Self {
+            signer: Hmac::<Sha256>::new_from_slice(&signing_key)
+                .expect("HMAC accepts keys of any size"),
+            ttl: chrono::Duration::seconds(settings.ttl_seconds as i64),
+        })
+    }
+
+    pub fn issue(
+        &self,
+        nip_05_id: &str,
+        pin_hash: &Secret<String>,
+        scopes: Vec<SessionScope>,
+    ) -> Result<IssuedSession, anyhow::Error> {
+        let now = Utc::now();
+        let expires_at = now + self.ttl;
+        let claims = SessionClaims {
+            sub: nip_05_id.to_string(),
+            scope: scopes.clone(),
+            iat: now.timestamp(),
+            exp: expires_at.timestamp(),
+            pin: self.pin_binding(pin_hash),
+        };
+        let signing_input = format!(
+            "{}.{}",
+            encode(HEADER),
+            encode(serde_json::to_vec(&claims)?)
+        );
+        let signature = encode(self.mac(&signing_input).finalize().into_bytes());
+        Ok(IssuedSession {
+            token: format!("{}.{}", signing_input, signature),
+            expires_at: expires_at.to_rfc3339(),
+            scopes,
+        })
+    }
+
+    /// Signature and expiry only, whether the pin changed since is up to the caller to
+    /// check with `is_bound_to`.
+    pub fn verify(&self, token: &str) -> Result<SessionClaims, SessionError> {
+        let invalid = || SessionError::Invalid("Session token is not valid.".to_string());
+        let (signing_input, signature) = token.rsplit_once('.').ok_or_else(invalid)?;
+        let (header, claims) = signing_input.split_once('.').ok_or_else(invalid)?;
+        if header != encode(HEADER) {
+            return Err(invalid());
+        }
+        let signature = decode(signature).map_err(|_| invalid())?;
+        self.mac(signing_input)
+            .verify_slice(&signature)
+            .map_err(|_| invalid())?;
+        let claims: SessionClaims = decode(claims)
+            .ok()
+            .and_then(|claims| serde_json::from_slice(&claims).ok())
+            .ok_or_else(invalid)?;
+        if claims.exp <= Utc::now().timestamp() {
+            return Err(SessionError::Invalid(
+                "Session token has expired.".to_string(),
+            ));
+        }
+        Ok(claims)
+    }
+
+    /// False once the pin the token was issued under has been replaced.
+    pub fn is_bound_to(&self, claims: &SessionClaims, pin_hash: &Secret<String>) -> bool {
+        let expected = self.pin_binding(pin_hash);
+        expected.len() == claims.pin.len()
+            && expected
+                .bytes()
+                .zip(claims.pin.bytes())
+                .fold(0, |acc, (x, y)| acc | (x ^ y))
+                == 0
+    }
+
+    /// Keyed, so the token does not carry anything derived from the pin hash alone.
+    fn pin_binding(&self, pin_hash: &Secret<String>) -> String {
+        let mut mac = self.mac("pin");
+        mac.update(pin_hash.expose_secret().as_bytes());
+        encode(mac.finalize().into_bytes())
+    }
+
+    fn mac(&self, data: &str) -> Hmac<Sha256> {
+        let mut mac = self.signer.clone();
+        mac.update(data.as_bytes());
+        mac
+    }
+}
+
+fn encode(data: impl AsRef<[u8]>) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+fn decode(data: &str) -> Result<Vec<u8>, base64::DecodeError> {
+    base64::decode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SessionScope, Sessions};
+    use crate::configuration::SessionSettings;
+    use claim::{assert_err, assert_ok};
+    use secrecy::Secret;
+
+    fn sessions(signing_key: &str) -> Sessions {
+        Sessions::new(&SessionSettings {
+            signing_key: Secret::new(signing_key.to_string()),
+            ttl_seconds: 300,
+        })
+        .unwrap()
+    }
+
+    const KEY: &str = "5e1b0f3c9a2d4e6f8a0b1c2d3e4f5a6b7c8d9e0f1a2b3c4d5e6f7a8b9c0d1e2f";
+
+    #[test]
+    fn issued_tokens_verify_and_carry_their_scopes() {
+        let sessions = sessions(KEY);
+        let pin_hash = Secret::new("$argon2id$pin".to_string());
+        let issued = sessions
+            .issue("bob@frogs.cloud", &pin_hash, vec![SessionScope::Update])
+            .unwrap();
+
+        let claims = assert_ok!(sessions.verify(&issued.token));
+        assert_eq!(claims.sub, "bob@frogs.cloud");
+        assert_eq!(claims.scope, vec![SessionScope::Update]);
+        assert!(sessions.is_bound_to(&claims, &pin_hash));
+        assert!(!sessions.is_bound_to(&claims, &Secret::new("$argon2id$new".to_string())));
+    }
+
+    #[test]
+    fn tampered_or_foreign_tokens_are_rejected() {
+        let pin_hash = Secret::new("$argon2id$pin".to_string());
+        let token = sessions(KEY)
+            .issue("bob@frogs.cloud", &pin_hash, vec![SessionScope::Delete])
+            .unwrap()
+            .token;
+        let other = sessions(&"ab".repeat(32));
+
+        assert_err!(other.verify(&token));
+        assert_err!(sessions(KEY).verify(&token.replacen('e', "f", 1)));
+        assert_err!(sessions(KEY).verify("not.a.token"));
+        assert_err!(sessions(KEY).verify(""));
+    }
+
+    #[test]
+    fn expired_tokens_are_rejected() {
+        let expired = Sessions::new(&SessionSettings {
+            signing_key: Secret::new(KEY.to_string()),
+            ttl_seconds: 0,
+        })
+        .unwrap();
+        let token = expired
+            .issue(
+                "bob@frogs.cloud",
+                &Secret::new("$argon2id$pin".to_string()),
+                vec![SessionScope::Update],
+            )
+            .unwrap()
+            .token;
+
+        assert_err!(expired.verify(&token));
+    }
+
+    #[test]
+    fn short_signing_keys_are_refused() {
+        assert_err!(Sessions::new(&SessionSettings {
+            signing_key: Secret::new("abcd".to_string()),
+            ttl_seconds: 300,
+        }));
+    }
+}
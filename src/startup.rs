@@ -1,13 +1,49 @@
+use crate::authentication::normalize_stored_nip_05_ids;
+use crate::challenge::{purge_challenges, ChallengeIssuer};
 use crate::configuration::{DatabaseSettings, Settings};
-use crate::routes::{fetch_key, health_check, upload_key};
-use actix_cors::Cors;
+use crate::cors::CorsPolicy;
+use crate::domain_policy::DomainPolicy;
+use crate::enumeration::{shield_lookup, EnumerationResistance};
+use crate::hashing;
+use crate::health::Readiness;
+use crate::idempotency::{purge_expired as purge_expired_idempotency_keys, Idempotency};
+use crate::key_expiry::KeyExpiry;
+use crate::lightning::node_from_settings;
+use crate::metrics::track_request;
+use crate::nip05_provider::Nip05Provider;
+use crate::passkeys::{purge_expired_ceremonies, Passkeys};
+use crate::payments::Payments;
+use crate::proof_of_work::ProofOfWork;
+use crate::relay_publisher::RelayPublisher;
+use crate::replication::Replicator;
+use crate::request_id::scope_request_id;
+use crate::routes::{
+    confirm_second_factor, create_key, create_session, delete_key, enrol_second_factor, fetch_key,
+    fetch_key_with_passkey, finish_passkey_registration, json_config, list_key_slots, livez,
+    metrics, nip05_availability, nostr_json, query_config, readyz, receive_replicated_key,
+    recover_key, replicated_keys, retrieve_key, route_not_found, start_passkey_authentication,
+    start_passkey_registration, update_key, upload_challenge, upload_key, work_challenge,
+    ReplicationAuthKey,
+};
+use crate::second_factor::SecondFactor;
+use crate::security_headers::{default_headers, example_ui_csp, no_store};
+use crate::session::Sessions;
+use crate::supervisor::TaskSupervisor;
+use crate::tls::{reload_on_sighup, server_config, ReloadableCertificate};
 use actix_files::Files;
-use actix_web::dev::Server;
+use actix_web::dev::{Server, ServerHandle};
+use actix_web::http::header::LINK;
+use actix_web::middleware::DefaultHeaders;
 use actix_web::web::Data;
-use actix_web::{web, App, HttpServer};
+use actix_web::{web, App, HttpServer, Route};
+use anyhow::anyhow;
+use rustls::ServerConfig;
+use secrecy::Secret;
 use sqlx::postgres::PgPoolOptions;
 use sqlx::PgPool;
+use std::future::Future;
 use std::net::TcpListener;
+use std::time::Duration;
 use tracing::info;
 use tracing_actix_web::TracingLogger;
 use utoipa::openapi::{License, LicenseBuilder};
@@ -17,15 +53,66 @@ use utoipa_swagger_ui::SwaggerUi;
 #[derive(OpenApi)]
 #[openapi(
     paths(
+        crate::routes::create_key,
+        crate::routes::retrieve_key,
+        crate::routes::update_key,
+        crate::routes::delete_key,
+        crate::routes::list_key_slots,
+        crate::routes::create_session,
         crate::routes::fetch_key,
-        crate::routes::health_check,
-        crate::routes::upload_key
+        crate::routes::recover_key,
+        crate::routes::enrol_second_factor,
+        crate::routes::confirm_second_factor,
+        crate::routes::start_passkey_registration,
+        crate::routes::finish_passkey_registration,
+        crate::routes::start_passkey_authentication,
+        crate::routes::fetch_key_with_passkey,
+        crate::routes::livez,
+        crate::routes::readyz,
+        crate::routes::metrics,
+        crate::routes::upload_challenge,
+        crate::routes::work_challenge,
+        crate::routes::upload_key,
+        crate::routes::receive_replicated_key,
+        crate::routes::replicated_keys,
+        crate::routes::nostr_json,
+        crate::routes::nip05_availability
     ),
     components(
         schemas(crate::routes::KeyLookup,
+                crate::routes::KeyRecovery,
+                crate::routes::SecondFactorEnrolment,
+                crate::routes::SecondFactorConfirmation,
+                crate::second_factor::TotpEnrolment,
+                crate::routes::NewPasskey,
+                crate::routes::PasskeyAuthenticationStart,
+                crate::routes::PasskeyLookup,
+                crate::passkeys::PasskeyAssertion,
+                crate::passkeys::PasskeyRegistrationOptions,
+                crate::passkeys::PasskeyAuthenticationOptions,
                 crate::authentication::StoredKey,
+                crate::authentication::KeySlots,
+                crate::authentication::PasskeySlot,
                 crate::routes::NewKey,
-                crate::routes::ErrorResponse)
+                crate::routes::KeyCredentials,
+                crate::routes::KeyUpdate,
+                crate::routes::SessionRequest,
+                crate::session::IssuedSession,
+                crate::session::SessionScope,
+                crate::challenge::Challenge,
+                crate::routes::WorkChallengeRequest,
+                crate::proof_of_work::WorkChallenge,
+                crate::proof_of_work::WorkProof,
+                crate::proof_of_work::WorkRoute,
+                crate::routes::ErrorResponse,
+                crate::routes::ErrorCode,
+                crate::domain::NostrEvent,
+                crate::replication::ReplicatedKey,
+                crate::nip05_provider::NostrJson,
+                crate::nip05_provider::NameAvailability,
+                crate::health::ReadinessReport,
+                crate::health::CheckResult,
+                crate::health::CheckStatus)
     ),
     tags(
         (name = "nostr-vault", description = "Simple api for storing nostr private keys")
@@ -35,7 +122,26 @@ struct ApiDoc;
 
 pub struct Application {
     port: u16,
+    metrics_port: Option<u16>,
     server: Server,
+    metrics_server: Option<ServerHandle>,
+    admin_port: Option<u16>,
+    admin_server: Option<ServerHandle>,
+    supervisor: TaskSupervisor,
+    db_pool: PgPool,
+    shutdown_timeout: Duration,
+}
+
+/// Stops a running `Application` from outside, e.g. at the end of a test.
+#[derive(Clone)]
+pub struct StopHandle(ServerHandle);
+
+impl StopHandle {
+    /// Asks the server to stop straight away, the returned future resolves once it has.
+    /// `run_until_stopped` then winds down the background tasks and the pool.
+    pub fn stop(&self, graceful: bool) -> impl Future<Output = ()> {
+        self.0.stop(graceful)
+    }
 }
 
 pub struct ApplicationBaseUrl(pub String);
@@ -47,24 +153,235 @@ impl Application {
             "{}:{}",
             configuration.application.host, configuration.application.port
         );
-        let listener = TcpListener::bind(address.clone())?;
+        let listener = TcpListener::bind(address)?;
         let port = listener.local_addr().unwrap().port();
+
+        let certificate = match &configuration.application.tls {
+            Some(tls) => Some(ReloadableCertificate::load(tls)?),
+            None => None,
+        };
+        if configuration.admin.client_ca_path.is_some() && certificate.is_none() {
+            return Err(anyhow!(
+                "admin.client_ca_path needs application.tls to be configured."
+            ));
+        }
+
+        hashing::init(&configuration.hashing)?;
+
+        let mut supervisor = TaskSupervisor::new();
+        if let Some(certificate) = &certificate {
+            supervisor.spawn("tls reload", reload_on_sighup(certificate.clone())?);
+        }
+        let pool = connection_pool.clone();
+        let apply = configuration.nip_05_id_normalization.apply;
+        supervisor.spawn("nip 05 id normalization", async move {
+            match normalize_stored_nip_05_ids(apply, &pool).await {
+                Ok(report) if apply => info!(
+                    "normalized {} stored nip 05 ids, moved {} to legacy_keys",
+                    report.normalized.len(),
+                    report.moved.len()
+                ),
+                Ok(report) => info!(
+                    "{} stored nip 05 ids would be normalized and {} moved to legacy_keys, \
+                    set nip_05_id_normalization.apply to do so",
+                    report.normalized.len(),
+                    report.moved.len()
+                ),
+                Err(e) => tracing::error!("Failed to normalize stored nip 05 ids: {:?}", e),
+            }
+        });
+        let replicator = Data::new(Replicator::new(&configuration.replication)?);
+        if replicator.has_peers() {
+            let replicator = replicator.clone();
+            let pool = connection_pool.clone();
+            supervisor.spawn("startup replication pull", async move {
+                let applied = replicator.pull_from_peers(&pool).await;
+                info!("pulled {} keys from peers on startup", applied);
+            });
+        }
+
+        let key_expiry = KeyExpiry::new(&configuration.key_expiry);
+        if key_expiry.is_enabled() {
+            let pool = connection_pool.clone();
+            supervisor.spawn_periodic("key expiry", key_expiry.interval(), move || {
+                let key_expiry = key_expiry.clone();
+                let pool = pool.clone();
+                async move {
+                    if let Err(e) = key_expiry.run_once(&pool).await {
+                        tracing::error!("Key expiry pass failed: {:?}", e);
+                    }
+                }
+            });
+        }
+
+        // Expired rows are already ignored, this only keeps the table small
+        let pool = connection_pool.clone();
+        supervisor.spawn_periodic(
+            "idempotency key purge",
+            Duration::from_secs(3600),
+            move || {
+                let pool = pool.clone();
+                async move {
+                    if let Err(e) = purge_expired_idempotency_keys(&pool).await {
+                        tracing::error!("Idempotency key purge failed: {:?}", e);
+                    }
+                }
+            },
+        );
+
+        // Same for challenges, only their nonces have to stay unique while they are valid
+        let pool = connection_pool.clone();
+        supervisor.spawn_periodic("challenge purge", Duration::from_secs(3600), move || {
+            let pool = pool.clone();
+            async move {
+                if let Err(e) = purge_challenges(&pool).await {
+                    tracing::error!("Challenge purge failed: {:?}", e);
+                }
+            }
+        });
+
+        let pool = connection_pool.clone();
+        supervisor.spawn_periodic(
+            "passkey ceremony purge",
+            Duration::from_secs(3600),
+            move || {
+                let pool = pool.clone();
+                async move {
+                    if let Err(e) = purge_expired_ceremonies(&pool).await {
+                        tracing::error!("Passkey ceremony purge failed: {:?}", e);
+                    }
+                }
+            },
+        );
+
+        let proof_of_work = ProofOfWork::new(&configuration.proof_of_work);
+        if proof_of_work.scales_with_failures() {
+            let pool = connection_pool.clone();
+            supervisor.spawn_periodic(
+                "failed attempt purge",
+                Duration::from_secs(600),
+                move || {
+                    let proof_of_work = proof_of_work.clone();
+                    let pool = pool.clone();
+                    async move {
+                        if let Err(e) = proof_of_work.purge_failed_attempts(&pool).await {
+                            tracing::error!("Failed attempt purge failed: {:?}", e);
+                        }
+                    }
+                },
+            );
+        }
+
+        let (metrics_server, metrics_port) = match configuration.metrics.port {
+            Some(metrics_port) => {
+                let listener = TcpListener::bind(format!(
+                    "{}:{}",
+                    configuration.application.host, metrics_port
+                ))?;
+                let metrics_port = listener.local_addr().unwrap().port();
+                let server = run_metrics(listener, connection_pool.clone())?;
+                let handle = server.handle();
+                supervisor.spawn("metrics server", async move {
+                    if let Err(e) = server.await {
+                        tracing::error!("Metrics server failed: {:?}", e);
+                    }
+                });
+                (Some(handle), Some(metrics_port))
+            }
+            None => (None, None),
+        };
+
+        let (admin_server, admin_port) = match configuration.admin.port {
+            Some(admin_port) => {
+                let listener = TcpListener::bind(format!(
+                    "{}:{}",
+                    configuration.application.host, admin_port
+                ))?;
+                let admin_port = listener.local_addr().unwrap().port();
+                let tls = match &certificate {
+                    Some(certificate) => Some(server_config(
+                        certificate.clone(),
+                        configuration.admin.client_ca_path.as_deref(),
+                    )?),
+                    None => None,
+                };
+                let server = run_admin(
+                    listener,
+                    connection_pool.clone(),
+                    configuration.replication.auth_key.clone(),
+                    configuration.application.max_body_bytes,
+                    tls,
+                )?;
+                let handle = server.handle();
+                supervisor.spawn("admin server", async move {
+                    if let Err(e) = server.await {
+                        tracing::error!("Admin server failed: {:?}", e);
+                    }
+                });
+                (Some(handle), Some(admin_port))
+            }
+            None => (None, None),
+        };
+
+        let shutdown_timeout =
+            Duration::from_secs(configuration.application.shutdown_timeout_seconds);
+        let tls = match certificate {
+            Some(certificate) => Some(server_config(certificate, None)?),
+            None => None,
+        };
         let server = run(
-            address,
             listener,
-            connection_pool,
-            configuration.application.base_url,
+            connection_pool.clone(),
+            replicator,
+            configuration,
+            tls,
         )
         .await?;
-        Ok(Self { port, server })
+        Ok(Self {
+            port,
+            metrics_port,
+            server,
+            metrics_server,
+            admin_port,
+            admin_server,
+            supervisor,
+            db_pool: connection_pool,
+            shutdown_timeout,
+        })
     }
 
     pub fn port(&self) -> u16 {
         self.port
     }
 
+    /// Port `/metrics` is served on when it is kept off the application port.
+    pub fn metrics_port(&self) -> Option<u16> {
+        self.metrics_port
+    }
+
+    /// Port the replication routes are served on when they are kept off the application port.
+    pub fn admin_port(&self) -> Option<u16> {
+        self.admin_port
+    }
+
+    pub fn stop_handle(&self) -> StopHandle {
+        StopHandle(self.server.handle())
+    }
+
+    /// Serves until stopped by SIGINT/SIGTERM or a `StopHandle`. In-flight requests get
+    /// `shutdown_timeout_seconds` to drain, background tasks get as long again after that.
     pub async fn run_until_stopped(self) -> Result<(), std::io::Error> {
-        self.server.await
+        let served = self.server.await;
+        info!("server stopped, shutting down background tasks");
+        if let Some(metrics_server) = self.metrics_server {
+            metrics_server.stop(true).await;
+        }
+        if let Some(admin_server) = self.admin_server {
+            admin_server.stop(true).await;
+        }
+        self.supervisor.shutdown(self.shutdown_timeout).await;
+        self.db_pool.close().await;
+        served
     }
 }
 
@@ -76,36 +393,283 @@ pub fn get_connection_pool(configuration: &DatabaseSettings) -> PgPool {
 
 //We are creating an App instance on every thread
 pub async fn run(
-    address: String,
     listener: TcpListener,
     db_pool: PgPool,
-    base_url: String,
+    replicator: Data<Replicator>,
+    configuration: Settings,
+    tls: Option<ServerConfig>,
 ) -> Result<Server, anyhow::Error> {
+    let address = listener.local_addr()?;
     let db_pool = Data::new(db_pool);
+    let base_url = configuration.application.base_url;
+    let passkeys = Data::new(Passkeys::new(
+        &base_url,
+        &configuration.challenges,
+        &configuration.second_factor.encryption_key,
+    ));
     let base_url = Data::new(ApplicationBaseUrl(base_url));
+    let relay_publisher = Data::new(RelayPublisher::new(&configuration.relay_publisher));
+    let nip05_provider = Data::new(Nip05Provider::new(&configuration.nip05_provider));
+    let challenge_issuer = Data::new(ChallengeIssuer::new(&configuration.challenges));
+    let proof_of_work = Data::new(ProofOfWork::new(&configuration.proof_of_work));
+    let domain_policy = Data::new(DomainPolicy::new(&configuration.domain_policy)?);
+    let payments = Data::new(Payments::new(
+        &configuration.payments,
+        configuration
+            .payments
+            .node
+            .as_ref()
+            .map(node_from_settings)
+            .transpose()?,
+    )?);
+    let idempotency = Data::new(Idempotency::new(
+        &configuration.idempotency,
+        &configuration.second_factor.encryption_key,
+    ));
+    let second_factor = Data::new(SecondFactor::new(&configuration.second_factor)?);
+    let sessions = Data::new(Sessions::new(&configuration.sessions)?);
+    let enumeration_resistance = Data::new(EnumerationResistance::new(
+        &configuration.enumeration_resistance,
+    ));
+    let readiness = Data::new(Readiness::new(&configuration.readiness));
+    let metrics_on_app_port = configuration.metrics.port.is_none();
+    let nip_05_lookups = !configuration.enumeration_resistance.enabled
+        || configuration.enumeration_resistance.serve_nip_05_lookups;
+    let replication_auth_key =
+        match configuration.admin.port.is_none() && configuration.replication.serve_on_app_port {
+            true => Some(Data::new(ReplicationAuthKey::new(
+                configuration.replication.auth_key,
+            )?)),
+            false => None,
+        };
+    let shutdown_timeout = configuration.application.shutdown_timeout_seconds;
+    let max_body_bytes = configuration.application.max_body_bytes;
+    let keys_cors = CorsPolicy::parse("keys", &configuration.application.cors.keys)?;
+    let public_cors = CorsPolicy::parse("public", &configuration.application.cors.public)?;
+    let security = configuration.security;
+    let swagger_ui = security.swagger_ui;
     let server = HttpServer::new(move || {
-        let cors = Cors::default().allow_any_origin().send_wildcard();
-        let mut openapi = ApiDoc::openapi();
-        openapi.info.license = get_license();
-
         App::new()
+            .wrap(default_headers(&security))
+            .wrap_fn(scope_request_id)
+            .wrap_fn(track_request)
             .wrap(TracingLogger::default())
-            .wrap(cors)
-            .route("/fetch_key", web::post().to(fetch_key))
-            .route("/upload_key", web::post().to(upload_key))
-            .route("/health_check", web::get().to(health_check))
+            .configure(|cfg| {
+                route_group(
+                    cfg,
+                    &keys_cors,
+                    &no_store(),
+                    true,
+                    vec![
+                        ("/v1/keys", web::post().to(create_key)),
+                        (
+                            "/v1/keys/{nip_05_id}/retrieve",
+                            web::post().to(retrieve_key),
+                        ),
+                        ("/v1/keys/{nip_05_id}", web::put().to(update_key)),
+                        ("/v1/keys/{nip_05_id}", web::delete().to(delete_key)),
+                        ("/v1/keys/{nip_05_id}/slots", web::get().to(list_key_slots)),
+                        (
+                            "/v1/keys/{nip_05_id}/sessions",
+                            web::post().to(create_session),
+                        ),
+                        ("/recover_key", web::post().to(recover_key)),
+                        ("/second_factor/enrol", web::post().to(enrol_second_factor)),
+                        (
+                            "/second_factor/confirm",
+                            web::post().to(confirm_second_factor),
+                        ),
+                        (
+                            "/passkey/register/start",
+                            web::post().to(start_passkey_registration),
+                        ),
+                        (
+                            "/passkey/register/finish",
+                            web::post().to(finish_passkey_registration),
+                        ),
+                        (
+                            "/passkey/authenticate/start",
+                            web::post().to(start_passkey_authentication),
+                        ),
+                        ("/passkey/fetch_key", web::post().to(fetch_key_with_passkey)),
+                        ("/upload_challenge", web::post().to(upload_challenge)),
+                        ("/work_challenge", web::post().to(work_challenge)),
+                    ],
+                );
+                legacy_key_routes(cfg, &keys_cors, &no_store());
+            })
+            .configure(|cfg| {
+                let mut routes = vec![
+                    ("/livez", web::get().to(livez)),
+                    ("/readyz", web::get().to(readyz)),
+                ];
+                if nip_05_lookups {
+                    routes.push(("/nip05/availability", web::get().to(nip05_availability)));
+                    // Outside the CORS groups, `nostr_json` lets any origin read it itself
+                    cfg.service(
+                        web::resource("/.well-known/nostr.json").route(web::get().to(nostr_json)),
+                    );
+                }
+                if metrics_on_app_port {
+                    routes.push(("/metrics", web::get().to(metrics)));
+                }
+                route_group(cfg, &public_cors, &DefaultHeaders::new(), false, routes);
+                if let Some(auth_key) = &replication_auth_key {
+                    cfg.app_data(auth_key.clone()).configure(replication_routes);
+                }
+            })
+            .app_data(json_config(max_body_bytes))
+            .app_data(query_config())
             .app_data(db_pool.clone())
             .app_data(base_url.clone())
-            .service(SwaggerUi::new("/swagger-ui/{_:.*}").url("/api-doc/openapi.json", openapi))
-            .service(
-                Files::new("/example", "./dist/")
-                    .index_file("index.html")
-                    .show_files_listing(),
-            )
+            .app_data(replicator.clone())
+            .app_data(relay_publisher.clone())
+            .app_data(nip05_provider.clone())
+            .app_data(challenge_issuer.clone())
+            .app_data(proof_of_work.clone())
+            .app_data(payments.clone())
+            .app_data(domain_policy.clone())
+            .app_data(idempotency.clone())
+            .app_data(second_factor.clone())
+            .app_data(sessions.clone())
+            .app_data(enumeration_resistance.clone())
+            .app_data(passkeys.clone())
+            .app_data(readiness.clone())
+            .configure(|cfg| {
+                if security.swagger_ui {
+                    let mut openapi = ApiDoc::openapi();
+                    openapi.info.license = get_license();
+                    cfg.service(
+                        SwaggerUi::new("/swagger-ui/{_:.*}").url("/api-doc/openapi.json", openapi),
+                    );
+                }
+                if security.example_ui {
+                    let mut files = Files::new("", "./dist/").index_file("index.html");
+                    if security.directory_listing {
+                        files = files.show_files_listing();
+                    }
+                    cfg.service(web::scope("/example").wrap(example_ui_csp()).service(files));
+                }
+            })
+            .default_service(web::route().to(route_not_found))
+    })
+    .shutdown_timeout(shutdown_timeout);
+    let (server, scheme) = match tls {
+        Some(tls) => (server.listen_rustls_0_21(listener, tls)?, "https"),
+        None => (server.listen(listener)?, "http"),
+    };
+    if swagger_ui {
+        info!("running at {}://{}/swagger-ui/  ", scheme, address);
+    } else {
+        info!("running at {}://{}", scheme, address);
+    }
+    Ok(server.run())
+}
+
+/// Registers each route as its own resource wrapped in the group's CORS policy and `headers`,
+/// and in `shield_lookup` for groups that take a pin. The groups all live at the root, and
+/// actix only runs the first scope matching a path, so the groups can not be scopes. Routes
+/// sharing a path end up on one resource, otherwise the first resource would answer every
+/// other method with a 405.
+fn route_group(
+    cfg: &mut web::ServiceConfig,
+    cors: &CorsPolicy,
+    headers: &DefaultHeaders,
+    shield_lookups: bool,
+    routes: Vec<(&str, Route)>,
+) {
+    let mut resources: Vec<(&str, Vec<Route>)> = vec![];
+    for (path, route) in routes {
+        match resources.iter_mut().find(|(existing, _)| *existing == path) {
+            Some((_, routes)) => routes.push(route),
+            None => resources.push((path, vec![route])),
+        }
+    }
+    for (path, routes) in resources {
+        let resource = routes
+            .into_iter()
+            .fold(web::resource(path), |resource, route| resource.route(route));
+        let resource = resource.wrap(cors.middleware()).wrap(headers.clone());
+        if shield_lookups {
+            cfg.service(resource.wrap_fn(shield_lookup));
+        } else {
+            cfg.service(resource);
+        }
+    }
+}
+
+/// The RPC style routes from before `/v1`, kept working but flagged so clients move over.
+#[allow(deprecated)]
+fn legacy_key_routes(cfg: &mut web::ServiceConfig, cors: &CorsPolicy, headers: &DefaultHeaders) {
+    for (path, route) in [
+        ("/upload_key", web::post().to(upload_key)),
+        ("/fetch_key", web::post().to(fetch_key)),
+    ] {
+        cfg.service(
+            web::resource(path)
+                .route(route)
+                .wrap(cors.middleware())
+                .wrap(headers.clone())
+                .wrap(
+                    DefaultHeaders::new()
+                        .add(("Deprecation", "true"))
+                        .add((LINK, "</v1/keys>; rel=\"successor-version\"")),
+                )
+                .wrap_fn(shield_lookup),
+        );
+    }
+}
+
+fn replication_routes(cfg: &mut web::ServiceConfig) {
+    cfg.route("/replication/keys", web::post().to(receive_replicated_key))
+        .route("/replication/keys", web::get().to(replicated_keys));
+}
+
+/// Serves only the replication routes, stopped by `Application` rather than by signals.
+fn run_admin(
+    listener: TcpListener,
+    db_pool: PgPool,
+    auth_key: Secret<String>,
+    max_body_bytes: usize,
+    tls: Option<ServerConfig>,
+) -> Result<Server, anyhow::Error> {
+    let address = listener.local_addr()?;
+    let db_pool = Data::new(db_pool);
+    let auth_key = Data::new(ReplicationAuthKey::new(auth_key)?);
+    let server = HttpServer::new(move || {
+        App::new()
+            .wrap_fn(scope_request_id)
+            .wrap(TracingLogger::default())
+            .configure(replication_routes)
+            .app_data(json_config(max_body_bytes))
+            .app_data(db_pool.clone())
+            .app_data(auth_key.clone())
+            .default_service(web::route().to(route_not_found))
+    })
+    .workers(1)
+    .disable_signals();
+    let (server, scheme) = match tls {
+        Some(tls) => (server.listen_rustls_0_21(listener, tls)?, "https"),
+        None => (server.listen(listener)?, "http"),
+    };
+    info!("serving admin routes at {}://{}", scheme, address);
+    Ok(server.run())
+}
+
+/// Serves only `/metrics`, stopped by `Application` rather than by signals.
+fn run_metrics(listener: TcpListener, db_pool: PgPool) -> Result<Server, anyhow::Error> {
+    let address = listener.local_addr()?;
+    let db_pool = Data::new(db_pool);
+    let server = HttpServer::new(move || {
+        App::new()
+            .route("/metrics", web::get().to(metrics))
+            .app_data(db_pool.clone())
     })
+    .workers(1)
+    .disable_signals()
     .listen(listener)?
     .run();
-    info!("running at http://{}/swagger-ui/  ", address);
+    info!("serving metrics at http://{}/metrics", address);
     Ok(server)
 }
 
@@ -0,0 +1,150 @@
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::Instrument;
+
+/// Owns the background work started alongside the server, so it can all be told to stop
+/// at once and be waited on before the process exits.
+pub struct TaskSupervisor {
+    shutdown: watch::Sender<bool>,
+    tasks: Vec<(&'static str, JoinHandle<()>)>,
+}
+
+impl Default for TaskSupervisor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TaskSupervisor {
+    pub fn new() -> Self {
+        let (shutdown, _) = watch::channel(false);
+        Self {
+            shutdown,
+            tasks: vec![],
+        }
+    }
+
+    /// Runs `task` once, it is dropped at its next `.await` if still running at shutdown.
+    pub fn spawn<F>(&mut self, name: &'static str, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let mut shutdown = self.shutdown.subscribe();
+        let handle = tokio::spawn(
+            async move {
+                tokio::select! {
+                    _ = task => {}
+                    _ = shutdown.changed() => {
+                        tracing::info!("Cancelled by shutdown.");
+                    }
+                }
+            }
+            .instrument(tracing::info_span!("Supervised task", task = name)),
+        );
+        self.tasks.push((name, handle));
+    }
+
+    /// Runs `job` every `period`, starting straight away. A run already in progress at
+    /// shutdown is allowed to finish.
+    pub fn spawn_periodic<F, Fut>(&mut self, name: &'static str, period: Duration, mut job: F)
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let mut shutdown = self.shutdown.subscribe();
+        let handle = tokio::spawn(
+            async move {
+                let mut interval = tokio::time::interval(period);
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => job().await,
+                        _ = shutdown.changed() => break,
+                    }
+                }
+            }
+            .instrument(tracing::info_span!("Periodic task", task = name)),
+        );
+        self.tasks.push((name, handle));
+    }
+
+    /// Signals every task to stop and waits up to `timeout` for them, aborting stragglers.
+    pub async fn shutdown(self, timeout: Duration) {
+        let _ = self.shutdown.send(true);
+        let deadline = tokio::time::Instant::now() + timeout;
+        for (name, mut handle) in self.tasks {
+            match tokio::time::timeout_at(deadline, &mut handle).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => tracing::error!(task = name, "Background task failed: {:?}", e),
+                Err(_) => {
+                    tracing::warn!(task = name, "Background task did not stop in time.");
+                    handle.abort();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TaskSupervisor;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn periodic_tasks_stop_on_shutdown() {
+        let mut supervisor = TaskSupervisor::new();
+        let runs = Arc::new(AtomicU64::new(0));
+        let counted = runs.clone();
+        supervisor.spawn_periodic("count", Duration::from_millis(10), move || {
+            let counted = counted.clone();
+            async move {
+                counted.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        supervisor.shutdown(Duration::from_secs(1)).await;
+        let stopped_at = runs.load(Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        assert!(stopped_at > 0);
+        assert_eq!(runs.load(Ordering::SeqCst), stopped_at);
+    }
+
+    #[tokio::test]
+    async fn a_run_in_progress_finishes_before_shutdown_returns() {
+        let mut supervisor = TaskSupervisor::new();
+        let finished = Arc::new(AtomicU64::new(0));
+        let counted = finished.clone();
+        supervisor.spawn_periodic("slow", Duration::from_secs(60), move || {
+            let counted = counted.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                counted.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        supervisor.shutdown(Duration::from_secs(1)).await;
+
+        assert_eq!(finished.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn stragglers_are_aborted_after_the_timeout() {
+        let mut supervisor = TaskSupervisor::new();
+        supervisor.spawn_periodic("stuck", Duration::from_secs(60), || {
+            std::future::pending::<()>()
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let shutdown = supervisor.shutdown(Duration::from_millis(50));
+
+        assert!(tokio::time::timeout(Duration::from_secs(1), shutdown)
+            .await
+            .is_ok());
+    }
+}
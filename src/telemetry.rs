@@ -1,14 +1,53 @@
-use tokio::task::JoinHandle;
+use crate::configuration::TelemetrySettings;
+use opentelemetry::sdk::propagation::TraceContextPropagator;
+use opentelemetry::sdk::trace::{self, Tracer};
+use opentelemetry::sdk::Resource;
+use opentelemetry::trace::TraceError;
+use opentelemetry::{global, runtime, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
 use tracing::{subscriber::set_global_default, Subscriber};
 use tracing_bunyan_formatter::{BunyanFormattingLayer, JsonStorageLayer};
 use tracing_log::LogTracer;
 use tracing_subscriber::fmt::MakeWriter;
 use tracing_subscriber::{layer::SubscriberExt, EnvFilter, Registry};
 
+/// Installs an OTLP/HTTP span exporter and the W3C trace context propagator, so a
+/// `traceparent` sent with a request becomes the parent of its spans. `None` when no
+/// collector is configured.
+pub fn otlp_tracer(settings: &TelemetrySettings) -> Result<Option<Tracer>, TraceError> {
+    let endpoint = match &settings.otlp_endpoint {
+        Some(endpoint) => endpoint,
+        None => return Ok(None),
+    };
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .http()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(
+            trace::config().with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                settings.service_name.clone(),
+            )])),
+        )
+        .install_batch(runtime::Tokio)?;
+    Ok(Some(tracer))
+}
+
+/// Exports any spans still buffered, call it once the application has stopped.
+pub async fn shutdown_tracing() {
+    // Blocks until the batch exporter has flushed, which needs the runtime to keep going.
+    let _ = tokio::task::spawn_blocking(global::shutdown_tracer_provider).await;
+}
+
 pub fn get_subscriber<Sink>(
     name: String,
     env_filter: String,
     sink: Sink,
+    tracer: Option<Tracer>,
 ) -> impl Subscriber + Send + Sync
 where
     // Means Sink implements the `MakeWriter` trait for all choices of the lifetime parameter `'a`
@@ -18,23 +57,16 @@ where
     let env_filter =
         EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(env_filter));
     let formatting_layer = BunyanFormattingLayer::new(name, sink);
+    let otlp_layer = tracer.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer));
 
     Registry::default()
         .with(env_filter)
         .with(JsonStorageLayer)
         .with(formatting_layer)
+        .with(otlp_layer)
 }
 
 pub fn init_subscriber(subscriber: impl Subscriber + Send + Sync) {
     LogTracer::init().expect("Failed to set logger.");
     set_global_default(subscriber).expect("Failed to set subscriber.");
 }
-
-pub fn spawn_blocking_with_tracing<F, R>(f: F) -> JoinHandle<R>
-where
-    F: FnOnce() -> R + Send + 'static,
-    R: Send + 'static,
-{
-    let current_span = tracing::Span::current();
-    tokio::task::spawn_blocking(move || current_span.in_scope(f))
-}
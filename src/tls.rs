@@ -0,0 +1,121 @@
+use crate::configuration::TlsSettings;
+use anyhow::{anyhow, Context};
+use rustls::server::{AllowAnyAuthenticatedClient, ClientHello, ResolvesServerCert};
+use rustls::sign::{self, CertifiedKey};
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::{Arc, RwLock};
+use tokio::signal::unix::{signal, SignalKind};
+
+/// Hands out the certificate read from `cert_path` and `key_path`. `reload` swaps it for
+/// whatever is on disk now, new handshakes pick it up while open connections keep theirs.
+pub struct ReloadableCertificate {
+    settings: TlsSettings,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ReloadableCertificate {
+    pub fn load(settings: &TlsSettings) -> Result<Arc<Self>, anyhow::Error> {
+        Ok(Arc::new(Self {
+            settings: settings.clone(),
+            current: RwLock::new(Arc::new(read_certified_key(settings)?)),
+        }))
+    }
+
+    /// Leaves the current certificate in place if the new one can not be read.
+    pub fn reload(&self) -> Result<(), anyhow::Error> {
+        let certified_key = read_certified_key(&self.settings)?;
+        *self.current.write().unwrap() = Arc::new(certified_key);
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for ReloadableCertificate {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+/// Rustls config serving `certificate`. With a `client_ca_path` only clients presenting a
+/// certificate signed by one of the CAs in that file can complete the handshake.
+pub fn server_config(
+    certificate: Arc<ReloadableCertificate>,
+    client_ca_path: Option<&str>,
+) -> Result<ServerConfig, anyhow::Error> {
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let config = match client_ca_path {
+        Some(path) => builder
+            .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(read_roots(path)?).boxed()),
+        None => builder.with_no_client_auth(),
+    }
+    .with_cert_resolver(certificate);
+    Ok(config)
+}
+
+/// Re-reads the certificate on every SIGHUP. The handler is installed before this returns,
+/// so a SIGHUP arriving afterwards no longer terminates the process.
+pub fn reload_on_sighup(
+    certificate: Arc<ReloadableCertificate>,
+) -> Result<impl std::future::Future<Output = ()>, std::io::Error> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    Ok(async move {
+        while hangup.recv().await.is_some() {
+            match certificate.reload() {
+                Ok(()) => tracing::info!("Reloaded TLS certificate."),
+                Err(e) => tracing::error!(
+                    "Failed to reload TLS certificate, keeping the current one: {:?}",
+                    e
+                ),
+            }
+        }
+    })
+}
+
+fn read_certified_key(settings: &TlsSettings) -> Result<CertifiedKey, anyhow::Error> {
+    let certificates = read_certificates(&settings.cert_path)?;
+    if certificates.is_empty() {
+        return Err(anyhow!("No certificate found in {}.", settings.cert_path));
+    }
+    let key = read_private_key(&settings.key_path)?;
+    let signing_key = sign::any_supported_type(&key)
+        .map_err(|_| anyhow!("Unsupported private key in {}.", settings.key_path))?;
+    Ok(CertifiedKey::new(certificates, signing_key))
+}
+
+fn read_certificates(path: &str) -> Result<Vec<Certificate>, anyhow::Error> {
+    let mut reader =
+        BufReader::new(File::open(path).with_context(|| format!("Failed to open {}.", path))?);
+    let certificates = rustls_pemfile::certs(&mut reader)
+        .with_context(|| format!("Failed to parse certificates in {}.", path))?;
+    Ok(certificates.into_iter().map(Certificate).collect())
+}
+
+fn read_private_key(path: &str) -> Result<PrivateKey, anyhow::Error> {
+    let mut reader =
+        BufReader::new(File::open(path).with_context(|| format!("Failed to open {}.", path))?);
+    let items = rustls_pemfile::read_all(&mut reader)
+        .with_context(|| format!("Failed to parse private key in {}.", path))?;
+    items
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(der)
+            | rustls_pemfile::Item::RSAKey(der)
+            | rustls_pemfile::Item::ECKey(der) => Some(PrivateKey(der)),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow!("No private key found in {}.", path))
+}
+
+fn read_roots(path: &str) -> Result<RootCertStore, anyhow::Error> {
+    let mut roots = RootCertStore::empty();
+    for certificate in read_certificates(path)? {
+        roots
+            .add(&certificate)
+            .with_context(|| format!("Invalid CA certificate in {}.", path))?;
+    }
+    if roots.is_empty() {
+        return Err(anyhow!("No CA certificate found in {}.", path));
+    }
+    Ok(roots)
+}
@@ -0,0 +1,102 @@
+use crate::helpers::{spawn_app, spawn_app_with, TestApp};
+use nostr_vault::configuration::get_configuration;
+use nostr_vault::startup::Application;
+use reqwest::{Method, StatusCode};
+use serde_json::json;
+
+const TRUSTED_CLIENT: &str = "https://client.example";
+
+async fn preflight(test_app: &TestApp, path: &str, origin: &str) -> reqwest::Response {
+    test_app
+        .api_client
+        .request(Method::OPTIONS, &format!("{}{}", &test_app.address, path))
+        .header("Origin", origin)
+        .header("Access-Control-Request-Method", "POST")
+        .header("Access-Control-Request-Headers", "content-type")
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+fn allowed_origin(response: &reqwest::Response) -> Option<&str> {
+    response
+        .headers()
+        .get("access-control-allow-origin")
+        .map(|value| value.to_str().unwrap())
+}
+
+#[tokio::test]
+async fn unknown_origins_can_not_call_key_routes() {
+    let test_app = spawn_app().await;
+
+    let response = preflight(&test_app, "/fetch_key", "https://evil.example").await;
+
+    assert!(!response.status().is_success());
+    assert_eq!(allowed_origin(&response), None);
+}
+
+#[tokio::test]
+async fn configured_origins_can_call_key_routes() {
+    let test_app = spawn_app_with(|c| {
+        c.application.cors.keys.allowed_origins = vec![TRUSTED_CLIENT.to_string()];
+        c.application.cors.keys.max_age_seconds = 600;
+    })
+    .await;
+
+    let response = preflight(&test_app, "/fetch_key", TRUSTED_CLIENT).await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(allowed_origin(&response), Some(TRUSTED_CLIENT));
+    assert_eq!(
+        response
+            .headers()
+            .get("access-control-max-age")
+            .map(|value| value.to_str().unwrap()),
+        Some("600")
+    );
+}
+
+#[tokio::test]
+async fn the_vaults_own_pages_can_call_key_routes() {
+    let test_app = spawn_app().await;
+
+    let response = test_app
+        .api_client
+        .post(&format!("{}/fetch_key", &test_app.address))
+        .header("Origin", &test_app.address)
+        .json(&json!({"nip_05_id":"nobody@test.com", "pin":374859}))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(allowed_origin(&response), Some(test_app.address.as_str()));
+}
+
+#[tokio::test]
+async fn public_routes_allow_any_origin() {
+    let test_app = spawn_app().await;
+
+    let response = test_app
+        .api_client
+        .get(&format!("{}/livez", &test_app.address))
+        .header("Origin", "https://anyone.example")
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(allowed_origin(&response), Some("*"));
+}
+
+#[tokio::test]
+async fn invalid_cors_settings_are_rejected_at_startup() {
+    let mut configuration = get_configuration().expect("Failed to read configuration.");
+    configuration.application.port = 0;
+    configuration.application.cors.keys.allowed_origins =
+        vec!["https://client.example/app".to_string()];
+
+    let application = Application::build(configuration).await;
+
+    assert!(application.is_err());
+}
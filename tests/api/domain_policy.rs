@@ -0,0 +1,71 @@
+use crate::helpers::{spawn_app_with, TestApp};
+use nostr_vault::configuration::DomainQuotaSettings;
+use nostr_vault::routes::{ErrorCode, ErrorResponse};
+use reqwest::StatusCode;
+use secp256k1::KeyPair;
+use serde_json::json;
+
+const PRIVATE_KEY_HASH: &str = "$PBKDF2$i=100000,l=256,s=0Bu5lWu4s66/iottrlUGdckjf5nwnpB05jwp4yDh8NU=$AESGM$OrScsD+hHGaRaPbc$XMXVVbjt3JV+QsNb7ZWRc8uNod2YzJL0lSvW1FOiY38ywOu7IEChKs/IqEQ7knhZAmRGYqoB4dhAbdOTwVhYIeQsuf1+f+9ARPEjtURsDg==";
+
+async fn upload(test_app: &TestApp, nip_05_id: &str) -> reqwest::Response {
+    let keypair = KeyPair::new_global(&mut rand::thread_rng());
+    test_app
+        .post_signed_upload(
+            &keypair,
+            json!({
+                "nip_05_id": nip_05_id,
+                "pin": 720461,
+                "private_key_hash": PRIVATE_KEY_HASH,
+            }),
+        )
+        .await
+}
+
+async fn error_code(response: reqwest::Response) -> ErrorCode {
+    response.json::<ErrorResponse>().await.unwrap().code
+}
+
+#[tokio::test]
+async fn only_allowed_domains_and_their_subdomains_are_accepted() {
+    let test_app = spawn_app_with(|c| {
+        c.domain_policy.allowed_domains =
+            vec!["ourdomain.com".to_string(), "*.ourdomain.com".to_string()];
+        c.domain_policy.denied_domains = vec!["spam.ourdomain.com".to_string()];
+    })
+    .await;
+
+    for nip_05_id in ["bob@ourdomain.com", "alice@members.ourdomain.com"] {
+        let response = upload(&test_app, nip_05_id).await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", nip_05_id);
+    }
+    for nip_05_id in ["bob@otherdomain.com", "eve@spam.ourdomain.com"] {
+        let response = upload(&test_app, nip_05_id).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN, "{}", nip_05_id);
+        assert_eq!(error_code(response).await, ErrorCode::DomainNotAllowed);
+    }
+}
+
+#[tokio::test]
+async fn a_domain_can_not_hold_more_keys_than_its_quota() {
+    let test_app = spawn_app_with(|c| {
+        c.domain_policy.max_keys_per_domain = 1;
+        c.domain_policy.quotas = vec![DomainQuotaSettings {
+            domain: "big.com".to_string(),
+            max_keys: 2,
+        }];
+    })
+    .await;
+
+    let response = upload(&test_app, "bob@small.com").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = upload(&test_app, "alice@small.com").await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert_eq!(error_code(response).await, ErrorCode::DomainQuotaExceeded);
+
+    for nip_05_id in ["bob@big.com", "alice@big.com"] {
+        let response = upload(&test_app, nip_05_id).await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", nip_05_id);
+    }
+    let response = upload(&test_app, "carol@big.com").await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
@@ -0,0 +1,208 @@
+use crate::helpers::{delete_row, spawn_app_with, TestApp};
+use nostr_vault::routes::{ErrorCode, ErrorResponse};
+use reqwest::StatusCode;
+use secp256k1::KeyPair;
+use serde_json::json;
+use std::time::{Duration, Instant};
+
+const PRIVATE_KEY_HASH: &str = "$PBKDF2$i=100000,l=256,s=0Bu5lWu4s66/iottrlUGdckjf5nwnpB05jwp4yDh8NU=$AESGM$OrScsD+hHGaRaPbc$XMXVVbjt3JV+QsNb7ZWRc8uNod2YzJL0lSvW1FOiY38ywOu7IEChKs/IqEQ7knhZAmRGYqoB4dhAbdOTwVhYIeQsuf1+f+9ARPEjtURsDg==";
+const PIN: u64 = 640213;
+const MIN_RESPONSE: Duration = Duration::from_millis(400);
+
+async fn spawn_resistant_app() -> TestApp {
+    spawn_app_with(|c| {
+        c.enumeration_resistance.enabled = true;
+        c.enumeration_resistance.min_response_milliseconds = MIN_RESPONSE.as_millis() as u64;
+        c.enumeration_resistance.jitter_milliseconds = 50;
+        c.enumeration_resistance.pad_to_bytes = 512;
+    })
+    .await
+}
+
+async fn upload(test_app: &TestApp, nip_05_id: &str) {
+    let keypair = KeyPair::new_global(&mut rand::thread_rng());
+    let response = test_app
+        .post_signed_upload(
+            &keypair,
+            json!({
+                "nip_05_id": nip_05_id,
+                "pin": PIN,
+                "private_key_hash": PRIVATE_KEY_HASH,
+            }),
+        )
+        .await;
+    assert!(response.status().is_success());
+}
+
+struct Observed {
+    status: StatusCode,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    elapsed: Duration,
+}
+
+async fn fetch(test_app: &TestApp, path: &str, body: serde_json::Value) -> Observed {
+    let started = Instant::now();
+    let response = test_app
+        .api_client
+        .post(&format!("{}{}", &test_app.address, path))
+        .json(&body)
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let status = response.status();
+    // Everything but the per request values
+    let mut headers: Vec<(String, String)> = response
+        .headers()
+        .iter()
+        .filter(|(name, _)| !matches!(name.as_str(), "x-request-id" | "date"))
+        .map(|(name, value)| (name.to_string(), value.to_str().unwrap().to_string()))
+        .collect();
+    headers.sort();
+    let body = response.bytes().await.unwrap().to_vec();
+    Observed {
+        status,
+        headers,
+        body,
+        elapsed: started.elapsed(),
+    }
+}
+
+/// The body with its request id blanked out, which differs between any two requests.
+fn without_request_id(body: &[u8]) -> Vec<u8> {
+    let error: ErrorResponse = serde_json::from_slice(body).unwrap();
+    let request_id = error.request_id.unwrap();
+    String::from_utf8(body.to_vec())
+        .unwrap()
+        .replace(&request_id, &"x".repeat(request_id.len()))
+        .into_bytes()
+}
+
+fn assert_indistinguishable(unknown: &Observed, wrong_pin: &Observed) {
+    assert_eq!(unknown.status, StatusCode::FORBIDDEN);
+    assert_eq!(unknown.status, wrong_pin.status);
+    assert_eq!(unknown.headers, wrong_pin.headers);
+    assert_eq!(unknown.body.len(), wrong_pin.body.len());
+    assert_eq!(unknown.body.len() % 512, 0);
+    assert_eq!(
+        without_request_id(&unknown.body),
+        without_request_id(&wrong_pin.body)
+    );
+    for observed in [unknown, wrong_pin] {
+        assert!(observed.elapsed >= MIN_RESPONSE);
+    }
+    let error: ErrorResponse = serde_json::from_slice(&unknown.body).unwrap();
+    assert_eq!(error.code, ErrorCode::CredentialsInvalid);
+}
+
+#[tokio::test]
+async fn fetch_key_answers_unknown_ids_like_wrong_pins() {
+    let test_app = spawn_resistant_app().await;
+    let nip_05_id = "enumeration_fetch_bob@test.com";
+    upload(&test_app, nip_05_id).await;
+
+    let unknown = fetch(
+        &test_app,
+        "/fetch_key",
+        json!({ "nip_05_id": "enumeration_fetch_eve@test.com", "pin": PIN }),
+    )
+    .await;
+    let wrong_pin = fetch(
+        &test_app,
+        "/fetch_key",
+        json!({ "nip_05_id": nip_05_id, "pin": 111111 }),
+    )
+    .await;
+
+    assert_indistinguishable(&unknown, &wrong_pin);
+    delete_row(&test_app.db_pool, nip_05_id.to_string()).await;
+}
+
+#[tokio::test]
+async fn retrieve_answers_unknown_ids_like_wrong_pins() {
+    let test_app = spawn_resistant_app().await;
+    let nip_05_id = "enumeration_retrieve_bob@test.com";
+    upload(&test_app, nip_05_id).await;
+
+    let unknown = fetch(
+        &test_app,
+        "/v1/keys/enumeration_retrieve_eve@test.com/retrieve",
+        json!({ "pin": PIN }),
+    )
+    .await;
+    let wrong_pin = fetch(
+        &test_app,
+        &format!("/v1/keys/{}/retrieve", nip_05_id),
+        json!({ "pin": 111111 }),
+    )
+    .await;
+
+    assert_indistinguishable(&unknown, &wrong_pin);
+    delete_row(&test_app.db_pool, nip_05_id.to_string()).await;
+}
+
+#[tokio::test]
+async fn stored_keys_are_still_returned_padded() {
+    let test_app = spawn_resistant_app().await;
+    let nip_05_id = "enumeration_success_bob@test.com";
+    upload(&test_app, nip_05_id).await;
+
+    let found = fetch(
+        &test_app,
+        "/fetch_key",
+        json!({ "nip_05_id": nip_05_id, "pin": PIN }),
+    )
+    .await;
+
+    assert_eq!(found.status, StatusCode::OK);
+    assert_eq!(found.body.len() % 512, 0);
+    let key: serde_json::Value = serde_json::from_slice(&found.body).unwrap();
+    assert_eq!(key["nip_05_id"], nip_05_id);
+    delete_row(&test_app.db_pool, nip_05_id.to_string()).await;
+}
+
+#[tokio::test]
+async fn unknown_ids_are_still_reported_while_disabled() {
+    let test_app = spawn_app_with(|_| {}).await;
+
+    let unknown = fetch(
+        &test_app,
+        "/fetch_key",
+        json!({ "nip_05_id": "enumeration_disabled_eve@test.com", "pin": PIN }),
+    )
+    .await;
+
+    assert_eq!(unknown.status, StatusCode::NOT_FOUND);
+    let error: ErrorResponse = serde_json::from_slice(&unknown.body).unwrap();
+    assert_eq!(error.code, ErrorCode::KeyNotFound);
+}
+
+#[tokio::test]
+async fn nip_05_lookups_are_not_served_unless_allowed() {
+    let lookup = |test_app: &TestApp, path: &str| {
+        test_app
+            .api_client
+            .get(&format!("{}{}", &test_app.address, path))
+            .query(&[("name", "bob")])
+            .send()
+    };
+    let resistant = spawn_app_with(|c| {
+        c.enumeration_resistance.enabled = true;
+        c.nip05_provider.domains = vec!["localhost".to_string()];
+    })
+    .await;
+    let allowed = spawn_app_with(|c| {
+        c.enumeration_resistance.enabled = true;
+        c.enumeration_resistance.serve_nip_05_lookups = true;
+        c.nip05_provider.domains = vec!["localhost".to_string()];
+    })
+    .await;
+
+    for path in ["/.well-known/nostr.json", "/nip05/availability"] {
+        let hidden = lookup(&resistant, path).await.unwrap();
+        let served = lookup(&allowed, path).await.unwrap();
+
+        assert_eq!(hidden.status(), StatusCode::NOT_FOUND, "{}", path);
+        assert_eq!(served.status(), StatusCode::OK, "{}", path);
+    }
+}
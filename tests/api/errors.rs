@@ -0,0 +1,127 @@
+use crate::helpers::{spawn_app, spawn_app_with};
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+
+async fn assert_error(response: reqwest::Response, status: StatusCode, code: &str) -> Value {
+    assert_eq!(response.status(), status);
+    assert_eq!(response.headers()["Content-Type"], "application/json");
+    let request_id = response.headers()["X-Request-Id"]
+        .to_str()
+        .unwrap()
+        .to_string();
+    let body = response.json::<Value>().await.unwrap();
+    assert_eq!(body["code"], code);
+    assert!(body["value"].as_str().is_some());
+    assert_eq!(body["request_id"], request_id.as_str());
+    body
+}
+
+#[tokio::test]
+async fn malformed_json_gets_a_json_error() {
+    let test_app = spawn_app().await;
+    let bodies = [
+        "{\"nip_05_id\": ",
+        "{\"pin\": 374859}",
+        "{\"nip_05_id\": \"bob@test.com\", \"pin\": \"not a number\"}",
+    ];
+
+    for body in bodies {
+        let response = test_app
+            .api_client
+            .post(&format!("{}/fetch_key", &test_app.address))
+            .header("Content-Type", "application/json")
+            .body(body)
+            .send()
+            .await
+            .expect("Failed to execute request.");
+
+        assert_error(response, StatusCode::BAD_REQUEST, "REQUEST_MALFORMED").await;
+    }
+}
+
+#[tokio::test]
+async fn other_content_types_are_rejected() {
+    let test_app = spawn_app().await;
+
+    let response = test_app
+        .api_client
+        .post(&format!("{}/v1/keys", &test_app.address))
+        .header("Content-Type", "text/plain")
+        .body("{}")
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_error(
+        response,
+        StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        "CONTENT_TYPE_UNSUPPORTED",
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn bodies_above_the_limit_are_rejected() {
+    let test_app = spawn_app_with(|c| c.application.max_body_bytes = 64).await;
+
+    let response = test_app
+        .api_client
+        .post(&format!("{}/fetch_key", &test_app.address))
+        .json(&json!({"nip_05_id": "a".repeat(128) + "@test.com", "pin": 374859}))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_error(response, StatusCode::PAYLOAD_TOO_LARGE, "PAYLOAD_TOO_LARGE").await;
+}
+
+#[tokio::test]
+async fn validation_failures_name_the_field() {
+    let test_app = spawn_app().await;
+
+    let response = test_app
+        .api_client
+        .post(&format!("{}/fetch_key", &test_app.address))
+        .json(&json!({"nip_05_id": "not a nip 05 id", "pin": 374859}))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_error(response, StatusCode::BAD_REQUEST, "NIP05_MALFORMED").await;
+}
+
+#[tokio::test]
+async fn unknown_keys_have_their_own_code() {
+    let test_app = spawn_app().await;
+
+    let response = test_app
+        .api_client
+        .post(&format!("{}/fetch_key", &test_app.address))
+        .json(&json!({"nip_05_id": "nobody_here@test.com", "pin": 374859}))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_error(response, StatusCode::NOT_FOUND, "KEY_NOT_FOUND").await;
+}
+
+#[tokio::test]
+async fn invalid_queries_and_unknown_routes_get_a_json_error() {
+    let test_app = spawn_app().await;
+
+    let query = test_app
+        .api_client
+        .get(&format!("{}/nip05/availability", &test_app.address))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let unknown = test_app
+        .api_client
+        .get(&format!("{}/no/such/route", &test_app.address))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_error(query, StatusCode::BAD_REQUEST, "REQUEST_MALFORMED").await;
+    assert_error(unknown, StatusCode::NOT_FOUND, "ROUTE_NOT_FOUND").await;
+}
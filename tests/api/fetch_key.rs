@@ -1,6 +1,7 @@
 use crate::helpers::{delete_row, spawn_app};
 use nostr_vault::authentication::StoredKey;
 use reqwest::StatusCode;
+use secp256k1::KeyPair;
 use serde_json::json;
 
 #[tokio::test]
@@ -11,12 +12,8 @@ async fn fetch_key_success() {
     let private_key_hash = "$PBKDF2$i=100000,l=256,s=0Bu5lWu4s66/iottrlUGdckjf5nwnpB05jwp4yDh8NU=$AESGM$OrScsD+hHGaRaPbc$XMXVVbjt3JV+QsNb7ZWRc8uNod2YzJL0lSvW1FOiY38ywOu7IEChKs/IqEQ7knhZAmRGYqoB4dhAbdOTwVhYIeQsuf1+f+9ARPEjtURsDg==";
     let pin = 374859;
     let form_data = json!({"nip_05_id":nip_05_id,"pin":pin, "private_key_hash":private_key_hash});
-    let response_upload = client
-        .post(&format!("{}/upload_key", &test_app.address))
-        .json(&form_data)
-        .send()
-        .await
-        .expect("Failed to execute request.");
+    let keypair = KeyPair::new_global(&mut rand::thread_rng());
+    let response_upload = test_app.post_signed_upload(&keypair, form_data).await;
     assert!(response_upload.status().is_success());
 
     let req_data = json!({"nip_05_id":nip_05_id, "pin":pin});
@@ -43,12 +40,8 @@ async fn fetch_key_invalid_pin() {
     let private_key_hash = "$PBKDF2$i=100000,l=256,s=0Bu5lWu4s66/iottrlUGdckjf5nwnpB05jwp4yDh8NU=$AESGM$OrScsD+hHGaRaPbc$XMXVVbjt3JV+QsNb7ZWRc8uNod2YzJL0lSvW1FOiY38ywOu7IEChKs/IqEQ7knhZAmRGYqoB4dhAbdOTwVhYIeQsuf1+f+9ARPEjtURsDg==";
     let pin = 374859;
     let form_data = json!({"nip_05_id":nip_05_id,"pin":pin, "private_key_hash":private_key_hash});
-    let response_upload = client
-        .post(&format!("{}/upload_key", &test_app.address))
-        .json(&form_data)
-        .send()
-        .await
-        .expect("Failed to execute request.");
+    let keypair = KeyPair::new_global(&mut rand::thread_rng());
+    let response_upload = test_app.post_signed_upload(&keypair, form_data).await;
     assert!(response_upload.status().is_success());
 
     let req_data = json!({"nip_05_id":nip_05_id, "pin":379953});
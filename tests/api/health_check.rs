@@ -1,12 +1,16 @@
 use crate::helpers::spawn_app;
+use nostr_vault::configuration::{get_configuration, ReadinessSettings};
+use nostr_vault::health::{CheckStatus, Readiness, ReadinessReport};
+use nostr_vault::startup::get_connection_pool;
+use reqwest::StatusCode;
 
 #[tokio::test]
-async fn health_check_works() {
+async fn livez_works() {
     let test_app = spawn_app().await;
-    let client = reqwest::Client::new();
 
-    let response = client
-        .get(&format!("{}/health_check", &test_app.address))
+    let response = test_app
+        .api_client
+        .get(&format!("{}/livez", &test_app.address))
         .send()
         .await
         .expect("Failed to execute request.");
@@ -14,3 +18,65 @@ async fn health_check_works() {
     assert!(response.status().is_success());
     assert_eq!(Some(0), response.content_length());
 }
+
+#[tokio::test]
+async fn readyz_reports_every_check_passing() {
+    let test_app = spawn_app().await;
+
+    let response = test_app
+        .api_client
+        .get(&format!("{}/readyz", &test_app.address))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let report = response.json::<ReadinessReport>().await.unwrap();
+    assert_eq!(report.status, CheckStatus::Ok);
+    assert_eq!(report.version, env!("CARGO_PKG_VERSION"));
+    for check in ["database", "migrations", "blocking_pool"] {
+        assert_eq!(report.checks[check].status, CheckStatus::Ok);
+    }
+}
+
+#[tokio::test]
+async fn readyz_fails_while_a_migration_is_missing() {
+    let test_app = spawn_app().await;
+    sqlx::query(
+        "DELETE FROM _sqlx_migrations WHERE version = (SELECT MAX(version) FROM _sqlx_migrations)",
+    )
+    .execute(&test_app.db_pool)
+    .await
+    .unwrap();
+
+    let response = test_app
+        .api_client
+        .get(&format!("{}/readyz", &test_app.address))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let report = response.json::<ReadinessReport>().await.unwrap();
+    assert_eq!(report.status, CheckStatus::Failing);
+    assert_eq!(report.checks["database"].status, CheckStatus::Ok);
+    assert_eq!(report.checks["migrations"].status, CheckStatus::Failing);
+}
+
+#[tokio::test]
+async fn readiness_fails_when_the_database_is_unreachable() {
+    let mut configuration = get_configuration().expect("Failed to read configuration.");
+    // Nothing listens on the discard port
+    configuration.database.port = 9;
+    let pool = get_connection_pool(&configuration.database);
+    let readiness = Readiness::new(&ReadinessSettings {
+        database_timeout_milliseconds: 500,
+        max_queued_blocking_tasks: 64,
+    });
+
+    let report = readiness.check(&pool).await;
+
+    assert_eq!(report.status, CheckStatus::Failing);
+    assert_eq!(report.checks["database"].status, CheckStatus::Failing);
+    assert_eq!(report.checks["migrations"].status, CheckStatus::Failing);
+}
@@ -1,9 +1,17 @@
+use futures_util::{SinkExt, StreamExt};
 use once_cell::sync::Lazy;
+use secp256k1::{KeyPair, Message};
+use serde_json::json;
+use std::sync::{Arc, Mutex};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite;
 
 use uuid::Uuid;
 
-use nostr_vault::configuration::{get_configuration, DatabaseSettings};
-use nostr_vault::startup::{get_connection_pool, Application};
+use nostr_vault::challenge::Challenge;
+use nostr_vault::configuration::{get_configuration, DatabaseSettings, Settings};
+use nostr_vault::domain::{KeyPossessionProof, NostrEvent, APP_DATA_KIND};
+use nostr_vault::startup::{get_connection_pool, Application, StopHandle};
 use nostr_vault::telemetry::{get_subscriber, init_subscriber};
 use sqlx::{Connection, Executor, PgConnection, PgPool};
 
@@ -12,10 +20,11 @@ static TRACING: Lazy<()> = Lazy::new(|| {
     let default_filter_level = "info".to_string();
     let subscriber_name = "test".to_string();
     if std::env::var("TEST_LOG").is_ok() {
-        let subscriber = get_subscriber(subscriber_name, default_filter_level, std::io::stdout);
+        let subscriber =
+            get_subscriber(subscriber_name, default_filter_level, std::io::stdout, None);
         init_subscriber(subscriber);
     } else {
-        let subscriber = get_subscriber(subscriber_name, default_filter_level, std::io::sink);
+        let subscriber = get_subscriber(subscriber_name, default_filter_level, std::io::sink, None);
         init_subscriber(subscriber);
     };
 });
@@ -24,10 +33,82 @@ pub struct TestApp {
     pub address: String,
     pub db_pool: PgPool,
     pub port: u16,
+    pub metrics_port: Option<u16>,
+    pub admin_port: Option<u16>,
     pub api_client: reqwest::Client,
+    stop_handle: StopHandle,
+    application: Option<JoinHandle<Result<(), std::io::Error>>>,
+}
+
+impl Drop for TestApp {
+    /// Apps that were not stopped explicitly are stopped without waiting for requests.
+    fn drop(&mut self) {
+        if self.application.is_some() {
+            drop(self.stop_handle.stop(false));
+        }
+    }
+}
+
+impl TestApp {
+    /// Stops the app gracefully and waits until its background tasks and pool are closed.
+    pub async fn stop(mut self) {
+        self.stop_handle.stop(true).await;
+        if let Some(application) = self.application.take() {
+            application
+                .await
+                .expect("Application task panicked.")
+                .expect("Application failed while stopping.");
+        }
+    }
+
+    pub async fn get_upload_challenge(&self) -> String {
+        self.api_client
+            .post(&format!("{}/upload_challenge", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+            .json::<Challenge>()
+            .await
+            .expect("Failed to parse challenge.")
+            .challenge
+    }
+
+    /// Posts `new_key` to `/upload_key` with a fresh challenge signed by `keypair`.
+    pub async fn post_signed_upload(
+        &self,
+        keypair: &KeyPair,
+        mut new_key: serde_json::Value,
+    ) -> reqwest::Response {
+        let challenge = self.get_upload_challenge().await;
+        let private_key_hash = new_key["private_key_hash"].as_str().unwrap_or_default();
+        new_key["npub"] = json!(keypair.x_only_public_key().0.to_string());
+        new_key["signature"] = json!(sign_upload_challenge(keypair, &challenge, private_key_hash));
+        new_key["challenge"] = json!(challenge);
+        self.api_client
+            .post(&format!("{}/upload_key", &self.address))
+            .json(&new_key)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+}
+
+pub fn sign_upload_challenge(keypair: &KeyPair, challenge: &str, private_key_hash: &str) -> String {
+    let message = KeyPossessionProof::message(challenge, private_key_hash);
+    keypair
+        .sign_schnorr(Message::from_slice(&message).unwrap())
+        .to_string()
 }
 
 pub async fn spawn_app() -> TestApp {
+    spawn_app_with(|_| {}).await
+}
+
+/// Same as `spawn_app`, but lets a test adjust the configuration before the app is built.
+pub async fn spawn_app_with<F>(customise: F) -> TestApp
+where
+    F: FnOnce(&mut Settings),
+{
     Lazy::force(&TRACING);
     // Randomise configuration to ensure test isolation
     let configuration = {
@@ -36,6 +117,7 @@ pub async fn spawn_app() -> TestApp {
         c.database.database_name = Uuid::new_v4().to_string();
         // Use a random OS port
         c.application.port = 0;
+        customise(&mut c);
         c
     };
 
@@ -47,18 +129,29 @@ pub async fn spawn_app() -> TestApp {
         .await
         .expect("Failed to build application.");
     let application_port = application.port();
+    let metrics_port = application.metrics_port();
+    let admin_port = application.admin_port();
+    let stop_handle = application.stop_handle();
 
-    let _ = tokio::spawn(application.run_until_stopped());
+    let application = tokio::spawn(application.run_until_stopped());
     let client = reqwest::Client::builder()
         .redirect(reqwest::redirect::Policy::none())
         .build()
         .unwrap();
 
+    let scheme = match configuration.application.tls {
+        Some(_) => "https",
+        None => "http",
+    };
     let test_app = TestApp {
-        address: format!("http://localhost:{}", application_port),
+        address: format!("{}://localhost:{}", scheme, application_port),
         port: application_port,
+        metrics_port,
+        admin_port,
         db_pool: get_connection_pool(&configuration.database),
         api_client: client,
+        stop_handle,
+        application: Some(application),
     };
     test_app
 }
@@ -96,3 +189,65 @@ pub async fn delete_row(db_pool: &PgPool, nip_05_id: String) {
     .await
     .expect("Failed to clean up inserted value");
 }
+
+pub struct TestRelay {
+    pub url: String,
+    pub received: Arc<Mutex<Vec<serde_json::Value>>>,
+}
+
+/// In-process stand-in for a nostr relay that records and accepts every event sent to it.
+pub async fn spawn_relay() -> TestRelay {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Failed to bind test relay.");
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+    let received = Arc::new(Mutex::new(vec![]));
+
+    let events = received.clone();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let events = events.clone();
+            tokio::spawn(async move {
+                let mut socket = match tokio_tungstenite::accept_async(stream).await {
+                    Ok(socket) => socket,
+                    Err(_) => return,
+                };
+                while let Some(Ok(tungstenite::Message::Text(text))) = socket.next().await {
+                    let request: serde_json::Value = match serde_json::from_str(&text) {
+                        Ok(request) => request,
+                        Err(_) => continue,
+                    };
+                    if request[0] != "EVENT" {
+                        continue;
+                    }
+                    let event = request[1].clone();
+                    let reply = json!(["OK", event["id"], true, ""]);
+                    events.lock().unwrap().push(event);
+                    let _ = socket
+                        .send(tungstenite::Message::Text(reply.to_string()))
+                        .await;
+                }
+            });
+        }
+    });
+
+    TestRelay { url, received }
+}
+
+pub fn signed_app_data_event(keypair: &KeyPair, content: &str) -> NostrEvent {
+    let mut event = NostrEvent {
+        id: String::new(),
+        pubkey: keypair.x_only_public_key().0.to_string(),
+        created_at: chrono::Utc::now().timestamp(),
+        kind: APP_DATA_KIND,
+        tags: vec![vec!["d".to_string(), "nostr-vault".to_string()]],
+        content: content.to_string(),
+        sig: String::new(),
+    };
+    let id = event.compute_id();
+    event.id = hex::encode(id);
+    event.sig = keypair
+        .sign_schnorr(Message::from_slice(&id).unwrap())
+        .to_string();
+    event
+}
@@ -0,0 +1,241 @@
+use crate::helpers::{delete_row, sign_upload_challenge, spawn_app, TestApp};
+use nostr_vault::authentication::StoredKey;
+use reqwest::StatusCode;
+use secp256k1::KeyPair;
+use serde_json::{json, Value};
+
+const PRIVATE_KEY_HASH: &str = "$PBKDF2$i=100000,l=256,s=0Bu5lWu4s66/iottrlUGdckjf5nwnpB05jwp4yDh8NU=$AESGM$OrScsD+hHGaRaPbc$XMXVVbjt3JV+QsNb7ZWRc8uNod2YzJL0lSvW1FOiY38ywOu7IEChKs/IqEQ7knhZAmRGYqoB4dhAbdOTwVhYIeQsuf1+f+9ARPEjtURsDg==";
+
+/// An upload body with a fresh challenge, kept so the very same request can be sent again.
+async fn signed_new_key(test_app: &TestApp, nip_05_id: &str) -> Value {
+    let keypair = KeyPair::new_global(&mut rand::thread_rng());
+    signed_new_key_by(test_app, &keypair, nip_05_id).await
+}
+
+async fn signed_new_key_by(test_app: &TestApp, keypair: &KeyPair, nip_05_id: &str) -> Value {
+    let challenge = test_app.get_upload_challenge().await;
+    json!({
+        "nip_05_id": nip_05_id,
+        "pin": 374859,
+        "private_key_hash": PRIVATE_KEY_HASH,
+        "npub": keypair.x_only_public_key().0.to_string(),
+        "signature": sign_upload_challenge(keypair, &challenge, PRIVATE_KEY_HASH),
+        "challenge": challenge,
+    })
+}
+
+async fn post(
+    test_app: &TestApp,
+    path: &str,
+    idempotency_key: Option<&str>,
+    body: &Value,
+) -> reqwest::Response {
+    let mut request = test_app
+        .api_client
+        .post(&format!("{}{}", &test_app.address, path))
+        .json(body);
+    if let Some(idempotency_key) = idempotency_key {
+        request = request.header("Idempotency-Key", idempotency_key);
+    }
+    request.send().await.expect("Failed to execute request.")
+}
+
+async fn count_keys(test_app: &TestApp, nip_05_id: &str) -> i64 {
+    sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM keys WHERE nip_05_id = $1")
+        .bind(nip_05_id)
+        .fetch_one(&test_app.db_pool)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn retried_upload_gets_the_original_response() {
+    let test_app = spawn_app().await;
+    let nip_05_id = "retrying_bob@test.com";
+    let body = signed_new_key(&test_app, nip_05_id).await;
+
+    let first = post(&test_app, "/upload_key", Some("retry-1"), &body).await;
+    let retry = post(&test_app, "/upload_key", Some("retry-1"), &body).await;
+    let retry_on_v1 = post(&test_app, "/v1/keys", Some("retry-1"), &body).await;
+
+    assert_eq!(first.status(), StatusCode::OK);
+    assert_eq!(retry.status(), StatusCode::OK);
+    assert_eq!(retry_on_v1.status(), StatusCode::CREATED);
+    let first = first.json::<StoredKey>().await.unwrap();
+    for replayed in [retry, retry_on_v1] {
+        let replayed = replayed.json::<StoredKey>().await.unwrap();
+        assert_eq!(replayed.id, first.id);
+        assert_eq!(replayed.created_at, first.created_at);
+        assert_eq!(replayed.recovery_codes, first.recovery_codes);
+    }
+    assert_eq!(count_keys(&test_app, nip_05_id).await, 1);
+    delete_row(&test_app.db_pool, nip_05_id.to_string()).await;
+}
+
+#[tokio::test]
+async fn reusing_a_key_for_another_request_is_rejected() {
+    let test_app = spawn_app().await;
+    let keypair = KeyPair::new_global(&mut rand::thread_rng());
+    let first = signed_new_key_by(&test_app, &keypair, "first_bob@test.com").await;
+    let second = signed_new_key_by(&test_app, &keypair, "second_bob@test.com").await;
+
+    post(&test_app, "/v1/keys", Some("reused-1"), &first).await;
+    let response = post(&test_app, "/v1/keys", Some("reused-1"), &second).await;
+
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body = response.json::<Value>().await.unwrap();
+    assert_eq!(body["code"], "IDEMPOTENCY_KEY_REUSED");
+    assert_eq!(count_keys(&test_app, "second_bob@test.com").await, 0);
+    delete_row(&test_app.db_pool, "first_bob@test.com".to_string()).await;
+}
+
+#[tokio::test]
+async fn keys_are_scoped_to_the_caller() {
+    let test_app = spawn_app().await;
+    let bob = signed_new_key(&test_app, "scoped_bob@test.com").await;
+    let alice = signed_new_key(&test_app, "scoped_alice@test.com").await;
+
+    let for_bob = post(&test_app, "/v1/keys", Some("shared-1"), &bob).await;
+    let for_alice = post(&test_app, "/v1/keys", Some("shared-1"), &alice).await;
+
+    assert_eq!(for_bob.status(), StatusCode::CREATED);
+    assert_eq!(for_alice.status(), StatusCode::CREATED);
+    assert_eq!(count_keys(&test_app, "scoped_alice@test.com").await, 1);
+    delete_row(&test_app.db_pool, "scoped_bob@test.com".to_string()).await;
+    delete_row(&test_app.db_pool, "scoped_alice@test.com".to_string()).await;
+}
+
+#[tokio::test]
+async fn a_request_that_never_finished_is_taken_over_once_its_lease_runs_out() {
+    let test_app = spawn_app().await;
+    let nip_05_id = "crashed_bob@test.com";
+    let body = signed_new_key(&test_app, nip_05_id).await;
+    post(&test_app, "/v1/keys", Some("crashed-1"), &body).await;
+    // As if the vault died half way: nothing was stored, the claim was never released
+    delete_row(&test_app.db_pool, nip_05_id.to_string()).await;
+    sqlx::query("UPDATE challenges SET used_at = NULL WHERE nonce = $1")
+        .bind(body["challenge"].as_str().unwrap())
+        .execute(&test_app.db_pool)
+        .await
+        .unwrap();
+    sqlx::query(
+        "UPDATE idempotency SET encrypted_response = NULL, expires_at = NOW() + INTERVAL '1 minute'
+        WHERE idempotency_key = 'crashed-1'",
+    )
+    .execute(&test_app.db_pool)
+    .await
+    .unwrap();
+
+    let leased = post(&test_app, "/v1/keys", Some("crashed-1"), &body).await;
+    sqlx::query("UPDATE idempotency SET expires_at = NOW() WHERE idempotency_key = 'crashed-1'")
+        .execute(&test_app.db_pool)
+        .await
+        .unwrap();
+    let taken_over = post(&test_app, "/v1/keys", Some("crashed-1"), &body).await;
+
+    assert_eq!(leased.status(), StatusCode::CONFLICT);
+    let leased = leased.json::<Value>().await.unwrap();
+    assert_eq!(leased["code"], "REQUEST_IN_PROGRESS");
+    assert_eq!(taken_over.status(), StatusCode::CREATED);
+    assert_eq!(count_keys(&test_app, nip_05_id).await, 1);
+    delete_row(&test_app.db_pool, nip_05_id.to_string()).await;
+}
+
+#[tokio::test]
+async fn failed_requests_can_be_retried_with_the_same_key() {
+    let test_app = spawn_app().await;
+    let nip_05_id = "unlucky_bob@test.com";
+    let mut body = signed_new_key(&test_app, nip_05_id).await;
+    let valid_challenge = body["challenge"].clone();
+    body["challenge"] = json!("0".repeat(64));
+
+    let failed = post(&test_app, "/v1/keys", Some("failed-1"), &body).await;
+    body["challenge"] = valid_challenge;
+    let retried = post(&test_app, "/v1/keys", Some("failed-1"), &body).await;
+
+    assert_eq!(failed.status(), StatusCode::FORBIDDEN);
+    assert_eq!(retried.status(), StatusCode::CREATED);
+    delete_row(&test_app.db_pool, nip_05_id.to_string()).await;
+}
+
+#[tokio::test]
+async fn uploading_a_taken_nip_05_id_is_a_conflict() {
+    let test_app = spawn_app().await;
+    let nip_05_id = "taken_bob@test.com";
+    let first = signed_new_key(&test_app, nip_05_id).await;
+    let second = signed_new_key(&test_app, nip_05_id).await;
+
+    post(&test_app, "/upload_key", None, &first).await;
+    let response = post(&test_app, "/upload_key", None, &second).await;
+
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    let body = response.json::<Value>().await.unwrap();
+    assert_eq!(body["code"], "NIP05_TAKEN");
+    delete_row(&test_app.db_pool, nip_05_id.to_string()).await;
+}
+
+#[tokio::test]
+async fn retried_recovery_gets_the_original_response() {
+    let test_app = spawn_app().await;
+    let nip_05_id = "recovering_bob@test.com";
+    let upload = post(
+        &test_app,
+        "/upload_key",
+        None,
+        &signed_new_key(&test_app, nip_05_id).await,
+    )
+    .await
+    .json::<StoredKey>()
+    .await
+    .unwrap();
+    let recovery = json!({
+        "nip_05_id": nip_05_id,
+        "recovery_code": upload.recovery_codes[0],
+        "new_pin": 918273,
+    });
+
+    let first = post(&test_app, "/recover_key", Some("recover-1"), &recovery).await;
+    let retry = post(&test_app, "/recover_key", Some("recover-1"), &recovery).await;
+    let without_key = post(&test_app, "/recover_key", None, &recovery).await;
+
+    assert_eq!(first.status(), StatusCode::OK);
+    assert_eq!(retry.status(), StatusCode::OK);
+    assert_eq!(without_key.status(), StatusCode::FORBIDDEN);
+    delete_row(&test_app.db_pool, nip_05_id.to_string()).await;
+}
+
+#[tokio::test]
+async fn malformed_idempotency_keys_are_rejected() {
+    let test_app = spawn_app().await;
+    let body = signed_new_key(&test_app, "malformed_key_bob@test.com").await;
+
+    let response = post(&test_app, "/v1/keys", Some(&"k".repeat(256)), &body).await;
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body = response.json::<Value>().await.unwrap();
+    assert_eq!(body["code"], "IDEMPOTENCY_KEY_MALFORMED");
+}
+
+#[tokio::test]
+async fn responses_are_not_stored_in_the_clear() {
+    let test_app = spawn_app().await;
+    let nip_05_id = "private_bob@test.com";
+    let body = signed_new_key(&test_app, nip_05_id).await;
+
+    let stored_key = post(&test_app, "/v1/keys", Some("private-1"), &body)
+        .await
+        .json::<StoredKey>()
+        .await
+        .unwrap();
+
+    let saved = sqlx::query_scalar::<_, String>(
+        "SELECT encrypted_response FROM idempotency WHERE idempotency_key = 'private-1'",
+    )
+    .fetch_one(&test_app.db_pool)
+    .await
+    .unwrap();
+    for recovery_code in stored_key.recovery_codes {
+        assert!(!saved.contains(&recovery_code));
+    }
+    delete_row(&test_app.db_pool, nip_05_id.to_string()).await;
+}
@@ -0,0 +1,301 @@
+use crate::helpers::{spawn_app, spawn_app_with, TestApp};
+use nostr_vault::configuration::{KeyExpirySettings, PeerSettings, ReplicationSettings};
+use nostr_vault::key_expiry::{ExpiryReport, KeyExpiry};
+use nostr_vault::metrics::{EXPIRED_KEYS, PURGEABLE_KEYS};
+use nostr_vault::replication::Replicator;
+use reqwest::StatusCode;
+use secp256k1::KeyPair;
+use secrecy::Secret;
+use serde_json::json;
+use sqlx::Row;
+use std::time::Duration;
+use tokio::sync::{Mutex, MutexGuard};
+
+const PRIVATE_KEY_HASH: &str = "$PBKDF2$i=100000,l=256,s=0Bu5lWu4s66/iottrlUGdckjf5nwnpB05jwp4yDh8NU=$AESGM$OrScsD+hHGaRaPbc$XMXVVbjt3JV+QsNb7ZWRc8uNod2YzJL0lSvW1FOiY38ywOu7IEChKs/IqEQ7knhZAmRGYqoB4dhAbdOTwVhYIeQsuf1+f+9ARPEjtURsDg==";
+const NIP_05_ID: &str = "idle_bob@test.com";
+const PIN: u64 = 374859;
+
+lazy_static::lazy_static! {
+    static ref METRICS: Mutex<()> = Mutex::new(());
+}
+
+/// The expiry metrics are process wide, tests running expiry passes take turns so the
+/// counts asserted on are their own.
+async fn observe_metrics() -> MutexGuard<'static, ()> {
+    METRICS.lock().await
+}
+
+fn expired(action: &str) -> u64 {
+    EXPIRED_KEYS.with_label_values(&[action]).get()
+}
+
+fn key_expiry(dry_run: bool) -> KeyExpiry {
+    KeyExpiry::new(&KeyExpirySettings {
+        enabled: true,
+        dry_run,
+        retention_days: 30,
+        warning_days: 7,
+        interval_seconds: 3600,
+    })
+}
+
+async fn upload(test_app: &TestApp) {
+    let keypair = KeyPair::new_global(&mut rand::thread_rng());
+    let form_data = json!({"nip_05_id":NIP_05_ID,"pin":PIN, "private_key_hash":PRIVATE_KEY_HASH});
+    let response = test_app.post_signed_upload(&keypair, form_data).await;
+    assert!(response.status().is_success());
+}
+
+async fn age(test_app: &TestApp, last_accessed_days: i32, warned_days: Option<i32>) {
+    sqlx::query(
+        r#"
+        UPDATE keys
+        SET last_accessed_at = NOW() - make_interval(days => $1),
+            expiry_warned_at = NOW() - make_interval(days => $2)
+        "#,
+    )
+    .bind(last_accessed_days)
+    .bind(warned_days)
+    .execute(&test_app.db_pool)
+    .await
+    .unwrap();
+}
+
+async fn is_warned(test_app: &TestApp) -> Option<bool> {
+    sqlx::query("SELECT expiry_warned_at IS NOT NULL AS warned FROM keys WHERE nip_05_id = $1")
+        .bind(NIP_05_ID)
+        .fetch_optional(&test_app.db_pool)
+        .await
+        .unwrap()
+        .map(|row| row.get("warned"))
+}
+
+#[tokio::test]
+async fn recently_used_keys_are_left_alone() {
+    let test_app = spawn_app().await;
+    let _metrics = observe_metrics().await;
+    upload(&test_app).await;
+
+    let report = key_expiry(false).run_once(&test_app.db_pool).await.unwrap();
+
+    assert_eq!(report, ExpiryReport::default());
+    assert_eq!(is_warned(&test_app).await, Some(false));
+}
+
+#[tokio::test]
+async fn inactive_keys_are_warned_about_before_being_purged() {
+    let test_app = spawn_app().await;
+    upload(&test_app).await;
+    let key_expiry = key_expiry(false);
+    let _metrics = observe_metrics().await;
+    let (warned, purged) = (expired("warned"), expired("purged"));
+
+    age(&test_app, 25, None).await;
+    let report = key_expiry.run_once(&test_app.db_pool).await.unwrap();
+    assert_eq!(
+        report,
+        ExpiryReport {
+            warned: 1,
+            purged: 0
+        }
+    );
+    assert_eq!(is_warned(&test_app).await, Some(true));
+
+    age(&test_app, 32, Some(7)).await;
+    let report = key_expiry.run_once(&test_app.db_pool).await.unwrap();
+    assert_eq!(
+        report,
+        ExpiryReport {
+            warned: 0,
+            purged: 1
+        }
+    );
+    assert_eq!(is_warned(&test_app).await, None);
+
+    assert_eq!(expired("warned") - warned, 1);
+    assert_eq!(expired("purged") - purged, 1);
+}
+
+#[tokio::test]
+async fn keys_past_retention_still_get_the_full_warning_period() {
+    let test_app = spawn_app().await;
+    let _metrics = observe_metrics().await;
+    upload(&test_app).await;
+
+    age(&test_app, 90, None).await;
+    let report = key_expiry(false).run_once(&test_app.db_pool).await.unwrap();
+
+    assert_eq!(
+        report,
+        ExpiryReport {
+            warned: 1,
+            purged: 0
+        }
+    );
+    assert_eq!(is_warned(&test_app).await, Some(true));
+}
+
+#[tokio::test]
+async fn fetching_a_key_clears_its_warning() {
+    let test_app = spawn_app().await;
+    let _metrics = observe_metrics().await;
+    upload(&test_app).await;
+    age(&test_app, 32, Some(7)).await;
+
+    assert_eq!(fetch(&test_app).await.status(), StatusCode::OK);
+
+    assert_eq!(is_warned(&test_app).await, Some(false));
+    let report = key_expiry(false).run_once(&test_app.db_pool).await.unwrap();
+    assert_eq!(report, ExpiryReport::default());
+}
+
+#[tokio::test]
+async fn dry_run_only_reports() {
+    let test_app = spawn_app().await;
+    upload(&test_app).await;
+    age(&test_app, 90, None).await;
+    let key_expiry = key_expiry(true);
+    let _metrics = observe_metrics().await;
+    let (warned, purged) = (expired("warned"), expired("purged"));
+
+    let report = key_expiry.run_once(&test_app.db_pool).await.unwrap();
+
+    assert_eq!(
+        report,
+        ExpiryReport {
+            warned: 1,
+            purged: 1
+        }
+    );
+    assert_eq!(is_warned(&test_app).await, Some(false));
+    assert_eq!(expired("warned"), warned);
+    assert_eq!(expired("purged"), purged);
+    assert_eq!(PURGEABLE_KEYS.get(), 1);
+}
+
+async fn has_key(test_app: &TestApp) -> bool {
+    sqlx::query("SELECT id FROM keys WHERE nip_05_id = $1")
+        .bind(NIP_05_ID)
+        .fetch_optional(&test_app.db_pool)
+        .await
+        .unwrap()
+        .is_some()
+}
+
+async fn wait_for(test_app: &TestApp, present: bool) -> bool {
+    for _ in 0..50 {
+        if has_key(test_app).await == present {
+            return true;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    false
+}
+
+async fn fetch(test_app: &TestApp) -> reqwest::Response {
+    test_app
+        .api_client
+        .post(&format!("{}/fetch_key", &test_app.address))
+        .json(&json!({"nip_05_id":NIP_05_ID, "pin":PIN}))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+#[tokio::test]
+async fn expiry_only_removes_the_local_copy_of_a_key_used_on_a_peer() {
+    let _metrics = observe_metrics().await;
+    let peer = spawn_app_with(|c| {
+        c.replication.node_id = "vault-b".to_string();
+        c.replication.auth_key = Secret::new("vault-b-key".to_string());
+        c.replication.serve_on_app_port = true;
+    })
+    .await;
+    let peer_url = peer.address.clone();
+    let test_app = spawn_app_with(move |c| {
+        c.replication.node_id = "vault-a".to_string();
+        c.replication.auth_key = Secret::new("vault-a-key".to_string());
+        c.replication.serve_on_app_port = true;
+        c.replication.peers = vec![PeerSettings {
+            url: peer_url,
+            auth_key: Secret::new("vault-b-key".to_string()),
+        }];
+    })
+    .await;
+    upload(&test_app).await;
+    assert!(wait_for(&peer, true).await);
+
+    // Only the peer sees the key being used
+    assert_eq!(fetch(&peer).await.status(), StatusCode::OK);
+    age(&test_app, 32, Some(7)).await;
+    let report = key_expiry(false).run_once(&test_app.db_pool).await.unwrap();
+    assert_eq!(report.purged, 1);
+    assert!(!has_key(&test_app).await);
+
+    // Neither side's pull undoes the other
+    let pull_into_peer = Replicator::new(&ReplicationSettings {
+        node_id: "vault-b".to_string(),
+        peers: vec![PeerSettings {
+            url: test_app.address.clone(),
+            auth_key: Secret::new("vault-a-key".to_string()),
+        }],
+        ..ReplicationSettings::default()
+    })
+    .unwrap();
+    pull_into_peer.pull_from_peers(&peer.db_pool).await;
+    assert!(has_key(&peer).await);
+    assert_eq!(fetch(&peer).await.status(), StatusCode::OK);
+    let pull_into_vault = Replicator::new(&ReplicationSettings {
+        node_id: "vault-a".to_string(),
+        peers: vec![PeerSettings {
+            url: peer.address.clone(),
+            auth_key: Secret::new("vault-b-key".to_string()),
+        }],
+        ..ReplicationSettings::default()
+    })
+    .unwrap();
+    assert_eq!(pull_into_vault.pull_from_peers(&test_app.db_pool).await, 0);
+    assert!(!has_key(&test_app).await);
+}
+
+#[tokio::test]
+async fn an_expired_key_comes_back_once_a_peer_changes_it() {
+    let _metrics = observe_metrics().await;
+    let peer = spawn_app_with(|c| {
+        c.replication.node_id = "vault-b".to_string();
+        c.replication.auth_key = Secret::new("vault-b-key".to_string());
+        c.replication.serve_on_app_port = true;
+    })
+    .await;
+    let replication = ReplicationSettings {
+        node_id: "vault-a".to_string(),
+        peers: vec![PeerSettings {
+            url: peer.address.clone(),
+            auth_key: Secret::new("vault-b-key".to_string()),
+        }],
+        ..ReplicationSettings::default()
+    };
+    let peers = replication.peers.clone();
+    let test_app = spawn_app_with(move |c| {
+        c.replication.node_id = "vault-a".to_string();
+        c.replication.peers = peers;
+    })
+    .await;
+    upload(&test_app).await;
+    assert!(wait_for(&peer, true).await);
+    age(&test_app, 32, Some(7)).await;
+    key_expiry(false).run_once(&test_app.db_pool).await.unwrap();
+
+    let response = peer
+        .api_client
+        .put(&format!("{}/v1/keys/{}", &peer.address, NIP_05_ID))
+        .json(&json!({"pin": PIN, "new_pin": 829134}))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let replicator = Replicator::new(&replication).unwrap();
+    assert_eq!(replicator.pull_from_peers(&test_app.db_pool).await, 1);
+    assert!(has_key(&test_app).await);
+}
@@ -0,0 +1,278 @@
+use crate::helpers::{delete_row, sign_upload_challenge, spawn_app, TestApp};
+use nostr_vault::authentication::StoredKey;
+use nostr_vault::routes::{ErrorCode, ErrorResponse};
+use reqwest::StatusCode;
+use secp256k1::KeyPair;
+use serde_json::json;
+
+const PRIVATE_KEY_HASH: &str = "$PBKDF2$i=100000,l=256,s=0Bu5lWu4s66/iottrlUGdckjf5nwnpB05jwp4yDh8NU=$AESGM$OrScsD+hHGaRaPbc$XMXVVbjt3JV+QsNb7ZWRc8uNod2YzJL0lSvW1FOiY38ywOu7IEChKs/IqEQ7knhZAmRGYqoB4dhAbdOTwVhYIeQsuf1+f+9ARPEjtURsDg==";
+const NEW_PRIVATE_KEY_HASH: &str = "$PBKDF2$i=100000,l=256,s=9Zu5lWu4s66/iottrlUGdckjf5nwnpB05jwp4yDh8NU=$AESGM$OrScsD+hHGaRaPbc$XMXVVbjt3JV+QsNb7ZWRc8uNod2YzJL0lSvW1FOiY38ywOu7IEChKs/IqEQ7knhZAmRGYqoB4dhAbdOTwVhYIeQsuf1+f+9ARPEjtURsDg==";
+const PIN: u64 = 374859;
+
+/// Creates `nip_05_id` through `POST /v1/keys` with a challenge signed by `keypair`.
+async fn create_key(test_app: &TestApp, keypair: &KeyPair, nip_05_id: &str) -> reqwest::Response {
+    let challenge = test_app.get_upload_challenge().await;
+    test_app
+        .api_client
+        .post(&format!("{}/v1/keys", &test_app.address))
+        .json(&json!({
+            "nip_05_id": nip_05_id,
+            "pin": PIN,
+            "private_key_hash": PRIVATE_KEY_HASH,
+            "npub": keypair.x_only_public_key().0.to_string(),
+            "signature": sign_upload_challenge(keypair, &challenge, PRIVATE_KEY_HASH),
+            "challenge": challenge,
+        }))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+async fn retrieve_key(test_app: &TestApp, nip_05_id: &str, pin: u64) -> reqwest::Response {
+    test_app
+        .api_client
+        .post(&format!(
+            "{}/v1/keys/{}/retrieve",
+            &test_app.address, nip_05_id
+        ))
+        .json(&json!({ "pin": pin }))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+async fn update_key(
+    test_app: &TestApp,
+    nip_05_id: &str,
+    update: serde_json::Value,
+) -> reqwest::Response {
+    test_app
+        .api_client
+        .put(&format!("{}/v1/keys/{}", &test_app.address, nip_05_id))
+        .json(&update)
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+#[tokio::test]
+async fn create_key_returns_created_with_a_location() {
+    let test_app = spawn_app().await;
+    let nip_05_id = "v1_create_bob@test.com";
+    let keypair = KeyPair::new_global(&mut rand::thread_rng());
+
+    let response = create_key(&test_app, &keypair, nip_05_id).await;
+
+    assert_eq!(response.status(), StatusCode::CREATED);
+    assert_eq!(
+        response.headers()["Location"].to_str().unwrap(),
+        format!("{}/v1/keys/{}", test_app.address, nip_05_id)
+    );
+    assert_eq!(
+        response.headers()["Content-Type"].to_str().unwrap(),
+        "application/json"
+    );
+    let stored_key = response.json::<StoredKey>().await.unwrap();
+    assert_eq!(stored_key.nip_05_id, nip_05_id);
+    delete_row(&test_app.db_pool, nip_05_id.to_string()).await;
+}
+
+#[tokio::test]
+async fn retrieve_key_returns_the_stored_key() {
+    let test_app = spawn_app().await;
+    let nip_05_id = "v1_retrieve_bob@test.com";
+    let keypair = KeyPair::new_global(&mut rand::thread_rng());
+    create_key(&test_app, &keypair, nip_05_id).await;
+
+    let found = retrieve_key(&test_app, nip_05_id, PIN).await;
+    let wrong_pin = retrieve_key(&test_app, nip_05_id, 111111).await;
+
+    assert_eq!(found.status(), StatusCode::OK);
+    let stored_key = found.json::<StoredKey>().await.unwrap();
+    assert_eq!(stored_key.private_key_hash, PRIVATE_KEY_HASH);
+    assert!(wrong_pin.status().is_client_error());
+    delete_row(&test_app.db_pool, nip_05_id.to_string()).await;
+}
+
+#[tokio::test]
+async fn update_key_changes_the_pin() {
+    let test_app = spawn_app().await;
+    let nip_05_id = "v1_new_pin_bob@test.com";
+    let keypair = KeyPair::new_global(&mut rand::thread_rng());
+    create_key(&test_app, &keypair, nip_05_id).await;
+
+    let response = update_key(
+        &test_app,
+        nip_05_id,
+        json!({ "pin": PIN, "new_pin": 829134 }),
+    )
+    .await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(retrieve_key(&test_app, nip_05_id, PIN)
+        .await
+        .status()
+        .is_client_error());
+    assert_eq!(
+        retrieve_key(&test_app, nip_05_id, 829134).await.status(),
+        StatusCode::OK
+    );
+    delete_row(&test_app.db_pool, nip_05_id.to_string()).await;
+}
+
+#[tokio::test]
+async fn update_key_replaces_the_blob_with_a_signed_challenge() {
+    let test_app = spawn_app().await;
+    let nip_05_id = "v1_new_blob_bob@test.com";
+    let keypair = KeyPair::new_global(&mut rand::thread_rng());
+    create_key(&test_app, &keypair, nip_05_id).await;
+    let challenge = test_app.get_upload_challenge().await;
+
+    let response = update_key(
+        &test_app,
+        nip_05_id,
+        json!({
+            "pin": PIN,
+            "private_key_hash": NEW_PRIVATE_KEY_HASH,
+            "signature": sign_upload_challenge(&keypair, &challenge, NEW_PRIVATE_KEY_HASH),
+            "challenge": challenge,
+        }),
+    )
+    .await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let stored_key = retrieve_key(&test_app, nip_05_id, PIN)
+        .await
+        .json::<StoredKey>()
+        .await
+        .unwrap();
+    assert_eq!(stored_key.private_key_hash, NEW_PRIVATE_KEY_HASH);
+    delete_row(&test_app.db_pool, nip_05_id.to_string()).await;
+}
+
+#[tokio::test]
+async fn update_key_rejects_a_blob_signed_by_another_key() {
+    let test_app = spawn_app().await;
+    let nip_05_id = "v1_other_signer_bob@test.com";
+    let keypair = KeyPair::new_global(&mut rand::thread_rng());
+    let other = KeyPair::new_global(&mut rand::thread_rng());
+    create_key(&test_app, &keypair, nip_05_id).await;
+    let challenge = test_app.get_upload_challenge().await;
+
+    let response = update_key(
+        &test_app,
+        nip_05_id,
+        json!({
+            "pin": PIN,
+            "private_key_hash": NEW_PRIVATE_KEY_HASH,
+            "signature": sign_upload_challenge(&other, &challenge, NEW_PRIVATE_KEY_HASH),
+            "challenge": challenge,
+        }),
+    )
+    .await;
+
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    delete_row(&test_app.db_pool, nip_05_id.to_string()).await;
+}
+
+#[tokio::test]
+async fn update_key_without_changes_is_rejected() {
+    let test_app = spawn_app().await;
+    let nip_05_id = "v1_no_changes_bob@test.com";
+    let keypair = KeyPair::new_global(&mut rand::thread_rng());
+    create_key(&test_app, &keypair, nip_05_id).await;
+
+    let response = update_key(&test_app, nip_05_id, json!({ "pin": PIN })).await;
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    delete_row(&test_app.db_pool, nip_05_id.to_string()).await;
+}
+
+#[tokio::test]
+async fn delete_key_removes_the_key() {
+    let test_app = spawn_app().await;
+    let nip_05_id = "v1_delete_bob@test.com";
+    let keypair = KeyPair::new_global(&mut rand::thread_rng());
+    create_key(&test_app, &keypair, nip_05_id).await;
+
+    let response = test_app
+        .api_client
+        .delete(&format!("{}/v1/keys/{}", &test_app.address, nip_05_id))
+        .json(&json!({ "pin": PIN }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert_eq!(
+        retrieve_key(&test_app, nip_05_id, PIN).await.status(),
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn delete_key_rejects_missing_and_malformed_credentials() {
+    let test_app = spawn_app().await;
+    let nip_05_id = "v1_delete_malformed_bob@test.com";
+    let keypair = KeyPair::new_global(&mut rand::thread_rng());
+    create_key(&test_app, &keypair, nip_05_id).await;
+    let url = format!("{}/v1/keys/{}", &test_app.address, nip_05_id);
+
+    let missing = test_app
+        .api_client
+        .delete(&url)
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let malformed = test_app
+        .api_client
+        .delete(&url)
+        .header("Content-Type", "application/json")
+        .body(r#"{"pin": 374859"#)
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(missing.status(), StatusCode::UNAUTHORIZED);
+    let missing = missing.json::<ErrorResponse>().await.unwrap();
+    assert_eq!(missing.code, ErrorCode::CredentialsMissing);
+    assert_eq!(malformed.status(), StatusCode::BAD_REQUEST);
+    let malformed = malformed.json::<ErrorResponse>().await.unwrap();
+    assert_eq!(malformed.code, ErrorCode::RequestMalformed);
+    assert_eq!(
+        retrieve_key(&test_app, nip_05_id, PIN).await.status(),
+        StatusCode::OK
+    );
+    delete_row(&test_app.db_pool, nip_05_id.to_string()).await;
+}
+
+#[tokio::test]
+async fn legacy_routes_are_flagged_as_deprecated() {
+    let test_app = spawn_app().await;
+    let nip_05_id = "v1_legacy_bob@test.com";
+    let keypair = KeyPair::new_global(&mut rand::thread_rng());
+    let upload = test_app
+        .post_signed_upload(
+            &keypair,
+            json!({"nip_05_id":nip_05_id,"pin":PIN, "private_key_hash":PRIVATE_KEY_HASH}),
+        )
+        .await;
+    let fetch = test_app
+        .api_client
+        .post(&format!("{}/fetch_key", &test_app.address))
+        .json(&json!({"nip_05_id":nip_05_id,"pin":PIN}))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    for response in [upload, fetch] {
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["Deprecation"], "true");
+        assert!(response.headers()["Link"]
+            .to_str()
+            .unwrap()
+            .contains("successor-version"));
+        assert_eq!(response.headers()["Content-Type"], "application/json");
+    }
+    delete_row(&test_app.db_pool, nip_05_id.to_string()).await;
+}
@@ -1,4 +1,24 @@
+mod cors;
+mod domain_policy;
+mod enumeration;
+mod errors;
 mod fetch_key;
 mod health_check;
 mod helpers;
+mod idempotency;
+mod key_expiry;
+mod keys;
+mod metrics;
+mod nip05_provider;
+mod passkeys;
+mod payments;
+mod proof_of_work;
+mod recover_key;
+mod relay_publisher;
+mod replication;
+mod second_factor;
+mod security_headers;
+mod sessions;
+mod shutdown;
+mod tls;
 mod upload_key;
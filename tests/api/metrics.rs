@@ -0,0 +1,84 @@
+use crate::helpers::{spawn_app, spawn_app_with, TestApp};
+use reqwest::StatusCode;
+use secp256k1::KeyPair;
+use serde_json::json;
+
+const PRIVATE_KEY_HASH: &str = "$PBKDF2$i=100000,l=256,s=0Bu5lWu4s66/iottrlUGdckjf5nwnpB05jwp4yDh8NU=$AESGM$OrScsD+hHGaRaPbc$XMXVVbjt3JV+QsNb7ZWRc8uNod2YzJL0lSvW1FOiY38ywOu7IEChKs/IqEQ7knhZAmRGYqoB4dhAbdOTwVhYIeQsuf1+f+9ARPEjtURsDg==";
+
+async fn get_metrics(test_app: &TestApp, address: &str) -> reqwest::Response {
+    test_app
+        .api_client
+        .get(&format!("{}/metrics", address))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+#[tokio::test]
+async fn metrics_count_requests_by_route() {
+    let test_app = spawn_app().await;
+    test_app
+        .api_client
+        .get(&format!("{}/livez", &test_app.address))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    let response = get_metrics(&test_app, &test_app.address).await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.text().await.unwrap();
+    assert!(body.contains(r#"http_requests_total{method="GET",route="/livez",status="200"}"#));
+    assert!(body.contains(r#"http_request_duration_seconds_bucket{method="GET",route="/livez""#));
+    assert!(body.contains(r#"db_pool_connections{state="idle"}"#));
+    assert!(body.contains("stored_keys "));
+}
+
+#[tokio::test]
+async fn metrics_never_contain_nip_05_ids() {
+    let test_app = spawn_app().await;
+    let nip_05_id = "very_private_bob@test.com";
+    let keypair = KeyPair::new_global(&mut rand::thread_rng());
+    let form_data =
+        json!({"nip_05_id":nip_05_id,"pin":374859, "private_key_hash":PRIVATE_KEY_HASH});
+    test_app.post_signed_upload(&keypair, form_data).await;
+    let response = test_app
+        .api_client
+        .post(&format!("{}/fetch_key", &test_app.address))
+        .json(&json!({"nip_05_id":nip_05_id, "pin":374859}))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    assert_eq!(response.status(), StatusCode::OK);
+    test_app
+        .api_client
+        .get(&format!(
+            "{}/.well-known/nostr.json?name=very_private_bob",
+            &test_app.address
+        ))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    let body = get_metrics(&test_app, &test_app.address)
+        .await
+        .text()
+        .await
+        .unwrap();
+
+    assert!(body.contains(r#"pin_verifications_total{outcome="success"}"#));
+    assert!(body.contains(r#"route="/fetch_key""#));
+    assert!(!body.contains("very_private_bob"));
+}
+
+#[tokio::test]
+async fn metrics_can_be_moved_to_their_own_port() {
+    let test_app = spawn_app_with(|c| c.metrics.port = Some(0)).await;
+    let metrics_address = format!("http://localhost:{}", test_app.metrics_port.unwrap());
+
+    let on_app_port = get_metrics(&test_app, &test_app.address).await;
+    let on_metrics_port = get_metrics(&test_app, &metrics_address).await;
+
+    assert_eq!(on_app_port.status(), StatusCode::NOT_FOUND);
+    assert_eq!(on_metrics_port.status(), StatusCode::OK);
+}
@@ -0,0 +1,188 @@
+use crate::helpers::{spawn_app_with, TestApp};
+use nostr_vault::nip05_provider::{NameAvailability, NostrJson};
+use reqwest::StatusCode;
+use secp256k1::KeyPair;
+use serde_json::json;
+
+const PRIVATE_KEY_HASH: &str = "$PBKDF2$i=100000,l=256,s=0Bu5lWu4s66/iottrlUGdckjf5nwnpB05jwp4yDh8NU=$AESGM$OrScsD+hHGaRaPbc$XMXVVbjt3JV+QsNb7ZWRc8uNod2YzJL0lSvW1FOiY38ywOu7IEChKs/IqEQ7knhZAmRGYqoB4dhAbdOTwVhYIeQsuf1+f+9ARPEjtURsDg==";
+
+async fn spawn_provider() -> TestApp {
+    spawn_app_with(|c| {
+        c.nip05_provider.domains = vec!["localhost".to_string()];
+        c.nip05_provider.reserved_names = vec!["admin".to_string()];
+    })
+    .await
+}
+
+async fn upload(test_app: &TestApp, keypair: &KeyPair, nip_05_id: &str) -> reqwest::Response {
+    let form_data = json!({
+        "nip_05_id": nip_05_id,
+        "pin": 374859,
+        "private_key_hash": PRIVATE_KEY_HASH,
+        "relays": ["wss://relay.damus.io"],
+    });
+    test_app.post_signed_upload(keypair, form_data).await
+}
+
+#[tokio::test]
+async fn nostr_json_serves_registered_pubkey_and_relays() {
+    let test_app = spawn_provider().await;
+    let keypair = KeyPair::new_global(&mut rand::thread_rng());
+    let pubkey = keypair.x_only_public_key().0.to_string();
+    assert!(upload(&test_app, &keypair, "bob@localhost")
+        .await
+        .status()
+        .is_success());
+
+    let response = test_app
+        .api_client
+        .get(&format!("{}/.well-known/nostr.json", &test_app.address))
+        .query(&[("name", "bob")])
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response
+            .headers()
+            .get("access-control-allow-origin")
+            .map(|value| value.to_str().unwrap()),
+        Some("*")
+    );
+    let nostr_json = response.json::<NostrJson>().await.unwrap();
+    assert_eq!(nostr_json.names.get("bob"), Some(&pubkey));
+    assert_eq!(
+        nostr_json.relays.get(&pubkey),
+        Some(&vec!["wss://relay.damus.io".to_string()])
+    );
+}
+
+#[tokio::test]
+async fn nostr_json_has_no_names_for_unknown_users() {
+    let test_app = spawn_provider().await;
+
+    let response = test_app
+        .api_client
+        .get(&format!("{}/.well-known/nostr.json", &test_app.address))
+        .query(&[("name", "nobody")])
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let nostr_json = response.json::<NostrJson>().await.unwrap();
+    assert!(nostr_json.names.is_empty());
+}
+
+#[tokio::test]
+async fn nostr_json_is_not_served_when_provider_is_disabled() {
+    let test_app = spawn_app_with(|_| {}).await;
+
+    let response = test_app
+        .api_client
+        .get(&format!("{}/.well-known/nostr.json", &test_app.address))
+        .query(&[("name", "bob")])
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn reserved_names_cannot_be_registered() {
+    let test_app = spawn_provider().await;
+
+    let keypair = KeyPair::new_global(&mut rand::thread_rng());
+    let response = upload(&test_app, &keypair, "Admin@localhost").await;
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn availability_reports_free_taken_and_reserved_names() {
+    let test_app = spawn_provider().await;
+    let keypair = KeyPair::new_global(&mut rand::thread_rng());
+    assert!(upload(&test_app, &keypair, "alice@localhost")
+        .await
+        .status()
+        .is_success());
+
+    for (name, expected) in [("carol", true), ("alice", false), ("admin", false)] {
+        let availability = test_app
+            .api_client
+            .get(&format!("{}/nip05/availability", &test_app.address))
+            .query(&[("name", name)])
+            .send()
+            .await
+            .expect("Failed to execute request.")
+            .json::<NameAvailability>()
+            .await
+            .unwrap();
+        assert_eq!(availability.domain, "localhost");
+        assert_eq!(
+            availability.available, expected,
+            "unexpected availability for {}",
+            name
+        );
+    }
+}
+
+async fn nostr_json_for(test_app: &TestApp, forwarded_host: &str) -> reqwest::Response {
+    test_app
+        .api_client
+        .get(&format!("{}/.well-known/nostr.json", &test_app.address))
+        .header("X-Forwarded-Host", forwarded_host)
+        .header("Origin", "https://anyone.example")
+        .query(&[("name", "bob")])
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+#[tokio::test]
+async fn forwarded_hosts_are_ignored_unless_sent_by_a_trusted_proxy() {
+    let direct = spawn_app_with(|c| {
+        c.nip05_provider.domains = vec!["provider.example".to_string()];
+    })
+    .await;
+    let proxied = spawn_app_with(|c| {
+        c.nip05_provider.domains = vec!["provider.example".to_string()];
+        c.nip05_provider.trusted_proxies = vec!["127.0.0.1".parse().unwrap()];
+    })
+    .await;
+
+    let spoofed = nostr_json_for(&direct, "provider.example").await;
+    let forwarded = nostr_json_for(&proxied, "provider.example").await;
+
+    assert_eq!(spoofed.status(), StatusCode::NOT_FOUND);
+    assert_eq!(forwarded.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn nostr_json_is_readable_from_any_origin_whatever_the_cors_settings() {
+    let test_app = spawn_app_with(|c| {
+        c.nip05_provider.domains = vec!["localhost".to_string()];
+        c.application.cors.public.allowed_origins = vec!["https://client.example".to_string()];
+    })
+    .await;
+
+    let response = test_app
+        .api_client
+        .get(&format!("{}/.well-known/nostr.json", &test_app.address))
+        .header("Origin", "https://anyone.example")
+        .query(&[("name", "bob")])
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response
+            .headers()
+            .get("access-control-allow-origin")
+            .map(|value| value.to_str().unwrap()),
+        Some("*")
+    );
+}
@@ -0,0 +1,374 @@
+use crate::helpers::{spawn_app_with, TestApp};
+use nostr_vault::passkeys::{purge_expired_ceremonies, PasskeyAssertion, PasskeyCeremony};
+use nostr_vault::second_factor::{current_step, totp_code, TotpEnrolment};
+use reqwest::StatusCode;
+use secp256k1::KeyPair;
+use serde_json::json;
+use webauthn_authenticator_rs::prelude::Url;
+use webauthn_authenticator_rs::softpasskey::SoftPasskey;
+use webauthn_authenticator_rs::WebauthnAuthenticator;
+use webauthn_rs::prelude::{CreationChallengeResponse, RequestChallengeResponse};
+
+const PRIVATE_KEY_HASH: &str = "$PBKDF2$i=100000,l=256,s=0Bu5lWu4s66/iottrlUGdckjf5nwnpB05jwp4yDh8NU=$AESGM$OrScsD+hHGaRaPbc$XMXVVbjt3JV+QsNb7ZWRc8uNod2YzJL0lSvW1FOiY38ywOu7IEChKs/IqEQ7knhZAmRGYqoB4dhAbdOTwVhYIeQsuf1+f+9ARPEjtURsDg==";
+const NIP_05_ID: &str = "passkey_bob@test.com";
+const PIN: u64 = 582716;
+const ORIGIN: &str = "http://localhost";
+
+/// WebAuthn needs a domain name as relying party, so the app is told it lives on localhost.
+async fn spawn_passkey_app() -> TestApp {
+    spawn_app_with(|c| c.application.base_url = ORIGIN.to_string()).await
+}
+
+fn authenticator() -> WebauthnAuthenticator<SoftPasskey> {
+    WebauthnAuthenticator::new(SoftPasskey::new())
+}
+
+async fn upload(test_app: &TestApp) {
+    let keypair = KeyPair::new_global(&mut rand::thread_rng());
+    let form_data = json!({"nip_05_id":NIP_05_ID,"pin":PIN, "private_key_hash":PRIVATE_KEY_HASH});
+    let response = test_app.post_signed_upload(&keypair, form_data).await;
+    assert!(response.status().is_success());
+}
+
+async fn post(test_app: &TestApp, path: &str, body: serde_json::Value) -> reqwest::Response {
+    test_app
+        .api_client
+        .post(&format!("{}{}", &test_app.address, path))
+        .json(&body)
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+async fn register(
+    test_app: &TestApp,
+    authenticator: &mut WebauthnAuthenticator<SoftPasskey>,
+    passkey: Option<&PasskeyAssertion>,
+    replaces_pin: bool,
+) -> reqwest::Response {
+    let response = post(
+        test_app,
+        "/passkey/register/start",
+        json!({"nip_05_id":NIP_05_ID, "pin":PIN, "passkey":passkey}),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let ceremony = response
+        .json::<PasskeyCeremony<CreationChallengeResponse>>()
+        .await
+        .unwrap();
+    let credential = authenticator
+        .do_registration(Url::parse(ORIGIN).unwrap(), ceremony.options)
+        .expect("Software authenticator failed to register.");
+
+    post(
+        test_app,
+        "/passkey/register/finish",
+        json!({
+            "ceremony_id":ceremony.ceremony_id,
+            "credential":credential,
+            "replaces_pin":replaces_pin,
+        }),
+    )
+    .await
+}
+
+async fn assert_passkey(
+    test_app: &TestApp,
+    authenticator: &mut WebauthnAuthenticator<SoftPasskey>,
+) -> PasskeyAssertion {
+    let response = post(
+        test_app,
+        "/passkey/authenticate/start",
+        json!({"nip_05_id":NIP_05_ID}),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let ceremony = response
+        .json::<PasskeyCeremony<RequestChallengeResponse>>()
+        .await
+        .unwrap();
+    let credential = authenticator
+        .do_authentication(Url::parse(ORIGIN).unwrap(), ceremony.options)
+        .expect("Software authenticator failed to sign.");
+    PasskeyAssertion {
+        ceremony_id: ceremony.ceremony_id,
+        credential,
+    }
+}
+
+async fn fetch(test_app: &TestApp, passkey: Option<&PasskeyAssertion>) -> reqwest::Response {
+    post(
+        test_app,
+        "/fetch_key",
+        json!({"nip_05_id":NIP_05_ID, "pin":PIN, "passkey":passkey}),
+    )
+    .await
+}
+
+#[tokio::test]
+async fn registered_passkey_is_required_by_fetch_key() {
+    let test_app = spawn_passkey_app().await;
+    upload(&test_app).await;
+    let mut authenticator = authenticator();
+    let response = register(&test_app, &mut authenticator, None, false).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    assert_eq!(
+        fetch(&test_app, None).await.status(),
+        StatusCode::UNAUTHORIZED
+    );
+    let assertion = assert_passkey(&test_app, &mut authenticator).await;
+    assert_eq!(
+        fetch(&test_app, Some(&assertion)).await.status(),
+        StatusCode::OK
+    );
+}
+
+#[tokio::test]
+async fn an_assertion_can_not_be_replayed() {
+    let test_app = spawn_passkey_app().await;
+    upload(&test_app).await;
+    let mut authenticator = authenticator();
+    register(&test_app, &mut authenticator, None, false).await;
+    let assertion = assert_passkey(&test_app, &mut authenticator).await;
+    assert_eq!(
+        fetch(&test_app, Some(&assertion)).await.status(),
+        StatusCode::OK
+    );
+
+    assert_eq!(
+        fetch(&test_app, Some(&assertion)).await.status(),
+        StatusCode::FORBIDDEN
+    );
+}
+
+#[tokio::test]
+async fn a_sign_count_that_does_not_increase_is_rejected() {
+    let test_app = spawn_passkey_app().await;
+    upload(&test_app).await;
+    let mut authenticator = authenticator();
+    register(&test_app, &mut authenticator, None, false).await;
+    // As if a cloned authenticator had already been used a number of times
+    sqlx::query("UPDATE passkeys SET sign_count = 100")
+        .execute(&test_app.db_pool)
+        .await
+        .unwrap();
+
+    let assertion = assert_passkey(&test_app, &mut authenticator).await;
+    assert_eq!(
+        fetch(&test_app, Some(&assertion)).await.status(),
+        StatusCode::FORBIDDEN
+    );
+}
+
+#[tokio::test]
+async fn adding_a_passkey_requires_the_existing_one() {
+    let test_app = spawn_passkey_app().await;
+    upload(&test_app).await;
+    let mut first = authenticator();
+    register(&test_app, &mut first, None, false).await;
+
+    let response = post(
+        &test_app,
+        "/passkey/register/start",
+        json!({"nip_05_id":NIP_05_ID, "pin":PIN}),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let assertion = assert_passkey(&test_app, &mut first).await;
+    let response = register(&test_app, &mut authenticator(), Some(&assertion), false).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn a_totp_factor_can_not_be_added_with_the_pin_alone() {
+    let test_app = spawn_passkey_app().await;
+    upload(&test_app).await;
+    let mut authenticator = authenticator();
+    register(&test_app, &mut authenticator, None, false).await;
+
+    let response = post(
+        &test_app,
+        "/second_factor/enrol",
+        json!({"nip_05_id":NIP_05_ID, "pin":PIN}),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let assertion = assert_passkey(&test_app, &mut authenticator).await;
+    let response = post(
+        &test_app,
+        "/second_factor/enrol",
+        json!({"nip_05_id":NIP_05_ID, "pin":PIN, "passkey":assertion}),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let enrolment = response.json::<TotpEnrolment>().await.unwrap();
+    let secret = data_encoding::BASE32_NOPAD
+        .decode(enrolment.secret.as_bytes())
+        .unwrap();
+    let code = totp_code(&secret, current_step());
+
+    let response = post(
+        &test_app,
+        "/second_factor/confirm",
+        json!({"nip_05_id":NIP_05_ID, "pin":PIN, "totp_code":code}),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = post(
+        &test_app,
+        "/fetch_key",
+        json!({"nip_05_id":NIP_05_ID, "pin":PIN, "totp_code":code}),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let assertion = assert_passkey(&test_app, &mut authenticator).await;
+    let response = post(
+        &test_app,
+        "/second_factor/confirm",
+        json!({"nip_05_id":NIP_05_ID, "pin":PIN, "totp_code":code, "passkey":assertion}),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn passkey_registered_to_replace_the_pin_fetches_the_key() {
+    let test_app = spawn_passkey_app().await;
+    upload(&test_app).await;
+    let mut authenticator = authenticator();
+    register(&test_app, &mut authenticator, None, true).await;
+
+    let assertion = assert_passkey(&test_app, &mut authenticator).await;
+    let response = post(
+        &test_app,
+        "/passkey/fetch_key",
+        json!({"nip_05_id":NIP_05_ID, "passkey":assertion}),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.json::<serde_json::Value>().await.unwrap();
+    assert_eq!(body["private_key_hash"], PRIVATE_KEY_HASH);
+}
+
+#[tokio::test]
+async fn second_factor_passkey_can_not_replace_the_pin() {
+    let test_app = spawn_passkey_app().await;
+    upload(&test_app).await;
+    let mut authenticator = authenticator();
+    register(&test_app, &mut authenticator, None, false).await;
+
+    let assertion = assert_passkey(&test_app, &mut authenticator).await;
+    let response = post(
+        &test_app,
+        "/passkey/fetch_key",
+        json!({"nip_05_id":NIP_05_ID, "passkey":assertion}),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn registration_with_a_credential_for_another_ceremony_is_rejected() {
+    let test_app = spawn_passkey_app().await;
+    upload(&test_app).await;
+    let mut authenticator = authenticator();
+    let response = post(
+        &test_app,
+        "/passkey/register/start",
+        json!({"nip_05_id":NIP_05_ID, "pin":PIN}),
+    )
+    .await;
+    let first = response
+        .json::<PasskeyCeremony<CreationChallengeResponse>>()
+        .await
+        .unwrap();
+    let response = post(
+        &test_app,
+        "/passkey/register/start",
+        json!({"nip_05_id":NIP_05_ID, "pin":PIN}),
+    )
+    .await;
+    let second = response
+        .json::<PasskeyCeremony<CreationChallengeResponse>>()
+        .await
+        .unwrap();
+    let credential = authenticator
+        .do_registration(Url::parse(ORIGIN).unwrap(), second.options)
+        .unwrap();
+
+    let response = post(
+        &test_app,
+        "/passkey/register/finish",
+        json!({"ceremony_id":first.ceremony_id, "credential":credential}),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn unknown_ids_get_decoy_options_shaped_like_real_ones() {
+    let test_app = spawn_passkey_app().await;
+    upload(&test_app).await;
+    let mut authenticator = authenticator();
+    let response = register(&test_app, &mut authenticator, None, false).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let start = |nip_05_id: &'static str| {
+        let test_app = &test_app;
+        async move {
+            let response = post(
+                test_app,
+                "/passkey/authenticate/start",
+                json!({ "nip_05_id": nip_05_id }),
+            )
+            .await;
+            assert_eq!(response.status(), StatusCode::OK);
+            response.json::<serde_json::Value>().await.unwrap()
+        }
+    };
+
+    let real = start(NIP_05_ID).await;
+    let decoy = start("nobody@test.com").await;
+    let again = start("nobody@test.com").await;
+
+    let real = &real["options"]["publicKey"];
+    let (decoy, again) = (
+        &decoy["options"]["publicKey"],
+        &again["options"]["publicKey"],
+    );
+    let keys = |options: &serde_json::Value| {
+        let mut keys: Vec<String> = options.as_object().unwrap().keys().cloned().collect();
+        keys.sort();
+        keys
+    };
+    assert_eq!(keys(real), keys(decoy));
+    for field in ["timeout", "rpId", "userVerification"] {
+        assert_eq!(real[field], decoy[field], "{}", field);
+    }
+    assert_eq!(decoy["allowCredentials"].as_array().unwrap().len(), 1);
+    assert_eq!(decoy["allowCredentials"], again["allowCredentials"]);
+    assert_ne!(decoy["challenge"], again["challenge"]);
+}
+
+#[tokio::test]
+async fn expired_ceremonies_are_purged() {
+    let test_app = spawn_passkey_app().await;
+    upload(&test_app).await;
+    let mut authenticator = authenticator();
+    let response = register(&test_app, &mut authenticator, None, false).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_passkey(&test_app, &mut authenticator).await;
+    sqlx::query!("UPDATE passkey_ceremonies SET expires_at = NOW() - INTERVAL '1 second'")
+        .execute(&test_app.db_pool)
+        .await
+        .unwrap();
+    assert_passkey(&test_app, &mut authenticator).await;
+
+    let purged = purge_expired_ceremonies(&test_app.db_pool).await.unwrap();
+
+    assert_eq!(purged, 1);
+}
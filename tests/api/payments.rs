@@ -0,0 +1,201 @@
+use crate::helpers::{sign_upload_challenge, spawn_app_with, TestApp};
+use nostr_vault::lightning::FakeNode;
+use nostr_vault::routes::{ErrorCode, ErrorResponse};
+use reqwest::StatusCode;
+use secp256k1::KeyPair;
+use serde_json::{json, Value};
+
+const PRIVATE_KEY_HASH: &str = "$PBKDF2$i=100000,l=256,s=0Bu5lWu4s66/iottrlUGdckjf5nwnpB05jwp4yDh8NU=$AESGM$OrScsD+hHGaRaPbc$XMXVVbjt3JV+QsNb7ZWRc8uNod2YzJL0lSvW1FOiY38ywOu7IEChKs/IqEQ7knhZAmRGYqoB4dhAbdOTwVhYIeQsuf1+f+9ARPEjtURsDg==";
+
+async fn spawn_paying_app() -> TestApp {
+    spawn_app_with(|c| c.payments.enabled = true).await
+}
+
+/// Upload body for a fresh key for `nip_05_id`, signed over a new challenge.
+async fn new_key(test_app: &TestApp, nip_05_id: &str) -> Value {
+    let keypair = KeyPair::new_global(&mut rand::thread_rng());
+    let challenge = test_app.get_upload_challenge().await;
+    json!({
+        "nip_05_id": nip_05_id,
+        "pin": 720461,
+        "private_key_hash": PRIVATE_KEY_HASH,
+        "npub": keypair.x_only_public_key().0.to_string(),
+        "challenge": challenge,
+        "signature": sign_upload_challenge(&keypair, &challenge, PRIVATE_KEY_HASH),
+    })
+}
+
+/// Posts `body` to `/upload_key` with the given `Authorization` and `Idempotency-Key` headers.
+async fn send(
+    test_app: &TestApp,
+    body: &Value,
+    authorization: Option<&str>,
+    idempotency_key: Option<&str>,
+) -> reqwest::Response {
+    let mut request = test_app
+        .api_client
+        .post(&format!("{}/upload_key", &test_app.address))
+        .json(body);
+    if let Some(authorization) = authorization {
+        request = request.header("Authorization", authorization);
+    }
+    if let Some(idempotency_key) = idempotency_key {
+        request = request.header("Idempotency-Key", idempotency_key);
+    }
+    request.send().await.expect("Failed to execute request.")
+}
+
+/// Uploads a fresh key for `nip_05_id`, with `authorization` as the `Authorization` header.
+async fn upload(
+    test_app: &TestApp,
+    nip_05_id: &str,
+    authorization: Option<&str>,
+) -> reqwest::Response {
+    let body = new_key(test_app, nip_05_id).await;
+    send(test_app, &body, authorization, None).await
+}
+
+async fn count_invoices(test_app: &TestApp) -> i64 {
+    sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM challenges WHERE purpose = 'payment'")
+        .fetch_one(&test_app.db_pool)
+        .await
+        .unwrap()
+}
+
+/// `quoted` value of `name` in an L402 `WWW-Authenticate` header.
+fn param(header: &str, name: &str) -> String {
+    let start = header.find(&format!("{}=\"", name)).unwrap() + name.len() + 2;
+    let end = start + header[start..].find('"').unwrap();
+    header[start..end].to_string()
+}
+
+/// Asks for an invoice, pays it with the fake node and returns the `Authorization` header.
+async fn pay(test_app: &TestApp, nip_05_id: &str) -> String {
+    let response = upload(test_app, nip_05_id, None).await;
+    assert_eq!(response.status(), StatusCode::PAYMENT_REQUIRED);
+    let header = response.headers()["WWW-Authenticate"]
+        .to_str()
+        .unwrap()
+        .to_string();
+    assert!(header.starts_with("L402 "));
+    let preimage = FakeNode::pay(&param(&header, "invoice")).unwrap();
+    format!(
+        "L402 {}:{}",
+        param(&header, "macaroon"),
+        hex::encode(preimage)
+    )
+}
+
+async fn error_code(response: reqwest::Response) -> ErrorCode {
+    response.json::<ErrorResponse>().await.unwrap().code
+}
+
+#[tokio::test]
+async fn uploads_are_free_while_payments_are_off() {
+    let test_app = spawn_app_with(|_| {}).await;
+
+    let response = upload(&test_app, "free_bob@test.com", None).await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn an_unpaid_upload_gets_an_invoice() {
+    let test_app = spawn_paying_app().await;
+
+    let response = upload(&test_app, "unpaid_bob@test.com", None).await;
+
+    assert_eq!(response.status(), StatusCode::PAYMENT_REQUIRED);
+    assert!(response.headers().contains_key("WWW-Authenticate"));
+    assert_eq!(error_code(response).await, ErrorCode::PaymentRequired);
+}
+
+#[tokio::test]
+async fn a_paid_token_stores_exactly_one_key() {
+    let test_app = spawn_paying_app().await;
+    let authorization = pay(&test_app, "paid_bob@test.com").await;
+
+    let response = upload(&test_app, "paid_bob@test.com", Some(&authorization)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = upload(&test_app, "paid_alice@test.com", Some(&authorization)).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert_eq!(error_code(response).await, ErrorCode::PaymentInvalid);
+}
+
+#[tokio::test]
+async fn a_wrong_preimage_is_rejected() {
+    let test_app = spawn_paying_app().await;
+    let authorization = pay(&test_app, "wrong_preimage_bob@test.com").await;
+    let (macaroon, _) = authorization.rsplit_once(':').unwrap();
+
+    let response = upload(
+        &test_app,
+        "wrong_preimage_bob@test.com",
+        Some(&format!("{}:{}", macaroon, "00".repeat(32))),
+    )
+    .await;
+
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert_eq!(error_code(response).await, ErrorCode::PaymentInvalid);
+}
+
+#[tokio::test]
+async fn a_taken_nip_05_id_does_not_use_up_the_payment() {
+    let test_app = spawn_paying_app().await;
+    let first = pay(&test_app, "taken_bob@test.com").await;
+    let response = upload(&test_app, "taken_bob@test.com", Some(&first)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let second = pay(&test_app, "taken_bob@test.com").await;
+    let response = upload(&test_app, "taken_bob@test.com", Some(&second)).await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+
+    let response = upload(&test_app, "taken_alice@test.com", Some(&second)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn a_malformed_upload_is_rejected_without_an_invoice() {
+    let test_app = spawn_paying_app().await;
+    let mut body = new_key(&test_app, "malformed_bob@test.com").await;
+    body["nip_05_id"] = json!("not a nip 05 id");
+
+    let response = send(&test_app, &body, None, None).await;
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert!(!response.headers().contains_key("WWW-Authenticate"));
+    assert_eq!(count_invoices(&test_app).await, 0);
+}
+
+#[tokio::test]
+async fn a_replayed_upload_does_not_ask_for_payment_again() {
+    let test_app = spawn_paying_app().await;
+    let authorization = pay(&test_app, "replayed_bob@test.com").await;
+    let body = new_key(&test_app, "replayed_bob@test.com").await;
+    let response = send(&test_app, &body, Some(&authorization), Some("paid-1")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let invoices = count_invoices(&test_app).await;
+
+    let retry = send(&test_app, &body, None, Some("paid-1")).await;
+
+    assert_eq!(retry.status(), StatusCode::OK);
+    assert_eq!(count_invoices(&test_app).await, invoices);
+}
+
+#[tokio::test]
+async fn a_spent_payment_does_not_use_up_the_upload_challenge() {
+    let test_app = spawn_paying_app().await;
+    let spent = pay(&test_app, "spent_bob@test.com").await;
+    let response = upload(&test_app, "spent_bob@test.com", Some(&spent)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = new_key(&test_app, "spent_alice@test.com").await;
+
+    let response = send(&test_app, &body, Some(&spent), None).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert_eq!(error_code(response).await, ErrorCode::PaymentInvalid);
+
+    let fresh = pay(&test_app, "spent_alice@test.com").await;
+    let response = send(&test_app, &body, Some(&fresh), None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
@@ -0,0 +1,199 @@
+use crate::helpers::{spawn_app_with, TestApp};
+use nostr_vault::proof_of_work::{leading_zero_bits, work_digest, WorkChallenge};
+use nostr_vault::routes::{ErrorCode, ErrorResponse};
+use reqwest::StatusCode;
+use secp256k1::KeyPair;
+use serde_json::json;
+
+const PRIVATE_KEY_HASH: &str = "$PBKDF2$i=100000,l=256,s=0Bu5lWu4s66/iottrlUGdckjf5nwnpB05jwp4yDh8NU=$AESGM$OrScsD+hHGaRaPbc$XMXVVbjt3JV+QsNb7ZWRc8uNod2YzJL0lSvW1FOiY38ywOu7IEChKs/IqEQ7knhZAmRGYqoB4dhAbdOTwVhYIeQsuf1+f+9ARPEjtURsDg==";
+const PIN: u64 = 720461;
+
+async fn work_challenge(test_app: &TestApp, nip_05_id: &str, route: &str) -> WorkChallenge {
+    let response = test_app
+        .api_client
+        .post(&format!("{}/work_challenge", &test_app.address))
+        .json(&json!({ "nip_05_id": nip_05_id, "route": route }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    assert_eq!(response.status(), StatusCode::OK);
+    response.json().await.unwrap()
+}
+
+/// A fresh challenge for `nip_05_id`, solved at the difficulty it was issued with.
+async fn solve(test_app: &TestApp, nip_05_id: &str, route: &str) -> serde_json::Value {
+    let challenge = work_challenge(test_app, nip_05_id, route).await;
+    let nonce = (0u64..)
+        .find(|nonce| {
+            leading_zero_bits(&work_digest(&challenge.challenge, nip_05_id, *nonce))
+                >= challenge.difficulty as u32
+        })
+        .unwrap();
+    json!({ "challenge": challenge.challenge, "nonce": nonce })
+}
+
+async fn upload(
+    test_app: &TestApp,
+    nip_05_id: &str,
+    proof_of_work: Option<serde_json::Value>,
+) -> reqwest::Response {
+    let keypair = KeyPair::new_global(&mut rand::thread_rng());
+    test_app
+        .post_signed_upload(
+            &keypair,
+            json!({
+                "nip_05_id": nip_05_id,
+                "pin": PIN,
+                "private_key_hash": PRIVATE_KEY_HASH,
+                "proof_of_work": proof_of_work,
+            }),
+        )
+        .await
+}
+
+async fn retrieve(
+    test_app: &TestApp,
+    nip_05_id: &str,
+    pin: u64,
+    proof_of_work: Option<serde_json::Value>,
+) -> reqwest::Response {
+    test_app
+        .api_client
+        .post(&format!(
+            "{}/v1/keys/{}/retrieve",
+            &test_app.address, nip_05_id
+        ))
+        .json(&json!({ "pin": pin, "proof_of_work": proof_of_work }))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+async fn error_code(response: reqwest::Response) -> ErrorCode {
+    response.json::<ErrorResponse>().await.unwrap().code
+}
+
+#[tokio::test]
+async fn uploads_need_work_once_a_difficulty_is_set() {
+    let test_app = spawn_app_with(|c| c.proof_of_work.upload_difficulty = 8).await;
+    let nip_05_id = "work_upload_bob@test.com";
+
+    let response = upload(&test_app, nip_05_id, None).await;
+    assert_eq!(response.status(), StatusCode::PRECONDITION_REQUIRED);
+    assert_eq!(error_code(response).await, ErrorCode::ProofOfWorkRequired);
+
+    let proof_of_work = solve(&test_app, nip_05_id, "upload").await;
+    let response = upload(&test_app, nip_05_id, Some(proof_of_work)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn fetches_need_work_once_a_difficulty_is_set() {
+    let test_app = spawn_app_with(|c| c.proof_of_work.fetch_difficulty = 8).await;
+    let nip_05_id = "work_fetch_bob@test.com";
+    let response = upload(&test_app, nip_05_id, None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = retrieve(&test_app, nip_05_id, PIN, None).await;
+    assert_eq!(response.status(), StatusCode::PRECONDITION_REQUIRED);
+
+    let proof_of_work = solve(&test_app, nip_05_id, "fetch").await;
+    let response = retrieve(&test_app, nip_05_id, PIN, Some(proof_of_work.clone())).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Challenges are single use
+    let response = retrieve(&test_app, nip_05_id, PIN, Some(proof_of_work)).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert_eq!(error_code(response).await, ErrorCode::ProofOfWorkInvalid);
+}
+
+#[tokio::test]
+async fn challenges_only_count_for_their_nip_05_id_and_route() {
+    let test_app = spawn_app_with(|c| c.proof_of_work.fetch_difficulty = 4).await;
+    let nip_05_id = "work_bound_bob@test.com";
+    let response = upload(&test_app, nip_05_id, None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let for_alice = solve(&test_app, "work_bound_alice@test.com", "fetch").await;
+    let response = retrieve(&test_app, nip_05_id, PIN, Some(for_alice)).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert_eq!(error_code(response).await, ErrorCode::ProofOfWorkInvalid);
+
+    let for_upload = solve(&test_app, nip_05_id, "upload").await;
+    let response = retrieve(&test_app, nip_05_id, PIN, Some(for_upload)).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn failed_attempts_raise_the_difficulty_for_the_nip_05_id() {
+    let test_app = spawn_app_with(|c| {
+        c.proof_of_work.fetch_difficulty = 0;
+        c.proof_of_work.difficulty_per_failure = 4;
+    })
+    .await;
+    let nip_05_id = "work_failures_bob@test.com";
+    let response = upload(&test_app, nip_05_id, None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let challenge = work_challenge(&test_app, nip_05_id, "fetch").await;
+    assert_eq!(challenge.difficulty, 0);
+
+    for _ in 0..2 {
+        let response = retrieve(&test_app, nip_05_id, 111111, None).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    let challenge = work_challenge(&test_app, nip_05_id, "fetch").await;
+    assert_eq!(challenge.difficulty, 8);
+    let other = work_challenge(&test_app, "work_failures_alice@test.com", "fetch").await;
+    assert_eq!(other.difficulty, 0);
+    let response = retrieve(&test_app, nip_05_id, PIN, None).await;
+    assert_eq!(response.status(), StatusCode::PRECONDITION_REQUIRED);
+
+    let proof_of_work = solve(&test_app, nip_05_id, "fetch").await;
+    let response = retrieve(&test_app, nip_05_id, PIN, Some(proof_of_work)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn bad_pins_on_updates_raise_the_difficulty_too() {
+    let test_app = spawn_app_with(|c| {
+        c.proof_of_work.fetch_difficulty = 0;
+        c.proof_of_work.difficulty_per_failure = 4;
+    })
+    .await;
+    let nip_05_id = "work_update_bob@test.com";
+    let response = upload(&test_app, nip_05_id, None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    for _ in 0..3 {
+        let response = test_app
+            .api_client
+            .put(&format!("{}/v1/keys/{}", &test_app.address, nip_05_id))
+            .json(&json!({ "pin": 111111, "new_pin": 222222 }))
+            .send()
+            .await
+            .expect("Failed to execute request.");
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    let challenge = work_challenge(&test_app, nip_05_id, "fetch").await;
+    assert_eq!(challenge.difficulty, 12);
+    let response = test_app
+        .api_client
+        .put(&format!("{}/v1/keys/{}", &test_app.address, nip_05_id))
+        .json(&json!({ "pin": PIN, "new_pin": 222222 }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    assert_eq!(response.status(), StatusCode::PRECONDITION_REQUIRED);
+
+    let proof_of_work = solve(&test_app, nip_05_id, "fetch").await;
+    let response = test_app
+        .api_client
+        .put(&format!("{}/v1/keys/{}", &test_app.address, nip_05_id))
+        .json(&json!({ "pin": PIN, "new_pin": 222222, "proof_of_work": proof_of_work }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    assert_eq!(response.status(), StatusCode::OK);
+}
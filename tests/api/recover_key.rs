@@ -0,0 +1,224 @@
+use crate::helpers::{spawn_app, spawn_app_with, TestApp};
+use nostr_vault::authentication::StoredKey;
+use nostr_vault::proof_of_work::WorkChallenge;
+use nostr_vault::second_factor::{current_step, totp_code, TotpEnrolment};
+use reqwest::StatusCode;
+use secp256k1::KeyPair;
+use serde_json::json;
+
+const PRIVATE_KEY_HASH: &str = "$PBKDF2$i=100000,l=256,s=0Bu5lWu4s66/iottrlUGdckjf5nwnpB05jwp4yDh8NU=$AESGM$OrScsD+hHGaRaPbc$XMXVVbjt3JV+QsNb7ZWRc8uNod2YzJL0lSvW1FOiY38ywOu7IEChKs/IqEQ7knhZAmRGYqoB4dhAbdOTwVhYIeQsuf1+f+9ARPEjtURsDg==";
+
+async fn upload(test_app: &TestApp, nip_05_id: &str) -> Vec<String> {
+    let keypair = KeyPair::new_global(&mut rand::thread_rng());
+    let form_data =
+        json!({"nip_05_id":nip_05_id,"pin":374859, "private_key_hash":PRIVATE_KEY_HASH});
+    let response = test_app.post_signed_upload(&keypair, form_data).await;
+    assert!(response.status().is_success());
+    response.json::<StoredKey>().await.unwrap().recovery_codes
+}
+
+async fn post(test_app: &TestApp, path: &str, body: serde_json::Value) -> reqwest::Response {
+    test_app
+        .api_client
+        .post(&format!("{}{}", &test_app.address, path))
+        .json(&body)
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+async fn recover(
+    test_app: &TestApp,
+    nip_05_id: &str,
+    recovery_code: &str,
+    new_pin: u64,
+) -> reqwest::Response {
+    let req_data = json!({"nip_05_id":nip_05_id, "recovery_code":recovery_code, "new_pin":new_pin});
+    post(test_app, "/recover_key", req_data).await
+}
+
+async fn fetch(test_app: &TestApp, nip_05_id: &str, pin: u64) -> reqwest::Response {
+    let req_data = json!({"nip_05_id":nip_05_id, "pin":pin});
+    post(test_app, "/fetch_key", req_data).await
+}
+
+#[tokio::test]
+async fn upload_key_returns_recovery_codes_once() {
+    let test_app = spawn_app().await;
+    let nip_05_id = "forgetful_bob@test.com";
+
+    let recovery_codes = upload(&test_app, nip_05_id).await;
+
+    assert_eq!(recovery_codes.len(), 10);
+    let fetched = fetch(&test_app, nip_05_id, 374859)
+        .await
+        .json::<StoredKey>()
+        .await
+        .unwrap();
+    assert!(fetched.recovery_codes.is_empty());
+}
+
+#[tokio::test]
+async fn recovery_code_replaces_the_pin() {
+    let test_app = spawn_app().await;
+    let nip_05_id = "forgetful_bob@test.com";
+    let recovery_codes = upload(&test_app, nip_05_id).await;
+
+    let response = recover(&test_app, nip_05_id, &recovery_codes[3], 112233).await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let recovered = response.json::<StoredKey>().await.unwrap();
+    assert_eq!(recovered.private_key_hash, PRIVATE_KEY_HASH);
+    assert_eq!(
+        fetch(&test_app, nip_05_id, 374859).await.status(),
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        fetch(&test_app, nip_05_id, 112233).await.status(),
+        StatusCode::OK
+    );
+}
+
+#[tokio::test]
+async fn recovery_code_is_accepted_without_separator_in_lowercase() {
+    let test_app = spawn_app().await;
+    let nip_05_id = "forgetful_bob@test.com";
+    let recovery_codes = upload(&test_app, nip_05_id).await;
+    let typed = recovery_codes[0].replace('-', "").to_lowercase();
+
+    let response = recover(&test_app, nip_05_id, &typed, 112233).await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn recovery_code_can_only_be_used_once() {
+    let test_app = spawn_app().await;
+    let nip_05_id = "forgetful_bob@test.com";
+    let recovery_codes = upload(&test_app, nip_05_id).await;
+
+    let first = recover(&test_app, nip_05_id, &recovery_codes[0], 112233).await;
+    let second = recover(&test_app, nip_05_id, &recovery_codes[0], 445566).await;
+
+    assert_eq!(first.status(), StatusCode::OK);
+    assert_eq!(second.status(), StatusCode::FORBIDDEN);
+    assert_eq!(
+        fetch(&test_app, nip_05_id, 112233).await.status(),
+        StatusCode::OK
+    );
+}
+
+#[tokio::test]
+async fn concurrent_recoveries_with_one_code_only_reset_the_pin_once() {
+    let test_app = spawn_app().await;
+    let nip_05_id = "racing_bob@test.com";
+    let recovery_codes = upload(&test_app, nip_05_id).await;
+
+    let (first, second) = tokio::join!(
+        recover(&test_app, nip_05_id, &recovery_codes[0], 112233),
+        recover(&test_app, nip_05_id, &recovery_codes[0], 445566),
+    );
+
+    let mut statuses = vec![first.status(), second.status()];
+    statuses.sort();
+    assert_eq!(statuses, vec![StatusCode::OK, StatusCode::FORBIDDEN]);
+    let unused = sqlx::query!(
+        "SELECT COUNT(*) AS unused FROM recovery_codes WHERE used_at IS NULL
+        AND key_id = (SELECT id FROM keys WHERE nip_05_id = $1)",
+        nip_05_id
+    )
+    .fetch_one(&test_app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(unused.unused, Some(9));
+}
+
+#[tokio::test]
+async fn recovery_codes_are_not_shared_between_users() {
+    let test_app = spawn_app().await;
+    let alice_codes = upload(&test_app, "forgetful_alice@test.com").await;
+    upload(&test_app, "forgetful_bob@test.com").await;
+
+    let response = recover(&test_app, "forgetful_bob@test.com", &alice_codes[0], 112233).await;
+
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn malformed_recovery_code_is_rejected() {
+    let test_app = spawn_app().await;
+    let nip_05_id = "forgetful_bob@test.com";
+    upload(&test_app, nip_05_id).await;
+
+    let response = recover(&test_app, nip_05_id, "not-a-code", 112233).await;
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn recovery_needs_the_enrolled_second_factor() {
+    let test_app = spawn_app().await;
+    let nip_05_id = "careful_forgetful_bob@test.com";
+    let recovery_codes = upload(&test_app, nip_05_id).await;
+    let response = post(
+        &test_app,
+        "/second_factor/enrol",
+        json!({"nip_05_id":nip_05_id, "pin":374859}),
+    )
+    .await;
+    let enrolment = response.json::<TotpEnrolment>().await.unwrap();
+    let secret = data_encoding::BASE32_NOPAD
+        .decode(enrolment.secret.as_bytes())
+        .unwrap();
+    let step = current_step();
+    let response = post(
+        &test_app,
+        "/second_factor/confirm",
+        json!({"nip_05_id":nip_05_id, "pin":374859, "totp_code":totp_code(&secret, step)}),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let without = recover(&test_app, nip_05_id, &recovery_codes[0], 112233).await;
+    let with = post(
+        &test_app,
+        "/recover_key",
+        json!({
+            "nip_05_id": nip_05_id,
+            "recovery_code": recovery_codes[0],
+            "new_pin": 112233,
+            "totp_code": totp_code(&secret, step + 1),
+        }),
+    )
+    .await;
+
+    assert_eq!(without.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(with.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn wrong_recovery_codes_raise_the_difficulty() {
+    let test_app = spawn_app_with(|c| {
+        c.proof_of_work.fetch_difficulty = 0;
+        c.proof_of_work.difficulty_per_failure = 4;
+    })
+    .await;
+    let nip_05_id = "guessed_bob@test.com";
+    upload(&test_app, nip_05_id).await;
+
+    for _ in 0..3 {
+        let response = recover(&test_app, nip_05_id, "ABCDE-FGH23", 112233).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    let response = post(
+        &test_app,
+        "/work_challenge",
+        json!({"nip_05_id":nip_05_id, "route":"fetch"}),
+    )
+    .await;
+    let challenge = response.json::<WorkChallenge>().await.unwrap();
+    assert_eq!(challenge.difficulty, 12);
+    let response = recover(&test_app, nip_05_id, "ABCDE-FGH23", 112233).await;
+    assert_eq!(response.status(), StatusCode::PRECONDITION_REQUIRED);
+}
@@ -0,0 +1,158 @@
+use crate::helpers::{sign_upload_challenge, signed_app_data_event, spawn_app_with, spawn_relay};
+use reqwest::StatusCode;
+use secp256k1::KeyPair;
+use serde_json::json;
+use std::time::Duration;
+
+const PRIVATE_KEY_HASH: &str = "$PBKDF2$i=100000,l=256,s=0Bu5lWu4s66/iottrlUGdckjf5nwnpB05jwp4yDh8NU=$AESGM$OrScsD+hHGaRaPbc$XMXVVbjt3JV+QsNb7ZWRc8uNod2YzJL0lSvW1FOiY38ywOu7IEChKs/IqEQ7knhZAmRGYqoB4dhAbdOTwVhYIeQsuf1+f+9ARPEjtURsDg==";
+
+#[tokio::test]
+async fn upload_key_publishes_app_data_event_to_relays() {
+    let relay = spawn_relay().await;
+    let relay_url = relay.url.clone();
+    let test_app = spawn_app_with(move |c| c.relay_publisher.relays = vec![relay_url]).await;
+    let keypair = KeyPair::new_global(&mut rand::thread_rng());
+    let event = signed_app_data_event(&keypair, PRIVATE_KEY_HASH);
+    let form_data = json!({
+        "nip_05_id": "relayed_bob@test.com",
+        "pin": 374859,
+        "private_key_hash": PRIVATE_KEY_HASH,
+        "nostr_event": event,
+    });
+
+    let response = test_app.post_signed_upload(&keypair, form_data).await;
+    assert!(response.status().is_success());
+
+    let mut published = None;
+    for _ in 0..50 {
+        published = relay.received.lock().unwrap().first().cloned();
+        if published.is_some() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    let published = published.expect("Relay never received the event.");
+    assert_eq!(published["id"], event.id.as_str());
+    assert_eq!(published["sig"], event.sig.as_str());
+    assert_eq!(published["content"], PRIVATE_KEY_HASH);
+}
+
+#[tokio::test]
+async fn upload_key_rejects_app_data_event_with_bad_signature() {
+    let relay = spawn_relay().await;
+    let relay_url = relay.url.clone();
+    let test_app = spawn_app_with(move |c| c.relay_publisher.relays = vec![relay_url]).await;
+    let keypair = KeyPair::new_global(&mut rand::thread_rng());
+    let mut event = signed_app_data_event(&keypair, PRIVATE_KEY_HASH);
+    event.created_at += 1;
+    let form_data = json!({
+        "nip_05_id": "forged_bob@test.com",
+        "pin": 374859,
+        "private_key_hash": PRIVATE_KEY_HASH,
+        "nostr_event": event,
+    });
+
+    let response = test_app.post_signed_upload(&keypair, form_data).await;
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(relay.received.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn upload_key_rejects_app_data_event_signed_by_another_key() {
+    let test_app = spawn_app_with(|_| {}).await;
+    let keypair = KeyPair::new_global(&mut rand::thread_rng());
+    let other = KeyPair::new_global(&mut rand::thread_rng());
+    let form_data = json!({
+        "nip_05_id": "mismatched_bob@test.com",
+        "pin": 374859,
+        "private_key_hash": PRIVATE_KEY_HASH,
+        "nostr_event": signed_app_data_event(&other, PRIVATE_KEY_HASH),
+    });
+
+    let response = test_app.post_signed_upload(&keypair, form_data).await;
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn update_key_publishes_the_new_app_data_event_to_relays() {
+    let relay = spawn_relay().await;
+    let relay_url = relay.url.clone();
+    let test_app = spawn_app_with(move |c| c.relay_publisher.relays = vec![relay_url]).await;
+    let keypair = KeyPair::new_global(&mut rand::thread_rng());
+    let nip_05_id = "relayed_update_bob@test.com";
+    let form_data = json!({
+        "nip_05_id": nip_05_id,
+        "pin": 374859,
+        "private_key_hash": PRIVATE_KEY_HASH,
+    });
+    let response = test_app.post_signed_upload(&keypair, form_data).await;
+    assert!(response.status().is_success());
+    let challenge = test_app.get_upload_challenge().await;
+    let event = signed_app_data_event(&keypair, NEW_PRIVATE_KEY_HASH);
+
+    let response = test_app
+        .api_client
+        .put(&format!("{}/v1/keys/{}", &test_app.address, nip_05_id))
+        .json(&json!({
+            "pin": 374859,
+            "private_key_hash": NEW_PRIVATE_KEY_HASH,
+            "signature": sign_upload_challenge(&keypair, &challenge, NEW_PRIVATE_KEY_HASH),
+            "challenge": challenge,
+            "nostr_event": event,
+        }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let mut published = None;
+    for _ in 0..50 {
+        published = relay.received.lock().unwrap().first().cloned();
+        if published.is_some() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    let published = published.expect("Relay never received the event.");
+    assert_eq!(published["id"], event.id.as_str());
+    assert_eq!(published["content"], NEW_PRIVATE_KEY_HASH);
+}
+
+#[tokio::test]
+async fn update_key_rejects_app_data_event_signed_by_another_key() {
+    let relay = spawn_relay().await;
+    let relay_url = relay.url.clone();
+    let test_app = spawn_app_with(move |c| c.relay_publisher.relays = vec![relay_url]).await;
+    let keypair = KeyPair::new_global(&mut rand::thread_rng());
+    let other = KeyPair::new_global(&mut rand::thread_rng());
+    let nip_05_id = "mismatched_update_bob@test.com";
+    let form_data = json!({
+        "nip_05_id": nip_05_id,
+        "pin": 374859,
+        "private_key_hash": PRIVATE_KEY_HASH,
+    });
+    let response = test_app.post_signed_upload(&keypair, form_data).await;
+    assert!(response.status().is_success());
+    let challenge = test_app.get_upload_challenge().await;
+
+    let response = test_app
+        .api_client
+        .put(&format!("{}/v1/keys/{}", &test_app.address, nip_05_id))
+        .json(&json!({
+            "pin": 374859,
+            "private_key_hash": NEW_PRIVATE_KEY_HASH,
+            "signature": sign_upload_challenge(&keypair, &challenge, NEW_PRIVATE_KEY_HASH),
+            "challenge": challenge,
+            "nostr_event": signed_app_data_event(&other, NEW_PRIVATE_KEY_HASH),
+        }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(relay.received.lock().unwrap().is_empty());
+}
@@ -0,0 +1,382 @@
+use crate::helpers::{spawn_app_with, TestApp};
+use nostr_vault::configuration::{get_configuration, PeerSettings};
+use nostr_vault::routes::{ErrorCode, ErrorResponse};
+use nostr_vault::second_factor::{current_step, totp_code, TotpEnrolment};
+use nostr_vault::startup::Application;
+use reqwest::StatusCode;
+use secp256k1::KeyPair;
+use secrecy::Secret;
+use serde_json::json;
+use std::time::Duration;
+
+const PRIVATE_KEY_HASH: &str = "$PBKDF2$i=100000,l=256,s=0Bu5lWu4s66/iottrlUGdckjf5nwnpB05jwp4yDh8NU=$AESGM$OrScsD+hHGaRaPbc$XMXVVbjt3JV+QsNb7ZWRc8uNod2YzJL0lSvW1FOiY38ywOu7IEChKs/IqEQ7knhZAmRGYqoB4dhAbdOTwVhYIeQsuf1+f+9ARPEjtURsDg==";
+
+async fn spawn_peer(node_id: &'static str, auth_key: &'static str) -> TestApp {
+    spawn_app_with(|c| {
+        c.replication.node_id = node_id.to_string();
+        c.replication.auth_key = Secret::new(auth_key.to_string());
+        c.replication.serve_on_app_port = true;
+    })
+    .await
+}
+
+async fn spawn_app_peered_with(node_id: &'static str, peer: &TestApp, peer_key: &str) -> TestApp {
+    let peer = PeerSettings {
+        url: peer.address.clone(),
+        auth_key: Secret::new(peer_key.to_string()),
+    };
+    spawn_app_with(move |c| {
+        c.replication.node_id = node_id.to_string();
+        c.replication.peers = vec![peer];
+    })
+    .await
+}
+
+async fn upload(test_app: &TestApp, nip_05_id: &str) {
+    let form_data =
+        json!({"nip_05_id":nip_05_id,"pin":374859, "private_key_hash":PRIVATE_KEY_HASH});
+    let keypair = KeyPair::new_global(&mut rand::thread_rng());
+    let response = test_app.post_signed_upload(&keypair, form_data).await;
+    assert!(response.status().is_success());
+}
+
+async fn wait_for_private_key_hash(test_app: &TestApp, nip_05_id: &str) -> Option<String> {
+    for _ in 0..50 {
+        let saved = sqlx::query!(
+            r#"SELECT private_key_hash FROM keys WHERE nip_05_id = $1"#,
+            nip_05_id
+        )
+        .fetch_optional(&test_app.db_pool)
+        .await
+        .expect("Failed to fetch replicated key");
+        if let Some(saved) = saved {
+            return Some(saved.private_key_hash);
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    None
+}
+
+#[tokio::test]
+async fn uploaded_key_is_pushed_to_peers() {
+    let peer = spawn_peer("vault-b", "vault-b-key").await;
+    let test_app = spawn_app_peered_with("vault-a", &peer, "vault-b-key").await;
+    let nip_05_id = "pushed_bob@test.com";
+
+    upload(&test_app, nip_05_id).await;
+
+    let replicated = wait_for_private_key_hash(&peer, nip_05_id).await;
+    assert_eq!(Some(PRIVATE_KEY_HASH.to_string()), replicated);
+}
+
+#[tokio::test]
+async fn keys_are_pulled_from_peers_on_startup() {
+    let peer = spawn_peer("vault-b", "vault-b-key").await;
+    let nip_05_id = "pulled_bob@test.com";
+    upload(&peer, nip_05_id).await;
+
+    let test_app = spawn_app_peered_with("vault-a", &peer, "vault-b-key").await;
+
+    let replicated = wait_for_private_key_hash(&test_app, nip_05_id).await;
+    assert_eq!(Some(PRIVATE_KEY_HASH.to_string()), replicated);
+
+    let fetched = test_app
+        .api_client
+        .post(&format!("{}/fetch_key", &test_app.address))
+        .json(&json!({"nip_05_id":nip_05_id, "pin":374859}))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    assert_eq!(fetched.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn replication_routes_are_not_served_without_an_opt_in() {
+    let test_app = spawn_app_with(|c| {
+        c.replication.auth_key = Secret::new("vault-a-key".to_string());
+    })
+    .await;
+
+    let response = test_app
+        .api_client
+        .get(&format!("{}/replication/keys", &test_app.address))
+        .bearer_auth("vault-a-key")
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn replication_is_not_served_with_an_unset_or_sample_key() {
+    for auth_key in ["", "replication-auth-key"] {
+        let mut configuration = get_configuration().expect("Failed to read configuration.");
+        configuration.application.port = 0;
+        configuration.replication.serve_on_app_port = true;
+        configuration.replication.auth_key = Secret::new(auth_key.to_string());
+
+        let application = Application::build(configuration).await;
+
+        assert!(application.is_err(), "{:?}", auth_key);
+    }
+}
+
+#[tokio::test]
+async fn replication_routes_reject_unknown_peers() {
+    let test_app = spawn_peer("vault-a", "vault-a-key").await;
+
+    let missing = test_app
+        .api_client
+        .get(&format!("{}/replication/keys", &test_app.address))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let wrong = test_app
+        .api_client
+        .get(&format!("{}/replication/keys", &test_app.address))
+        .bearer_auth("not-the-key")
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(missing.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(wrong.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn newer_writes_win_and_stale_writes_are_ignored() {
+    let test_app = spawn_peer("vault-b", "vault-b-key").await;
+    let nip_05_id = "conflicted_bob@test.com";
+    upload(&test_app, nip_05_id).await;
+
+    let stored = sqlx::query!(
+        r#"SELECT pin_hash, created_at FROM keys WHERE nip_05_id = $1"#,
+        nip_05_id
+    )
+    .fetch_one(&test_app.db_pool)
+    .await
+    .expect("Failed to fetch saved key");
+    let replicated_key = |private_key_hash: &str, version_vector: serde_json::Value| {
+        json!({
+            "nip_05_id": nip_05_id,
+            "pin_hash": stored.pin_hash,
+            "private_key_hash": private_key_hash,
+            "created_at": stored.created_at.to_rfc3339(),
+            "updated_at": chrono::Utc::now().to_rfc3339(),
+            "version_vector": version_vector,
+        })
+    };
+    let stale = PRIVATE_KEY_HASH.replace("OrScsD", "Stale0");
+    let newer = PRIVATE_KEY_HASH.replace("OrScsD", "Newer0");
+
+    let stale_response = test_app
+        .api_client
+        .post(&format!("{}/replication/keys", &test_app.address))
+        .bearer_auth("vault-b-key")
+        .json(&replicated_key(&stale, json!({})))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    assert_eq!(stale_response.status(), StatusCode::OK);
+    let saved = wait_for_private_key_hash(&test_app, nip_05_id).await;
+    assert_eq!(Some(PRIVATE_KEY_HASH.to_string()), saved);
+
+    let newer_response = test_app
+        .api_client
+        .post(&format!("{}/replication/keys", &test_app.address))
+        .bearer_auth("vault-b-key")
+        .json(&replicated_key(&newer, json!({"vault-a": 1, "vault-b": 1})))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    assert_eq!(newer_response.status(), StatusCode::OK);
+    let saved = wait_for_private_key_hash(&test_app, nip_05_id).await;
+    assert_eq!(Some(newer), saved);
+}
+
+async fn wait_for_deletion(test_app: &TestApp, nip_05_id: &str) -> bool {
+    for _ in 0..50 {
+        let saved = sqlx::query!(r#"SELECT id FROM keys WHERE nip_05_id = $1"#, nip_05_id)
+            .fetch_optional(&test_app.db_pool)
+            .await
+            .expect("Failed to fetch replicated key");
+        if saved.is_none() {
+            return true;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    false
+}
+
+#[tokio::test]
+async fn deleted_keys_are_not_brought_back_by_stale_copies() {
+    let peer = spawn_peer("vault-b", "vault-b-key").await;
+    let test_app = spawn_app_peered_with("vault-a", &peer, "vault-b-key").await;
+    let nip_05_id = "deleted_bob@test.com";
+    upload(&test_app, nip_05_id).await;
+    assert!(wait_for_private_key_hash(&peer, nip_05_id).await.is_some());
+    let copies = peer
+        .api_client
+        .get(&format!("{}/replication/keys", &peer.address))
+        .bearer_auth("vault-b-key")
+        .send()
+        .await
+        .expect("Failed to execute request.")
+        .json::<Vec<serde_json::Value>>()
+        .await
+        .unwrap();
+    let stale_copy = copies
+        .into_iter()
+        .find(|copy| copy["nip_05_id"] == nip_05_id)
+        .unwrap();
+
+    let response = test_app
+        .api_client
+        .delete(&format!("{}/v1/keys/{}", &test_app.address, nip_05_id))
+        .json(&json!({"pin": 374859}))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert!(wait_for_deletion(&peer, nip_05_id).await);
+
+    let response = peer
+        .api_client
+        .post(&format!("{}/replication/keys", &peer.address))
+        .bearer_auth("vault-b-key")
+        .json(&stale_copy)
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(wait_for_deletion(&peer, nip_05_id).await);
+}
+
+#[tokio::test]
+async fn a_key_uploaded_again_after_a_delete_replicates() {
+    let peer = spawn_peer("vault-b", "vault-b-key").await;
+    let test_app = spawn_app_peered_with("vault-a", &peer, "vault-b-key").await;
+    let nip_05_id = "reuploaded_bob@test.com";
+    upload(&test_app, nip_05_id).await;
+    assert!(wait_for_private_key_hash(&peer, nip_05_id).await.is_some());
+    let response = test_app
+        .api_client
+        .delete(&format!("{}/v1/keys/{}", &test_app.address, nip_05_id))
+        .json(&json!({"pin": 374859}))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert!(wait_for_deletion(&peer, nip_05_id).await);
+
+    upload(&test_app, nip_05_id).await;
+
+    let replicated = wait_for_private_key_hash(&peer, nip_05_id).await;
+    assert_eq!(Some(PRIVATE_KEY_HASH.to_string()), replicated);
+}
+
+#[tokio::test]
+async fn replicated_nip_05_ids_are_normalized() {
+    let test_app = spawn_peer("vault-b", "vault-b-key").await;
+    let replicated_key = |nip_05_id: &str| {
+        json!({
+            "nip_05_id": nip_05_id,
+            "pin_hash": "$argon2id$v=19$m=15000,t=2,p=1$gZiV/M1gPc22ElAH/Jh1Hw$CWOrkoo7oJBQ/iyh7uJ0LO2aLEfrHwTWllSAxT0zRno",
+            "private_key_hash": PRIVATE_KEY_HASH,
+            "created_at": chrono::Utc::now().to_rfc3339(),
+            "updated_at": chrono::Utc::now().to_rfc3339(),
+            "version_vector": {"vault-a": 1},
+        })
+    };
+    let push = |nip_05_id: &str| {
+        test_app
+            .api_client
+            .post(&format!("{}/replication/keys", &test_app.address))
+            .bearer_auth("vault-b-key")
+            .json(&replicated_key(nip_05_id))
+            .send()
+    };
+
+    let response = push("Mixed_Bob@Bücher.example")
+        .await
+        .expect("Failed to execute request.");
+    assert_eq!(response.status(), StatusCode::OK);
+    let saved = wait_for_private_key_hash(&test_app, "mixed_bob@xn--bcher-kva.example").await;
+    assert_eq!(Some(PRIVATE_KEY_HASH.to_string()), saved);
+
+    let response = push("mixed+bob@test.com")
+        .await
+        .expect("Failed to execute request.");
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+async fn wait_for_second_factor_flag(test_app: &TestApp, nip_05_id: &str) -> bool {
+    for _ in 0..50 {
+        let enrolled = sqlx::query!(
+            r#"SELECT second_factor_enrolled FROM keys WHERE nip_05_id = $1"#,
+            nip_05_id
+        )
+        .fetch_optional(&test_app.db_pool)
+        .await
+        .expect("Failed to fetch replicated key")
+        .map_or(false, |row| row.second_factor_enrolled);
+        if enrolled {
+            return true;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    false
+}
+
+#[tokio::test]
+async fn peers_refuse_keys_whose_second_factor_they_can_not_check() {
+    let peer = spawn_peer("vault-b", "vault-b-key").await;
+    let test_app = spawn_app_peered_with("vault-a", &peer, "vault-b-key").await;
+    let nip_05_id = "two_factor_bob@test.com";
+    upload(&test_app, nip_05_id).await;
+    assert!(wait_for_private_key_hash(&peer, nip_05_id).await.is_some());
+
+    let enrolment = test_app
+        .api_client
+        .post(&format!("{}/second_factor/enrol", &test_app.address))
+        .json(&json!({"nip_05_id": nip_05_id, "pin": 374859}))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+        .json::<TotpEnrolment>()
+        .await
+        .unwrap();
+    let secret = data_encoding::BASE32_NOPAD
+        .decode(enrolment.secret.as_bytes())
+        .unwrap();
+    let response = test_app
+        .api_client
+        .post(&format!("{}/second_factor/confirm", &test_app.address))
+        .json(&json!({
+            "nip_05_id": nip_05_id,
+            "pin": 374859,
+            "totp_code": totp_code(&secret, current_step()),
+        }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(wait_for_second_factor_flag(&peer, nip_05_id).await);
+
+    for fetch in [
+        json!({"nip_05_id": nip_05_id, "pin": 374859}),
+        json!({"nip_05_id": nip_05_id, "pin": 374859, "totp_code": "123456"}),
+    ] {
+        let response = peer
+            .api_client
+            .post(&format!("{}/fetch_key", &peer.address))
+            .json(&fetch)
+            .send()
+            .await
+            .expect("Failed to execute request.");
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let error = response.json::<ErrorResponse>().await.unwrap();
+        assert_eq!(error.code, ErrorCode::SecondFactorElsewhere);
+    }
+}
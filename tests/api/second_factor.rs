@@ -0,0 +1,163 @@
+use crate::helpers::{spawn_app, TestApp};
+use nostr_vault::second_factor::{current_step, totp_code, TotpEnrolment};
+use reqwest::StatusCode;
+use secp256k1::KeyPair;
+use serde_json::json;
+
+const PRIVATE_KEY_HASH: &str = "$PBKDF2$i=100000,l=256,s=0Bu5lWu4s66/iottrlUGdckjf5nwnpB05jwp4yDh8NU=$AESGM$OrScsD+hHGaRaPbc$XMXVVbjt3JV+QsNb7ZWRc8uNod2YzJL0lSvW1FOiY38ywOu7IEChKs/IqEQ7knhZAmRGYqoB4dhAbdOTwVhYIeQsuf1+f+9ARPEjtURsDg==";
+const NIP_05_ID: &str = "careful_bob@test.com";
+const PIN: u64 = 374859;
+
+async fn upload(test_app: &TestApp) {
+    let keypair = KeyPair::new_global(&mut rand::thread_rng());
+    let form_data = json!({"nip_05_id":NIP_05_ID,"pin":PIN, "private_key_hash":PRIVATE_KEY_HASH});
+    let response = test_app.post_signed_upload(&keypair, form_data).await;
+    assert!(response.status().is_success());
+}
+
+async fn post(test_app: &TestApp, path: &str, body: serde_json::Value) -> reqwest::Response {
+    test_app
+        .api_client
+        .post(&format!("{}{}", &test_app.address, path))
+        .json(&body)
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+async fn enrol(test_app: &TestApp) -> Vec<u8> {
+    let response = post(
+        test_app,
+        "/second_factor/enrol",
+        json!({"nip_05_id":NIP_05_ID, "pin":PIN}),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let enrolment = response.json::<TotpEnrolment>().await.unwrap();
+    assert!(enrolment.otpauth_uri.starts_with("otpauth://totp/"));
+    data_encoding::BASE32_NOPAD
+        .decode(enrolment.secret.as_bytes())
+        .unwrap()
+}
+
+async fn confirm(test_app: &TestApp, code: &str) -> reqwest::Response {
+    post(
+        test_app,
+        "/second_factor/confirm",
+        json!({"nip_05_id":NIP_05_ID, "pin":PIN, "totp_code":code}),
+    )
+    .await
+}
+
+async fn fetch(test_app: &TestApp, totp_code: Option<&str>) -> reqwest::Response {
+    post(
+        test_app,
+        "/fetch_key",
+        json!({"nip_05_id":NIP_05_ID, "pin":PIN, "totp_code":totp_code}),
+    )
+    .await
+}
+
+#[tokio::test]
+async fn pending_enrolment_does_not_require_a_code() {
+    let test_app = spawn_app().await;
+    upload(&test_app).await;
+    enrol(&test_app).await;
+
+    assert_eq!(fetch(&test_app, None).await.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn confirmed_second_factor_is_required_by_fetch_key() {
+    let test_app = spawn_app().await;
+    upload(&test_app).await;
+    let secret = enrol(&test_app).await;
+    let step = current_step();
+    assert_eq!(
+        confirm(&test_app, &totp_code(&secret, step)).await.status(),
+        StatusCode::OK
+    );
+
+    assert_eq!(
+        fetch(&test_app, None).await.status(),
+        StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(
+        fetch(&test_app, Some(&totp_code(&secret, step + 1)))
+            .await
+            .status(),
+        StatusCode::OK
+    );
+}
+
+#[tokio::test]
+async fn used_codes_are_rejected() {
+    let test_app = spawn_app().await;
+    upload(&test_app).await;
+    let secret = enrol(&test_app).await;
+    let step = current_step();
+    confirm(&test_app, &totp_code(&secret, step - 1)).await;
+    let code = totp_code(&secret, step);
+
+    let first = fetch(&test_app, Some(&code)).await;
+    let replayed = fetch(&test_app, Some(&code)).await;
+
+    assert_eq!(first.status(), StatusCode::OK);
+    assert_eq!(replayed.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn wrong_code_is_rejected() {
+    let test_app = spawn_app().await;
+    upload(&test_app).await;
+    let secret = enrol(&test_app).await;
+    confirm(&test_app, &totp_code(&secret, current_step())).await;
+
+    let response = fetch(&test_app, Some(&totp_code(&secret, current_step() + 5))).await;
+
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn confirmation_with_wrong_code_keeps_enrolment_pending() {
+    let test_app = spawn_app().await;
+    upload(&test_app).await;
+    let secret = enrol(&test_app).await;
+
+    let response = confirm(&test_app, &totp_code(&secret, current_step() + 5)).await;
+
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert_eq!(fetch(&test_app, None).await.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn confirmed_second_factor_can_not_be_replaced() {
+    let test_app = spawn_app().await;
+    upload(&test_app).await;
+    let secret = enrol(&test_app).await;
+    confirm(&test_app, &totp_code(&secret, current_step())).await;
+
+    let response = post(
+        &test_app,
+        "/second_factor/enrol",
+        json!({"nip_05_id":NIP_05_ID, "pin":PIN}),
+    )
+    .await;
+
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+}
+
+#[tokio::test]
+async fn enrolment_requires_the_pin() {
+    let test_app = spawn_app().await;
+    upload(&test_app).await;
+
+    let response = post(
+        &test_app,
+        "/second_factor/enrol",
+        json!({"nip_05_id":NIP_05_ID, "pin":111111}),
+    )
+    .await;
+
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
@@ -0,0 +1,122 @@
+use crate::helpers::{spawn_app, spawn_app_with, TestApp};
+use nostr_vault::security_headers::EXAMPLE_UI_CSP;
+use reqwest::StatusCode;
+
+async fn get(test_app: &TestApp, path: &str) -> reqwest::Response {
+    test_app
+        .api_client
+        .get(&format!("{}{}", &test_app.address, path))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+fn header<'a>(response: &'a reqwest::Response, name: &str) -> Option<&'a str> {
+    response
+        .headers()
+        .get(name)
+        .map(|value| value.to_str().unwrap())
+}
+
+#[tokio::test]
+async fn every_response_is_sent_with_nosniff_and_no_referrer() {
+    let test_app = spawn_app().await;
+
+    for path in ["/livez", "/example", "/does_not_exist"] {
+        let response = get(&test_app, path).await;
+
+        assert_eq!(header(&response, "x-content-type-options"), Some("nosniff"));
+        assert_eq!(header(&response, "referrer-policy"), Some("no-referrer"));
+    }
+}
+
+#[tokio::test]
+async fn key_responses_are_not_cached() {
+    let test_app = spawn_app().await;
+
+    let challenge = test_app
+        .api_client
+        .post(&format!("{}/upload_challenge", &test_app.address))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let legacy = test_app
+        .api_client
+        .post(&format!("{}/fetch_key", &test_app.address))
+        .json(&serde_json::json!({ "nip_05_id": "bob@example.com", "pin": 123456 }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(header(&challenge, "cache-control"), Some("no-store"));
+    assert_eq!(header(&legacy, "cache-control"), Some("no-store"));
+    assert_eq!(
+        header(&get(&test_app, "/livez").await, "cache-control"),
+        None
+    );
+}
+
+#[tokio::test]
+async fn hsts_is_only_sent_once_configured() {
+    let plain = spawn_app().await;
+    let hardened = spawn_app_with(|c| c.security.hsts_max_age_seconds = Some(31536000)).await;
+
+    let response = get(&plain, "/livez").await;
+    assert_eq!(header(&response, "strict-transport-security"), None);
+
+    let response = get(&hardened, "/livez").await;
+    assert_eq!(
+        header(&response, "strict-transport-security"),
+        Some("max-age=31536000")
+    );
+}
+
+#[tokio::test]
+async fn the_example_page_is_served_with_a_strict_csp() {
+    let test_app = spawn_app().await;
+
+    let page = get(&test_app, "/example").await;
+    let script = get(&test_app, "/example/main.js").await;
+
+    assert_eq!(page.status(), StatusCode::OK);
+    assert_eq!(
+        header(&page, "content-security-policy"),
+        Some(EXAMPLE_UI_CSP)
+    );
+    assert_eq!(script.status(), StatusCode::OK);
+    assert_eq!(
+        header(&script, "content-security-policy"),
+        Some(EXAMPLE_UI_CSP)
+    );
+    assert_eq!(
+        header(&get(&test_app, "/livez").await, "content-security-policy"),
+        None
+    );
+}
+
+#[tokio::test]
+async fn swagger_and_the_example_page_can_be_turned_off() {
+    let test_app = spawn_app_with(|c| {
+        c.security.swagger_ui = false;
+        c.security.example_ui = false;
+    })
+    .await;
+
+    for path in [
+        "/swagger-ui/",
+        "/api-doc/openapi.json",
+        "/example",
+        "/example/main.js",
+    ] {
+        assert_eq!(get(&test_app, path).await.status(), StatusCode::NOT_FOUND);
+    }
+}
+
+#[tokio::test]
+async fn swagger_is_served_by_default() {
+    let test_app = spawn_app().await;
+
+    let response = get(&test_app, "/api-doc/openapi.json").await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+}
@@ -0,0 +1,255 @@
+use crate::helpers::{delete_row, spawn_app, TestApp};
+use nostr_vault::authentication::{KeySlots, StoredKey};
+use nostr_vault::routes::{ErrorCode, ErrorResponse};
+use nostr_vault::session::IssuedSession;
+use reqwest::StatusCode;
+use secp256k1::KeyPair;
+use serde_json::json;
+
+const PRIVATE_KEY_HASH: &str = "$PBKDF2$i=100000,l=256,s=0Bu5lWu4s66/iottrlUGdckjf5nwnpB05jwp4yDh8NU=$AESGM$OrScsD+hHGaRaPbc$XMXVVbjt3JV+QsNb7ZWRc8uNod2YzJL0lSvW1FOiY38ywOu7IEChKs/IqEQ7knhZAmRGYqoB4dhAbdOTwVhYIeQsuf1+f+9ARPEjtURsDg==";
+const PIN: u64 = 582913;
+
+/// Uploads `nip_05_id` and returns its recovery codes.
+async fn upload(test_app: &TestApp, nip_05_id: &str) -> Vec<String> {
+    let keypair = KeyPair::new_global(&mut rand::thread_rng());
+    let response = test_app
+        .post_signed_upload(
+            &keypair,
+            json!({
+                "nip_05_id": nip_05_id,
+                "pin": PIN,
+                "private_key_hash": PRIVATE_KEY_HASH,
+            }),
+        )
+        .await;
+    assert!(response.status().is_success());
+    response.json::<StoredKey>().await.unwrap().recovery_codes
+}
+
+async fn create_session(
+    test_app: &TestApp,
+    nip_05_id: &str,
+    scopes: serde_json::Value,
+) -> reqwest::Response {
+    test_app
+        .api_client
+        .post(&format!(
+            "{}/v1/keys/{}/sessions",
+            &test_app.address, nip_05_id
+        ))
+        .json(&json!({ "pin": PIN, "scopes": scopes }))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+async fn token(test_app: &TestApp, nip_05_id: &str, scopes: serde_json::Value) -> String {
+    let response = create_session(test_app, nip_05_id, scopes).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    response.json::<IssuedSession>().await.unwrap().token
+}
+
+async fn update_with_token(
+    test_app: &TestApp,
+    nip_05_id: &str,
+    token: &str,
+    new_pin: u64,
+) -> reqwest::Response {
+    test_app
+        .api_client
+        .put(&format!("{}/v1/keys/{}", &test_app.address, nip_05_id))
+        .bearer_auth(token)
+        .json(&json!({ "new_pin": new_pin }))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+async fn delete_with_token(test_app: &TestApp, nip_05_id: &str, token: &str) -> reqwest::Response {
+    test_app
+        .api_client
+        .delete(&format!("{}/v1/keys/{}", &test_app.address, nip_05_id))
+        .bearer_auth(token)
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+async fn list_slots(test_app: &TestApp, nip_05_id: &str, token: Option<&str>) -> reqwest::Response {
+    let mut request = test_app.api_client.get(&format!(
+        "{}/v1/keys/{}/slots",
+        &test_app.address, nip_05_id
+    ));
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+    request.send().await.expect("Failed to execute request.")
+}
+
+async fn error_code(response: reqwest::Response) -> ErrorCode {
+    response.json::<ErrorResponse>().await.unwrap().code
+}
+
+#[tokio::test]
+async fn a_session_token_deletes_the_key_without_the_pin() {
+    let test_app = spawn_app().await;
+    let nip_05_id = "session_delete_bob@test.com";
+    upload(&test_app, nip_05_id).await;
+    let token = token(&test_app, nip_05_id, json!(["delete"])).await;
+
+    let response = delete_with_token(&test_app, nip_05_id, &token).await;
+
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let remaining: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM keys WHERE nip_05_id = $1")
+        .bind(nip_05_id)
+        .fetch_one(&test_app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(remaining, 0);
+}
+
+#[tokio::test]
+async fn a_session_token_only_covers_its_scopes_and_key() {
+    let test_app = spawn_app().await;
+    let nip_05_id = "session_scope_bob@test.com";
+    let other_nip_05_id = "session_scope_alice@test.com";
+    upload(&test_app, nip_05_id).await;
+    upload(&test_app, other_nip_05_id).await;
+    let token = token(&test_app, nip_05_id, json!(["update"])).await;
+
+    let response = delete_with_token(&test_app, nip_05_id, &token).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert_eq!(error_code(response).await, ErrorCode::SessionScopeMissing);
+
+    let response = update_with_token(&test_app, other_nip_05_id, &token, 111222).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert_eq!(error_code(response).await, ErrorCode::SessionScopeMissing);
+
+    delete_row(&test_app.db_pool, nip_05_id.to_string()).await;
+    delete_row(&test_app.db_pool, other_nip_05_id.to_string()).await;
+}
+
+#[tokio::test]
+async fn changing_the_pin_revokes_earlier_tokens() {
+    let test_app = spawn_app().await;
+    let nip_05_id = "session_revoke_bob@test.com";
+    upload(&test_app, nip_05_id).await;
+    let token = token(&test_app, nip_05_id, json!(["update", "delete"])).await;
+
+    let response = update_with_token(&test_app, nip_05_id, &token, 111222).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = delete_with_token(&test_app, nip_05_id, &token).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(error_code(response).await, ErrorCode::SessionInvalid);
+    delete_row(&test_app.db_pool, nip_05_id.to_string()).await;
+}
+
+#[tokio::test]
+async fn recovering_the_key_revokes_earlier_tokens() {
+    let test_app = spawn_app().await;
+    let nip_05_id = "session_recover_bob@test.com";
+    let recovery_codes = upload(&test_app, nip_05_id).await;
+    let token = token(&test_app, nip_05_id, json!(["delete"])).await;
+
+    let response = test_app
+        .api_client
+        .post(&format!("{}/recover_key", &test_app.address))
+        .json(&json!({
+            "nip_05_id": nip_05_id,
+            "recovery_code": recovery_codes[0],
+            "new_pin": 334455,
+        }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = delete_with_token(&test_app, nip_05_id, &token).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    delete_row(&test_app.db_pool, nip_05_id.to_string()).await;
+}
+
+#[tokio::test]
+async fn sessions_need_the_pin_and_a_scope() {
+    let test_app = spawn_app().await;
+    let nip_05_id = "session_issue_bob@test.com";
+    upload(&test_app, nip_05_id).await;
+
+    let response = create_session(&test_app, nip_05_id, json!([])).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let response = test_app
+        .api_client
+        .post(&format!(
+            "{}/v1/keys/{}/sessions",
+            &test_app.address, nip_05_id
+        ))
+        .json(&json!({ "pin": 999999, "scopes": ["delete"] }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    delete_row(&test_app.db_pool, nip_05_id.to_string()).await;
+}
+
+#[tokio::test]
+async fn malformed_tokens_are_rejected() {
+    let test_app = spawn_app().await;
+    let nip_05_id = "session_malformed_bob@test.com";
+    upload(&test_app, nip_05_id).await;
+
+    let response = delete_with_token(&test_app, nip_05_id, "not.a.token").await;
+
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(error_code(response).await, ErrorCode::SessionInvalid);
+    delete_row(&test_app.db_pool, nip_05_id.to_string()).await;
+}
+
+#[tokio::test]
+async fn a_list_slots_token_lists_what_is_stored_with_the_key() {
+    let test_app = spawn_app().await;
+    let nip_05_id = "session_slots_bob@test.com";
+    let recovery_codes = upload(&test_app, nip_05_id).await;
+    test_app
+        .api_client
+        .post(&format!("{}/recover_key", &test_app.address))
+        .json(&json!({
+            "nip_05_id": nip_05_id,
+            "recovery_code": recovery_codes[0],
+            "new_pin": PIN,
+        }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let token = token(&test_app, nip_05_id, json!(["list_slots"])).await;
+
+    let response = list_slots(&test_app, nip_05_id, Some(&token)).await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let slots = response.json::<KeySlots>().await.unwrap();
+    assert_eq!(slots.nip_05_id, nip_05_id);
+    assert_eq!(slots.unused_recovery_codes, 9);
+    assert!(!slots.totp);
+    assert!(slots.passkeys.is_empty());
+    delete_row(&test_app.db_pool, nip_05_id.to_string()).await;
+}
+
+#[tokio::test]
+async fn slots_are_only_listed_with_the_list_slots_scope() {
+    let test_app = spawn_app().await;
+    let nip_05_id = "session_no_slots_bob@test.com";
+    upload(&test_app, nip_05_id).await;
+    let token = token(&test_app, nip_05_id, json!(["update", "delete"])).await;
+
+    let without_scope = list_slots(&test_app, nip_05_id, Some(&token)).await;
+    let without_token = list_slots(&test_app, nip_05_id, None).await;
+
+    assert_eq!(without_scope.status(), StatusCode::FORBIDDEN);
+    assert_eq!(
+        error_code(without_scope).await,
+        ErrorCode::SessionScopeMissing
+    );
+    assert_eq!(without_token.status(), StatusCode::UNAUTHORIZED);
+    delete_row(&test_app.db_pool, nip_05_id.to_string()).await;
+}
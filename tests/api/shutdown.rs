@@ -0,0 +1,13 @@
+use crate::helpers::spawn_app;
+
+#[tokio::test]
+async fn stopped_app_no_longer_accepts_requests() {
+    let test_app = spawn_app().await;
+    let address = test_app.address.clone();
+    let client = reqwest::Client::new();
+
+    test_app.stop().await;
+
+    let response = client.get(&format!("{}/livez", &address)).send().await;
+    assert!(response.is_err());
+}
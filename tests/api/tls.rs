@@ -0,0 +1,257 @@
+use crate::helpers::{spawn_app_with, TestApp};
+use nostr_vault::configuration::{get_configuration, PeerSettings, TlsSettings};
+use nostr_vault::startup::Application;
+use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, IsCa};
+use reqwest::StatusCode;
+use secp256k1::KeyPair;
+use secrecy::Secret;
+use serde_json::json;
+use std::path::PathBuf;
+use std::time::Duration;
+use uuid::Uuid;
+
+const PRIVATE_KEY_HASH: &str = "$PBKDF2$i=100000,l=256,s=0Bu5lWu4s66/iottrlUGdckjf5nwnpB05jwp4yDh8NU=$AESGM$OrScsD+hHGaRaPbc$XMXVVbjt3JV+QsNb7ZWRc8uNod2YzJL0lSvW1FOiY38ywOu7IEChKs/IqEQ7knhZAmRGYqoB4dhAbdOTwVhYIeQsuf1+f+9ARPEjtURsDg==";
+const AUTH_KEY: &str = "admin-peer-key";
+
+/// A throwaway CA, with the pem files it issues written to their own directory.
+struct TestCa {
+    certificate: Certificate,
+    dir: PathBuf,
+}
+
+impl TestCa {
+    fn new(name: &str) -> Self {
+        let mut params = CertificateParams::new(vec![]);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.distinguished_name.push(DnType::CommonName, name);
+        let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let ca = Self {
+            certificate: Certificate::from_params(params).unwrap(),
+            dir,
+        };
+        std::fs::write(ca.path("ca.pem"), ca.pem()).unwrap();
+        ca
+    }
+
+    fn path(&self, file: &str) -> String {
+        self.dir.join(file).to_str().unwrap().to_string()
+    }
+
+    fn pem(&self) -> String {
+        self.certificate.serialize_pem().unwrap()
+    }
+
+    /// Writes `<name>.pem` and `<name>.key` for a certificate valid for localhost.
+    fn issue(&self, name: &str) -> TlsSettings {
+        let mut params = CertificateParams::new(vec!["localhost".to_string()]);
+        params.distinguished_name.push(DnType::CommonName, name);
+        let certificate = Certificate::from_params(params).unwrap();
+        let settings = TlsSettings {
+            cert_path: self.path(&format!("{}.pem", name)),
+            key_path: self.path(&format!("{}.key", name)),
+        };
+        std::fs::write(
+            &settings.cert_path,
+            certificate
+                .serialize_pem_with_signer(&self.certificate)
+                .unwrap(),
+        )
+        .unwrap();
+        std::fs::write(&settings.key_path, certificate.serialize_private_key_pem()).unwrap();
+        settings
+    }
+
+    /// Certificate and key in one file, as `reqwest::Identity` and peers expect them.
+    fn issue_identity(&self, name: &str) -> String {
+        let settings = self.issue(name);
+        let identity = self.path(&format!("{}.identity.pem", name));
+        let pem = std::fs::read_to_string(&settings.cert_path).unwrap()
+            + &std::fs::read_to_string(&settings.key_path).unwrap();
+        std::fs::write(&identity, pem).unwrap();
+        identity
+    }
+
+    fn client(&self, identity: Option<&str>) -> reqwest::Client {
+        let mut client = reqwest::Client::builder()
+            .add_root_certificate(reqwest::Certificate::from_pem(self.pem().as_bytes()).unwrap());
+        if let Some(identity) = identity {
+            let pem = std::fs::read(identity).unwrap();
+            client = client.identity(reqwest::Identity::from_pem(&pem).unwrap());
+        }
+        client.build().unwrap()
+    }
+}
+
+async fn spawn_tls_app(tls: TlsSettings) -> TestApp {
+    spawn_app_with(|c| c.application.tls = Some(tls)).await
+}
+
+async fn spawn_admin_app(ca: &TestCa) -> TestApp {
+    let tls = ca.issue("server");
+    let client_ca = ca.path("ca.pem");
+    spawn_app_with(move |c| {
+        c.application.tls = Some(tls);
+        c.admin.port = Some(0);
+        c.admin.client_ca_path = Some(client_ca);
+        c.replication.auth_key = Secret::new(AUTH_KEY.to_string());
+    })
+    .await
+}
+
+fn admin_address(test_app: &TestApp) -> String {
+    format!("https://localhost:{}", test_app.admin_port.unwrap())
+}
+
+#[tokio::test]
+async fn application_is_served_over_https() {
+    let ca = TestCa::new("https ca");
+    let test_app = spawn_tls_app(ca.issue("server")).await;
+
+    let response = ca
+        .client(None)
+        .get(&format!("{}/livez", &test_app.address))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert!(test_app.address.starts_with("https://"));
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn certificate_is_reloaded_on_sighup() {
+    let old_ca = TestCa::new("old ca");
+    let tls = old_ca.issue("server");
+    let test_app = spawn_tls_app(tls.clone()).await;
+    let new_ca = TestCa::new("new ca");
+    let renewed = new_ca.issue("server");
+    std::fs::copy(&renewed.cert_path, &tls.cert_path).unwrap();
+    std::fs::copy(&renewed.key_path, &tls.key_path).unwrap();
+
+    let status = std::process::Command::new("kill")
+        .args(["-HUP", &std::process::id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let mut reloaded = false;
+    for _ in 0..50 {
+        // A fresh client each time, so no connection from before the reload is reused
+        let response = new_ca
+            .client(None)
+            .get(&format!("{}/livez", &test_app.address))
+            .send()
+            .await;
+        if response.is_ok() {
+            reloaded = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(reloaded);
+}
+
+#[tokio::test]
+async fn admin_listener_requires_a_client_certificate() {
+    let ca = TestCa::new("admin ca");
+    let test_app = spawn_admin_app(&ca).await;
+    let identity = ca.issue_identity("client");
+    let address = format!("{}/replication/keys", admin_address(&test_app));
+
+    let without_certificate = ca
+        .client(None)
+        .get(&address)
+        .bearer_auth(AUTH_KEY)
+        .send()
+        .await;
+    let from_other_ca = TestCa::new("other ca");
+    let other_identity = from_other_ca.issue_identity("client");
+    let with_unknown_certificate = ca
+        .client(Some(&other_identity))
+        .get(&address)
+        .bearer_auth(AUTH_KEY)
+        .send()
+        .await;
+    let with_certificate = ca
+        .client(Some(&identity))
+        .get(&address)
+        .bearer_auth(AUTH_KEY)
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert!(without_certificate.is_err());
+    assert!(with_unknown_certificate.is_err());
+    assert_eq!(with_certificate.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn replication_routes_move_to_the_admin_listener() {
+    let ca = TestCa::new("admin ca");
+    let test_app = spawn_admin_app(&ca).await;
+
+    let response = ca
+        .client(None)
+        .get(&format!("{}/replication/keys", &test_app.address))
+        .bearer_auth(AUTH_KEY)
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn peers_replicate_over_mutual_tls() {
+    let ca = TestCa::new("replication ca");
+    let peer = spawn_admin_app(&ca).await;
+    let peer_settings = PeerSettings {
+        url: admin_address(&peer),
+        auth_key: Secret::new(AUTH_KEY.to_string()),
+    };
+    let identity = ca.issue_identity("vault");
+    let ca_cert_path = ca.path("ca.pem");
+    let test_app = spawn_app_with(move |c| {
+        c.replication.node_id = "mtls-vault".to_string();
+        c.replication.peers = vec![peer_settings];
+        c.replication.client_identity_path = Some(identity);
+        c.replication.ca_cert_path = Some(ca_cert_path);
+    })
+    .await;
+
+    let nip_05_id = "mtls_bob@test.com";
+    let keypair = KeyPair::new_global(&mut rand::thread_rng());
+    let form_data =
+        json!({"nip_05_id":nip_05_id,"pin":374859, "private_key_hash":PRIVATE_KEY_HASH});
+    let response = test_app.post_signed_upload(&keypair, form_data).await;
+    assert!(response.status().is_success());
+
+    let mut replicated = None;
+    for _ in 0..50 {
+        replicated = sqlx::query_scalar::<_, String>(
+            "SELECT private_key_hash FROM keys WHERE nip_05_id = $1",
+        )
+        .bind(nip_05_id)
+        .fetch_optional(&peer.db_pool)
+        .await
+        .unwrap();
+        if replicated.is_some() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(replicated.as_deref(), Some(PRIVATE_KEY_HASH));
+}
+
+#[tokio::test]
+async fn client_certificates_need_tls_on_the_application() {
+    let mut configuration = get_configuration().expect("Failed to read configuration.");
+    configuration.application.port = 0;
+    configuration.admin.port = Some(0);
+    configuration.admin.client_ca_path = Some(TestCa::new("unused ca").path("ca.pem"));
+
+    let application = Application::build(configuration).await;
+
+    assert!(application.is_err());
+}
@@ -1,27 +1,27 @@
-use crate::helpers::{delete_row, spawn_app};
-use nostr_vault::authentication::StoredKey;
+use crate::helpers::{delete_row, sign_upload_challenge, spawn_app};
+use nostr_vault::authentication::{normalize_stored_nip_05_ids, NormalizationReport, StoredKey};
+use nostr_vault::challenge::purge_challenges;
+use reqwest::StatusCode;
+use secp256k1::KeyPair;
 use serde_json::json;
 
+const PRIVATE_KEY_HASH: &str = "$PBKDF2$i=100000,l=256,s=0Bu5lWu4s66/iottrlUGdckjf5nwnpB05jwp4yDh8NU=$AESGM$OrScsD+hHGaRaPbc$XMXVVbjt3JV+QsNb7ZWRc8uNod2YzJL0lSvW1FOiY38ywOu7IEChKs/IqEQ7knhZAmRGYqoB4dhAbdOTwVhYIeQsuf1+f+9ARPEjtURsDg==";
+
 #[tokio::test]
 async fn upload_key_success() {
     let test_app = spawn_app().await;
-    let client = reqwest::Client::new();
     let nip_05_id = "the_name_is_smith_bob_smith@test.com";
-    let private_key_hash = "$PBKDF2$i=100000,l=256,s=0Bu5lWu4s66/iottrlUGdckjf5nwnpB05jwp4yDh8NU=$AESGM$OrScsD+hHGaRaPbc$XMXVVbjt3JV+QsNb7ZWRc8uNod2YzJL0lSvW1FOiY38ywOu7IEChKs/IqEQ7knhZAmRGYqoB4dhAbdOTwVhYIeQsuf1+f+9ARPEjtURsDg==";
+    let private_key_hash = PRIVATE_KEY_HASH;
     let form_data =
         json!({"nip_05_id":nip_05_id,"pin":374859, "private_key_hash":private_key_hash});
-    let response = client
-        .post(&format!("{}/upload_key", &test_app.address))
-        .json(&form_data)
-        .send()
-        .await
-        .expect("Failed to execute request.");
+    let keypair = KeyPair::new_global(&mut rand::thread_rng());
+    let response = test_app.post_signed_upload(&keypair, form_data).await;
     assert!(response.status().is_success());
 
     let response_body = response.json::<StoredKey>().await.unwrap();
 
     let saved = sqlx::query!(
-        r#"SELECT id, pin_hash, private_key_hash, created_at 
+        r#"SELECT id, pin_hash, private_key_hash, created_at, pubkey 
         FROM keys 
         WHERE nip_05_id = $1"#,
         nip_05_id
@@ -36,4 +36,285 @@ async fn upload_key_success() {
     assert_eq!(saved.created_at.to_rfc3339(), response_body.created_at);
     assert_eq!(saved.id, response_body.id);
     assert_eq!(nip_05_id, response_body.nip_05_id);
+    assert_eq!(
+        saved.pubkey,
+        Some(keypair.x_only_public_key().0.to_string())
+    );
+}
+
+#[tokio::test]
+async fn upload_key_accepts_an_npub() {
+    let test_app = spawn_app().await;
+    // nsec of the NIP-19 example npub10elfcs4fr0l0r8af98jlmgdh9c8tcxjvz9qkw038js35mp4dma8qzvjptg
+    let keypair = KeyPair::from_seckey_str_global(
+        "67dea2ed018072d675f5415ecfaed7d2597555e202d85b3d65ea4e58d2d92ffa",
+    )
+    .unwrap();
+    let challenge = test_app.get_upload_challenge().await;
+    let form_data = json!({
+        "nip_05_id": "npub_bob@test.com",
+        "pin": 374859,
+        "private_key_hash": PRIVATE_KEY_HASH,
+        "npub": "npub10elfcs4fr0l0r8af98jlmgdh9c8tcxjvz9qkw038js35mp4dma8qzvjptg",
+        "signature": sign_upload_challenge(&keypair, &challenge, PRIVATE_KEY_HASH),
+        "challenge": challenge,
+    });
+
+    let response = test_app
+        .api_client
+        .post(&format!("{}/upload_key", &test_app.address))
+        .json(&form_data)
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert!(response.status().is_success());
+    let saved = sqlx::query!(
+        r#"SELECT pubkey FROM keys WHERE nip_05_id = $1"#,
+        "npub_bob@test.com"
+    )
+    .fetch_one(&test_app.db_pool)
+    .await
+    .expect("Failed to fetch saved key");
+    assert_eq!(
+        saved.pubkey.as_deref(),
+        Some("7e7e9c42a91bfef19fa929e5fda1b72e0ebc1a4c1141673e2794234d86addf4e")
+    );
+}
+
+#[tokio::test]
+async fn upload_key_without_proof_is_rejected() {
+    let test_app = spawn_app().await;
+    let form_data = json!({"nip_05_id":"unproven_bob@test.com","pin":374859, "private_key_hash":PRIVATE_KEY_HASH});
+
+    let response = test_app
+        .api_client
+        .post(&format!("{}/upload_key", &test_app.address))
+        .json(&form_data)
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert!(response.status().is_client_error());
+}
+
+#[tokio::test]
+async fn upload_key_signed_by_another_key_is_forbidden() {
+    let test_app = spawn_app().await;
+    let keypair = KeyPair::new_global(&mut rand::thread_rng());
+    let other = KeyPair::new_global(&mut rand::thread_rng());
+    let challenge = test_app.get_upload_challenge().await;
+    let form_data = json!({
+        "nip_05_id": "spoofed_bob@test.com",
+        "pin": 374859,
+        "private_key_hash": PRIVATE_KEY_HASH,
+        "npub": keypair.x_only_public_key().0.to_string(),
+        "signature": sign_upload_challenge(&other, &challenge, PRIVATE_KEY_HASH),
+        "challenge": challenge,
+    });
+
+    let response = test_app
+        .api_client
+        .post(&format!("{}/upload_key", &test_app.address))
+        .json(&form_data)
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn upload_challenge_can_only_be_used_once() {
+    let test_app = spawn_app().await;
+    let keypair = KeyPair::new_global(&mut rand::thread_rng());
+    let challenge = test_app.get_upload_challenge().await;
+    let signature = sign_upload_challenge(&keypair, &challenge, PRIVATE_KEY_HASH);
+    let upload = |nip_05_id: &str| {
+        json!({
+            "nip_05_id": nip_05_id,
+            "pin": 374859,
+            "private_key_hash": PRIVATE_KEY_HASH,
+            "npub": keypair.x_only_public_key().0.to_string(),
+            "signature": signature,
+            "challenge": challenge,
+        })
+    };
+
+    let first = test_app
+        .api_client
+        .post(&format!("{}/upload_key", &test_app.address))
+        .json(&upload("first_bob@test.com"))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let replayed = test_app
+        .api_client
+        .post(&format!("{}/upload_key", &test_app.address))
+        .json(&upload("replayed_bob@test.com"))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert!(first.status().is_success());
+    assert_eq!(replayed.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn unknown_upload_challenge_is_forbidden() {
+    let test_app = spawn_app().await;
+    let keypair = KeyPair::new_global(&mut rand::thread_rng());
+    let challenge = "9c1185a5c5e9fc54612808977ee8f548b2258d31ddadef707ba62c2a8b3fd0e6";
+    let form_data = json!({
+        "nip_05_id": "made_up_bob@test.com",
+        "pin": 374859,
+        "private_key_hash": PRIVATE_KEY_HASH,
+        "npub": keypair.x_only_public_key().0.to_string(),
+        "signature": sign_upload_challenge(&keypair, challenge, PRIVATE_KEY_HASH),
+        "challenge": challenge,
+    });
+
+    let response = test_app
+        .api_client
+        .post(&format!("{}/upload_key", &test_app.address))
+        .json(&form_data)
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn nip_05_ids_differing_only_in_case_are_the_same_key() {
+    let test_app = spawn_app().await;
+    let keypair = KeyPair::new_global(&mut rand::thread_rng());
+    let form_data = json!({
+        "nip_05_id": "Case_Bob@Test.COM",
+        "pin": 374859,
+        "private_key_hash": PRIVATE_KEY_HASH,
+    });
+    let response = test_app.post_signed_upload(&keypair, form_data).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let stored = response.json::<StoredKey>().await.unwrap();
+    assert_eq!(stored.nip_05_id, "case_bob@test.com");
+
+    let form_data = json!({
+        "nip_05_id": "case_bob@test.com",
+        "pin": 374859,
+        "private_key_hash": PRIVATE_KEY_HASH,
+    });
+    let response = test_app.post_signed_upload(&keypair, form_data).await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+
+    let response = test_app
+        .api_client
+        .post(&format!(
+            "{}/v1/keys/CASE_BOB@test.com/retrieve",
+            &test_app.address
+        ))
+        .json(&json!({ "pin": 374859 }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn legacy_nip_05_ids_are_normalized_or_moved_aside() {
+    let test_app = spawn_app().await;
+    for (nip_05_id, legacy_id) in [
+        ("umlaut_bob@test.com", "umlaut_bob@bücher.example"),
+        ("plus_bob@test.com", "plus+bob@test.com"),
+        ("case_bob@test.com", "Case_Bob@test.com"),
+    ] {
+        let keypair = KeyPair::new_global(&mut rand::thread_rng());
+        let form_data =
+            json!({"nip_05_id":nip_05_id,"pin":374859, "private_key_hash":PRIVATE_KEY_HASH});
+        let response = test_app.post_signed_upload(&keypair, form_data).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        sqlx::query!(
+            "UPDATE keys SET nip_05_id = $2 WHERE nip_05_id = $1",
+            nip_05_id,
+            legacy_id
+        )
+        .execute(&test_app.db_pool)
+        .await
+        .unwrap();
+    }
+
+    let report = normalize_stored_nip_05_ids(false, &test_app.db_pool)
+        .await
+        .unwrap();
+    let expected = NormalizationReport {
+        normalized: vec![
+            (
+                "umlaut_bob@bücher.example".to_string(),
+                "umlaut_bob@xn--bcher-kva.example".to_string(),
+            ),
+            (
+                "Case_Bob@test.com".to_string(),
+                "case_bob@test.com".to_string(),
+            ),
+        ],
+        moved: vec![("plus+bob@test.com".to_string(), "invalid nip 05 id")],
+    };
+    assert_eq!(report, expected);
+    let legacy = sqlx::query!("SELECT nip_05_id FROM keys WHERE nip_05_id = 'plus+bob@test.com'")
+        .fetch_optional(&test_app.db_pool)
+        .await
+        .unwrap();
+    assert!(legacy.is_some(), "A dry run must not move keys.");
+
+    let report = normalize_stored_nip_05_ids(true, &test_app.db_pool)
+        .await
+        .unwrap();
+
+    assert_eq!(report, expected);
+    let response = test_app
+        .api_client
+        .post(&format!("{}/fetch_key", &test_app.address))
+        .json(&json!({"nip_05_id":"Umlaut_Bob@Bücher.example", "pin":374859}))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    assert_eq!(response.status(), StatusCode::OK);
+    let moved = sqlx::query!("SELECT nip_05_id, reason FROM legacy_keys")
+        .fetch_one(&test_app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(moved.nip_05_id, "plus+bob@test.com");
+    assert_eq!(moved.reason, "invalid nip 05 id");
+}
+
+#[tokio::test]
+async fn expired_and_used_challenges_are_purged() {
+    let test_app = spawn_app().await;
+    let expired = test_app.get_upload_challenge().await;
+    let used = test_app.get_upload_challenge().await;
+    let fresh = test_app.get_upload_challenge().await;
+    sqlx::query!(
+        "UPDATE challenges SET expires_at = NOW() - INTERVAL '1 second' WHERE nonce = $1",
+        expired
+    )
+    .execute(&test_app.db_pool)
+    .await
+    .unwrap();
+    sqlx::query!(
+        "UPDATE challenges SET used_at = NOW() - INTERVAL '2 hours' WHERE nonce = $1",
+        used
+    )
+    .execute(&test_app.db_pool)
+    .await
+    .unwrap();
+
+    let purged = purge_challenges(&test_app.db_pool).await.unwrap();
+
+    assert_eq!(purged, 2);
+    let remaining = sqlx::query!("SELECT nonce FROM challenges")
+        .fetch_all(&test_app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(remaining.len(), 1);
+    assert_eq!(remaining[0].nonce, fresh);
 }
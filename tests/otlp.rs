@@ -0,0 +1,80 @@
+//! Lives in its own binary as the global subscriber can only be set once per process,
+//! the api tests run without an exporter.
+use actix_web::{test, web, App, HttpResponse};
+use nostr_vault::configuration::TelemetrySettings;
+use nostr_vault::telemetry::{get_subscriber, init_subscriber, otlp_tracer, shutdown_tracing};
+use tracing_actix_web::TracingLogger;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+
+#[tracing::instrument(name = "Traced handler")]
+async fn traced() -> HttpResponse {
+    HttpResponse::Ok().finish()
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn spans_are_exported_under_the_incoming_trace() {
+    let collector = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/v1/traces"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&collector)
+        .await;
+    let tracer = otlp_tracer(&TelemetrySettings {
+        otlp_endpoint: Some(format!("{}/v1/traces", collector.uri())),
+        service_name: "nostr-vault-test".to_string(),
+    })
+    .expect("Failed to install the OTLP pipeline.");
+    assert!(tracer.is_some());
+    init_subscriber(get_subscriber(
+        "test".into(),
+        "info".into(),
+        std::io::sink,
+        tracer,
+    ));
+
+    let app = test::init_service(
+        App::new()
+            .wrap(TracingLogger::default())
+            .route("/traced", web::get().to(traced)),
+    )
+    .await;
+    let request = test::TestRequest::get()
+        .uri("/traced")
+        .insert_header((
+            "traceparent",
+            format!("00-{}-00f067aa0ba902b7-01", TRACE_ID),
+        ))
+        .to_request();
+    assert!(test::call_service(&app, request)
+        .await
+        .status()
+        .is_success());
+    shutdown_tracing().await;
+
+    let exported: Vec<u8> = collector
+        .received_requests()
+        .await
+        .unwrap()
+        .into_iter()
+        .flat_map(|request| request.body)
+        .collect();
+    assert!(contains(&exported, &hex::decode(TRACE_ID).unwrap()));
+    assert!(contains(&exported, b"Traced handler"));
+    assert!(contains(&exported, b"nostr-vault-test"));
+}
+
+#[test]
+fn no_tracer_without_an_endpoint() {
+    let tracer = otlp_tracer(&TelemetrySettings::default()).unwrap();
+
+    assert!(tracer.is_none());
+}